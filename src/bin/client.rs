@@ -1,28 +1,162 @@
-use anyhow::{bail, Result};
-use bincode;
-use cloud_p2p_project::{lsb, CombinedPayload, ImagePermissions};
-use clap::{Parser, Subcommand};
-use std::collections::HashMap;
+use anyhow::{bail, Context, Result};
+use cloud_p2p_project::{app_address, capped_backoff, decode_payload, find_leader, load_server_list, lsb, negotiate_protocol, query_log_consistency, query_peer_latency, query_status, set_single_port, single_port, BadRequest, CombinedPayload, ImagePermissions, LoadBalancingMessage, LogVerdict, RaftStatus, ServerRole, gunzip_frame, gzip_if_smaller, BATCH_ITEM_OK, BATCH_MARKER, COMPRESSED_MARKER, UNIFIED_OVERRIDE_MARKER, FRAME_GZIP, FRAME_RAW, MAX_BATCH_SIZE, MUX_CLIENT, MAX_NOTE_LEN, MAX_VIEW_TOKENS, TokenStatus};
+use clap::{Parser, Subcommand, ValueEnum};
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
+use image::imageops::FilterType;
+use image::ImageFormat;
 use std::fs;
-use std::io::{Read, Write};
-use std::net::TcpStream;
+use std::io::{Cursor, IsTerminal, Read, Write};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
 use std::os::unix::io::AsRawFd;
-use std::path::PathBuf;
-use std::time::Duration;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use std::thread;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex};
 
 const ENCRYPTED_OUTPUT_IMAGE: &str = "encrypted_lsb_image.png";
 const VIEWABLE_OUTPUT_IMAGE: &str = "viewable_image.png";
+const DEFAULT_JPEG_QUALITY: u8 = 85;
 const SERVER_CONFIG_FILE: &str = "servers.conf";
+const LEADER_CACHE_FILE: &str = ".leader_cache";
+const STATUS_QUERY_TIMEOUT: Duration = Duration::from_secs(2);
+// Covers a node's pings to a peer that has stopped answering
+const PING_QUERY_TIMEOUT: Duration = Duration::from_secs(20);
+// Request bodies are written in pieces this size, checking for an early reply in between
+const REQUEST_CHUNK: usize = 256 * 1024;
+
+const EXIT_CODES_HELP: &str = "\
+Exit codes:
+  0  success
+  1  any other failure
+  2  no leader or no quorum yet (worth retrying later)
+  3  no server could be reached
+  4  the image is too small to hold the payload
+  5  invalid input: bad arguments, an unreadable file or a request the servers refuse";
+
+/// What went wrong, as far as a script deciding whether to retry cares.
+/// The discriminant is the process exit code.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Failure {
+    NoLeader = 2,
+    Network = 3,
+    Capacity = 4,
+    InvalidInput = 5,
+}
+
+/// An error that sets the client's exit code
+#[derive(Debug)]
+struct ClientError {
+    failure: Failure,
+    message: String,
+}
+
+impl std::fmt::Display for ClientError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for ClientError {}
+
+fn fail(failure: Failure, message: impl Into<String>) -> anyhow::Error {
+    ClientError { failure, message: message.into() }.into()
+}
+
+/// The exit code for an error: the first ClientError or BadRequest in its chain decides, 1 otherwise
+fn exit_code(e: &anyhow::Error) -> i32 {
+    for cause in e.chain() {
+        if let Some(err) = cause.downcast_ref::<ClientError>() {
+            return err.failure as i32;
+        }
+        if let Some(bad) = cause.downcast_ref::<BadRequest>() {
+            return bad_request_failure(bad) as i32;
+        }
+    }
+    1
+}
 
 #[derive(Parser)]
-#[command(version, about, long_about = None)]
+#[command(version, about, long_about = None, after_help = EXIT_CODES_HELP)]
 struct Cli {
+    /// Rewrite servers.conf with the leader's view of the cluster if they differ
+    #[arg(long, global = true)]
+    refresh_servers: bool,
+
+    /// Multicast attempts per image before giving up
+    #[arg(long, global = true, default_value = "5", value_parser = clap::value_parser!(u32).range(1..))]
+    max_attempts: u32,
+
+    /// Stop retrying an image after this many seconds (no limit by default)
+    #[arg(long, global = true)]
+    deadline: Option<u64>,
+
+    /// Wait before the first retry of an image (milliseconds); each later retry
+    /// waits twice as long as the one before, up to --max-backoff-ms
+    #[arg(long, global = true, default_value = "2000", value_name = "MS")]
+    retry_backoff_ms: u64,
+
+    /// Longest wait between retries (milliseconds). The default equals the base
+    /// wait, so every retry waits 2 s unless this is raised
+    #[arg(long, global = true, default_value = "2000", value_name = "MS")]
+    max_backoff_ms: u64,
+
+    /// Gzip encrypt requests and accept gzipped replies where that makes them
+    /// smaller (batches and redirected requests are always sent raw)
+    #[arg(long, global = true)]
+    compress: bool,
+
+    /// The servers run with --single-port: reach Raft and status on the application port
+    #[arg(long, global = true)]
+    single_port: bool,
+
+    /// Owner secret that signs the permissions (an HMAC-SHA256 over owner, quotas,
+    /// note, cooldown and last views) on encrypt and every rewrite, and that `view`
+    /// checks before honoring quotas. The signature is symmetric: every viewer of a
+    /// signed image needs this same key, since each view rewrites the quotas and
+    /// signs them again, and anyone holding it can also sign changed quotas. A
+    /// signed image can't be viewed or changed without the key, and with the key an
+    /// unsigned image is refused by `view`, so stripping the signature is caught
+    /// too; a viewer who never passes it can't tell a stripped image from one that
+    /// was never signed. The key shows up in the process list, keep it off shared hosts
+    #[arg(long, global = true, value_name = "KEY", value_parser = clap::builder::NonEmptyStringValueParser::new())]
+    sign_key: Option<String>,
+
     #[command(subcommand)]
     command: Commands,
 }
 
+/// How --auto-denied derives the access-denied image from the carrier
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum DeniedStyle {
+    /// A heavily blurred, downscaled copy
+    Blur,
+    /// A downscaled copy made of large blocks
+    Pixelate,
+    /// A plain downscaled copy
+    Thumbnail,
+}
+
+/// File format `view` writes its output image in
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+enum ViewFormat {
+    Png,
+    /// Lossy, see --jpeg-quality; fine since the viewable image carries no payload
+    Jpeg,
+    Bmp,
+}
+
+impl ViewFormat {
+    fn extension(self) -> &'static str {
+        match self {
+            ViewFormat::Png => "png",
+            ViewFormat::Jpeg => "jpg",
+            ViewFormat::Bmp => "bmp",
+        }
+    }
+}
+
 #[derive(Subcommand)]
 enum Commands {
     /// Encrypt an image by multicasting to all servers
@@ -34,6 +168,112 @@ enum Commands {
         /// The user who owns this image
         #[arg(short, long)]
         owner: String,
+
+        /// A note shown to every viewer, even when access is denied
+        #[arg(long, value_parser = parse_note)]
+        note: Option<String>,
+
+        /// Minimum seconds between two counted views by the same user
+        #[arg(long, value_name = "SECS")]
+        view_cooldown: Option<u64>,
+
+        /// Also issue this many one-time view tokens, printed once the image is
+        /// saved. Each lets whoever presents it (view --token) view the image once
+        #[arg(long, value_name = "N", value_parser = clap::value_parser!(u32).range(1..=MAX_VIEW_TOKENS as i64))]
+        tokens: Option<u32>,
+
+        /// Seconds the --tokens stay valid, counted from now (no limit if not given)
+        #[arg(long, value_name = "SECS", requires = "tokens")]
+        token_ttl: Option<u64>,
+
+        /// Upscale the image if it is too small to hold the payload
+        #[arg(long)]
+        autofit: bool,
+
+        /// The unified image the servers embed, used by --autofit to size the payload
+        #[arg(long, default_value = "unified_image.png", requires = "autofit", conflicts_with = "auto_denied")]
+        unified_image: PathBuf,
+
+        /// Generate the access-denied image from the input and send it with the
+        /// request, instead of the servers embedding their unified image
+        #[arg(long, value_enum, value_name = "STYLE")]
+        auto_denied: Option<DeniedStyle>,
+
+        /// Longest side, in pixels, of the image generated by --auto-denied
+        #[arg(long, default_value = "64", value_name = "PX", requires = "auto_denied",
+              value_parser = clap::value_parser!(u32).range(8..=1024))]
+        denied_size: u32,
+
+        /// Encrypt the input even if it is already protected, overwriting its payload
+        #[arg(long)]
+        force: bool,
+
+        /// Re-embed the payload the servers return as N copies, one per tile of the
+        /// image and each with its own CRC, so it survives damage to part of the
+        /// image. Every tile must hold the whole payload; views keep the layout
+        #[arg(long, value_name = "N", value_parser = clap::value_parser!(u32).range(2..=lsb::MAX_REDUNDANT_COPIES as i64))]
+        copies: Option<u32>,
+
+        /// Re-embed the payload the servers return as Reed-Solomon shards with this
+        /// many parity shards per data shard, in (0, 1], so a few flipped bits (e.g.
+        /// from an editor touching the pixels) are repaired when it is read
+        #[arg(long, value_name = "RATIO", value_parser = parse_parity, conflicts_with = "copies")]
+        parity: Option<f32>,
+
+        /// With --autofit, spread the payload over the input and these extra carriers
+        /// instead of upscaling the input: every carrier keeps its size and gets one
+        /// fragment, saved as encrypted_lsb_image.png, encrypted_lsb_image.1.png and
+        /// so on. View them together with `view --fragment` (repeatable)
+        #[arg(long, value_name = "CARRIER", requires = "autofit", conflicts_with_all = ["copies", "parity"])]
+        spill: Vec<PathBuf>,
+
+        /// The one server to send the request to, with --force-direct
+        #[arg(long, value_name = "HOST:PORT", requires = "force_direct")]
+        server: Option<SocketAddr>,
+
+        /// Send the request to --server only, for debugging a single node: no leader
+        /// discovery or retries, and its answer (NOT_LEADER included) is reported as-is
+        #[arg(long, requires = "server")]
+        force_direct: bool,
+    },
+    /// Encrypt every image in a directory
+    EncryptDir {
+        /// Directory of images to encrypt
+        #[arg(long)]
+        input_dir: PathBuf,
+
+        /// The user who owns these images
+        #[arg(short, long)]
+        owner: String,
+
+        /// Grant a user views as user=N (repeatable; defaults to the same grants as encrypt)
+        #[arg(long, value_parser = parse_grant)]
+        grant: Vec<(String, u32)>,
+
+        /// A note shown to every viewer, even when access is denied
+        #[arg(long, value_parser = parse_note)]
+        note: Option<String>,
+
+        /// Minimum seconds between two counted views by the same user
+        #[arg(long, value_name = "SECS")]
+        view_cooldown: Option<u64>,
+
+        /// Directory to write the encrypted images to
+        #[arg(long, default_value = "encrypted")]
+        output_dir: PathBuf,
+
+        /// Maximum number of images encrypted at the same time
+        #[arg(long, default_value = "1", value_parser = clap::value_parser!(u16).range(1..))]
+        parallel: u16,
+
+        /// Send up to this many images per request on one connection to the leader
+        #[arg(long, default_value = "1", value_parser = clap::value_parser!(u32).range(1..=MAX_BATCH_SIZE as i64))]
+        batch: u32,
+
+        /// Also encrypt images that are already protected, overwriting their payloads
+        /// (they are skipped otherwise)
+        #[arg(long)]
+        force: bool,
     },
     /// View a protected image, acting as a peer
     View {
@@ -42,19 +282,226 @@ enum Commands {
         input: PathBuf,
 
         /// The user who is trying to view the image
+        #[arg(short, long, required_unless_present = "token")]
+        user: Option<String>,
+
+        /// View with a one-time token from `encrypt --tokens` instead of a user's quota.
+        /// The token is used up, unless with --preview
+        #[arg(long, conflicts_with = "user")]
+        token: Option<String>,
+
+        /// Produce the image the user would see without spending a view or touching the file
+        #[arg(long)]
+        preview: bool,
+
+        /// Write the viewable image to this FIFO or open file descriptor number
+        /// instead of viewable_image.png, e.g. for a viewer reading it directly
+        #[arg(long, value_name = "FIFO|FD")]
+        pipe: Option<String>,
+
+        /// Format of the image written, for both the viewable and the access-denied
+        /// image (the file is named viewable_image.jpg or .bmp to match)
+        #[arg(long, value_enum, default_value_t = ViewFormat::Png)]
+        output_format: ViewFormat,
+
+        /// JPEG quality for --output-format jpeg
+        #[arg(long, value_parser = clap::value_parser!(u8).range(1..=100))]
+        jpeg_quality: Option<u8>,
+
+        /// Another fragment of a payload spread over several images by `encrypt
+        /// --spill` (repeatable, any order). A view rewrites every fragment
+        #[arg(long, value_name = "FILE")]
+        fragment: Vec<PathBuf>,
+    },
+    /// Remove a user's access from a protected image (owner only)
+    Revoke {
+        /// The protected image file to modify
+        #[arg(short, long)]
+        input: PathBuf,
+
+        /// The user whose access is revoked
+        #[arg(short, long)]
+        user: String,
+
+        /// The owner of the image, must match the embedded owner
+        #[arg(short, long)]
+        owner: String,
+    },
+    /// Give a user more views of a protected image (owner only)
+    Topup {
+        /// The protected image file to modify
+        #[arg(short, long)]
+        input: PathBuf,
+
+        /// The user who gets the extra views (granted access if they had none)
         #[arg(short, long)]
         user: String,
+
+        /// Views to add to the user's remaining views
+        #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+        add: u32,
+
+        /// The owner of the image, must match the embedded owner
+        #[arg(short, long)]
+        owner: String,
+    },
+    /// Replace every grant on a protected image offline, keeping the carrier
+    /// and the embedded unified image (owner only)
+    Rehydrate {
+        /// The protected image file to modify
+        #[arg(short, long)]
+        input: PathBuf,
+
+        /// The owner of the image, must match the embedded owner
+        #[arg(short, long)]
+        owner: String,
+
+        /// Grant a user views as user=N (repeatable); users not listed lose access
+        #[arg(long, value_parser = parse_grant, required = true)]
+        grant: Vec<(String, u32)>,
+
+        /// Replace the note shown to every viewer (the existing note is kept otherwise)
+        #[arg(long, value_parser = parse_note, conflicts_with = "clear_note")]
+        note: Option<String>,
+
+        /// Remove the existing note
+        #[arg(long)]
+        clear_note: bool,
+
+        /// Replace the view cooldown in seconds, 0 removes it (the existing one is kept otherwise)
+        #[arg(long, value_name = "SECS")]
+        view_cooldown: Option<u64>,
+    },
+    /// Check that every node's log matches the committed log of one node
+    VerifyLog {
+        /// The node whose committed log is compared (defaults to the leader)
+        #[arg(long)]
+        server: Option<String>,
+    },
+    /// Measure round-trip latency between each node and its peers
+    Ping {
+        /// Only ask this server (defaults to every server in servers.conf)
+        #[arg(long)]
+        server: Option<String>,
+    },
+    /// Print every server's Raft view as a table, flagging nodes that disagree
+    Status,
+    /// Compare the permissions embedded in two protected images
+    Diff {
+        /// First protected image
+        #[arg(long)]
+        a: PathBuf,
+
+        /// Second protected image
+        #[arg(long)]
+        b: PathBuf,
+    },
+    /// List the images in a directory that carry a payload, with its size, reading
+    /// only each image's length header (the payloads themselves aren't checked)
+    Inspect {
+        /// Directory of images to check
+        #[arg(long)]
+        input_dir: PathBuf,
+    },
+    /// Encrypt one image repeatedly and report end-to-end latency and throughput
+    Bench {
+        /// The image to encrypt on every iteration
+        #[arg(short, long)]
+        input: PathBuf,
+
+        /// How many times to encrypt it
+        #[arg(long, default_value = "10", value_parser = clap::value_parser!(u32).range(1..))]
+        iterations: u32,
+
+        /// The owner recorded in the permissions sent
+        #[arg(short, long, default_value = "bench")]
+        owner: String,
+
+        /// Save the last encrypted image to encrypted_lsb_image.png, to check it with `view`
+        #[arg(long)]
+        keep: bool,
     },
 }
 
-fn main() -> Result<()> {
-    let cli = Cli::parse();
+fn main() {
+    let cli = match Cli::try_parse() {
+        Ok(cli) => cli,
+        Err(e) => {
+            // --help and --version come through here too, and aren't failures
+            let code = if e.use_stderr() { Failure::InvalidInput as i32 } else { 0 };
+            let _ = e.print();
+            std::process::exit(code);
+        }
+    };
+
+    if let Err(e) = run(&cli) {
+        eprintln!("Error: {:?}", e);
+        std::process::exit(exit_code(&e));
+    }
+}
+
+fn run(cli: &Cli) -> Result<()> {
+    COMPRESS_TRANSFERS.store(cli.compress, Ordering::Relaxed);
+    set_single_port(cli.single_port);
+    let sign_key = cli.sign_key.as_deref().map(str::as_bytes);
     match &cli.command {
-        Commands::Encrypt { ref input, ref owner } => {
-            handle_encrypt(input, owner)?;
+        Commands::Encrypt { ref input, ref owner, ref note, view_cooldown, tokens, token_ttl, autofit, ref unified_image, auto_denied, denied_size, force, copies, parity, ref spill, server, force_direct: _ } => {
+            let autofit = autofit.then_some(unified_image.as_path());
+            let auto_denied = auto_denied.map(|style| (style, *denied_size));
+            let tokens = tokens.map(|count| (count, *token_ttl));
+            // clap accepts only one of --copies, --parity and --spill
+            let output = match (copies, parity) {
+                (Some(copies), _) => EncryptOutput::Layout(lsb::Layout::Redundant(*copies as usize)),
+                (_, Some(parity)) => EncryptOutput::Layout(lsb::Layout::Ecc(*parity)),
+                _ if !spill.is_empty() => EncryptOutput::Spill(spill),
+                _ => EncryptOutput::AsReturned,
+            };
+            // clap only accepts --server together with --force-direct
+            let direct = server.map(|addr| addr.to_string());
+            handle_encrypt(input, owner, note.as_deref(), *view_cooldown, tokens, sign_key, autofit, auto_denied, *force, output, direct.as_deref(), cli.refresh_servers, &RetryPolicy::from_cli(cli))?;
+        }
+        Commands::EncryptDir { ref input_dir, ref owner, ref grant, ref note, view_cooldown, ref output_dir, parallel, batch, force } => {
+            handle_encrypt_dir(input_dir, owner, grant, note.as_deref(), *view_cooldown, sign_key, output_dir, *parallel as usize, *batch as usize, *force, cli.refresh_servers, &RetryPolicy::from_cli(cli))?;
+        }
+        Commands::View { ref input, ref user, ref token, preview, ref pipe, output_format, jpeg_quality, ref fragment } => {
+            if jpeg_quality.is_some() && *output_format != ViewFormat::Jpeg {
+                bail!("--jpeg-quality only applies to --output-format jpeg");
+            }
+            let encoding = ViewEncoding { format: *output_format, jpeg_quality: jpeg_quality.unwrap_or(DEFAULT_JPEG_QUALITY) };
+            // clap requires exactly one of --user and --token
+            let viewer = match (user, token) {
+                (_, Some(token)) => Viewer::Token(token),
+                (Some(user), None) => Viewer::User(user),
+                (None, None) => unreachable!(),
+            };
+            handle_view(input, fragment, viewer, *preview, pipe.as_deref(), encoding, sign_key)?;
+        }
+        Commands::Revoke { ref input, ref user, ref owner } => {
+            handle_revoke(input, user, owner, sign_key)?;
+        }
+        Commands::Topup { ref input, ref user, add, ref owner } => {
+            handle_topup(input, user, *add, owner, sign_key)?;
+        }
+        Commands::Rehydrate { ref input, ref owner, ref grant, ref note, clear_note, view_cooldown } => {
+            handle_rehydrate(input, owner, grant, note.as_deref(), *clear_note, *view_cooldown, sign_key)?;
+        }
+        Commands::VerifyLog { ref server } => {
+            handle_verify_log(server.as_deref())?;
+        }
+        Commands::Ping { ref server } => {
+            handle_ping(server.as_deref())?;
+        }
+        Commands::Status => {
+            handle_status()?;
+        }
+        Commands::Diff { ref a, ref b } => {
+            handle_diff(a, b)?;
         }
-        Commands::View { ref input, ref user } => {
-            handle_view(input, user)?;
+        Commands::Inspect { ref input_dir } => {
+            handle_inspect(input_dir)?;
+        }
+        Commands::Bench { ref input, iterations, ref owner, keep } => {
+            handle_bench(input, *iterations, owner, *keep, sign_key, cli.refresh_servers, &RetryPolicy::from_cli(cli))?;
         }
     }
 
@@ -65,28 +512,41 @@ fn main() -> Result<()> {
 // --- ROLE 1: ENCRYPTOR with TRUE MULTICAST + FAULT TOLERANCE ---
 // -------------------------------------------------------------------
 
-/// Reads server addresses from the config file
-fn load_servers() -> Result<Vec<String>> {
-    let content = fs::read_to_string(SERVER_CONFIG_FILE)?;
-    let servers: Vec<String> = content
-        .lines()
-        .filter(|&s| !s.is_empty())
-        .map(String::from)
-        .collect();
-    if servers.is_empty() {
-        bail!("No servers found in '{}'", SERVER_CONFIG_FILE);
-    }
-    Ok(servers)
-}
-
 #[derive(Debug, Clone)]
 enum ServerResponse {
-    Success(Vec<u8>),           // Got encrypted image
+    Success(Vec<u8>, Option<u64>), // Got encrypted image, with committed log index if reported
     NotLeader(String),          // Server is not leader, with leader hint
     NoLeader,                   // No leader elected yet
+    NotCommitted(String),       // Leader couldn't commit the request (lost quorum)
+    Rejected(BadRequest),       // The request itself is bad, retrying won't help
+    Unavailable(String),        // Leader is turning work away until an operator fixes it
     ConnectionFailed(String),   // Network error or timeout
 }
 
+/// Limits on retrying one image across leader changes
+struct RetryPolicy {
+    max_attempts: u32,
+    deadline: Option<Duration>, // measured from the first attempt for the image
+    backoff: Duration,          // wait before the first retry, doubled for each one after
+    max_backoff: Duration,
+}
+
+impl RetryPolicy {
+    fn from_cli(cli: &Cli) -> Self {
+        Self {
+            max_attempts: cli.max_attempts,
+            deadline: cli.deadline.map(Duration::from_secs),
+            backoff: Duration::from_millis(cli.retry_backoff_ms),
+            max_backoff: Duration::from_millis(cli.max_backoff_ms),
+        }
+    }
+
+    /// How long to wait before `attempt` (2 for the first retry)
+    fn wait_before(&self, attempt: u32) -> Duration {
+        capped_backoff(self.backoff, attempt.saturating_sub(2), self.max_backoff)
+    }
+}
+
 /// Configure TCP socket for large file transfers
 fn configure_tcp_socket(stream: &TcpStream) -> Result<()> {
     let raw_fd = stream.as_raw_fd();
@@ -120,169 +580,937 @@ fn configure_tcp_socket(stream: &TcpStream) -> Result<()> {
     Ok(())
 }
 
-fn handle_encrypt(input_path: &PathBuf, owner: &String) -> Result<()> {
+/// `autofit` is the unified image to size the payload with when the carrier
+/// may be upscaled to fit it. `auto_denied` generates the unified image from
+/// the input instead, at the given size, and sends it with the request. An
+/// input that is already protected is refused unless `force` is set.
+#[allow(clippy::too_many_arguments)]
+fn handle_encrypt(input_path: &PathBuf, owner: &str, note: Option<&str>, view_cooldown: Option<u64>, tokens: Option<(u32, Option<u64>)>, sign_key: Option<&[u8]>, autofit: Option<&Path>, auto_denied: Option<(DeniedStyle, u32)>, force: bool, output: EncryptOutput, direct: Option<&str>, refresh_servers: bool, policy: &RetryPolicy) -> Result<()> {
     println!("=== Encryptor Mode (Multicast with Fault Tolerance) ===");
 
-    // 1. Load server list
-    let servers = load_servers()?;
-    println!("Loaded {} servers from '{}'", servers.len(), SERVER_CONFIG_FILE);
+    // 1. Load server list, unless the request goes to one named server
+    let servers = match direct {
+        Some(_) => Vec::new(),
+        None => {
+            let servers = load_server_list(SERVER_CONFIG_FILE).map_err(|e| fail(Failure::InvalidInput, format!("{:#}", e)))?;
+            println!("Loaded {} servers from '{}'", servers.len(), SERVER_CONFIG_FILE);
+            check_server_list(servers, refresh_servers)?
+        }
+    };
 
     // 2. Prepare metadata and image
-    let img_buf = fs::read(input_path)?;
+    let img_buf = fs::read(input_path)
+        .map_err(|e| fail(Failure::InvalidInput, format!("Cannot read '{}': {}", input_path.display(), e)))?;
     println!("Read '{}' ({} bytes = {:.2} MB)", 
              input_path.display(), 
              img_buf.len(),
              img_buf.len() as f64 / 1_048_576.0);
 
-    let mut quotas = HashMap::new();
-    quotas.insert(owner.clone(), 3);
-    quotas.insert("alice".to_string(), 2);
-    quotas.insert("bob".to_string(), 1);
+    if let Some(existing) = existing_protection(&img_buf) {
+        if !force {
+            return Err(fail(Failure::InvalidInput, format!(
+                "'{}' is already protected (owner '{}'); encrypting it again would overwrite its payload. \
+                 Pass --force to do it anyway", input_path.display(), existing.permissions.owner)));
+        }
+        println!("⚠ --force: '{}' is already protected (owner '{}'), its payload will be overwritten",
+                 input_path.display(), existing.permissions.owner);
+    }
 
-    let permissions = ImagePermissions {
-        owner: owner.clone(),
-        quotas,
+    let mut permissions = build_permissions(owner, &[], note, view_cooldown);
+    // Issued before signing, so the signature covers them
+    let mut issued_tokens = Vec::new();
+    if let Some((count, ttl)) = tokens {
+        let expires_at = match ttl {
+            Some(secs) => Some(SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs().saturating_add(secs)),
+            None => None,
+        };
+        issued_tokens = (0..count).map(|_| permissions.issue_token(expires_at)).collect();
+    }
+    if let Some(key) = sign_key {
+        permissions.sign(key)?;
+    }
+    let meta_bytes = permissions.to_bytes()?;
+
+    let denied_image = match auto_denied {
+        Some((style, size)) => Some(generate_denied_image(&img_buf, style, size)?),
+        None => None,
     };
-    let meta_bytes = bincode::serialize(&permissions)?;
 
-    // 3. MULTICAST with retry logic for leader failures
-    println!("\n=== MULTICASTING to all {} servers ===", servers.len());
-    
-    let max_attempts = 5;  // More attempts for fault tolerance
-    let mut attempt = 0;
-    
-    while attempt < max_attempts {
-        attempt += 1;
-        
-        if attempt > 1 {
-            println!("\n=== ATTEMPT {} of {} ===", attempt, max_attempts);
-            println!("Waiting 2 seconds before retry...");
-            thread::sleep(Duration::from_secs(2));
-        } else {
-            println!("\n=== ATTEMPT {} of {} ===", attempt, max_attempts);
+    // The input itself is kept for --spill, which only uses the fitted carrier
+    // to have the servers build the payload
+    let fitted_buf = match autofit {
+        Some(unified_image_path) => {
+            let unified_image = match &denied_image {
+                Some(png) => png.clone(),
+                None => fs::read(unified_image_path).map_err(|e| {
+                    fail(Failure::InvalidInput, format!("--autofit needs the unified image, cannot read '{}': {}", unified_image_path.display(), e))
+                })?,
+            };
+            let payload = CombinedPayload { permissions, unified_image }.to_bytes()?;
+            Some(autofit_carrier(&img_buf, &payload)?)
         }
+        None => None,
+    };
+    let request_buf = fitted_buf.as_deref().unwrap_or(&img_buf);
 
-        // Perform multicast and collect responses
-        let responses = multicast_to_servers(&servers, &meta_bytes, &img_buf);
-        
-        // Analyze responses
-        let mut success_response = None;
-        let mut not_leader_count = 0;
-        let mut no_leader_count = 0;
-        let mut connection_failed_count = 0;
-        let mut leader_might_have_failed = false;
-
-        for (server_addr, response) in &responses {
-            match response {
-                ServerResponse::Success(image_data) => {
-                    println!("  ✓ SUCCESS from {}", server_addr);
-                    success_response = Some(image_data.clone());
-                    break;
-                }
-                ServerResponse::NotLeader(hint) => {
-                    println!("  ✗ {} is NOT_LEADER (hint: {})", server_addr, hint);
-                    not_leader_count += 1;
-                }
-                ServerResponse::NoLeader => {
-                    println!("  ✗ {} says NO_LEADER (election in progress)", server_addr);
-                    no_leader_count += 1;
-                }
-                ServerResponse::ConnectionFailed(reason) => {
-                    println!("  ✗ {} connection failed: {}", server_addr, reason);
-                    connection_failed_count += 1;
-                }
-            }
+    // 3. MULTICAST with retry logic for leader failures, starting with the cached leader
+    let encrypted_image = match direct {
+        Some(server) => encrypt_direct(server, &meta_bytes, request_buf, denied_image.as_deref())?,
+        None => {
+            let leader_hint = Mutex::new(load_cached_leader());
+            let result = encrypt_with_retries(&servers, &meta_bytes, request_buf, denied_image.as_deref(), &leader_hint, policy);
+            save_cached_leader(leader_hint.lock().unwrap().as_deref());
+            result?
         }
+    };
 
-        // Check if we got success
-        if let Some(encrypted_image) = success_response {
-            println!("\n=== ✓ ENCRYPTION SUCCESSFUL ===");
-            println!("Received encrypted image ({} bytes = {:.2} MB)", 
-                     encrypted_image.len(),
-                     encrypted_image.len() as f64 / 1_048_576.0);
-            
-            fs::write(ENCRYPTED_OUTPUT_IMAGE, &encrypted_image)?;
+    match output {
+        EncryptOutput::Spill(extra) => {
+            let paths = spill_encrypted_output(&encrypted_image, &img_buf, extra, Path::new(ENCRYPTED_OUTPUT_IMAGE))?;
+            let names: Vec<String> = paths.iter().map(|path| format!("'{}'", path.display())).collect();
+            println!("Saved the payload as {} fragments: {}", paths.len(), names.join(", "));
+            println!("  Pass the others to `view --input {}` with --fragment", paths[0].display());
+        }
+        EncryptOutput::Layout(layout) => {
+            write_encrypted_output(Path::new(ENCRYPTED_OUTPUT_IMAGE), &relayout(&encrypted_image, layout)?)?;
             println!("Saved encrypted image to '{}'", ENCRYPTED_OUTPUT_IMAGE);
-            
-            return Ok(());
         }
-
-        // Analyze failure reasons
-        println!("\n--- Response Summary ---");
-        println!("  NOT_LEADER responses: {}", not_leader_count);
-        println!("  NO_LEADER responses: {}", no_leader_count);
-        println!("  Connection failures: {}", connection_failed_count);
-
-        // Detect if leader might have failed
-        if not_leader_count > 0 && connection_failed_count > 0 {
-            // Some servers said "not leader" and one failed to respond
-            // The failed one might have been the leader
-            leader_might_have_failed = true;
-            println!("\n⚠ Detected possible LEADER FAILURE!");
-            println!("  → Some servers identified a leader, but it didn't respond");
-            println!("  → Raft should elect a new leader...");
-        } else if no_leader_count == servers.len() {
-            // All servers say no leader - election in progress
-            println!("\n⚠ No leader currently elected");
-            println!("  → Waiting for Raft election to complete...");
-        } else if connection_failed_count == servers.len() {
-            // All servers unreachable - network problem
-            println!("\n⚠ All servers unreachable - network issue?");
-            bail!("Cannot connect to any server");
-        } else if not_leader_count == servers.len() {
-            // All servers say they're not leader - inconsistent state
-            println!("\n⚠ All servers claim to NOT be leader");
-            println!("  → This shouldn't happen! Waiting for re-election...");
+        EncryptOutput::AsReturned => {
+            write_encrypted_output(Path::new(ENCRYPTED_OUTPUT_IMAGE), &encrypted_image)?;
+            println!("Saved encrypted image to '{}'", ENCRYPTED_OUTPUT_IMAGE);
         }
+    }
 
-        if attempt < max_attempts {
-            if leader_might_have_failed {
-                println!("  → Will retry after new leader election...");
-            } else if no_leader_count > 0 {
-                println!("  → Will retry once election completes...");
-            } else {
-                println!("  → Will retry multicast...");
-            }
+    // Only their hashes are embedded, so this is the one chance to see them
+    if !issued_tokens.is_empty() {
+        match tokens.and_then(|(_, ttl)| ttl) {
+            Some(secs) => println!("One-time view tokens, each good for one view within {}s:", secs),
+            None => println!("One-time view tokens, each good for one view:"),
+        }
+        for token in &issued_tokens {
+            println!("  {}", token);
         }
     }
 
-    bail!("Failed to encrypt image after {} attempts. Possible reasons: leader keeps failing, network issues, or cluster unstable", max_attempts)
+    Ok(())
 }
 
-/// Multicast request to all servers and collect responses
-fn multicast_to_servers(
-    servers: &[String],
-    meta_bytes: &[u8],
-    img_buf: &[u8],
-) -> Vec<(String, ServerResponse)> {
-    println!("Multicasting to all servers simultaneously...");
-    
-    // Shared storage for responses
-    let responses: Arc<Mutex<Vec<(String, ServerResponse)>>> = Arc::new(Mutex::new(Vec::new()));
-    let mut thread_handles = vec![];
+/// Encrypt one image `iterations` times through the same path as `encrypt`,
+/// and print each iteration's time and a latency summary. The leader found by
+/// the first iteration is reused by the rest, so later iterations measure one
+/// request to the leader rather than a multicast.
+fn handle_bench(input_path: &Path, iterations: u32, owner: &str, keep: bool, sign_key: Option<&[u8]>, refresh_servers: bool, policy: &RetryPolicy) -> Result<()> {
+    println!("=== Benchmark ===");
 
-    // Spawn thread for each server
-    for server_addr in servers {
-        let meta_clone = meta_bytes.to_vec();
-        let img_clone = img_buf.to_vec();
-        let responses_clone = Arc::clone(&responses);
-        let addr_clone = server_addr.clone();
+    let servers = load_server_list(SERVER_CONFIG_FILE).map_err(|e| fail(Failure::InvalidInput, format!("{:#}", e)))?;
+    let servers = check_server_list(servers, refresh_servers)?;
+    let img_buf = fs::read(input_path)
+        .map_err(|e| fail(Failure::InvalidInput, format!("Cannot read '{}': {}", input_path.display(), e)))?;
+    println!("Encrypting '{}' ({} bytes) {} times against {} servers",
+             input_path.display(), img_buf.len(), iterations, servers.len());
 
-        let handle = thread::spawn(move || {
-            println!("  [Thread-{}] Connecting...", addr_clone);
-            
-            let response = match send_multicast_request(&addr_clone, &meta_clone, &img_clone) {
-                Ok(image_data) => {
-                    println!("  [Thread-{}] ✓ Got encrypted image!", addr_clone);
-                    ServerResponse::Success(image_data)
-                }
-                Err(e) => {
-                    let err_msg = e.to_string();
+    let leader_hint = Mutex::new(load_cached_leader());
+    let mut times = Vec::with_capacity(iterations as usize);
+    let mut last_output = Vec::new();
+    let bench_start = Instant::now();
+    for iteration in 1..=iterations {
+        // Servers return a cached result for a repeated request, so give each
+        // iteration its own permissions version to measure a real encryption
+        let mut permissions = build_permissions(owner, &[], None, None);
+        permissions.version = iteration as u64;
+        if let Some(key) = sign_key {
+            permissions.sign(key)?;
+        }
+        let meta_bytes = permissions.to_bytes()?;
+
+        let start = Instant::now();
+        let result = encrypt_with_retries(&servers, &meta_bytes, &img_buf, None, &leader_hint, policy);
+        let elapsed = start.elapsed();
+        last_output = result.with_context(|| format!("Iteration {} of {} failed", iteration, iterations))?;
+        println!("[{}/{}] {:.1} ms ({} bytes back)", iteration, iterations, elapsed.as_secs_f64() * 1000.0, last_output.len());
+        times.push(elapsed);
+    }
+    let total = bench_start.elapsed();
+    save_cached_leader(leader_hint.lock().unwrap().as_deref());
+
+    times.sort();
+    let ms = |d: Duration| d.as_secs_f64() * 1000.0;
+    let percentile = |pct: usize| times[times.len() * pct / 100];
+    let avg = times.iter().sum::<Duration>() / iterations;
+    println!("\n=== Summary ({} iterations in {:.2}s) ===", iterations, total.as_secs_f64());
+    println!("Latency: min {:.1} ms, avg {:.1} ms, p50 {:.1} ms, p99 {:.1} ms, max {:.1} ms",
+             ms(times[0]), ms(avg), ms(percentile(50)), ms(percentile(99)), ms(times[times.len() - 1]));
+    println!("Throughput: {:.2} images/s, {:.2} MB/s sent",
+             iterations as f64 / total.as_secs_f64(),
+             (img_buf.len() as f64 * iterations as f64) / 1_048_576.0 / total.as_secs_f64());
+
+    if keep {
+        write_encrypted_output(Path::new(ENCRYPTED_OUTPUT_IMAGE), &last_output)?;
+        println!("Saved the last encrypted image to '{}'", ENCRYPTED_OUTPUT_IMAGE);
+    }
+    Ok(())
+}
+
+/// What `encrypt` saves of the image the servers return
+#[derive(Clone, Copy)]
+enum EncryptOutput<'a> {
+    /// The image as returned
+    AsReturned,
+    /// Its payload re-embedded with --copies or --parity
+    Layout(lsb::Layout),
+    /// Its payload spread over the input and these --spill carriers
+    Spill(&'a [PathBuf]),
+}
+
+/// Spread the payload of an image the servers returned over the input and the
+/// `extra` carriers, one fragment each, saved as `first` and then `first`
+/// with .1.png, .2.png... Returns the paths written, in fragment order.
+fn spill_encrypted_output(encrypted_image: &[u8], input_buf: &[u8], extra: &[PathBuf], first: &Path) -> Result<Vec<PathBuf>> {
+    let returned = image::load_from_memory(encrypted_image)?;
+    let payload = lsb::decode_protected(&returned).context("The servers returned an image without a payload")?;
+
+    let mut carriers = vec![image::load_from_memory(input_buf)?];
+    for path in extra {
+        let carrier = image::open(path)
+            .map_err(|e| fail(Failure::InvalidInput, format!("Cannot read the carrier '{}': {}", path.display(), e)))?;
+        carriers.push(carrier);
+    }
+    let fragments = lsb::encode_fragmented(&payload, &carriers).map_err(|e| fail(Failure::InvalidInput, format!("{:#}", e)))?;
+
+    let paths: Vec<PathBuf> = (0..fragments.len())
+        .map(|i| if i == 0 { first.to_path_buf() } else { first.with_extension(format!("{}.png", i)) })
+        .collect();
+    for (path, fragment) in paths.iter().zip(&fragments) {
+        let mut bytes = Vec::new();
+        fragment.write_to(&mut Cursor::new(&mut bytes), ImageFormat::Png)?;
+        write_atomic(path, &bytes)?;
+    }
+    // Read back as a set, so a fragment that didn't land is reported now
+    read_fragmented_images(&paths)?;
+    Ok(paths)
+}
+
+/// Re-embed the payload of an image the servers returned with `layout`, in
+/// the same image format.
+fn relayout(encrypted_image: &[u8], layout: lsb::Layout) -> Result<Vec<u8>> {
+    let format = image::guess_format(encrypted_image)?;
+    let img = image::load_from_memory_with_format(encrypted_image, format)?;
+    let payload = lsb::decode_protected(&img).context("The servers returned an image without a payload")?;
+    let img = lsb::encode_layout(&img, &payload, layout).map_err(|e| fail(Failure::InvalidInput, format!("{:#}", e)))?;
+
+    let mut bytes = Vec::new();
+    img.write_to(&mut Cursor::new(&mut bytes), format)?;
+    Ok(bytes)
+}
+
+/// The payload an input already carries, if it is a protected image. Encrypting
+/// it again would overwrite the payload's length header and lose it.
+fn existing_protection(img_buf: &[u8]) -> Option<CombinedPayload> {
+    let img = image::load_from_memory(img_buf).ok()?;
+    decode_payload(&img).ok().map(|(payload, _)| payload)
+}
+
+/// Build the access-denied image from the carrier itself (--auto-denied) and
+/// report its size and PSNR against the carrier, to help tune --denied-size.
+fn generate_denied_image(img_buf: &[u8], style: DeniedStyle, size: u32) -> Result<Vec<u8>> {
+    let carrier = image::load_from_memory(img_buf)
+        .map_err(|e| fail(Failure::InvalidInput, format!("Cannot read the input as an image: {}", e)))?;
+
+    let small = carrier.thumbnail(size, size);
+    let denied = match style {
+        DeniedStyle::Thumbnail => small,
+        DeniedStyle::Blur => small.blur((size as f32 / 16.0).max(1.0)),
+        DeniedStyle::Pixelate => {
+            // Blocks of 8x8 pixels at the generated size
+            let (width, height) = (small.width(), small.height());
+            small
+                .resize_exact((width / 8).max(1), (height / 8).max(1), FilterType::Triangle)
+                .resize_exact(width, height, FilterType::Nearest)
+        }
+    };
+    let png = png_bytes(&denied)?;
+
+    let capacity = lsb::capacity_bytes(&carrier);
+    let style_name = style.to_possible_value().map(|value| value.get_name().to_string()).unwrap_or_default();
+    println!("--auto-denied {}: {}x{} denied image, {} bytes ({:.1}% of the carrier's {} byte capacity), PSNR {:.1} dB",
+             style_name, denied.width(), denied.height(), png.len(),
+             png.len() as f64 * 100.0 / capacity.max(1) as f64, capacity, psnr(&carrier, &denied));
+    Ok(png)
+}
+
+/// Peak signal-to-noise ratio, in dB, of `approximation` scaled back up to the
+/// size of `original`. Higher means denied users see more of the image.
+fn psnr(original: &image::DynamicImage, approximation: &image::DynamicImage) -> f64 {
+    let original = original.to_rgb8();
+    let restored = approximation
+        .resize_exact(original.width(), original.height(), FilterType::Triangle)
+        .to_rgb8();
+    let squared_error: f64 = original
+        .as_raw()
+        .iter()
+        .zip(restored.as_raw())
+        .map(|(&a, &b)| (a as f64 - b as f64).powi(2))
+        .sum();
+    let mse = squared_error / original.as_raw().len().max(1) as f64;
+    if mse == 0.0 {
+        f64::INFINITY
+    } else {
+        10.0 * (255.0f64 * 255.0 / mse).log10()
+    }
+}
+
+/// Upscale the carrier to the smallest size, keeping its aspect ratio, whose
+/// LSB capacity holds `payload`. Returns the image unchanged if it already fits.
+fn autofit_carrier(img_buf: &[u8], payload: &[u8]) -> Result<Vec<u8>> {
+    let img = image::load_from_memory(img_buf)
+        .map_err(|e| fail(Failure::InvalidInput, format!("Cannot read the input as an image: {}", e)))?;
+    let capacity = lsb::capacity_bytes(&img);
+    if capacity >= payload.len() {
+        return Ok(img_buf.to_vec());
+    }
+
+    // Capacity grows with the pixel count, so scale each side by the square root
+    // of the shortfall, then nudge up in case rounding left it just short
+    let mut scale = ((payload.len() + 4) as f64 / (capacity + 4) as f64).sqrt();
+    let fitted = loop {
+        let width = (img.width() as f64 * scale).ceil() as u32;
+        let height = (img.height() as f64 * scale).ceil() as u32;
+        let resized = img.resize_exact(width, height, FilterType::Lanczos3);
+        if lsb::capacity_bytes(&resized) >= payload.len() {
+            break resized;
+        }
+        scale *= 1.01;
+    };
+
+    // Make sure the payload survives a round trip through the upscaled carrier
+    let decoded = lsb::decode_protected(&lsb::encode(&fitted, payload)?)?;
+    if decoded != payload {
+        bail!("Upscaled carrier did not round-trip the payload");
+    }
+
+    println!("⚠ --autofit: upscaled the image from {}x{} to {}x{} to hold the {} byte payload ({} bytes fit before)",
+             img.width(), img.height(), fitted.width(), fitted.height(), payload.len(), capacity);
+    println!("  The encrypted image will have the new dimensions");
+
+    let mut fitted_buf = Vec::new();
+    fitted.write_to(&mut Cursor::new(&mut fitted_buf), ImageFormat::Png)?;
+    Ok(fitted_buf)
+}
+
+/// The image files in `dir`, sorted. Anything the image crate recognises by
+/// extension is treated as an image; finding none is an error.
+fn image_files(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut files: Vec<PathBuf> = fs::read_dir(dir)
+        .map_err(|e| fail(Failure::InvalidInput, format!("Cannot read '{}': {}", dir.display(), e)))?
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|path| path.is_file() && ImageFormat::from_path(path).is_ok())
+        .collect();
+    files.sort();
+
+    if files.is_empty() {
+        return Err(fail(Failure::InvalidInput, format!("No image files found in '{}'", dir.display())));
+    }
+    Ok(files)
+}
+
+/// Where each of `files` is saved in `output_dir`. Encrypted images are always
+/// PNG, so `photo.jpg` becomes `photo.png`; inputs that share a stem keep their
+/// own extension too (`photo.jpg.png`, `photo.png.png`) rather than overwrite
+/// each other. Names that still collide are refused before anything is sent.
+fn output_paths(files: &[PathBuf], output_dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut stems: HashMap<&std::ffi::OsStr, usize> = HashMap::new();
+    for path in files {
+        *stems.entry(path.file_stem().unwrap_or_default()).or_default() += 1;
+    }
+
+    let outputs: Vec<PathBuf> = files
+        .iter()
+        .map(|path| {
+            let stem = path.file_stem().unwrap_or_default();
+            // Appended rather than set with `with_extension`, which would cut
+            // `my.photo` down to `my`
+            let mut name = if stems[stem] > 1 { path.file_name().unwrap_or_default() } else { stem }.to_os_string();
+            name.push(".png");
+            output_dir.join(name)
+        })
+        .collect();
+
+    let mut seen: HashMap<&Path, &Path> = HashMap::new();
+    for (input, output) in files.iter().zip(&outputs) {
+        if let Some(other) = seen.insert(output, input) {
+            return Err(fail(Failure::InvalidInput, format!(
+                "'{}' and '{}' would both be saved as '{}'; rename one of them",
+                other.display(), input.display(), output.display())));
+        }
+    }
+    Ok(outputs)
+}
+
+#[allow(clippy::too_many_arguments)]
+fn handle_encrypt_dir(
+    input_dir: &Path,
+    owner: &str,
+    grants: &[(String, u32)],
+    note: Option<&str>,
+    view_cooldown: Option<u64>,
+    sign_key: Option<&[u8]>,
+    output_dir: &Path,
+    parallel: usize,
+    batch: usize,
+    force: bool,
+    refresh_servers: bool,
+    policy: &RetryPolicy,
+) -> Result<()> {
+    println!("=== Bulk Encryptor Mode ===");
+
+    let servers = load_server_list(SERVER_CONFIG_FILE).map_err(|e| fail(Failure::InvalidInput, format!("{:#}", e)))?;
+    println!("Loaded {} servers from '{}'", servers.len(), SERVER_CONFIG_FILE);
+    let servers = check_server_list(servers, refresh_servers)?;
+
+    let mut files = image_files(input_dir)?;
+
+    // Encrypting a protected image again would lose its payload
+    let mut skipped = Vec::new();
+    if !force {
+        files.retain(|path| {
+            let protected = fs::read(path).ok().and_then(|img_buf| existing_protection(&img_buf)).is_some();
+            if protected {
+                skipped.push(path.clone());
+            }
+            !protected
+        });
+        for path in &skipped {
+            println!("Skipping '{}': already protected (--force encrypts it anyway)", path.display());
+        }
+        if files.is_empty() {
+            return Err(fail(Failure::InvalidInput, format!(
+                "Every image in '{}' is already protected", input_dir.display())));
+        }
+    }
+    let outputs: HashMap<&PathBuf, PathBuf> = files.iter().zip(output_paths(&files, output_dir)?).collect();
+    fs::create_dir_all(output_dir)?;
+
+    let mut permissions = build_permissions(owner, grants, note, view_cooldown);
+    if let Some(key) = sign_key {
+        permissions.sign(key)?;
+    }
+    let meta_bytes = permissions.to_bytes()?;
+    println!("Encrypting {} images from '{}' ({} at a time)", files.len(), input_dir.display(), parallel);
+
+    // Workers pull chunks of files off a shared queue and share what they learn about the leader
+    let queue = Mutex::new(files.chunks(batch));
+    let leader_hint = Mutex::new(load_cached_leader());
+    let failures: Mutex<Vec<(PathBuf, String)>> = Mutex::new(Vec::new());
+
+    thread::scope(|scope| {
+        for _ in 0..parallel.min(files.len().div_ceil(batch)) {
+            scope.spawn(|| loop {
+                let Some(chunk) = queue.lock().unwrap().next() else {
+                    break;
+                };
+
+                for input_path in chunk {
+                    console_line(&format!("\n>>> {}", input_path.display()));
+                }
+                for (input_path, result) in encrypt_chunk(chunk, &servers, &meta_bytes, &leader_hint, policy) {
+                    let result = result.and_then(|encrypted_image| {
+                        let output_path = &outputs[input_path];
+                        write_encrypted_output(output_path, &encrypted_image)?;
+                        Ok(output_path)
+                    });
+
+                    match result {
+                        Ok(output_path) => console_line(&format!("<<< {} -> {}", input_path.display(), output_path.display())),
+                        Err(e) => {
+                            console_line(&format!("<<< {} FAILED: {}", input_path.display(), e));
+                            failures.lock().unwrap().push((input_path.clone(), e.to_string()));
+                        }
+                    }
+                }
+            });
+        }
+    });
+
+    save_cached_leader(leader_hint.lock().unwrap().as_deref());
+
+    let failures = failures.into_inner().unwrap();
+    println!("\n=== BULK ENCRYPTION SUMMARY ===");
+    println!("  Succeeded: {}", files.len() - failures.len());
+    println!("  Failed:    {}", failures.len());
+    if !skipped.is_empty() {
+        println!("  Skipped:   {} (already protected)", skipped.len());
+    }
+    for (path, reason) in &failures {
+        println!("    ✗ {}: {}", path.display(), reason);
+    }
+    println!("  Output directory: {}", output_dir.display());
+
+    if !failures.is_empty() {
+        bail!("{} of {} images failed to encrypt", failures.len(), files.len());
+    }
+    Ok(())
+}
+
+/// Encrypt a chunk of files. More than one goes to the leader as a single
+/// batch request; if that can't be done (no leader known, leadership moved,
+/// or a server without batch support) each image is sent on its own instead.
+fn encrypt_chunk<'a>(
+    chunk: &'a [PathBuf],
+    servers: &[String],
+    meta_bytes: &[u8],
+    leader_hint: &Mutex<Option<String>>,
+    policy: &RetryPolicy,
+) -> Vec<(&'a PathBuf, Result<Vec<u8>>)> {
+    let mut results = Vec::with_capacity(chunk.len());
+    let mut pending = Vec::with_capacity(chunk.len());
+    for input_path in chunk {
+        match fs::read(input_path) {
+            Ok(img_buf) => pending.push((input_path, img_buf)),
+            Err(e) => results.push((input_path, Err(e.into()))),
+        }
+    }
+
+    if pending.len() > 1 {
+        let hint = leader_hint.lock().unwrap().clone();
+        let leader = hint.or_else(|| find_leader(servers, STATUS_QUERY_TIMEOUT).map(|(addr, _)| addr));
+        if let Some(leader) = leader {
+            let images: Vec<&[u8]> = pending.iter().map(|(_, img_buf)| img_buf.as_slice()).collect();
+            match send_batch_request(&leader, meta_bytes, &images) {
+                Ok(replies) => {
+                    console_line(&format!("  ✓ Batch of {} images answered by leader {}", images.len(), leader));
+                    *leader_hint.lock().unwrap() = Some(leader);
+                    let replies = replies.into_iter().map(|reply| reply.map_err(anyhow::Error::msg));
+                    results.extend(pending.iter().map(|(input_path, _)| *input_path).zip(replies));
+                    return results;
+                }
+                Err(e) => {
+                    console_line(&format!("  ✗ Batch to {} failed ({}), sending images one at a time", leader, e));
+                    *leader_hint.lock().unwrap() = None;
+                }
+            }
+        }
+    }
+
+    for (input_path, img_buf) in pending {
+        results.push((input_path, encrypt_with_retries(servers, meta_bytes, &img_buf, None, leader_hint, policy)));
+    }
+    results
+}
+
+/// Compare servers.conf with the cluster as the leader sees it (itself plus its
+/// Raft peers) and warn about drift, so a server missing from the file doesn't
+/// cause mysterious failures. With `refresh`, the file is rewritten to match.
+fn check_server_list(servers: Vec<String>, refresh: bool) -> Result<Vec<String>> {
+    let Some((leader_addr, status)) = find_leader(&servers, STATUS_QUERY_TIMEOUT) else {
+        println!("(Could not reach a leader to validate '{}', using it as is)", SERVER_CONFIG_FILE);
+        return Ok(servers);
+    };
+
+    let mut cluster = vec![leader_addr];
+    cluster.extend(status.raft.peers.iter().filter_map(|peer| app_address(&peer.address).ok()));
+
+    // Compare resolved socket addresses so "localhost:9080" matches "127.0.0.1:9080"
+    let normalize = |addr: &String| {
+        addr.to_socket_addrs()
+            .ok()
+            .and_then(|mut resolved| resolved.next())
+            .map(|a| a.to_string())
+            .unwrap_or_else(|| addr.clone())
+    };
+    let local: HashSet<String> = servers.iter().map(normalize).collect();
+    let remote: HashSet<String> = cluster.iter().map(normalize).collect();
+
+    let missing: Vec<&String> = cluster.iter().filter(|a| !local.contains(&normalize(a))).collect();
+    let unknown: Vec<&String> = servers.iter().filter(|a| !remote.contains(&normalize(a))).collect();
+    if missing.is_empty() && unknown.is_empty() {
+        return Ok(servers);
+    }
+
+    println!("⚠ '{}' does not match the cluster reported by leader {}:", SERVER_CONFIG_FILE, status.raft.server_id);
+    for addr in &missing {
+        println!("  + {} is in the cluster but missing from '{}'", addr, SERVER_CONFIG_FILE);
+    }
+    for addr in &unknown {
+        println!("  - {} is in '{}' but not in the cluster", addr, SERVER_CONFIG_FILE);
+    }
+
+    if !refresh {
+        println!("  (run with --refresh-servers to update '{}')", SERVER_CONFIG_FILE);
+        return Ok(servers);
+    }
+
+    let mut contents = cluster.join("\n");
+    contents.push('\n');
+    write_atomic(Path::new(SERVER_CONFIG_FILE), contents.as_bytes())?;
+    println!("  Updated '{}' with {} servers", SERVER_CONFIG_FILE, cluster.len());
+    Ok(cluster)
+}
+
+/// Read the leader saved by a previous run, if it still reports itself leader.
+/// The check uses the short status timeout, so a stale or dead entry costs
+/// at most a couple of seconds before falling back to multicast.
+fn load_cached_leader() -> Option<String> {
+    let cached = fs::read_to_string(LEADER_CACHE_FILE).ok()?.trim().to_string();
+    if cached.is_empty() {
+        return None;
+    }
+
+    match query_status(&cached, STATUS_QUERY_TIMEOUT) {
+        Ok(status) if status.raft.role == ServerRole::Leader => {
+            println!("Using cached leader {} from '{}'", cached, LEADER_CACHE_FILE);
+            Some(cached)
+        }
+        _ => {
+            println!("Cached leader {} is no longer leader, ignoring '{}'", cached, LEADER_CACHE_FILE);
+            save_cached_leader(None);
+            None
+        }
+    }
+}
+
+/// Remember the leader for the next run, or forget it if it stopped answering
+fn save_cached_leader(leader: Option<&str>) {
+    let result = match leader {
+        Some(addr) => write_atomic(Path::new(LEADER_CACHE_FILE), format!("{}\n", addr).as_bytes()),
+        None => match fs::remove_file(LEADER_CACHE_FILE) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        },
+    };
+    if let Err(e) = result {
+        println!("(Could not update '{}': {})", LEADER_CACHE_FILE, e);
+    }
+}
+
+/// Build the permissions embedded with an image. Without explicit grants the
+/// owner gets 3 views, alice 2 and bob 1.
+fn build_permissions(owner: &str, grants: &[(String, u32)], note: Option<&str>, view_cooldown: Option<u64>) -> ImagePermissions {
+    let quotas: HashMap<String, u32> = if grants.is_empty() {
+        let mut quotas = HashMap::new();
+        quotas.insert(owner.to_string(), 3);
+        quotas.insert("alice".to_string(), 2);
+        quotas.insert("bob".to_string(), 1);
+        quotas
+    } else {
+        grants.iter().cloned().collect()
+    };
+
+    ImagePermissions {
+        owner: owner.to_string(),
+        quotas,
+        note: note.map(String::from),
+        version: 0,
+        view_cooldown_secs: view_cooldown.filter(|&secs| secs > 0),
+        last_views: HashMap::new(),
+        signature: None,
+        tokens: HashMap::new(),
+    }
+}
+
+/// Parse a `--note` argument, enforcing the embedded note size limit
+fn parse_note(arg: &str) -> std::result::Result<String, String> {
+    if arg.len() > MAX_NOTE_LEN {
+        return Err(format!("note is {} bytes, the limit is {}", arg.len(), MAX_NOTE_LEN));
+    }
+    Ok(arg.to_string())
+}
+
+/// Parse a `--parity` ratio, which `lsb::encode_ecc` needs in (0, 1]
+fn parse_parity(arg: &str) -> std::result::Result<f32, String> {
+    let ratio: f32 = arg.parse().map_err(|e| format!("'{}' is not a number: {}", arg, e))?;
+    if !(ratio > 0.0 && ratio <= 1.0) {
+        return Err(format!("the parity ratio must be in (0, 1], got {}", ratio));
+    }
+    Ok(ratio)
+}
+
+/// Parse a `--grant user=views` argument
+fn parse_grant(arg: &str) -> std::result::Result<(String, u32), String> {
+    let (user, views) = arg
+        .split_once('=')
+        .ok_or_else(|| format!("expected user=views, got '{}'", arg))?;
+    let views = views
+        .parse()
+        .map_err(|_| format!("invalid view count '{}' for user '{}'", views, user))?;
+    Ok((user.to_string(), views))
+}
+
+/// Encrypt one image, retrying across leader changes. If `leader_hint` holds
+/// the last server that answered, it's tried directly before multicasting.
+fn encrypt_with_retries(
+    servers: &[String],
+    meta_bytes: &[u8],
+    img_buf: &[u8],
+    unified_image: Option<&[u8]>,
+    leader_hint: &Mutex<Option<String>>,
+    policy: &RetryPolicy,
+) -> Result<Vec<u8>> {
+    let encrypt_start = Instant::now();
+
+    let hint = leader_hint.lock().unwrap().clone();
+    if let Some(leader) = hint {
+        match send_multicast_request(&leader, meta_bytes, img_buf, unified_image) {
+            Ok((encrypted_image, _)) => {
+                println!("  ✓ SUCCESS from known leader {}", leader);
+                return Ok(encrypted_image);
+            }
+            Err(e) => {
+                if let Some(bad) = BadRequest::from_reply(&e.to_string()) {
+                    return Err(fail(bad_request_failure(&bad), describe_bad_request(&bad)));
+                }
+                println!("  ✗ Known leader {} failed ({}), falling back to multicast", leader, e);
+                *leader_hint.lock().unwrap() = None;
+            }
+        }
+    }
+
+    println!("\n=== MULTICASTING to all {} servers ===", servers.len());
+    
+    let max_attempts = policy.max_attempts;
+    let mut attempt = 0;
+    let mut last_failure = Failure::NoLeader; // why the latest attempt failed, for the exit code
+    
+    while attempt < max_attempts {
+        attempt += 1;
+        
+        if attempt > 1 {
+            // Don't start a retry whose wait alone would overrun the deadline
+            let wait = policy.wait_before(attempt);
+            if let Some(deadline) = policy.deadline {
+                let remaining = deadline.saturating_sub(encrypt_start.elapsed());
+                if remaining <= wait {
+                    return Err(fail(last_failure, format!("Failed to encrypt image: deadline of {}s reached after {} of {} attempts",
+                                                          deadline.as_secs(), attempt - 1, max_attempts)));
+                }
+            }
+            println!("\n=== ATTEMPT {} of {} ===", attempt, max_attempts);
+            println!("Waiting {:.1} seconds before retry...", wait.as_secs_f64());
+            thread::sleep(wait);
+        } else {
+            println!("\n=== ATTEMPT {} of {} ===", attempt, max_attempts);
+        }
+
+        // Perform multicast and collect responses
+        let attempt_start = Instant::now();
+        let responses = multicast_to_servers(servers, meta_bytes, img_buf, unified_image);
+        let attempt_elapsed = attempt_start.elapsed();
+        
+        // Analyze responses
+        let mut success_response = None;
+        let mut success_server = None;
+        let mut not_leader_count = 0;
+        let mut no_leader_count = 0;
+        let mut not_committed_count = 0;
+        let mut unavailable_count = 0;
+        let mut connection_failed_count = 0;
+        let mut leader_might_have_failed = false;
+
+        for (server_addr, response) in &responses {
+            match response {
+                ServerResponse::Success(image_data, committed_index) => {
+                    match committed_index {
+                        Some(index) => println!("  ✓ SUCCESS from {} (committed at log index {})", server_addr, index),
+                        None => println!("  ✓ SUCCESS from {}", server_addr),
+                    }
+                    success_response = Some(image_data.clone());
+                    success_server = Some(server_addr.clone());
+                    break;
+                }
+                ServerResponse::NotLeader(hint) => {
+                    println!("  ✗ {} is NOT_LEADER (hint: {})", server_addr, hint);
+                    not_leader_count += 1;
+                }
+                ServerResponse::NoLeader => {
+                    println!("  ✗ {} says NO_LEADER (election in progress)", server_addr);
+                    no_leader_count += 1;
+                }
+                ServerResponse::NotCommitted(reason) => {
+                    println!("  ✗ {} could not commit the request: {}", server_addr, reason);
+                    not_committed_count += 1;
+                }
+                ServerResponse::ConnectionFailed(reason) => {
+                    println!("  ✗ {} connection failed: {}", server_addr, reason);
+                    connection_failed_count += 1;
+                }
+                ServerResponse::Unavailable(reason) => {
+                    println!("  ✗ {} is not accepting work: {}", server_addr, reason);
+                    unavailable_count += 1;
+                }
+                ServerResponse::Rejected(bad) => {
+                    println!("  ✗ {} rejected the request: {}", server_addr, bad);
+                    return Err(fail(bad_request_failure(bad), describe_bad_request(bad)));
+                }
+            }
+        }
+
+        // Check if we got success
+        if let Some(encrypted_image) = success_response {
+            println!("\n=== ✓ ENCRYPTION SUCCESSFUL ===");
+            println!("Received encrypted image ({} bytes = {:.2} MB)", 
+                     encrypted_image.len(),
+                     encrypted_image.len() as f64 / 1_048_576.0);
+            
+            // Round trip covers the request we sent plus the image we got back
+            let round_trip_bytes = meta_bytes.len() + img_buf.len() + encrypted_image.len();
+            println!("Total time: {:.1}s (this attempt: {:.1}s, {:.2} MB/s round trip)",
+                     encrypt_start.elapsed().as_secs_f64(),
+                     attempt_elapsed.as_secs_f64(),
+                     round_trip_bytes as f64 / 1_048_576.0 / attempt_elapsed.as_secs_f64().max(0.001));
+
+            // Whoever answered is the leader: try it first next time
+            *leader_hint.lock().unwrap() = success_server;
+            
+            return Ok(encrypted_image);
+        }
+
+        // Analyze failure reasons
+        println!("\n--- Response Summary ---");
+        println!("  NOT_LEADER responses: {}", not_leader_count);
+        println!("  NO_LEADER responses: {}", no_leader_count);
+        println!("  NOT_COMMITTED responses: {}", not_committed_count);
+        println!("  UNAVAILABLE responses: {}", unavailable_count);
+        println!("  Connection failures: {}", connection_failed_count);
+
+        // Only connection failures means the cluster couldn't be reached at all
+        last_failure = if not_leader_count + no_leader_count + not_committed_count + unavailable_count == 0 {
+            Failure::Network
+        } else {
+            Failure::NoLeader
+        };
+
+        // Detect if leader might have failed
+        if not_leader_count > 0 && connection_failed_count > 0 {
+            // Some servers said "not leader" and one failed to respond
+            // The failed one might have been the leader
+            leader_might_have_failed = true;
+            println!("\n⚠ Detected possible LEADER FAILURE!");
+            println!("  → Some servers identified a leader, but it didn't respond");
+            println!("  → Raft should elect a new leader...");
+        } else if unavailable_count > 0 {
+            // Leader is up but its unified image failed a check
+            println!("\n⚠ Leader is refusing work until its unified image is fixed");
+            println!("  → Waiting for the operator...");
+        } else if not_committed_count > 0 {
+            // Leader is up but can't reach a majority of followers
+            println!("\n⚠ Leader could not replicate the request to a majority");
+            println!("  → Waiting for quorum to recover...");
+        } else if no_leader_count == servers.len() {
+            // All servers say no leader - election in progress
+            println!("\n⚠ No leader currently elected");
+            println!("  → Waiting for Raft election to complete...");
+        } else if connection_failed_count == servers.len() {
+            // All servers unreachable - network problem
+            println!("\n⚠ All servers unreachable - network issue?");
+            return Err(fail(Failure::Network, "Cannot connect to any server"));
+        } else if not_leader_count == servers.len() {
+            // All servers say they're not leader - inconsistent state
+            println!("\n⚠ All servers claim to NOT be leader");
+            println!("  → This shouldn't happen! Waiting for re-election...");
+        }
+
+        if attempt < max_attempts {
+            if leader_might_have_failed {
+                println!("  → Will retry after new leader election...");
+            } else if no_leader_count > 0 {
+                println!("  → Will retry once election completes...");
+            } else {
+                println!("  → Will retry multicast...");
+            }
+        }
+    }
+
+    Err(fail(last_failure, format!("Failed to encrypt image: all {} attempts used. Possible reasons: leader keeps failing, network issues, or cluster unstable", max_attempts)))
+}
+
+/// Send one request to `server` and report its answer as-is, for debugging a
+/// single node (encrypt --force-direct). Nothing is retried and the leader
+/// cache is left alone: a NOT_LEADER is the result, not a hint to follow.
+fn encrypt_direct(server: &str, meta_bytes: &[u8], img_buf: &[u8], unified_image: Option<&[u8]>) -> Result<Vec<u8>> {
+    println!("\n=== Sending directly to {} (--force-direct, no retries) ===", server);
+    let start = Instant::now();
+    match send_multicast_request(server, meta_bytes, img_buf, unified_image) {
+        Ok((encrypted_image, committed_index)) => {
+            match committed_index {
+                Some(index) => println!("  ✓ SUCCESS from {} in {:.2}s (committed at log index {})", server, start.elapsed().as_secs_f64(), index),
+                None => println!("  ✓ SUCCESS from {} in {:.2}s", server, start.elapsed().as_secs_f64()),
+            }
+            Ok(encrypted_image)
+        }
+        Err(e) => {
+            let reply = e.to_string();
+            println!("  ✗ {} answered after {:.2}s: {}", server, start.elapsed().as_secs_f64(), reply);
+            if let Some(bad) = BadRequest::from_reply(&reply) {
+                return Err(fail(bad_request_failure(&bad), describe_bad_request(&bad)));
+            }
+            // A reply about the cluster's state, or no reply at all
+            let failure = if ["NOT_LEADER", "NO_LEADER", "NOT_COMMITTED", "UNAVAILABLE"].iter().any(|prefix| reply.starts_with(prefix)) {
+                Failure::NoLeader
+            } else {
+                Failure::Network
+            };
+            Err(fail(failure, format!("{} did not encrypt the image: {}", server, reply)))
+        }
+    }
+}
+
+/// Explain a rejected request in terms of what the user sent
+fn describe_bad_request(bad: &BadRequest) -> String {
+    match bad {
+        BadRequest::Image(reason) => format!("The servers could not read the file as an image: {}", reason),
+        BadRequest::Metadata(reason) => format!("The servers refused the image permissions: {}", reason),
+        BadRequest::Capacity(reason) => format!("The image is too small to hold the payload (try --autofit): {}", reason),
+    }
+}
+
+fn bad_request_failure(bad: &BadRequest) -> Failure {
+    match bad {
+        BadRequest::Capacity(_) => Failure::Capacity,
+        BadRequest::Image(_) | BadRequest::Metadata(_) => Failure::InvalidInput,
+    }
+}
+
+/// Multicast request to all servers and collect responses
+fn multicast_to_servers(
+    servers: &[String],
+    meta_bytes: &[u8],
+    img_buf: &[u8],
+    unified_image: Option<&[u8]>,
+) -> Vec<(String, ServerResponse)> {
+    println!("Multicasting to all servers simultaneously...");
+    
+    // Shared storage for responses
+    let responses: Arc<Mutex<Vec<(String, ServerResponse)>>> = Arc::new(Mutex::new(Vec::new()));
+    let mut thread_handles = vec![];
+
+    // Spawn thread for each server
+    for server_addr in servers {
+        let meta_clone = meta_bytes.to_vec();
+        let img_clone = img_buf.to_vec();
+        let unified_clone = unified_image.map(<[u8]>::to_vec);
+        let responses_clone = Arc::clone(&responses);
+        let addr_clone = server_addr.clone();
+
+        let handle = thread::spawn(move || {
+            console_line(&format!("  [Thread-{}] Connecting...", addr_clone));
+            
+            let response = match send_multicast_request(&addr_clone, &meta_clone, &img_clone, unified_clone.as_deref()) {
+                Ok((image_data, committed_index)) => {
+                    console_line(&format!("  [Thread-{}] ✓ Got encrypted image!", addr_clone));
+                    ServerResponse::Success(image_data, committed_index)
+                }
+                Err(e) => {
+                    let err_msg = e.to_string();
                     if err_msg.starts_with("NOT_LEADER:") {
                         let hint = err_msg.strip_prefix("NOT_LEADER:").unwrap_or("unknown");
                         ServerResponse::NotLeader(hint.to_string())
                     } else if err_msg.starts_with("NO_LEADER") {
                         ServerResponse::NoLeader
+                    } else if let Some(reason) = err_msg.strip_prefix("NOT_COMMITTED:") {
+                        ServerResponse::NotCommitted(reason.trim().to_string())
+                    } else if let Some(reason) = err_msg.strip_prefix("UNAVAILABLE:") {
+                        ServerResponse::Unavailable(reason.trim().to_string())
+                    } else if let Some(bad) = BadRequest::from_reply(&err_msg) {
+                        ServerResponse::Rejected(bad)
                     } else {
                         // Connection error, timeout, etc.
                         ServerResponse::ConnectionFailed(err_msg)
@@ -290,148 +1518,1557 @@ fn multicast_to_servers(
                 }
             };
 
-            // Store response
-            let mut responses_lock = responses_clone.lock().unwrap();
-            responses_lock.push((addr_clone.clone(), response));
-        });
+            // Store response
+            let mut responses_lock = responses_clone.lock().unwrap();
+            responses_lock.push((addr_clone.clone(), response));
+        });
+
+        thread_handles.push(handle);
+    }
+
+    // Show progress while the blocking requests are in flight
+    let done = Arc::new(AtomicBool::new(false));
+    let spinner = spawn_progress_spinner(Arc::clone(&done), Arc::clone(&responses), servers.len());
+
+    // Wait for all threads to complete
+    for handle in thread_handles {
+        let _ = handle.join();
+    }
+
+    done.store(true, Ordering::Relaxed);
+    if let Some(spinner) = spinner {
+        let _ = spinner.join();
+    }
+
+    // Return collected responses
+    let responses_lock = responses.lock().unwrap();
+    responses_lock.clone()
+}
+
+/// Serialises console output between the request threads and the progress spinner
+static CONSOLE: Mutex<()> = Mutex::new(());
+
+/// Set from --compress: send single encrypt requests with compressed framing
+static COMPRESS_TRANSFERS: AtomicBool = AtomicBool::new(false);
+
+/// Print a full log line, clearing any spinner text currently on the line
+fn console_line(line: &str) {
+    let _guard = CONSOLE.lock().unwrap_or_else(|e| e.into_inner());
+    if std::io::stdout().is_terminal() {
+        print!("\r\x1b[2K");
+    }
+    println!("{}", line);
+}
+
+/// Redraw an elapsed-time spinner until `done` is set. Only runs on a terminal
+/// so redirected output isn't filled with carriage returns.
+fn spawn_progress_spinner(
+    done: Arc<AtomicBool>,
+    responses: Arc<Mutex<Vec<(String, ServerResponse)>>>,
+    total: usize,
+) -> Option<thread::JoinHandle<()>> {
+    if !std::io::stdout().is_terminal() {
+        return None;
+    }
+
+    Some(thread::spawn(move || {
+        const FRAMES: [char; 4] = ['|', '/', '-', '\\'];
+        let start = Instant::now();
+        let mut frame = 0;
+
+        while !done.load(Ordering::Relaxed) {
+            let answered = responses.lock().map(|r| r.len()).unwrap_or(0);
+            {
+                let _guard = CONSOLE.lock().unwrap_or_else(|e| e.into_inner());
+                print!("\r\x1b[2K  {} Waiting for responses ({}/{} answered) - {}s elapsed",
+                       FRAMES[frame % FRAMES.len()], answered, total, start.elapsed().as_secs());
+                let _ = std::io::stdout().flush();
+            }
+            frame += 1;
+            thread::sleep(Duration::from_millis(250));
+        }
+
+        // Leave the line clean for whatever is printed next
+        let _guard = CONSOLE.lock().unwrap_or_else(|e| e.into_inner());
+        print!("\r\x1b[2K");
+        let _ = std::io::stdout().flush();
+    }))
+}
+
+/// Send multicast request to a single server, with our own unified image if given.
+/// Returns the encrypted image and, if the server reported it, the committed log index.
+fn send_multicast_request(addr: &str, meta_bytes: &[u8], img_buf: &[u8], unified_image: Option<&[u8]>) -> Result<(Vec<u8>, Option<u64>)> {
+    // Connection timeout: 10 seconds (increased for large images)
+    let mut stream = TcpStream::connect_timeout(
+        &addr.parse()?, 
+        Duration::from_secs(10)
+    )?;
+    
+    // Configure TCP socket for large transfers
+    configure_tcp_socket(&stream)?;
+    
+    // Read/Write timeout: 120 seconds (to account for large image processing)
+    stream.set_read_timeout(Some(Duration::from_secs(120)))?;
+    stream.set_write_timeout(Some(Duration::from_secs(120)))?;
+
+    if single_port() {
+        stream.write_all(&[MUX_CLIENT])?;
+    }
+    negotiate_protocol(&mut stream)?;
+
+    let compress = COMPRESS_TRANSFERS.load(Ordering::Relaxed);
+    let written = write_encrypt_request(&mut stream, meta_bytes, img_buf, unified_image, compress);
+    if !matches!(written, Ok(true)) {
+        // A follower answers NOT_LEADER without reading the request, and a leader
+        // redirects us before reading the image; we stop writing once we see the
+        // answer, or the connection closing breaks our write: its answer may be waiting
+        if let Some(reply) = read_frame(&mut stream).ok().and_then(|reply| String::from_utf8(reply).ok()) {
+            if let Some(target) = reply.strip_prefix("REDIRECT:") {
+                drop(stream);
+                return follow_redirect(addr, target, meta_bytes, img_buf, unified_image);
+            }
+            bail!("{}", reply);
+        }
+        written?;
+        bail!("Server stopped reading the request without replying");
+    }
+
+    // Read response
+    let mut response_buf = read_frame(&mut stream)?;
+
+    // Check if response is an error message
+    if let Ok(msg) = std::str::from_utf8(&response_buf) {
+        // Only sent to clients that asked for compression: the image frame follows gzipped
+        if let Some(inflated_len) = msg.strip_prefix("GZIP:").filter(|_| compress) {
+            let inflated_len: usize = inflated_len.parse().with_context(|| format!("Malformed header: {}", msg))?;
+            let image = gunzip_frame(&read_frame(&mut stream)?)?;
+            if image.len() != inflated_len {
+                bail!("Gzipped reply inflated to {} bytes, header said {}", image.len(), inflated_len);
+            }
+            response_buf = image;
+        }
+    }
+    if let Ok(msg) = std::str::from_utf8(&response_buf) {
+        if msg.starts_with("NOT_LEADER") || 
+           msg.starts_with("NO_LEADER") ||
+           msg.starts_with("NOT_COMMITTED") ||
+           msg.starts_with("UNAVAILABLE") ||
+           BadRequest::from_reply(msg).is_some() {
+            bail!("{}", msg);
+        }
+
+        // Leader chose another server to do the work: resend directly to it
+        if let Some(target) = msg.strip_prefix("REDIRECT:") {
+            drop(stream);
+            return follow_redirect(addr, target, meta_bytes, img_buf, unified_image);
+        }
+    }
+
+    // Otherwise it's the encrypted image, followed by the committed index
+    // (older servers close the connection without sending one)
+    let mut index_bytes = [0u8; 8];
+    let committed_index = stream
+        .read_exact(&mut index_bytes)
+        .ok()
+        .map(|_| u64::from_be_bytes(index_bytes));
+
+    Ok((response_buf, committed_index))
+}
+
+/// Resend a request to the worker named in a leader's REDIRECT:<worker>:<ticket>
+/// reply. The result counts only once the worker reports it committed.
+fn follow_redirect(addr: &str, target: &str, meta_bytes: &[u8], img_buf: &[u8], unified_image: Option<&[u8]>) -> Result<(Vec<u8>, Option<u64>)> {
+    let (worker_addr, ticket) = target
+        .rsplit_once(':')
+        .ok_or_else(|| anyhow::anyhow!("Malformed redirect: REDIRECT:{}", target))?;
+    console_line(&format!("  [Thread-{}] Redirected by leader to worker {}", addr, worker_addr));
+    match send_delegated_request(worker_addr, ticket, meta_bytes, img_buf, unified_image)? {
+        (image_data, Some(index)) => Ok((image_data, Some(index))),
+        // Workers from before delegated results were committed send no index
+        (_, None) => bail!("NOT_COMMITTED: worker {} did not report the request committed", worker_addr),
+    }
+}
+
+/// Write an encrypt request: the unified image override if given, then the
+/// permissions and image frames. False if the server answered before reading
+/// it all, so the rest was not sent.
+fn write_encrypt_request(stream: &mut TcpStream, meta_bytes: &[u8], img_buf: &[u8], unified_image: Option<&[u8]>, compress: bool) -> Result<bool> {
+    // The unified image goes ahead of the request proper
+    if let Some(unified_image) = unified_image {
+        stream.write_all(&UNIFIED_OVERRIDE_MARKER.to_be_bytes())?;
+        stream.write_all(&(unified_image.len() as u64).to_be_bytes())?;
+        stream.write_all(unified_image)?;
+    }
+
+    if compress {
+        // Each frame carries a flag byte saying whether it's gzipped
+        stream.write_all(&COMPRESSED_MARKER.to_be_bytes())?;
+        if !write_flagged_frame(stream, meta_bytes)? || !write_flagged_frame(stream, img_buf)? {
+            return Ok(false);
+        }
+    } else {
+        // Send metadata size and data
+        let meta_size = meta_bytes.len() as u64;
+        stream.write_all(&meta_size.to_be_bytes())?;
+        stream.write_all(meta_bytes)?;
+
+        // Send image size and data
+        let img_size = img_buf.len() as u64;
+        stream.write_all(&img_size.to_be_bytes())?;
+        if !write_body(stream, img_buf)? {
+            return Ok(false);
+        }
+    }
+
+    stream.flush()?; // Ensure all data is sent
+    Ok(true)
+}
+
+/// Write a request frame for COMPRESSED_MARKER framing, gzipped if that's
+/// smaller. False if the server answered before the body was all sent.
+fn write_flagged_frame(stream: &mut TcpStream, data: &[u8]) -> Result<bool> {
+    let gzipped = gzip_if_smaller(data);
+    let (flag, body) = match &gzipped {
+        Some(gzipped) => (FRAME_GZIP, gzipped.as_slice()),
+        None => (FRAME_RAW, data),
+    };
+    stream.write_all(&[flag])?;
+    stream.write_all(&(body.len() as u64).to_be_bytes())?;
+    write_body(stream, body)
+}
+
+/// Write a request body in REQUEST_CHUNK pieces, stopping early if the server
+/// has already answered or closed: it won't read the rest. False if stopped.
+fn write_body(stream: &mut TcpStream, body: &[u8]) -> Result<bool> {
+    for chunk in body.chunks(REQUEST_CHUNK) {
+        if server_answered(stream)? {
+            return Ok(false);
+        }
+        stream.write_all(chunk)?;
+    }
+    Ok(true)
+}
+
+/// Whether the server has sent something, or closed, without waiting for it
+fn server_answered(stream: &TcpStream) -> Result<bool> {
+    stream.set_nonblocking(true)?;
+    let peeked = stream.peek(&mut [0u8; 1]);
+    stream.set_nonblocking(false)?;
+    match peeked {
+        Ok(_) => Ok(true),
+        Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => Ok(false),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Read one u64-length-prefixed reply frame
+fn read_frame(stream: &mut TcpStream) -> Result<Vec<u8>> {
+    let mut size_bytes = [0u8; 8];
+    stream.read_exact(&mut size_bytes)?;
+    let mut buf = vec![0; u64::from_be_bytes(size_bytes) as usize];
+    stream.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+/// Send several images to the leader on one connection as a batch request.
+/// Returns each image's encrypted result or the server's error for it; fails
+/// as a whole if the server rejected the batch (e.g. it isn't the leader).
+fn send_batch_request(addr: &str, meta_bytes: &[u8], images: &[&[u8]]) -> Result<Vec<std::result::Result<Vec<u8>, String>>> {
+    let mut stream = TcpStream::connect_timeout(
+        &addr.parse()?,
+        Duration::from_secs(10)
+    )?;
+
+    configure_tcp_socket(&stream)?;
+    stream.set_read_timeout(Some(Duration::from_secs(120)))?;
+    stream.set_write_timeout(Some(Duration::from_secs(120)))?;
+
+    if single_port() {
+        stream.write_all(&[MUX_CLIENT])?;
+    }
+    negotiate_protocol(&mut stream)?;
+
+    // The marker takes the place of the metadata length, then every image
+    // is framed as in a single request
+    stream.write_all(&BATCH_MARKER.to_be_bytes())?;
+    stream.write_all(&(images.len() as u32).to_be_bytes())?;
+    for img_buf in images {
+        stream.write_all(&(meta_bytes.len() as u64).to_be_bytes())?;
+        stream.write_all(meta_bytes)?;
+        stream.write_all(&(img_buf.len() as u64).to_be_bytes())?;
+        stream.write_all(img_buf)?;
+    }
+    stream.flush()?;
+
+    let mut size_bytes = [0u8; 8];
+    stream.read_exact(&mut size_bytes)?;
+    let mut header = vec![0; u64::from_be_bytes(size_bytes) as usize];
+    stream.read_exact(&mut header)?;
+
+    // Anything but the batch header is a rejection like NOT_LEADER
+    let header = String::from_utf8_lossy(&header);
+    match header.strip_prefix("BATCH:").and_then(|count| count.parse::<usize>().ok()) {
+        Some(count) if count == images.len() => {}
+        Some(count) => bail!("Server answered {} of {} images", count, images.len()),
+        None => bail!("{}", header),
+    }
+
+    let mut results = Vec::with_capacity(images.len());
+    for _ in images {
+        let mut status = [0u8; 1];
+        stream.read_exact(&mut status)?;
+        stream.read_exact(&mut size_bytes)?;
+        let mut data = vec![0; u64::from_be_bytes(size_bytes) as usize];
+        stream.read_exact(&mut data)?;
+
+        if status[0] == BATCH_ITEM_OK {
+            // The committed index follows each image
+            let mut index_bytes = [0u8; 8];
+            stream.read_exact(&mut index_bytes)?;
+            results.push(Ok(data));
+        } else {
+            results.push(Err(String::from_utf8_lossy(&data).into_owned()));
+        }
+    }
+    Ok(results)
+}
+
+/// Send a request directly to the worker the leader redirected us to. Returns
+/// the encrypted image and the log index the leader committed it at, if reported.
+fn send_delegated_request(
+    worker_addr: &str,
+    ticket: &str,
+    meta_bytes: &[u8],
+    img_buf: &[u8],
+    unified_image: Option<&[u8]>,
+) -> Result<(Vec<u8>, Option<u64>)> {
+    let mut stream = TcpStream::connect_timeout(
+        &worker_addr.parse()?,
+        Duration::from_secs(10)
+    )?;
+
+    configure_tcp_socket(&stream)?;
+    stream.set_read_timeout(Some(Duration::from_secs(120)))?;
+    stream.set_write_timeout(Some(Duration::from_secs(120)))?;
+
+    // The work receiver speaks the length-prefixed JSON load-balancing protocol
+    let message = LoadBalancingMessage::DelegatedWork {
+        ticket: ticket.to_string(),
+        metadata: meta_bytes.to_vec(),
+        image_data: img_buf.to_vec(),
+        unified_image: unified_image.map(<[u8]>::to_vec),
+    };
+    let json = serde_json::to_vec(&message)?;
+    stream.write_all(&(json.len() as u32).to_be_bytes())?;
+    stream.write_all(&json)?;
+    stream.flush()?;
+
+    let mut size_bytes = [0u8; 4];
+    stream.read_exact(&mut size_bytes)?;
+    let mut response_buf = vec![0; u32::from_be_bytes(size_bytes) as usize];
+    stream.read_exact(&mut response_buf)?;
+
+    match serde_json::from_slice(&response_buf)? {
+        LoadBalancingMessage::DelegatedResult { encrypted_image, committed_index } => Ok((encrypted_image, Some(committed_index))),
+        LoadBalancingMessage::WorkResult { encrypted_image } => Ok((encrypted_image, None)),
+        // Passed on as-is so the caller sees the BAD_* or NOT_COMMITTED prefix
+        LoadBalancingMessage::WorkRejected { reason } if BadRequest::from_reply(&reason).is_some() || reason.starts_with("NOT_COMMITTED") => bail!("{}", reason),
+        LoadBalancingMessage::WorkRejected { reason } => bail!("Worker {} rejected redirect: {}", worker_addr, reason),
+        _ => bail!("Unexpected response type from worker {}", worker_addr),
+    }
+}
+
+// -------------------------------------------------------------------
+// --- ROLE 2: P2P VIEWER (Unchanged) ---
+// -------------------------------------------------------------------
+
+/// Who is viewing: a user spending their quota, or the holder of a one-time token
+#[derive(Clone, Copy)]
+enum Viewer<'a> {
+    User(&'a str),
+    Token(&'a str),
+}
+
+/// With `preview`, only the authorization check runs: the viewable or denied
+/// image is still written, but the quota and the source file are left alone.
+/// A view that comes sooner than the image's cooldown after the same user's
+/// last one is refused without spending a view. Permissions whose signature
+/// doesn't check out against `sign_key` deny access, see `Signature`.
+fn handle_view(input_path: &Path, fragments: &[PathBuf], viewer: Viewer, preview: bool, pipe: Option<&str>, encoding: ViewEncoding, sign_key: Option<&[u8]>) -> Result<()> {
+    println!("\n=== Simulating P2P client-to-client view{} ===", if preview { " (preview)" } else { "" });
+    match viewer {
+        Viewer::User(user) => println!("Viewing user: {}", user),
+        Viewer::Token(_) => println!("Viewing with a one-time token"),
+    }
+    println!("Viewing image: {}", input_path.display());
+
+    // Load the encrypted image with its embedded payload and layout, or the
+    // payload reassembled from every fragment if it was spread over several images
+    let paths: Vec<PathBuf> = std::iter::once(input_path.to_path_buf()).chain(fragments.iter().cloned()).collect();
+    let (carriers, combined_data, layout) = if fragments.is_empty() {
+        let (encoded_img, combined_data, layout) = read_protected_image(input_path)?;
+        (vec![encoded_img], combined_data, Some(layout))
+    } else {
+        let (carriers, combined_data) = read_fragmented_images(&paths)?;
+        (carriers, combined_data, None)
+    };
+    let encoded_img = &carriers[0];
+
+    // Extract permissions and unified image
+    let mut permissions = combined_data.permissions;
+    let unified_image_bytes = combined_data.unified_image;
+
+    println!("Decoded metadata before view: {:#?}", permissions);
+    if let Some(note) = &permissions.note {
+        println!("Note from {}: {}", permissions.owner, note);
+    }
+
+    let now_secs = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+
+    // Quotas are only honored if their signature holds up
+    let signature = Signature::check(&permissions, sign_key)?;
+    let has_access = if let Some(problem) = signature.problem_for_view() {
+        println!("Access denied. {}", problem);
+        false
+    } else {
+        match viewer {
+            Viewer::Token(token) => {
+                match permissions.token_status(token, now_secs) {
+                    TokenStatus::Valid => {
+                        println!("Access granted by a one-time token.");
+                        if !preview {
+                            permissions.consume_token(token);
+                        }
+                        true
+                    }
+                    TokenStatus::Expired(at) => {
+                        println!("Access denied. This token expired {}s ago!", now_secs - at);
+                        false
+                    }
+                    TokenStatus::Unknown => {
+                        println!("Access denied. This token was not issued for this image, or was already used!");
+                        false
+                    }
+                }
+            }
+            Viewer::User(current_user) => {
+                // Check if current user is authorized
+                match permissions.quotas.get_mut(current_user) {
+                    Some(views_left) if *views_left > 0 => {
+                        println!("Access granted. You have {} views left.", *views_left);
+                        if !preview {
+                            *views_left -= 1;
+                        }
+                        true
+                    }
+                    Some(_) => {
+                        println!("Access denied. No remaining views!");
+                        false
+                    }
+                    None => {
+                        println!("Access denied. You are not authorized to view this image!");
+                        false
+                    }
+                }
+            }
+        }
+    };
+
+    // The cooldown limits how fast a user's views are spent, so a preview isn't
+    // held to it, and neither is a token, which is only good for one view anyway
+    match viewer {
+        Viewer::User(current_user) if has_access && !preview => {
+            if let Some(wait) = permissions.view_cooldown_remaining(current_user, now_secs) {
+                bail!(
+                    "'{}' viewed '{}' too recently, try again in {}s; no view was spent",
+                    current_user,
+                    input_path.display(),
+                    wait
+                );
+            }
+            permissions.last_views.insert(current_user.to_string(), now_secs);
+        }
+        _ => {}
+    }
+
+    // Opened before a view is spent: a pipe that can't be opened costs nothing
+    let (mut output, output_name) = open_view_output(pipe, encoding.format)?;
+
+    if has_access && preview {
+        output.write_all(&encoding.encode(encoded_img)?)?;
+        println!("Saved viewable image to {}", output_name);
+        println!("Preview only: no view was spent and '{}' is unchanged", input_path.display());
+    } else if has_access {
+        let remaining = match viewer {
+            Viewer::User(current_user) => format!("Updated views left (for next peer): {}", permissions.quotas.get(current_user).unwrap_or(&0)),
+            Viewer::Token(_) => format!("Token used up; {} unused tokens left", permissions.tokens.len()),
+        };
+
+        // Record the view first: if another viewer got there before us the
+        // write is refused, and this view must not count
+        let updated_combined_payload = CombinedPayload {
+            permissions,
+            unified_image: unified_image_bytes,
+        };
+        match layout {
+            Some(layout) => write_protected_image(input_path, encoded_img, layout, updated_combined_payload, sign_key)?,
+            None => write_fragmented_images(&paths, &carriers, updated_combined_payload, sign_key)?,
+        }
+
+        println!(
+            "Re-embedded updated metadata back into -> '{}'",
+            input_path.display()
+        );
+
+        // Save the viewable image
+        output.write_all(&encoding.encode(encoded_img)?)?;
+        println!("Saved viewable image to {}", output_name);
+        println!("{}", remaining);
+    } else {
+        // Save the "Access Denied" image, as sent unless another format was asked for
+        if encoding.format == ViewFormat::Png {
+            output.write_all(&unified_image_bytes)?;
+        } else {
+            let denied = image::load_from_memory(&unified_image_bytes)
+                .context("Cannot decode the embedded access-denied image to convert it")?;
+            output.write_all(&encoding.encode(&denied)?)?;
+        }
+        println!("Saved default 'Access Denied' image to {}", output_name);
+    }
+    output.flush()?;
+
+    Ok(())
+}
+
+/// Where `view` writes the image: VIEWABLE_OUTPUT_IMAGE (with the format's
+/// extension), or with --pipe an existing FIFO or an inherited file descriptor.
+/// Also returns how to name it in messages.
+fn open_view_output(pipe: Option<&str>, format: ViewFormat) -> Result<(Box<dyn Write>, String)> {
+    let Some(target) = pipe else {
+        let path = Path::new(VIEWABLE_OUTPUT_IMAGE).with_extension(format.extension());
+        let file = fs::File::create(&path)?;
+        return Ok((Box::new(file), format!("'{}'", path.display())));
+    };
+
+    let path = match target.parse::<u32>() {
+        Ok(1) => bail!("--pipe 1 would mix the image into the messages on stdout, pass another descriptor"),
+        Ok(fd) => format!("/dev/fd/{}", fd),
+        Err(_) => target.to_string(),
+    };
+
+    // Never created: a mistyped FIFO path should fail, not become a regular file.
+    // Opening a FIFO waits here until the viewer opens it for reading
+    let file = fs::OpenOptions::new()
+        .write(true)
+        .open(&path)
+        .with_context(|| format!("Cannot open '{}' for --pipe", target))?;
+    Ok((Box::new(file), format!("pipe '{}'", target)))
+}
+
+/// How `view` encodes the image it writes
+#[derive(Clone, Copy)]
+struct ViewEncoding {
+    format: ViewFormat,
+    jpeg_quality: u8,
+}
+
+impl ViewEncoding {
+    fn encode(&self, img: &image::DynamicImage) -> Result<Vec<u8>> {
+        match self.format {
+            ViewFormat::Png => png_bytes(img),
+            ViewFormat::Jpeg => {
+                // JPEG has no alpha channel
+                let mut buf = Vec::new();
+                image::codecs::jpeg::JpegEncoder::new_with_quality(&mut buf, self.jpeg_quality)
+                    .encode_image(&img.to_rgb8())?;
+                Ok(buf)
+            }
+            ViewFormat::Bmp => {
+                let mut buf = Vec::new();
+                img.write_to(&mut Cursor::new(&mut buf), ImageFormat::Bmp)?;
+                Ok(buf)
+            }
+        }
+    }
+}
+
+/// Encode an image as PNG in memory
+fn png_bytes(img: &image::DynamicImage) -> Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    img.write_to(&mut Cursor::new(&mut buf), ImageFormat::Png)?;
+    Ok(buf)
+}
+
+/// Revoke a user's access by removing them from the embedded quotas.
+/// Only the owner recorded in the image may do this.
+fn handle_revoke(input_path: &Path, user: &str, owner: &str, sign_key: Option<&[u8]>) -> Result<()> {
+    println!("=== Revoking access ===");
+
+    let (encoded_img, mut combined_data, layout) = read_protected_image(input_path)?;
+
+    // Without this check anyone holding the file could rewrite its quotas
+    if combined_data.permissions.owner != owner {
+        bail!("Only the owner of '{}' can revoke access ('{}' is not the owner)", input_path.display(), owner);
+    }
+    Signature::check(&combined_data.permissions, sign_key)?.allow_update(input_path)?;
+
+    combined_data.permissions.last_views.remove(user);
+    match combined_data.permissions.quotas.remove(user) {
+        Some(views_left) => println!("Removed '{}' ({} views left) from '{}'", user, views_left, input_path.display()),
+        None => {
+            println!("'{}' has no access to '{}', nothing to revoke", user, input_path.display());
+            return Ok(());
+        }
+    }
+
+    write_protected_image(input_path, &encoded_img, layout, combined_data, sign_key)?;
+    println!("Re-embedded updated metadata back into -> '{}'", input_path.display());
+
+    Ok(())
+}
+
+/// Add views to a user's quota, the inverse of `revoke`. Only the owner
+/// recorded in the image may do this; a user without access is granted it.
+fn handle_topup(input_path: &Path, user: &str, add: u32, owner: &str, sign_key: Option<&[u8]>) -> Result<()> {
+    println!("=== Topping up views ===");
+
+    let (encoded_img, mut combined_data, layout) = read_protected_image(input_path)?;
+
+    // Without this check anyone holding the file could grant themselves views
+    if combined_data.permissions.owner != owner {
+        bail!("Only the owner of '{}' can top up views ('{}' is not the owner)", input_path.display(), owner);
+    }
+    Signature::check(&combined_data.permissions, sign_key)?.allow_update(input_path)?;
+
+    let views = combined_data.permissions.quotas.entry(user.to_string()).or_insert(0);
+    let before = *views;
+    *views = before
+        .checked_add(add)
+        .ok_or_else(|| anyhow::anyhow!("'{}' has {} views left, adding {} would overflow", user, before, add))?;
+    println!("'{}' now has {} views left on '{}' (was {})", user, *views, input_path.display(), before);
+
+    write_protected_image(input_path, &encoded_img, layout, combined_data, sign_key)?;
+    println!("Re-embedded updated metadata back into -> '{}'", input_path.display());
+
+    Ok(())
+}
+
+/// Rewrite the whole permission set of a protected image without a server
+/// round trip: the unified image is already embedded, so only the payload is
+/// re-encoded into the carrier's low bits. Only the embedded owner may do
+/// this, and ownership itself cannot be changed.
+fn handle_rehydrate(
+    input_path: &Path,
+    owner: &str,
+    grants: &[(String, u32)],
+    note: Option<&str>,
+    clear_note: bool,
+    view_cooldown: Option<u64>,
+    sign_key: Option<&[u8]>,
+) -> Result<()> {
+    println!("=== Rewriting permissions ===");
+
+    let (encoded_img, mut combined_data, layout) = read_protected_image(input_path)?;
+
+    if combined_data.permissions.owner != owner {
+        bail!("Only the owner of '{}' can rewrite its permissions ('{}' is not the owner)", input_path.display(), owner);
+    }
+    Signature::check(&combined_data.permissions, sign_key)?.allow_update(input_path)?;
+
+    println!("Permissions before: {:#?}", combined_data.permissions);
+    let permissions = &mut combined_data.permissions;
+    permissions.quotas = grants.iter().cloned().collect();
+    // Regranted users keep their last view, so a rewrite can't reset a cooldown
+    let quotas = &permissions.quotas;
+    permissions.last_views.retain(|user, _| quotas.contains_key(user));
+    if let Some(secs) = view_cooldown {
+        permissions.view_cooldown_secs = (secs > 0).then_some(secs);
+    }
+    if clear_note {
+        permissions.note = None;
+    } else if let Some(note) = note {
+        permissions.note = Some(note.to_string());
+    }
+    println!("Permissions after: {:#?}", permissions);
+
+    write_protected_image(input_path, &encoded_img, layout, combined_data, sign_key)?;
+    println!("Re-embedded updated metadata back into -> '{}'", input_path.display());
+
+    Ok(())
+}
+
+// -------------------------------------------------------------------
+// --- ADMIN: CLUSTER HEALTH ---
+// -------------------------------------------------------------------
+
+/// Have each node ping its peers and print the round-trip times it measured,
+/// to tell a slow network apart from slow servers
+fn handle_ping(server: Option<&str>) -> Result<()> {
+    let servers = match server {
+        Some(addr) => vec![addr.to_string()],
+        None => load_server_list(SERVER_CONFIG_FILE)?,
+    };
+
+    println!("=== Peer latency ===");
+    let mut reachable = 0;
+    for addr in &servers {
+        let status = match query_peer_latency(addr, PING_QUERY_TIMEOUT) {
+            Ok(status) => status,
+            Err(e) => {
+                println!("\n{}: unreachable ({})", addr, e);
+                continue;
+            }
+        };
+        reachable += 1;
+
+        println!("\n{} ({}, {:?}, term {}):", status.raft.server_id, addr, status.raft.role, status.raft.current_term);
+        for peer in &status.raft.peers {
+            let rtt = match peer.rtt_ms {
+                Some(rtt) => format!("{:.2} ms", rtt),
+                None => "no reply".to_string(),
+            };
+            let lag = match peer.replication_lag {
+                Some(lag) => format!(", {} entries behind", lag),
+                None => String::new(),
+            };
+            let learner = if peer.learner { ", learner" } else { "" };
+            println!("  -> {:<22} {:>11}  (breaker {:?}{}{})", peer.address, rtt, peer.breaker, lag, learner);
+        }
+    }
+
+    if reachable == 0 {
+        bail!("No server answered");
+    }
+    Ok(())
+}
 
-        thread_handles.push(handle);
+/// Ask every server in servers.conf for its status at once and print one row
+/// per node, then point out disagreements: several leaders, differing terms,
+/// or nodes following different leaders. Servers that don't answer show as
+/// down; the command only fails if none answers.
+fn handle_status() -> Result<()> {
+    let servers = load_server_list(SERVER_CONFIG_FILE)?;
+    let statuses: Vec<(String, Option<RaftStatus>)> = thread::scope(|scope| {
+        let queries: Vec<_> = servers
+            .iter()
+            .map(|addr| scope.spawn(move || query_status(addr, STATUS_QUERY_TIMEOUT).ok().map(|status| status.raft)))
+            .collect();
+        servers
+            .iter()
+            .cloned()
+            .zip(queries.into_iter().map(|query| query.join().unwrap_or(None)))
+            .collect()
+    });
+
+    println!("=== Cluster status ({} servers) ===", servers.len());
+    let row = |node: &str, addr: &str, role: &str, term: &str, leader: &str, commit: &str, last_index: &str, reachable: &str| {
+        println!("{:<10} {:<22} {:<10} {:>5} {:<10} {:>7} {:>10} {}", node, addr, role, term, leader, commit, last_index, reachable);
+    };
+    row("NODE", "ADDRESS", "ROLE", "TERM", "LEADER", "COMMIT", "LAST INDEX", "REACHABLE");
+    for (addr, status) in &statuses {
+        match status {
+            Some(raft) => row(&raft.server_id, addr, &format!("{:?}", raft.role), &raft.current_term.to_string(),
+                              raft.leader_id.as_deref().unwrap_or("-"), &raft.commit_index.to_string(),
+                              &raft.last_log_index.to_string(), "up"),
+            None => row("?", addr, "-", "-", "-", "-", "-", "down"),
+        }
     }
 
-    // Wait for all threads to complete
-    for handle in thread_handles {
-        let _ = handle.join();
+    let up: Vec<&RaftStatus> = statuses.iter().filter_map(|(_, status)| status.as_ref()).collect();
+    if up.is_empty() {
+        bail!("No server answered");
     }
 
-    // Return collected responses
-    let responses_lock = responses.lock().unwrap();
-    responses_lock.clone()
+    let mut warnings = Vec::new();
+    let leaders: Vec<String> = up
+        .iter()
+        .filter(|raft| raft.role == ServerRole::Leader)
+        .map(|raft| format!("{} (term {})", raft.server_id, raft.current_term))
+        .collect();
+    match leaders.len() {
+        0 => warnings.push("no node is leader".to_string()),
+        1 => {}
+        _ => warnings.push(format!("{} nodes claim to lead: {}", leaders.len(), leaders.join(", "))),
+    }
+    let terms: std::collections::BTreeSet<u64> = up.iter().map(|raft| raft.current_term).collect();
+    if terms.len() > 1 {
+        warnings.push(format!("nodes are in different terms: {:?}", terms));
+    }
+    let followed: std::collections::BTreeSet<&str> = up.iter().filter_map(|raft| raft.leader_id.as_deref()).collect();
+    if followed.len() > 1 {
+        warnings.push(format!("nodes follow different leaders: {:?}", followed));
+    }
+    let down = statuses.len() - up.len();
+    if down > 0 {
+        warnings.push(format!("{} of {} servers down", down, statuses.len()));
+    }
+
+    if warnings.is_empty() {
+        println!("\nAll {} nodes agree", up.len());
+    } else {
+        println!();
+        for warning in &warnings {
+            println!("⚠ {}", warning);
+        }
+    }
+    Ok(())
 }
 
-/// Send multicast request to a single server
-fn send_multicast_request(addr: &str, meta_bytes: &[u8], img_buf: &[u8]) -> Result<Vec<u8>> {
-    // Connection timeout: 10 seconds (increased for large images)
-    let mut stream = TcpStream::connect_timeout(
-        &addr.parse()?, 
-        Duration::from_secs(10)
-    )?;
-    
-    // Configure TCP socket for large transfers
-    configure_tcp_socket(&stream)?;
-    
-    // Read/Write timeout: 120 seconds (to account for large image processing)
-    stream.set_read_timeout(Some(Duration::from_secs(120)))?;
-    stream.set_write_timeout(Some(Duration::from_secs(120)))?;
+/// Have one node compare its committed log prefix with its peers' logs and
+/// print the result, failing if any peer's committed entries differ
+fn handle_verify_log(server: Option<&str>) -> Result<()> {
+    let addr = match server {
+        Some(addr) => addr.to_string(),
+        None => {
+            let servers = load_server_list(SERVER_CONFIG_FILE)?;
+            match find_leader(&servers, STATUS_QUERY_TIMEOUT) {
+                Some((leader, _)) => leader,
+                None => bail!("No leader found in '{}', pick a node with --server", SERVER_CONFIG_FILE),
+            }
+        }
+    };
 
-    // Send metadata size and data
-    let meta_size = meta_bytes.len() as u64;
-    stream.write_all(&meta_size.to_be_bytes())?;
-    stream.write_all(meta_bytes)?;
+    let report = query_log_consistency(&addr, PING_QUERY_TIMEOUT)?;
+    let own = &report.checked_by;
+    println!("=== Log consistency ===");
+    println!("{} ({}) checked entries 0..={} (last index {}, last term {})",
+             own.server_id, addr, own.up_to_index, own.last_log_index, own.last_log_term);
 
-    // Send image size and data
-    let img_size = img_buf.len() as u64;
-    stream.write_all(&img_size.to_be_bytes())?;
-    stream.write_all(img_buf)?;
-    
-    stream.flush()?; // Ensure all data is sent
+    for peer in &report.peers {
+        let detail = match &peer.summary {
+            Some(summary) => format!("{}: last index {}, last term {}, commit {}",
+                                     summary.server_id, summary.last_log_index, summary.last_log_term, summary.commit_index),
+            None => "no reply".to_string(),
+        };
+        let mark = match peer.verdict {
+            LogVerdict::Match => "✓",
+            LogVerdict::Diverged => "✗",
+            LogVerdict::Behind | LogVerdict::Unreachable => "?",
+        };
+        println!("  {} {:<22} {:?} ({})", mark, peer.address, peer.verdict, detail);
+    }
 
-    // Receive response size
-    let mut size_bytes = [0u8; 8];
-    stream.read_exact(&mut size_bytes)?;
-    let response_size = u64::from_be_bytes(size_bytes);
+    if !report.is_consistent() {
+        bail!("Committed log entries differ between nodes");
+    }
+    println!("No divergence found");
+    Ok(())
+}
 
-    // Read response
-    let mut response_buf = vec![0; response_size as usize];
-    stream.read_exact(&mut response_buf)?;
+/// Decode the payload of an image for `diff`. Files that can't be read as
+/// images are errors; an image without a usable payload gives the reason.
+fn payload_for_diff(path: &Path) -> Result<std::result::Result<CombinedPayload, String>> {
+    let img_data = fs::read(path).with_context(|| format!("Failed to read '{}'", path.display()))?;
+    let img = image::load_from_memory(&img_data).with_context(|| format!("'{}' is not an image", path.display()))?;
+    let payload = match lsb::decode_protected(&img) {
+        Ok(payload) => payload,
+        Err(e) => return Ok(Err(e.to_string())),
+    };
+    Ok(CombinedPayload::from_bytes(&payload).map_err(|e| e.to_string()))
+}
 
-    // Check if response is an error message
-    if let Ok(msg) = std::str::from_utf8(&response_buf) {
-        if msg.starts_with("NOT_LEADER") || 
-           msg.starts_with("NO_LEADER") {
-            bail!("{}", msg);
+/// Hex SHA-256 and size of a unified image, enough to tell two apart
+fn unified_image_summary(bytes: &[u8]) -> String {
+    let digest: String = Sha256::digest(bytes).iter().map(|byte| format!("{:02x}", byte)).collect();
+    format!("sha256 {} ({} bytes)", digest, bytes.len())
+}
+
+/// List the images in `input_dir` with the payload size their length header
+/// claims, to triage a directory without decoding every payload.
+fn handle_inspect(input_dir: &Path) -> Result<()> {
+    let files = image_files(input_dir)?;
+    println!("=== Inspect: {} images in '{}' ===", files.len(), input_dir.display());
+
+    let mut with_payload = 0;
+    for (path, length) in inspect_files(&files) {
+        match length {
+            Ok(Some(len)) => {
+                with_payload += 1;
+                println!("{}: {} byte payload", path.display(), len);
+            }
+            Ok(None) => println!("{}: no payload", path.display()),
+            Err(e) => println!("{}: cannot read ({:#})", path.display(), e),
         }
     }
+    println!("{} of {} images carry a payload", with_payload, files.len());
+    Ok(())
+}
 
-    // Otherwise it's the encrypted image
-    Ok(response_buf)
+/// The payload length each image's header claims, from `lsb::peek_length`.
+fn inspect_files(files: &[PathBuf]) -> Vec<(&Path, Result<Option<usize>>)> {
+    files
+        .iter()
+        .map(|path| {
+            let length = image::open(path).map_err(anyhow::Error::from).and_then(|img| lsb::peek_length(&img));
+            (path.as_path(), length)
+        })
+        .collect()
 }
 
-// -------------------------------------------------------------------
-// --- ROLE 2: P2P VIEWER (Unchanged) ---
-// -------------------------------------------------------------------
+/// Print what differs between the permissions embedded in two protected
+/// images. Read-only; images without a payload are reported, not errors.
+fn handle_diff(a_path: &Path, b_path: &Path) -> Result<()> {
+    let a = payload_for_diff(a_path)?;
+    let b = payload_for_diff(b_path)?;
+    println!("=== Diff ===");
+    println!("a: {}", a_path.display());
+    println!("b: {}", b_path.display());
 
-fn handle_view(input_path: &PathBuf, current_user: &String) -> Result<()> {
-    println!("\n=== Simulating P2P client-to-client view ===");
-    println!("Viewing user: {}", current_user);
-    println!("Viewing image: {}", input_path.display());
+    let (a, b) = match (a, b) {
+        (Ok(a), Ok(b)) => (a, b),
+        (a, b) => {
+            for (label, side) in [("a", &a), ("b", &b)] {
+                match side {
+                    Ok(_) => println!("{}: protected", label),
+                    Err(reason) => println!("{}: no payload ({})", label, reason),
+                }
+            }
+            return Ok(());
+        }
+    };
 
-    // Load the encrypted image
-    let img_data = fs::read(input_path)?;
-    let encoded_img = image::load_from_memory(&img_data)?;
+    let (pa, pb) = (&a.permissions, &b.permissions);
+    let mut differences = Vec::new();
+    let mut compare = |label: String, left: String, right: String| {
+        if left != right {
+            differences.push(format!("{}: {} -> {}", label, left, right));
+        }
+    };
+    let none = || "(none)".to_string();
 
-    // Decode embedded payload
-    let payload = lsb::decode(&encoded_img)?
-        .ok_or_else(|| anyhow::anyhow!("No hidden metadata found!"))?;
+    compare("owner".to_string(), pa.owner.clone(), pb.owner.clone());
+    let users: std::collections::BTreeSet<&String> = pa.quotas.keys().chain(pb.quotas.keys()).collect();
+    for user in users {
+        compare(format!("quota[{}]", user),
+                pa.quotas.get(user).map_or_else(none, |views| views.to_string()),
+                pb.quotas.get(user).map_or_else(none, |views| views.to_string()));
+    }
+    compare("note".to_string(),
+            pa.note.as_ref().map_or_else(none, |note| format!("{:?}", note)),
+            pb.note.as_ref().map_or_else(none, |note| format!("{:?}", note)));
+    compare("view cooldown".to_string(),
+            pa.view_cooldown_secs.map_or_else(none, |secs| format!("{}s", secs)),
+            pb.view_cooldown_secs.map_or_else(none, |secs| format!("{}s", secs)));
+    let viewers: std::collections::BTreeSet<&String> = pa.last_views.keys().chain(pb.last_views.keys()).collect();
+    for user in viewers {
+        compare(format!("last view[{}]", user),
+                pa.last_views.get(user).map_or_else(none, |at| at.to_string()),
+                pb.last_views.get(user).map_or_else(none, |at| at.to_string()));
+    }
+    compare("unused tokens".to_string(), pa.tokens.len().to_string(), pb.tokens.len().to_string());
+    compare("version".to_string(), pa.version.to_string(), pb.version.to_string());
+    compare("signature".to_string(), pa.signature.clone().unwrap_or_else(none), pb.signature.clone().unwrap_or_else(none));
+    compare("unified image".to_string(), unified_image_summary(&a.unified_image), unified_image_summary(&b.unified_image));
 
-    // Deserialize the CombinedPayload
-    let combined_data: CombinedPayload = bincode::deserialize(&payload)?;
+    if differences.is_empty() {
+        println!("Permissions and unified image are identical");
+    } else {
+        for line in &differences {
+            println!("  {}", line);
+        }
+        println!("{} difference(s)", differences.len());
+    }
+    Ok(())
+}
 
-    // Extract permissions and unified image
-    let mut permissions = combined_data.permissions;
-    let unified_image_bytes = combined_data.unified_image;
+/// How an image's permissions stand against the --sign-key given, if any
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Signature {
+    /// Neither signed nor a key given: quotas are taken as they are
+    NotUsed,
+    /// Signed with the key given
+    Valid,
+    /// Signed, but no key was given to check it
+    NoKey,
+    /// Signed, but not with the key given, or changed since it was signed
+    Mismatch,
+    /// A key was given but the image is unsigned; its signature may have been stripped
+    Unsigned,
+}
 
-    println!("Decoded metadata before view: {:#?}", permissions);
+impl Signature {
+    fn check(permissions: &ImagePermissions, sign_key: Option<&[u8]>) -> Result<Self> {
+        Ok(match (&permissions.signature, sign_key) {
+            (None, None) => Signature::NotUsed,
+            (Some(_), None) => Signature::NoKey,
+            (None, Some(_)) => Signature::Unsigned,
+            (Some(_), Some(key)) if permissions.signature_matches(key)? => Signature::Valid,
+            (Some(_), Some(_)) => Signature::Mismatch,
+        })
+    }
 
-    // Check if current user is authorized
-    let has_access = match permissions.quotas.get_mut(current_user) {
-        Some(views_left) if *views_left > 0 => {
-            println!("Access granted. You have {} views left.", *views_left);
-            *views_left -= 1;
-            true
+    /// Why a view must be denied, if it must
+    fn problem_for_view(self) -> Option<&'static str> {
+        match self {
+            Signature::NotUsed | Signature::Valid => None,
+            Signature::NoKey => Some("The permissions are signed, pass the owner's --sign-key to view this image"),
+            Signature::Mismatch => Some("The permissions don't match their signature: they were changed without the owner's key, or --sign-key is wrong"),
+            Signature::Unsigned => Some("--sign-key was given but the permissions are unsigned: the signature may have been stripped"),
         }
-        Some(_) => {
-            println!("Access denied. No remaining views!");
-            false
+    }
+
+    /// Whether the owner may rewrite the permissions. An unsigned image is
+    /// signed by the rewrite when a key is given.
+    fn allow_update(self, input_path: &Path) -> Result<()> {
+        match self {
+            Signature::NotUsed | Signature::Valid => Ok(()),
+            Signature::Unsigned => {
+                println!("'{}' is unsigned, its permissions will be signed with --sign-key", input_path.display());
+                Ok(())
+            }
+            Signature::NoKey => Err(fail(Failure::InvalidInput, format!(
+                "'{}' has signed permissions, pass the owner's --sign-key to change them", input_path.display()))),
+            Signature::Mismatch => Err(fail(Failure::InvalidInput, format!(
+                "The permissions of '{}' don't match their signature: they were changed without the owner's key, \
+                 or --sign-key is wrong", input_path.display()))),
         }
-        None => {
-            println!("Access denied. You are not authorized to view this image!");
-            false
+    }
+}
+
+/// Load a protected image and decode the payload embedded in it, along with
+/// the layout to hand back to `write_protected_image`
+fn read_protected_image(input_path: &Path) -> Result<(image::DynamicImage, CombinedPayload, lsb::Layout)> {
+    let img_data = fs::read(input_path)?;
+    let encoded_img = image::load_from_memory(&img_data)?;
+    let (combined_data, layout) = decode_payload(&encoded_img)
+        .with_context(|| format!("Cannot read the payload of '{}'", input_path.display()))?;
+    Ok((encoded_img, combined_data, layout))
+}
+
+/// Re-embed an updated payload into a protected image and replace the file.
+/// `payload` carries the version it was read at; if the file has been
+/// re-embedded since, nothing is written, so concurrent views can't silently
+/// overwrite each other's updates. The written payload gets the next version,
+/// and is signed again when a `sign_key` is given. It is embedded with
+/// `layout`, the one `read_protected_image` found, so the image keeps it.
+fn write_protected_image(input_path: &Path, encoded_img: &image::DynamicImage, layout: lsb::Layout, mut payload: CombinedPayload, sign_key: Option<&[u8]>) -> Result<()> {
+    let read_version = payload.permissions.version;
+    payload.permissions.version += 1;
+    if let Some(key) = sign_key {
+        payload.permissions.sign(key)?;
+    }
+
+    let updated_payload = payload.to_bytes()?;
+    let updated_img = lsb::encode_layout(encoded_img, &updated_payload, layout)?;
+
+    // Encode fully in memory, then swap the file in atomically so an
+    // interrupted write can't leave a corrupt image with the views lost
+    let mut updated_bytes = Vec::new();
+    updated_img.write_to(
+        &mut Cursor::new(&mut updated_bytes),
+        ImageFormat::from_path(input_path)?,
+    )?;
+
+    // Checked as late as possible to keep the window for a lost update small
+    let (_, on_disk, _) = read_protected_image(input_path)?;
+    refuse_stale_write(input_path, on_disk.permissions.version, read_version)?;
+    write_atomic(input_path, &updated_bytes)
+}
+
+/// Fails if the payload on disk is newer than the one an update was made from
+fn refuse_stale_write(input_path: &Path, on_disk_version: u64, read_version: u64) -> Result<()> {
+    if on_disk_version > read_version {
+        bail!(
+            "'{}' was updated by someone else while this ran (version {} on disk, {} when read); \
+             nothing was written, run again against the updated file",
+            input_path.display(),
+            on_disk_version,
+            read_version
+        );
+    }
+    Ok(())
+}
+
+/// Read a payload spread over several images by `encrypt --spill`, along
+/// with the images in the order given
+fn read_fragmented_images(paths: &[PathBuf]) -> Result<(Vec<image::DynamicImage>, CombinedPayload)> {
+    let carriers = paths
+        .iter()
+        .map(|path| image::open(path).with_context(|| format!("Cannot read '{}'", path.display())))
+        .collect::<Result<Vec<_>>>()?;
+    let payload = lsb::decode_fragmented(&carriers)
+        .with_context(|| format!("Cannot reassemble the payload of '{}'", paths[0].display()))?;
+    Ok((carriers, CombinedPayload::from_bytes(&payload)?))
+}
+
+/// `write_protected_image` for a payload spread over several images: the
+/// update is spread over the same images again, and refused the same way if
+/// the set was updated since it was read. Each file is swapped in atomically
+/// but the set isn't: an interruption between two files leaves fragments of
+/// two sets, which `decode_fragmented` refuses rather than mixing them.
+fn write_fragmented_images(paths: &[PathBuf], carriers: &[image::DynamicImage], mut payload: CombinedPayload, sign_key: Option<&[u8]>) -> Result<()> {
+    let read_version = payload.permissions.version;
+    payload.permissions.version += 1;
+    if let Some(key) = sign_key {
+        payload.permissions.sign(key)?;
+    }
+
+    let fragments = lsb::encode_fragmented(&payload.to_bytes()?, carriers)?;
+    let mut encoded = Vec::with_capacity(fragments.len());
+    for (path, fragment) in paths.iter().zip(&fragments) {
+        let mut bytes = Vec::new();
+        fragment.write_to(&mut Cursor::new(&mut bytes), ImageFormat::from_path(path)?)?;
+        encoded.push(bytes);
+    }
+
+    let (_, on_disk) = read_fragmented_images(paths)?;
+    refuse_stale_write(&paths[0], on_disk.permissions.version, read_version)?;
+    for (path, bytes) in paths.iter().zip(&encoded) {
+        write_atomic(path, bytes)?;
+    }
+    Ok(())
+}
+
+/// Save an image returned by the servers. The temp file is read back and
+/// must decode to a valid payload before it replaces `path`, so a partial or
+/// corrupted write is caught here rather than when someone tries to view it.
+fn write_encrypted_output(path: &Path, encrypted_image: &[u8]) -> Result<()> {
+    write_atomic_checked(path, encrypted_image, |tmp_path| {
+        let written = fs::read(tmp_path)?;
+        if written != encrypted_image {
+            bail!("wrote {} bytes but read back {} different bytes", encrypted_image.len(), written.len());
         }
+        read_protected_image(tmp_path).map(|_| ())
+    })
+    .with_context(|| format!("Failed to save '{}', it was left untouched", path.display()))
+}
+
+/// Write `data` to `path` through a temp file in the same directory and an
+/// atomic rename, so an interruption leaves the previous contents intact.
+fn write_atomic(path: &Path, data: &[u8]) -> Result<()> {
+    write_atomic_checked(path, data, |_| Ok(()))
+}
+
+/// `write_atomic`, running `check` on the synced temp file before the
+/// rename; if it fails the temp file is removed and `path` is not touched.
+fn write_atomic_checked(path: &Path, data: &[u8], check: impl FnOnce(&Path) -> Result<()>) -> Result<()> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
     };
+    let file_name = path
+        .file_name()
+        .ok_or_else(|| anyhow::anyhow!("'{}' is not a file path", path.display()))?;
+    // Per-process name, so concurrent writers don't clobber each other's temp file
+    let tmp_path = dir.join(format!(".{}.{}.tmp", file_name.to_string_lossy(), std::process::id()));
 
-    if has_access {
-        // Save the viewable image
-        encoded_img.save(VIEWABLE_OUTPUT_IMAGE)?;
-        println!("Saved viewable image to '{}'", VIEWABLE_OUTPUT_IMAGE);
+    let result = (|| -> Result<()> {
+        let mut tmp_file = fs::File::create(&tmp_path)?;
+        tmp_file.write_all(data)?;
+        tmp_file.sync_all()?;
+        drop(tmp_file);
+        check(&tmp_path)?;
+        fs::rename(&tmp_path, path)?;
+        Ok(())
+    })();
 
-        println!(
-            "Updated views left (for next peer): {}",
-            permissions.quotas.get(current_user).unwrap_or(&0)
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp_path);
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An empty scratch directory for one test
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("client-test-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Files in `dir`, sorted
+    fn dir_entries(dir: &Path) -> Vec<String> {
+        let mut names: Vec<String> = fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        names.sort();
+        names
+    }
+
+    fn permissions(owner: &str, quotas: &[(&str, u32)]) -> ImagePermissions {
+        ImagePermissions {
+            owner: owner.to_string(),
+            quotas: quotas.iter().map(|(user, views)| (user.to_string(), *views)).collect(),
+            note: None,
+            version: 0,
+            view_cooldown_secs: None,
+            last_views: HashMap::new(),
+            signature: None,
+            tokens: HashMap::new(),
+        }
+    }
+
+    /// Write a protected PNG carrying `permissions` into `dir`
+    fn protect(dir: &Path, permissions: ImagePermissions) -> PathBuf {
+        let carrier = image::DynamicImage::ImageRgb8(image::RgbImage::from_pixel(64, 64, image::Rgb([90, 120, 150])));
+        let payload = CombinedPayload { permissions, unified_image: vec![7; 16] };
+        let protected = lsb::encode(&carrier, &payload.to_bytes().unwrap()).unwrap();
+        let path = dir.join("protected.png");
+        protected.save(&path).unwrap();
+        path
+    }
+
+    fn embedded_permissions(path: &Path) -> ImagePermissions {
+        read_protected_image(path).unwrap().1.permissions
+    }
+
+    #[test]
+    fn inputs_sharing_a_stem_keep_their_extension_in_the_output_name() {
+        let out = Path::new("out");
+        let files = [PathBuf::from("in/a.jpg"), PathBuf::from("in/a.png"), PathBuf::from("in/b.jpeg"), PathBuf::from("in/my.photo.jpg")];
+        assert_eq!(
+            output_paths(&files, out).unwrap(),
+            vec![out.join("a.jpg.png"), out.join("a.png.png"), out.join("b.png"), out.join("my.photo.png")]
         );
 
-        // Re-create the CombinedPayload with updated permissions
-        let updated_combined_payload = CombinedPayload {
-            permissions,
-            unified_image: unified_image_bytes,
+        // a.jpg.png has a stem of its own, but the name a.jpg gets
+        let files = [PathBuf::from("in/a.jpg"), PathBuf::from("in/a.jpg.png"), PathBuf::from("in/a.png")];
+        let err = output_paths(&files, out).unwrap_err();
+        assert_eq!(exit_code(&err), Failure::InvalidInput as i32);
+        assert!(err.to_string().contains("'in/a.jpg' and 'in/a.jpg.png' would both be saved as 'out/a.jpg.png'"), "{}", err);
+    }
+
+    #[test]
+    fn interleaved_views_refuse_the_second_write() {
+        let dir = scratch_dir("interleaved-view");
+        let path = protect(&dir, permissions("alice", &[("bob", 2), ("carol", 2)]));
+
+        // Both viewers read version 0 before either writes back
+        let (bob_img, mut bob_read, layout) = read_protected_image(&path).unwrap();
+        let (carol_img, mut carol_read, _) = read_protected_image(&path).unwrap();
+        *bob_read.permissions.quotas.get_mut("bob").unwrap() -= 1;
+        *carol_read.permissions.quotas.get_mut("carol").unwrap() -= 1;
+
+        write_protected_image(&path, &bob_img, layout, bob_read, None).unwrap();
+        let after_bob = fs::read(&path).unwrap();
+        let err = write_protected_image(&path, &carol_img, layout, carol_read, None).unwrap_err();
+        assert!(err.to_string().contains("was updated by someone else while this ran (version 1 on disk, 0 when read)"), "{}", err);
+
+        assert_eq!(fs::read(&path).unwrap(), after_bob, "the refused write leaves the file alone");
+        let on_disk = embedded_permissions(&path);
+        assert_eq!(on_disk.version, 1);
+        assert_eq!((on_disk.quotas["bob"], on_disk.quotas["carol"]), (1, 2));
+        assert_eq!(dir_entries(&dir), vec!["protected.png"]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn views_within_the_cooldown_are_refused_but_previews_are_not() {
+        let dir = scratch_dir("view-cooldown");
+        let mut limited = permissions("alice", &[("bob", 3)]);
+        limited.view_cooldown_secs = Some(60);
+        let path = protect(&dir, limited);
+        let sink = dir.join("viewable");
+        fs::write(&sink, b"").unwrap();
+        let view = |preview| {
+            let encoding = ViewEncoding { format: ViewFormat::Png, jpeg_quality: DEFAULT_JPEG_QUALITY };
+            handle_view(&path, &[], Viewer::User("bob"), preview, Some(sink.to_str().unwrap()), encoding, None)
         };
 
-        // Re-encode back into the image
-        let updated_payload = bincode::serialize(&updated_combined_payload)?;
-        let updated_img = lsb::encode(&encoded_img, &updated_payload)?;
-        updated_img.save(input_path)?;
-        
-        println!(
-            "Re-embedded updated metadata back into -> '{}'",
-            input_path.display()
-        );
-    } else {
-        // Save the "Access Denied" image
-        fs::write(VIEWABLE_OUTPUT_IMAGE, unified_image_bytes)?;
-        println!(
-            "Saved default 'Access Denied' image to '{}'",
-            VIEWABLE_OUTPUT_IMAGE
-        );
+        view(false).unwrap();
+        let after_first = fs::read(&path).unwrap();
+        assert_eq!(embedded_permissions(&path).quotas["bob"], 2);
+
+        let err = view(false).unwrap_err();
+        assert!(err.to_string().contains("'bob' viewed"), "{}", err);
+        assert!(err.to_string().contains("too recently, try again in"), "{}", err);
+        assert_eq!(fs::read(&path).unwrap(), after_first, "the refused view spends nothing");
+
+        fs::write(&sink, b"").unwrap();
+        view(true).unwrap();
+        assert!(!fs::read(&sink).unwrap().is_empty(), "the preview is still written");
+        assert_eq!(fs::read(&path).unwrap(), after_first);
+
+        fs::remove_dir_all(&dir).unwrap();
     }
 
-    Ok(())
-}
\ No newline at end of file
+    #[test]
+    fn write_atomic_replaces_the_file_and_leaves_no_temp_file() {
+        let dir = scratch_dir("write-atomic");
+        let path = dir.join("image.png");
+        fs::write(&path, b"old").unwrap();
+
+        write_atomic(&path, b"new").unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"new");
+        assert_eq!(dir_entries(&dir), vec!["image.png"]);
+
+        // A failed write (here: the target is a directory) cleans up its temp file
+        let target = dir.join("taken");
+        fs::create_dir(&target).unwrap();
+        assert!(write_atomic(&target, b"data").is_err());
+        assert!(target.is_dir());
+        assert_eq!(dir_entries(&dir), vec!["image.png", "taken"]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn rehydrate_replaces_every_grant_and_only_for_the_owner() {
+        let dir = scratch_dir("rehydrate");
+        let mut before = permissions("alice", &[("bob", 1), ("carol", 2)]);
+        before.note = Some("keep me".to_string());
+        before.view_cooldown_secs = Some(60);
+        before.last_views = HashMap::from([("bob".to_string(), 100), ("carol".to_string(), 200)]);
+        let path = protect(&dir, before);
+
+        let grants = vec![("carol".to_string(), 5), ("dave".to_string(), 1)];
+        handle_rehydrate(&path, "alice", &grants, None, false, None, None).unwrap();
+        let after = embedded_permissions(&path);
+        assert_eq!(after.quotas, HashMap::from([("carol".to_string(), 5), ("dave".to_string(), 1)]));
+        assert_eq!(after.last_views, HashMap::from([("carol".to_string(), 200)]), "bob lost access, carol keeps her cooldown");
+        assert_eq!(after.note.as_deref(), Some("keep me"));
+        assert_eq!(after.view_cooldown_secs, Some(60));
+        assert_eq!(after.version, 1);
+
+        handle_rehydrate(&path, "alice", &grants, None, true, Some(0), None).unwrap();
+        let cleared = embedded_permissions(&path);
+        assert_eq!(cleared.note, None);
+        assert_eq!(cleared.view_cooldown_secs, None);
+
+        let unchanged = fs::read(&path).unwrap();
+        assert!(handle_rehydrate(&path, "bob", &[("bob".to_string(), 99)], None, false, None, None).is_err());
+        assert_eq!(fs::read(&path).unwrap(), unchanged);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn encrypt_refuses_a_protected_input_without_force() {
+        let dir = scratch_dir("encrypt-protected");
+        let path = protect(&dir, permissions("alice", &[]));
+        let plain = dir.join("plain.png");
+        image::RgbImage::from_pixel(64, 64, image::Rgb([90, 120, 150])).save(&plain).unwrap();
+        assert!(existing_protection(&fs::read(&plain).unwrap()).is_none());
+        assert_eq!(existing_protection(&fs::read(&path).unwrap()).unwrap().permissions.owner, "alice");
+
+        // Nothing listens here, so an encrypt that gets past the check fails on the network
+        let server = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().to_string();
+        let encrypt = |force| {
+            handle_encrypt(&path, "mallory", None, None, None, None, None, None, force, EncryptOutput::AsReturned, Some(&server), false,
+                           &RetryPolicy { max_attempts: 1, deadline: None, backoff: Duration::ZERO, max_backoff: Duration::ZERO })
+                .unwrap_err()
+        };
+
+        let refused = encrypt(false);
+        assert_eq!(exit_code(&refused), Failure::InvalidInput as i32);
+        assert!(refused.to_string().contains("already protected (owner 'alice')"), "{}", refused);
+        assert_ne!(exit_code(&encrypt(true)), Failure::InvalidInput as i32, "--force must go past the check");
+        assert_eq!(existing_protection(&fs::read(&path).unwrap()).unwrap().permissions.owner, "alice");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn topup_adds_views_for_the_owner_only() {
+        let dir = scratch_dir("topup");
+        let path = protect(&dir, permissions("alice", &[("bob", 2)]));
+
+        handle_topup(&path, "bob", 3, "alice", None).unwrap();
+        handle_topup(&path, "carol", 1, "alice", None).unwrap();
+        assert_eq!(embedded_permissions(&path).quotas,
+                   HashMap::from([("bob".to_string(), 5), ("carol".to_string(), 1)]));
+
+        let before = fs::read(&path).unwrap();
+        let err = handle_topup(&path, "bob", 10, "bob", None).unwrap_err();
+        assert!(err.to_string().contains("Only the owner"), "{}", err);
+        let err = handle_topup(&path, "bob", u32::MAX, "alice", None).unwrap_err();
+        assert!(err.to_string().contains("would overflow"), "{}", err);
+        assert_eq!(fs::read(&path).unwrap(), before);
+
+        // Signing with the owner's key on the first top-up, which later ones then need
+        handle_topup(&path, "bob", 1, "alice", Some(b"key")).unwrap();
+        assert!(embedded_permissions(&path).signature_matches(b"key").unwrap());
+        assert_eq!(exit_code(&handle_topup(&path, "bob", 1, "alice", None).unwrap_err()), Failure::InvalidInput as i32);
+        assert_eq!(exit_code(&handle_topup(&path, "bob", 1, "alice", Some(b"other")).unwrap_err()), Failure::InvalidInput as i32);
+        assert_eq!(embedded_permissions(&path).quotas["bob"], 6);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn spilled_payload_is_viewed_and_rewritten_across_its_fragments() {
+        let dir = scratch_dir("spill");
+        // Stands in for the image the servers return, its payload too big for any one carrier
+        let carrier = |name: &str| {
+            let path = dir.join(name);
+            image::RgbImage::from_pixel(16, 16, image::Rgb([90, 120, 150])).save(&path).unwrap();
+            path
+        };
+        let payload = CombinedPayload { permissions: permissions("alice", &[("bob", 2)]), unified_image: vec![7; 60] };
+        let payload = payload.to_bytes().unwrap();
+        let small = image::open(carrier("input.png")).unwrap();
+        assert!(payload.len() > lsb::capacity_bytes(&small));
+        let mut returned = Vec::new();
+        let big = image::DynamicImage::ImageRgb8(image::RgbImage::from_pixel(64, 64, image::Rgb([90, 120, 150])));
+        lsb::encode(&big, &payload).unwrap().write_to(&mut Cursor::new(&mut returned), ImageFormat::Png).unwrap();
+
+        let extra = vec![carrier("extra1.png"), carrier("extra2.png")];
+        let first = dir.join("out.png");
+        let paths = spill_encrypted_output(&returned, &fs::read(dir.join("input.png")).unwrap(), &extra, &first).unwrap();
+        assert_eq!(paths, vec![first.clone(), dir.join("out.1.png"), dir.join("out.2.png")]);
+        assert!(read_protected_image(&first).is_err());
+
+        // Fragments are given in any order; a missing one fails before anything is spent
+        let sink = dir.join("viewable");
+        fs::write(&sink, b"").unwrap();
+        let encoding = ViewEncoding { format: ViewFormat::Png, jpeg_quality: DEFAULT_JPEG_QUALITY };
+        let view = |input: &Path, fragments: &[PathBuf]| {
+            handle_view(input, fragments, Viewer::User("bob"), false, Some(sink.to_str().unwrap()), encoding, None)
+        };
+        let err = view(&paths[0], &paths[1..2]).unwrap_err();
+        assert!(format!("{:#}", err).contains("Missing 1 of 3 fragments: 2"), "{:#}", err);
+        view(&paths[2], &paths[..2]).unwrap();
+
+        let (_, viewed) = read_fragmented_images(&paths).unwrap();
+        assert_eq!(viewed.permissions.quotas["bob"], 1);
+        assert_eq!(viewed.permissions.version, 1);
+        assert!(viewed.permissions.last_views.contains_key("bob"));
+        assert_eq!(image::load_from_memory(&fs::read(&sink).unwrap()).unwrap().to_rgb8().dimensions(), (16, 16));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn inspect_reports_the_payload_length_of_each_image() {
+        let dir = scratch_dir("inspect");
+        let protected = protect(&dir, permissions("alice", &[]));
+        let (img, _, _) = read_protected_image(&protected).unwrap();
+        let length = lsb::decode(&img).unwrap().unwrap().len();
+        // Every LSB set claims a length no image can hold
+        let plain = dir.join("plain.png");
+        image::RgbImage::from_pixel(16, 16, image::Rgb([91, 121, 151])).save(&plain).unwrap();
+        let broken = dir.join("broken.png");
+        fs::write(&broken, b"not a png").unwrap();
+        fs::write(dir.join("notes.txt"), b"skipped").unwrap();
+
+        let files = image_files(&dir).unwrap();
+        assert_eq!(files, vec![broken.clone(), plain.clone(), protected.clone()]);
+        let lengths = inspect_files(&files);
+        assert!(lengths[0].1.is_err());
+        assert_eq!(lengths[1].1.as_ref().unwrap(), &None);
+        assert_eq!(lengths[2].1.as_ref().unwrap(), &Some(length));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn redundant_copies_are_read_past_a_damaged_tile_and_kept_on_rewrite() {
+        let dir = scratch_dir("redundant");
+        let path = protect(&dir, permissions("alice", &[("bob", 1)]));
+        let redundant = relayout(&fs::read(&path).unwrap(), lsb::Layout::Redundant(4)).unwrap();
+        fs::write(&path, redundant).unwrap();
+
+        // Break the length header of the first copy, in the top-left tile
+        let mut img = image::open(&path).unwrap().to_rgb8();
+        for x in 0..32 {
+            img.get_pixel_mut(x, 0).0[0] ^= 1;
+        }
+        img.save(&path).unwrap();
+        assert_eq!(existing_protection(&fs::read(&path).unwrap()).unwrap().permissions.owner, "alice");
+
+        handle_topup(&path, "bob", 2, "alice", None).unwrap();
+        let (_, payload, layout) = read_protected_image(&path).unwrap();
+        assert_eq!(payload.permissions.quotas["bob"], 3);
+        assert_eq!(layout, lsb::Layout::Redundant(4));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn parity_repairs_flipped_bits_and_is_kept_on_rewrite() {
+        let dir = scratch_dir("parity");
+        let path = protect(&dir, permissions("alice", &[("bob", 1)]));
+        let ecc = relayout(&fs::read(&path).unwrap(), lsb::Layout::Ecc(1.0)).unwrap();
+        fs::write(&path, ecc).unwrap();
+
+        // Flip a bit of the first data shard, right after the header copies
+        let mut img = image::open(&path).unwrap().to_rgb8();
+        img.get_pixel_mut(40, 1).0[0] ^= 1;
+        img.save(&path).unwrap();
+
+        handle_topup(&path, "bob", 2, "alice", None).unwrap();
+        let (_, payload, layout) = read_protected_image(&path).unwrap();
+        assert_eq!(payload.permissions.quotas["bob"], 3);
+        assert_eq!(layout, lsb::Layout::Ecc(1.0));
+
+        assert!(parse_parity("0.25").is_ok());
+        assert!(parse_parity("0").is_err());
+        assert!(parse_parity("1.5").is_err());
+        assert!(parse_parity("NaN").is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn encrypted_output_that_fails_its_check_leaves_the_old_file() {
+        let dir = scratch_dir("checked-output");
+        let protected = fs::read(protect(&dir, permissions("alice", &[]))).unwrap();
+        let path = dir.join("encrypted.png");
+        fs::write(&path, b"previous output").unwrap();
+
+        // A reply that isn't a protected image, e.g. cut short on the way
+        let err = write_encrypted_output(&path, &protected[..protected.len() / 2]).unwrap_err();
+        assert!(format!("{:#}", err).contains("it was left untouched"), "{:#}", err);
+        assert_eq!(fs::read(&path).unwrap(), b"previous output");
+        assert_eq!(dir_entries(&dir), vec!["encrypted.png", "protected.png"]);
+
+        write_encrypted_output(&path, &protected).unwrap();
+        assert_eq!(fs::read(&path).unwrap(), protected);
+        assert_eq!(embedded_permissions(&path).owner, "alice");
+
+        // The check runs on the synced temp file, before the rename
+        let err = write_atomic_checked(&path, b"new", |tmp_path| {
+            assert_eq!(fs::read(tmp_path).unwrap(), b"new");
+            bail!("rejected")
+        })
+        .unwrap_err();
+        assert_eq!(err.to_string(), "rejected");
+        assert_eq!(fs::read(&path).unwrap(), protected);
+        assert_eq!(dir_entries(&dir), vec!["encrypted.png", "protected.png"]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// A worker that answers one redirected request with `reply`; returns its address
+    fn fake_worker(reply: LoadBalancingMessage) -> String {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut size_bytes = [0u8; 4];
+            stream.read_exact(&mut size_bytes).unwrap();
+            let mut request = vec![0u8; u32::from_be_bytes(size_bytes) as usize];
+            stream.read_exact(&mut request).unwrap();
+            let json = serde_json::to_vec(&reply).unwrap();
+            stream.write_all(&(json.len() as u32).to_be_bytes()).unwrap();
+            stream.write_all(&json).unwrap();
+        });
+        addr
+    }
+
+    #[test]
+    fn redirected_results_count_only_with_a_committed_index() {
+        let follow = |reply| {
+            let target = format!("{}:ticket", fake_worker(reply));
+            follow_redirect("leader", &target, b"meta", b"image", None)
+        };
+
+        let committed = follow(LoadBalancingMessage::DelegatedResult { encrypted_image: vec![1, 2, 3], committed_index: 7 });
+        assert_eq!(committed.unwrap(), (vec![1, 2, 3], Some(7)));
+
+        // A worker from before results were committed sends none, and one whose leader didn't commit says so
+        for reply in [
+            LoadBalancingMessage::WorkResult { encrypted_image: vec![1, 2, 3] },
+            LoadBalancingMessage::WorkRejected { reason: "NOT_COMMITTED: the leader did not commit the request".to_string() },
+        ] {
+            let err = follow(reply).unwrap_err();
+            assert!(err.to_string().starts_with("NOT_COMMITTED:"), "{}", err);
+        }
+    }
+}
@@ -0,0 +1,298 @@
+//! End-to-end check of a real 3-node cluster
+//!
+//! Launches three `server` processes on localhost, waits for a leader, then
+//! drives the real `client` binary through encrypt and view and checks what
+//! ends up embedded in the images. Catches framing and protocol mismatches
+//! between client and server that nothing else exercises. After the encrypt it
+//! also checks that every follower's committed log is the leader's, entry for
+//! entry, and that the encrypt committed exactly one entry.
+//!
+//! Run examples:
+//! # Build everything, then run against the load-balancing server
+//! cargo build --bins && cargo run --bin e2e
+//!
+//! # Against the server without load balancing, keeping logs and images
+//! cargo run --bin e2e -- --server-bin target/debug/server_No_load_Balancing --keep
+//!
+//! # Pass extra flags through to every server
+//! cargo run --bin e2e -- --server-arg=--redirect --server-arg=--cluster-key=secret
+
+use anyhow::{bail, ensure, Context, Result};
+use clap::Parser;
+use cloud_p2p_project::{find_leader, lsb, query_health, query_log_consistency, CombinedPayload, LogConsistencyReport, LogVerdict};
+use image::{DynamicImage, RgbImage};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const OWNER: &str = "carol";
+const INPUT_IMAGE: &str = "e2e_input.png";
+const ENCRYPTED_IMAGE: &str = "encrypted_lsb_image.png";
+const VIEWABLE_IMAGE: &str = "viewable_image.png";
+// Followers learn the new commit index from the next heartbeat
+const REPLICATION_TIMEOUT: Duration = Duration::from_secs(20);
+
+#[derive(Parser)]
+#[command(version, about = "End-to-end test of a local 3-node cluster", long_about = None)]
+struct Cli {
+    /// Server binary to launch (defaults to `server` next to this binary)
+    #[arg(long)]
+    server_bin: Option<PathBuf>,
+
+    /// Client binary to drive (defaults to `client` next to this binary)
+    #[arg(long)]
+    client_bin: Option<PathBuf>,
+
+    /// Extra argument passed to every server (repeatable)
+    #[arg(long, allow_hyphen_values = true)]
+    server_arg: Vec<String>,
+
+    /// Application port of the first node; the others use the next two
+    #[arg(long, default_value = "19080")]
+    base_port: u16,
+
+    /// Access-denied image the servers embed
+    #[arg(long, default_value = "unified_image.png")]
+    unified_image: PathBuf,
+
+    /// Seconds to wait for the cluster to elect a leader
+    #[arg(long, default_value = "40")]
+    startup_timeout: u64,
+
+    /// Keep the working directory (logs, images, Raft state) after a pass
+    #[arg(long)]
+    keep: bool,
+}
+
+/// Kills the server processes when dropped, so a failed check never leaves
+/// a cluster running in the background
+struct Cluster {
+    servers: Vec<String>,
+    children: Vec<Child>,
+}
+
+impl Drop for Cluster {
+    fn drop(&mut self) {
+        for child in &mut self.children {
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+    }
+}
+
+fn main() -> Result<()> {
+    let cli = Cli::parse();
+
+    let work_dir = std::env::temp_dir().join(format!("e2e_{}", std::process::id()));
+    fs::create_dir_all(&work_dir)?;
+    println!("=== End-to-end test (working directory: {}) ===", work_dir.display());
+
+    let result = run(&cli, &work_dir);
+    match &result {
+        Ok(()) => {
+            println!("\n✅ All end-to-end checks passed");
+            if !cli.keep {
+                let _ = fs::remove_dir_all(&work_dir);
+            }
+        }
+        Err(_) => println!("\n❌ End-to-end test failed; server logs are in {}", work_dir.display()),
+    }
+    result
+}
+
+fn run(cli: &Cli, work_dir: &Path) -> Result<()> {
+    let server_bin = sibling_binary(cli.server_bin.as_deref(), "server")?;
+    let client_bin = sibling_binary(cli.client_bin.as_deref(), "client")?;
+
+    // Servers read unified_image.png from their working directory
+    fs::copy(&cli.unified_image, work_dir.join("unified_image.png"))
+        .with_context(|| format!("Cannot copy unified image '{}'", cli.unified_image.display()))?;
+
+    // A synthetic carrier big enough for the permissions plus the unified image
+    let carrier = DynamicImage::ImageRgb8(RgbImage::from_fn(512, 512, |x, y| {
+        image::Rgb([(x / 2) as u8, (y / 2) as u8, ((x + y) / 4) as u8])
+    }));
+    carrier.save(work_dir.join(INPUT_IMAGE))?;
+
+    let cluster = start_cluster(cli, &server_bin, work_dir)?;
+    fs::write(work_dir.join("servers.conf"), cluster.servers.join("\n") + "\n")?;
+
+    let deadline = Instant::now() + Duration::from_secs(cli.startup_timeout);
+    let (leader, status) = loop {
+        if let Some(found) = find_leader(&cluster.servers, Duration::from_secs(1)) {
+            break found;
+        }
+        if Instant::now() >= deadline {
+            bail!("No leader elected within {}s", cli.startup_timeout);
+        }
+        thread::sleep(Duration::from_millis(500));
+    };
+    println!("✓ Leader elected: {} at {} (term {})", status.raft.server_id, leader, status.raft.current_term);
+
+    // Encrypt through the cluster. The leader's no-op is already in its log,
+    // so the encrypt must commit exactly the next entry
+    let expected_commit = status.raft.last_log_index + 1;
+    run_client(&client_bin, work_dir, &["encrypt", "--input", INPUT_IMAGE, "--owner", OWNER])?;
+    let encrypted_path = work_dir.join(ENCRYPTED_IMAGE);
+    let (encrypted, payload) = read_payload(&encrypted_path)?;
+    ensure!(
+        encrypted.width() == carrier.width() && encrypted.height() == carrier.height(),
+        "Encrypted image is {}x{}, expected the carrier's 512x512",
+        encrypted.width(),
+        encrypted.height()
+    );
+    ensure!(payload.permissions.owner == OWNER, "Owner is '{}', expected '{}'", payload.permissions.owner, OWNER);
+    let expected: HashMap<String, u32> = [(OWNER, 3), ("alice", 2), ("bob", 1)]
+        .into_iter()
+        .map(|(user, views)| (user.to_string(), views))
+        .collect();
+    ensure!(payload.permissions.quotas == expected, "Quotas are {:?}, expected {:?}", payload.permissions.quotas, expected);
+    image::load_from_memory(&payload.unified_image).context("Embedded unified image does not decode")?;
+    println!("✓ Encrypt: owner and quotas embedded correctly");
+
+    let report = wait_for_matching_logs(&leader, expected_commit)?;
+    println!("✓ Replication: {} followers hold the leader's {} committed entries, in order",
+             report.peers.len(), report.checked_by.commit_index);
+
+    let health = query_health(&leader, Duration::from_secs(1))?;
+    ensure!(health.is_leader && health.healthy, "Leader reports itself unhealthy: {:?}", health);
+    println!("✓ Health: leader in contact with a quorum, load score {:?}", health.load_score);
+
+    // An authorized view decrements that user's quota only
+    run_client(&client_bin, work_dir, &["view", "--input", ENCRYPTED_IMAGE, "--user", "alice"])?;
+    let (_, payload) = read_payload(&encrypted_path)?;
+    check_quota(&payload, "alice", 1)?;
+    check_quota(&payload, "bob", 1)?;
+    check_quota(&payload, OWNER, 3)?;
+    println!("✓ View as alice: quota 2 -> 1");
+
+    // Using up the last view, then viewing again, gets the access denied image
+    run_client(&client_bin, work_dir, &["view", "--input", ENCRYPTED_IMAGE, "--user", "bob"])?;
+    check_quota(&read_payload(&encrypted_path)?.1, "bob", 0)?;
+    run_client(&client_bin, work_dir, &["view", "--input", ENCRYPTED_IMAGE, "--user", "bob"])?;
+    let (_, payload) = read_payload(&encrypted_path)?;
+    check_quota(&payload, "bob", 0)?;
+    let viewable = fs::read(work_dir.join(VIEWABLE_IMAGE))?;
+    ensure!(viewable == payload.unified_image, "Denied view did not produce the embedded unified image");
+    println!("✓ View as bob: quota 1 -> 0, then access denied");
+
+    drop(cluster);
+    Ok(())
+}
+
+/// Launch three nodes on consecutive ports, each logging to n<i>.log
+fn start_cluster(cli: &Cli, server_bin: &Path, work_dir: &Path) -> Result<Cluster> {
+    let servers: Vec<String> = (0..3).map(|i| format!("127.0.0.1:{}", cli.base_port + i)).collect();
+    let mut cluster = Cluster { servers: servers.clone(), children: Vec::new() };
+
+    for (i, addr) in servers.iter().enumerate() {
+        let node_id = format!("n{}", i + 1);
+        let log = fs::File::create(work_dir.join(format!("{}.log", node_id)))?;
+        let peers = servers.iter().filter(|peer| *peer != addr);
+
+        let child = Command::new(server_bin)
+            .arg((cli.base_port + i as u16).to_string())
+            .arg(&node_id)
+            .args(peers)
+            .arg("--data-dir")
+            .arg(work_dir.join(&node_id))
+            .args(&cli.server_arg)
+            .current_dir(work_dir)
+            .env("RUST_LOG", "info")
+            .stdout(Stdio::from(log.try_clone()?))
+            .stderr(Stdio::from(log))
+            .spawn()
+            .with_context(|| format!("Cannot start '{}'", server_bin.display()))?;
+        cluster.children.push(child);
+    }
+
+    println!("Started 3 servers on {}", servers.join(", "));
+    Ok(cluster)
+}
+
+/// Run the client in the working directory, failing on a non-zero exit
+fn run_client(client_bin: &Path, work_dir: &Path, args: &[&str]) -> Result<()> {
+    let output = Command::new(client_bin)
+        .args(args)
+        .current_dir(work_dir)
+        .output()
+        .with_context(|| format!("Cannot run '{}'", client_bin.display()))?;
+
+    if !output.status.success() {
+        bail!(
+            "client {} exited with {}\n--- stdout ---\n{}\n--- stderr ---\n{}",
+            args.join(" "),
+            output.status,
+            String::from_utf8_lossy(&output.stdout),
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+    Ok(())
+}
+
+/// Wait until every follower has committed the leader's committed prefix and
+/// holds the same entries, compared as a digest of all entries in order, so a
+/// missing, extra or reordered entry fails where a check for one command would
+/// pass. Also checks the leader committed exactly `expected_commit` entries.
+fn wait_for_matching_logs(leader: &str, expected_commit: u64) -> Result<LogConsistencyReport> {
+    let deadline = Instant::now() + REPLICATION_TIMEOUT;
+    loop {
+        let report = query_log_consistency(leader, Duration::from_secs(10))?;
+        let diverged: Vec<&str> = report
+            .peers
+            .iter()
+            .filter(|peer| peer.verdict == LogVerdict::Diverged)
+            .map(|peer| peer.address.as_str())
+            .collect();
+        ensure!(diverged.is_empty(), "Committed log differs from the leader's on {}", diverged.join(", "));
+
+        let committed = report.checked_by.commit_index;
+        ensure!(committed <= expected_commit,
+                "Leader committed {} entries, expected {}: something was committed twice", committed, expected_commit);
+        // A follower's commit index only moves with the next heartbeat
+        let caught_up = report.peers.iter().all(|peer| {
+            peer.verdict == LogVerdict::Match && peer.summary.as_ref().is_some_and(|summary| summary.commit_index >= committed)
+        });
+        if committed == expected_commit && caught_up {
+            return Ok(report);
+        }
+        if Instant::now() >= deadline {
+            let verdicts: Vec<String> = report
+                .peers
+                .iter()
+                .map(|peer| format!("{} {:?}", peer.address, peer.verdict))
+                .collect();
+            bail!("Logs did not converge within {:?}: leader committed {} of {} entries, peers: {}",
+                  REPLICATION_TIMEOUT, committed, expected_commit, verdicts.join(", "));
+        }
+        thread::sleep(Duration::from_millis(500));
+    }
+}
+
+fn read_payload(path: &Path) -> Result<(DynamicImage, CombinedPayload)> {
+    let img = image::open(path).with_context(|| format!("Cannot open '{}'", path.display()))?;
+    let bytes = lsb::decode_protected(&img).with_context(|| format!("No payload in '{}'", path.display()))?;
+    Ok((img, CombinedPayload::from_bytes(&bytes)?))
+}
+
+fn check_quota(payload: &CombinedPayload, user: &str, expected: u32) -> Result<()> {
+    let actual = payload.permissions.quotas.get(user).copied();
+    ensure!(actual == Some(expected), "{} has {:?} views left, expected {}", user, actual, expected);
+    Ok(())
+}
+
+/// Resolve a binary given on the command line, or the one built alongside this one
+fn sibling_binary(explicit: Option<&Path>, name: &str) -> Result<PathBuf> {
+    let path = match explicit {
+        Some(path) => path.to_path_buf(),
+        None => std::env::current_exe()?.with_file_name(name),
+    };
+    if !path.exists() {
+        bail!("'{}' not found; build it first with `cargo build --bins`", path.display());
+    }
+    // Absolute, since the processes are started in the working directory
+    Ok(path.canonicalize()?)
+}
@@ -1,82 +1,231 @@
 use anyhow::{bail, Result};
-use bincode;
-use cloud_p2p_project::raft::{RaftConfig, RaftNode};
-use cloud_p2p_project::{lsb, CombinedPayload, ImagePermissions, LoadBalancingMessage, RaftMessage, ServerMetrics};
-use image::ImageOutputFormat;
-use log::{error, info};
-use std::env;
-use std::fs;
-use std::io::Cursor;
+use clap::Parser;
+use cloud_p2p_project::cache::EncryptionCache;
+use cloud_p2p_project::raft::{election_seed_from_env, RaftConfig, RaftNode, TimeoutDistribution, DEFAULT_MAX_RPC_BYTES};
+use cloud_p2p_project::server::{check_unified_image_periodically, commit_encryption_digest, encryption_digest, still_leader_for, default_encode_threads, finish_request, handle_raft_message, is_raft_connection, open_request, process_encryption_work, read_request_image, serve_client, start_raft_listener, EncodePool, Opened, RequestHandler, ENCODE_POOL, NO_RAFT, PNG_COMPRESSION, REFUSE_INVALID_UNIFIED, SOCKET_BUFFER_BYTES, UNIFIED_IMAGE_FIT};
+use cloud_p2p_project::{raft_addresses, offset_address, guess_advertised_address, init_logging, is_self_address, load_server_list, delegation_mac, delegation_mac_matches, delegation_report_mac, delegation_report_mac_matches, set_single_port, run_startup_checks, print_dry_run, BadRequest, PngCompression, LoadBalancingMessage, ServerMetrics, RAFT_PORT_OFFSET};
+use log::{error, info, warn};
+use std::collections::HashMap;
+use std::path::PathBuf;
 use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
-use std::sync::Arc;
-use std::time::Instant;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::{TcpListener, TcpStream};
-use std::os::unix::io::AsRawFd;
 
-/// Configure TCP socket for large file transfers
-fn configure_large_transfer_socket(stream: &TcpStream) -> Result<()> {
-    #[cfg(unix)]
-    {
-        use std::mem;
-        
-        unsafe {
-            let fd = stream.as_raw_fd();
-            let size: libc::c_int = 8 * 1024 * 1024; // 8MB buffer
-            
-            // Set send buffer
-            libc::setsockopt(
-                fd,
-                libc::SOL_SOCKET,
-                libc::SO_SNDBUF,
-                &size as *const _ as *const libc::c_void,
-                mem::size_of_val(&size) as libc::socklen_t,
-            );
-            
-            // Set receive buffer
-            libc::setsockopt(
-                fd,
-                libc::SOL_SOCKET,
-                libc::SO_RCVBUF,
-                &size as *const _ as *const libc::c_void,
-                mem::size_of_val(&size) as libc::socklen_t,
-            );
-        }
-    }
-    Ok(())
-}
-
-const RAFT_PORT_OFFSET: u16 = 1000;    // Raft runs on port + 1000
+// Raft runs on port + RAFT_PORT_OFFSET (1000, shared with clients)
 const METRICS_PORT_OFFSET: u16 = 2000; // Metrics server on port + 2000
 const WORK_PORT_OFFSET: u16 = 3000;    // Work receiver on port + 3000
 
+/// How long a worker honours a delegation ticket before the client must have used it
+const DELEGATION_TICKET_TTL: Duration = Duration::from_secs(60);
+
+/// How long the leader waits for a redirected client to stop sending its image and hang up
+const REDIRECT_DRAIN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long after granting a delegation the leader still commits its reported result
+const DELEGATION_REPORT_TTL: Duration = Duration::from_secs(300);
+
+/// How long the leader waits for a worker to take a delegation before forwarding instead
+const DELEGATION_GRANT_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Parser)]
+#[command(version, about = "Distributed image encryption server", long_about = None)]
+struct Cli {
+    /// Port for the client-facing application listener
+    port: u16,
+
+    /// Unique id of this server in the Raft cluster
+    server_id: String,
+
+    /// Application addresses of the other servers (host:port)
+    peers: Vec<String>,
+
+    /// Read peers from a file (one host:port per line); takes precedence over PEERS
+    #[arg(long)]
+    peers_file: Option<String>,
+
+    /// Redirect clients to the chosen worker instead of proxying work through the leader
+    #[arg(long, requires = "cluster_key")]
+    redirect: bool,
+
+    /// Shared secret authenticating the leader's delegation grants to workers.
+    /// Give every server the same one; a server without it accepts no redirected
+    /// clients. It shows up in the process list, keep it off shared hosts
+    #[arg(long, value_name = "KEY")]
+    cluster_key: Option<String>,
+
+    /// Number of encrypted results to keep for identical requests (0 disables)
+    #[arg(long, default_value = "16")]
+    dedup_cache_size: usize,
+
+    /// Directory for Raft state files
+    #[arg(long, default_value = ".")]
+    data_dir: PathBuf,
+
+    /// Address clients should use to reach this server, sent to followers while
+    /// leader so their NOT_LEADER replies can point at it (guessed if omitted)
+    #[arg(long)]
+    advertised_addr: Option<String>,
+
+    /// Serve Raft and status traffic on the application port instead of port + 1000.
+    /// Connections starting with the byte 'R' are Raft; all peers must use this too
+    #[arg(long)]
+    single_port: bool,
+
+    /// Cap on the serialized log entries in one AppendEntries RPC, in bytes
+    /// (an entry larger than this is still sent, on its own)
+    #[arg(long, default_value_t = DEFAULT_MAX_RPC_BYTES)]
+    max_rpc_bytes: usize,
+
+    /// Validate the unified image, peers, Raft state and data directory, then exit
+    #[arg(long)]
+    check: bool,
+
+    /// Print the addresses this server would bind and its resolved Raft config, then exit
+    #[arg(long, conflicts_with = "check")]
+    dry_run: bool,
+
+    /// Write this node's saved Raft state (term, vote and log) to FILE as JSON, then exit
+    #[arg(long, value_name = "FILE", conflicts_with_all = ["check", "dry_run", "import_state"])]
+    export_state: Option<PathBuf>,
+
+    /// Replace this node's saved Raft state with a file from --export-state, then exit.
+    /// The node must be stopped
+    #[arg(long, value_name = "FILE", conflicts_with_all = ["check", "dry_run"])]
+    import_state: Option<PathBuf>,
+
+    /// Import even if this node's state has a higher term or entries the export lacks
+    #[arg(long, requires = "import_state")]
+    force_import: bool,
+
+    /// Keep client connections open for further requests after a successful reply
+    #[arg(long)]
+    keepalive: bool,
+
+    /// Write logs as one JSON object per line instead of plain text
+    #[arg(long)]
+    json_logs: bool,
+
+    /// How election timeouts are drawn between 4 and 10 s: uniform, or exponential
+    /// to favor short timeouts so a leaderless cluster elects sooner
+    #[arg(long, value_name = "DIST", default_value_t = TimeoutDistribution::Uniform)]
+    election_timeout_distribution: TimeoutDistribution,
+
+    /// Log every Raft role change (old role, new role, term and reason) as one line
+    #[arg(long)]
+    verbose_raft: bool,
+
+    /// Also append Raft role changes to FILE, one JSON object per line
+    #[arg(long, value_name = "FILE")]
+    raft_trace_file: Option<PathBuf>,
+
+    /// PNG compression for encrypted images: fast (least CPU, larger output),
+    /// default, or best (smallest output, most CPU). The payload is the same either way
+    #[arg(long, value_name = "LEVEL", default_value_t = PngCompression::Default)]
+    png_compression: PngCompression,
+
+    /// Shrink the unified image to fit the capacity each carrier has left
+    #[arg(long)]
+    fit_unified_image: bool,
+
+    /// Also cap the unified image's width and height (implies --fit-unified-image)
+    #[arg(long)]
+    unified_max_dimension: Option<u32>,
+
+    /// While leader, reload the unified image every this many seconds (0 disables)
+    #[arg(long, default_value = "30")]
+    unified_check_interval: u64,
+
+    /// Answer new requests with UNAVAILABLE while the last unified image check failed
+    #[arg(long)]
+    refuse_invalid_unified: bool,
+
+    /// Send and receive buffer size for client connections, in KiB (defaults to
+    /// the request's image size, between 256 KiB and 8 MiB)
+    #[arg(long, value_name = "KB", value_parser = clap::value_parser!(u32).range(1..=1024 * 1024))]
+    socket_buffer_kb: Option<u32>,
+
+    /// Relative capacity of this server for load balancing: a server declaring 2
+    /// looks half as loaded as one declaring 1 with the same connections
+    #[arg(long, default_value_t = 1.0, value_parser = parse_capacity)]
+    capacity: f32,
+
+    /// Most encryptions run at the same time; more requests wait for a slot
+    /// (defaults to the number of CPUs)
+    #[arg(long, value_parser = clap::value_parser!(u16).range(1..))]
+    encode_threads: Option<u16>,
+
+    /// Run as a Raft learner: keep a full copy of the log but never vote or lead
+    #[arg(long)]
+    learner: bool,
+
+    /// Application address of a peer that runs with --learner (repeatable). It is
+    /// sent the log but left out of elections and commits; added to the peers if missing
+    #[arg(long = "learner-peer", value_name = "HOST:PORT")]
+    learner_peers: Vec<String>,
+
+    /// Development mode: skip Raft entirely (no elections, no Raft port, nothing
+    /// committed) and always act as the leader. Peers are ignored
+    #[arg(long, conflicts_with_all = ["learner", "learner_peers", "initial_leader"])]
+    no_raft: bool,
+
+    /// On a fresh cluster, let the node with this id win the first election at
+    /// once while the others hold off (give every node the same value). Ignored
+    /// by a node that already has persisted state
+    #[arg(long, value_name = "ID")]
+    initial_leader: Option<String>,
+}
+
+fn parse_capacity(value: &str) -> Result<f32, String> {
+    match value.parse::<f32>() {
+        Ok(capacity) if capacity.is_finite() && capacity > 0.0 => Ok(capacity),
+        Ok(_) => Err("must be a positive number".to_string()),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
 // =============================================================================
 // LOAD BALANCING STATE
 // =============================================================================
 
 pub struct LoadBalancingState {
+    pub capacity: f32, // from --capacity, reported in our metrics
     pub active_connections: AtomicU32,
     pub total_requests: AtomicU64,
     pub total_response_time_ms: AtomicU64,
+    pub delegation_tickets: Mutex<HashMap<String, Instant>>, // ticket -> time granted
+    pub granted_tickets: Mutex<HashMap<String, (Instant, u64)>>, // as leader: ticket -> time and term granted
+    pub cluster_key: Option<Vec<u8>>, // from --cluster-key, authenticates delegation grants
+}
+
+impl Default for LoadBalancingState {
+    fn default() -> Self {
+        Self::new(1.0, None)
+    }
 }
 
 impl LoadBalancingState {
-    pub fn new() -> Self {
+    pub fn new(capacity: f32, cluster_key: Option<Vec<u8>>) -> Self {
         Self {
+            capacity,
             active_connections: AtomicU32::new(0),
             total_requests: AtomicU64::new(0),
             total_response_time_ms: AtomicU64::new(0),
+            delegation_tickets: Mutex::new(HashMap::new()),
+            granted_tickets: Mutex::new(HashMap::new()),
+            cluster_key,
         }
     }
     
     /// Get current metrics for this server
     pub fn get_metrics(&self, server_id: String) -> ServerMetrics {
         let total_requests = self.total_requests.load(Ordering::Relaxed);
-        let avg_response = if total_requests > 0 {
-            self.total_response_time_ms.load(Ordering::Relaxed) / total_requests
-        } else {
-            0
-        };
+        let avg_response = self
+            .total_response_time_ms
+            .load(Ordering::Relaxed)
+            .checked_div(total_requests)
+            .unwrap_or(0);
         
         let active_conns = self.active_connections.load(Ordering::Relaxed);
         
@@ -87,6 +236,7 @@ impl LoadBalancingState {
             avg_response_time_ms: avg_response,
             total_requests,
             timestamp: std::time::SystemTime::now(),
+            capacity: self.capacity,
         }
     }
     
@@ -109,6 +259,38 @@ impl LoadBalancingState {
         self.total_requests.fetch_add(1, Ordering::Relaxed);
         self.total_response_time_ms.fetch_add(response_time_ms, Ordering::Relaxed);
     }
+
+    /// Remember a ticket the leader granted so the redirected client can redeem it
+    pub fn accept_ticket(&self, ticket: String) {
+        let mut tickets = self.delegation_tickets.lock().unwrap();
+        tickets.retain(|_, granted| granted.elapsed() < DELEGATION_TICKET_TTL);
+        tickets.insert(ticket, Instant::now());
+    }
+
+    /// Consume a ticket; each ticket is valid for exactly one request
+    pub fn redeem_ticket(&self, ticket: &str) -> bool {
+        let mut tickets = self.delegation_tickets.lock().unwrap();
+        match tickets.remove(ticket) {
+            Some(granted) => granted.elapsed() < DELEGATION_TICKET_TTL,
+            None => false,
+        }
+    }
+
+    /// As leader, remember a ticket we granted so the worker can report its result
+    pub fn record_grant(&self, ticket: String, term: u64) {
+        let mut grants = self.granted_tickets.lock().unwrap();
+        grants.retain(|_, (granted, _)| granted.elapsed() < DELEGATION_REPORT_TTL);
+        grants.insert(ticket, (Instant::now(), term));
+    }
+
+    /// Consume a grant when its result is reported, returning the term it was granted in
+    pub fn take_grant(&self, ticket: &str) -> Option<u64> {
+        let mut grants = self.granted_tickets.lock().unwrap();
+        grants
+            .remove(ticket)
+            .filter(|(granted, _)| granted.elapsed() < DELEGATION_REPORT_TTL)
+            .map(|(_, term)| term)
+    }
 }
 
 // =============================================================================
@@ -117,34 +299,108 @@ impl LoadBalancingState {
 
 #[tokio::main]
 async fn main() -> Result<()> {
+    // Parse command-line arguments
+    let cli = Cli::parse();
+
     // Initialize logger
-    env_logger::init();
+    init_logging(cli.json_logs, &cli.server_id);
+    let port = cli.port;
+    let server_id = cli.server_id;
 
-    // Parse command-line arguments
-    let args: Vec<String> = env::args().collect();
-    if args.len() < 3 {
-        bail!("Usage: server <port> <server_id> [peer1:port] [peer2:port] ...");
+    // A peers file wins over argv; our own entry is skipped so servers.conf can be reused
+    let mut peers: Vec<String> = match &cli.peers_file {
+        Some(path) => {
+            if !cli.peers.is_empty() {
+                info!("Using peers from '{}', ignoring {} peer(s) given on the command line",
+                      path, cli.peers.len());
+            }
+            load_server_list(path)?
+                .into_iter()
+                .filter(|p| !is_self_address(p, port))
+                .collect()
+        }
+        None => cli.peers,
+    };
+    for learner in &cli.learner_peers {
+        if !peers.contains(learner) {
+            peers.push(learner.clone());
+        }
     }
-
-    let port: u16 = args[1].parse()?;
-    let server_id = args[2].clone();
-    let peers: Vec<String> = args[3..].to_vec();
+    if cli.no_raft && !peers.is_empty() {
+        warn!("--no-raft: ignoring {} peer(s)", peers.len());
+        peers.clear();
+    }
+    let redirect = cli.redirect;
+    let keepalive = cli.keepalive;
+    let single_port = cli.single_port;
+    set_single_port(single_port);
 
     info!("Starting server {} on port {}", server_id, port);
     info!("Peers: {:?}", peers);
+    if cli.no_raft {
+        warn!("CONSENSUS DISABLED (--no-raft): acting as a single-node leader, nothing is replicated or committed");
+    }
+    if cli.learner {
+        info!("Running as a learner: replicating the log without voting");
+    }
+    if !cli.learner_peers.is_empty() {
+        info!("Learner peers (not in the quorum): {:?}", cli.learner_peers);
+    }
+    if redirect {
+        info!("Redirect mode enabled: clients will be sent directly to the chosen worker");
+    }
 
     // Create load balancing state
-    let lb_state = Arc::new(LoadBalancingState::new());
-
-    // Convert peer addresses to include Raft port
-    let raft_peers: Vec<String> = peers
-        .iter()
-        .map(|p| {
-            let parts: Vec<&str> = p.split(':').collect();
-            let peer_port: u16 = parts[1].parse().unwrap();
-            format!("{}:{}", parts[0], peer_port + RAFT_PORT_OFFSET)
-        })
-        .collect();
+    let lb_state = Arc::new(LoadBalancingState::new(cli.capacity, cli.cluster_key.as_ref().map(|key| key.as_bytes().to_vec())));
+    if cli.capacity != 1.0 {
+        info!("Declaring capacity {} to the load balancer", cli.capacity);
+    }
+
+    // Cache of encrypted results for identical requests
+    let cache = Arc::new(EncryptionCache::new(cli.dedup_cache_size));
+
+    if let Some(kb) = cli.socket_buffer_kb {
+        SOCKET_BUFFER_BYTES.store(kb as usize * 1024, Ordering::Relaxed);
+    }
+
+    let encode_threads = cli.encode_threads.map_or_else(default_encode_threads, usize::from);
+    let _ = ENCODE_POOL.set(EncodePool::new(encode_threads));
+    info!("Running at most {} encryptions at a time", encode_threads);
+
+    let _ = PNG_COMPRESSION.set(cli.png_compression);
+    if cli.png_compression != PngCompression::Default {
+        info!("Encoding encrypted images with {} PNG compression", cli.png_compression);
+    }
+
+    if cli.fit_unified_image || cli.unified_max_dimension.is_some() {
+        info!("Fitting the unified image to each carrier (max dimension: {:?})", cli.unified_max_dimension);
+        let _ = UNIFIED_IMAGE_FIT.set(cli.unified_max_dimension);
+    }
+
+    if cli.check {
+        let passed = run_startup_checks(&peers, &server_id, &cli.data_dir);
+        std::process::exit(if passed { 0 } else { 1 });
+    }
+
+    if let Some(out) = &cli.export_state {
+        println!("{}", RaftNode::export_state(&cli.data_dir, &server_id, out)?);
+        return Ok(());
+    }
+    if let Some(file) = &cli.import_state {
+        // Overwriting the state under a running node would be undone by its next persist
+        if let Err(e) = std::net::TcpListener::bind(("0.0.0.0", port)) {
+            if e.kind() == std::io::ErrorKind::AddrInUse {
+                bail!("port {} is in use: stop {} before importing its state", port, server_id);
+            }
+        }
+        println!("{}", RaftNode::import_state(&cli.data_dir, &server_id, file, cli.force_import)?);
+        return Ok(());
+    }
+
+    // Convert peer addresses to include Raft port (the same port when multiplexed);
+    // a malformed peer stops startup with a message naming it
+    let raft_peers = raft_addresses(&peers)?;
+    let raft_learners = raft_addresses(&cli.learner_peers)?;
 
     // Create Raft configuration
     let raft_config = RaftConfig {
@@ -152,23 +408,77 @@ async fn main() -> Result<()> {
         peers: raft_peers,
         election_timeout_min: 4000,
         election_timeout_max: 10000,
+        election_timeout_distribution: cli.election_timeout_distribution,
         heartbeat_interval: 2000,
+        election_tick: 100,
+        data_dir: cli.data_dir,
+        advertised_addr: cli.advertised_addr.or_else(|| guess_advertised_address(&peers, port)),
+        election_seed: election_seed_from_env(),
+        max_rpc_bytes: cli.max_rpc_bytes,
+        single_port,
+        learner: cli.learner,
+        learners: raft_learners,
+        trace_roles: cli.verbose_raft,
+        trace_file: cli.raft_trace_file,
+        initial_leader: cli.initial_leader,
     };
 
-    // Create and start Raft node
-    let raft_node = Arc::new(RaftNode::new(raft_config));
-    let raft_clone = Arc::clone(&raft_node);
-    raft_clone.start().await;
+    if cli.dry_run {
+        let raft_listener = if cli.no_raft {
+            "none (--no-raft)".to_string()
+        } else if single_port {
+            format!("0.0.0.0:{} (shared with the application port)", port)
+        } else {
+            format!("0.0.0.0:{}", port + RAFT_PORT_OFFSET)
+        };
+        let listeners = [
+            ("Application", format!("0.0.0.0:{}", port)),
+            ("Raft", raft_listener),
+        ("Metrics", format!("0.0.0.0:{}", port + METRICS_PORT_OFFSET)),
+        ("Work receiver", format!("0.0.0.0:{}", port + WORK_PORT_OFFSET)),
+        ];
+        let valid = print_dry_run(&listeners, &peers, &raft_config, cli.no_raft);
+        std::process::exit(if valid { 0 } else { 1 });
+    }
+
+    // Create and start Raft node. Under --no-raft it is only created, for the
+    // handlers and status replies that expect one, and never started
+    let raft_node = Arc::new(RaftNode::new(raft_config)?);
+    NO_RAFT.store(cli.no_raft, Ordering::Relaxed);
+    if !cli.no_raft {
+        let raft_clone = Arc::clone(&raft_node);
+        raft_clone.start().await;
+    }
 
-    // Start Raft message listener on separate port
-    let raft_port = port + RAFT_PORT_OFFSET;
-    let raft_listener_node = Arc::clone(&raft_node);
-    tokio::spawn(async move {
-        if let Err(e) = start_raft_listener(raft_port, raft_listener_node).await {
-            error!("Raft listener error: {}", e);
-        }
+    REFUSE_INVALID_UNIFIED.store(cli.refuse_invalid_unified, Ordering::Relaxed);
+    if cli.unified_check_interval > 0 {
+        tokio::spawn(check_unified_image_periodically(
+            Arc::clone(&raft_node),
+            Duration::from_secs(cli.unified_check_interval),
+        ));
+    }
+
+    let handler = Arc::new(LoadBalancedHandler {
+        raft_node: Arc::clone(&raft_node),
+        lb_state: Arc::clone(&lb_state),
+        cache: Arc::clone(&cache),
+        peers: peers.clone(),
+        redirect,
     });
 
+    // Start Raft message listener on separate port, unless it shares the app port
+    let raft_port = if single_port { port } else { port + RAFT_PORT_OFFSET };
+    if !single_port && !cli.no_raft {
+        let raft_listener_node = Arc::clone(&raft_node);
+        let raft_listener_cache = Arc::clone(&cache);
+        let raft_listener_handler = Arc::clone(&handler);
+        tokio::spawn(async move {
+            if let Err(e) = start_raft_listener(raft_port, raft_listener_node, raft_listener_cache, raft_listener_handler).await {
+                error!("Raft listener error: {}", e);
+            }
+        });
+    }
+
     // Start metrics server (for load balancing)
     let metrics_port = port + METRICS_PORT_OFFSET;
     let metrics_lb_state = Arc::clone(&lb_state);
@@ -182,8 +492,10 @@ async fn main() -> Result<()> {
     // Start work receiver (for forwarded work from leader)
     let work_port = port + WORK_PORT_OFFSET;
     let work_lb_state = Arc::clone(&lb_state);
+    let work_raft_node = Arc::clone(&raft_node);
+    let work_cache = Arc::clone(&cache);
     tokio::spawn(async move {
-        if let Err(e) = start_work_receiver(work_port, work_lb_state).await {
+        if let Err(e) = start_work_receiver(work_port, work_lb_state, work_raft_node, work_cache).await {
             error!("Work receiver error: {}", e);
         }
     });
@@ -192,77 +504,40 @@ async fn main() -> Result<()> {
     let bind_addr = format!("0.0.0.0:{}", port);
     let listener = TcpListener::bind(&bind_addr).await?;
     info!("Application server listening on {}", bind_addr);
-    info!("Raft consensus running on port {}", raft_port);
+    if cli.no_raft {
+        warn!("Raft consensus disabled, no Raft port bound");
+    } else {
+        info!("Raft consensus running on port {}{}", raft_port, if single_port { " (multiplexed)" } else { "" });
+    }
     info!("Metrics server running on port {}", metrics_port);
     info!("Work receiver running on port {}", work_port);
 
     loop {
         match listener.accept().await {
-            Ok((stream, addr)) => {
-                info!("Client connected from {}", addr);
+            Ok((mut stream, addr)) => {
                 let raft_ref = Arc::clone(&raft_node);
-                let lb_ref = Arc::clone(&lb_state);
-                let peers_clone = peers.clone();
+                let cache_ref = Arc::clone(&cache);
+                let handler_ref = Arc::clone(&handler);
                 tokio::spawn(async move {
-                    if let Err(e) = handle_client_with_load_balancing(
-                        stream,
-                        raft_ref,
-                        lb_ref,
-                        peers_clone,
-                    ).await {
-                        error!("Error handling client: {}", e);
+                    // With a shared port, Raft connections announce themselves with MUX_RAFT
+                    if single_port && is_raft_connection(&mut stream).await {
+                        if let Err(e) = handle_raft_message(stream, raft_ref, cache_ref, handler_ref).await {
+                            error!("Error handling Raft message: {}", e);
+                        }
+                        return;
                     }
-                });
-            }
-            Err(e) => error!("Failed to accept connection: {}", e),
-        }
-    }
-}
-
-// =============================================================================
-// RAFT LISTENER
-// =============================================================================
 
-async fn start_raft_listener(port: u16, raft_node: Arc<RaftNode>) -> Result<()> {
-    let bind_addr = format!("0.0.0.0:{}", port);
-    let listener = TcpListener::bind(&bind_addr).await?;
-    info!("Raft listener started on {}", bind_addr);
-
-    loop {
-        match listener.accept().await {
-            Ok((stream, _)) => {
-                let raft_ref = Arc::clone(&raft_node);
-                tokio::spawn(async move {
-                    if let Err(e) = handle_raft_message(stream, raft_ref).await {
-                        error!("Error handling Raft message: {}", e);
+                    info!("Client connected from {}", addr);
+                    if let Err(e) = serve_client(stream, handler_ref, keepalive).await {
+                        error!("Error handling client: {}", e);
                     }
                 });
             }
-            Err(e) => error!("Failed to accept Raft connection: {}", e),
+            Err(e) => error!("Failed to accept connection: {}", e),
         }
     }
 }
 
-async fn handle_raft_message(mut stream: TcpStream, raft_node: Arc<RaftNode>) -> Result<()> {
-    // Read message
-    let msg_len = stream.read_u32().await?;
-    let mut msg_buf = vec![0u8; msg_len as usize];
-    stream.read_exact(&mut msg_buf).await?;
-    
-    let message: RaftMessage = serde_json::from_slice(&msg_buf)?;
-    
-    // Handle message and get response
-    if let Some(response) = raft_node.handle_raft_message(message).await {
-        let response_json = serde_json::to_string(&response)?;
-        let response_bytes = response_json.as_bytes();
-        stream.write_u32(response_bytes.len() as u32).await?;
-        stream.write_all(response_bytes).await?;
-        stream.flush().await?;
-    }
-
-    Ok(())
-}
-
 // =============================================================================
 // METRICS SERVER (for Load Balancing)
 // =============================================================================
@@ -334,6 +609,8 @@ async fn handle_metrics_request(
 async fn start_work_receiver(
     port: u16,
     lb_state: Arc<LoadBalancingState>,
+    raft_node: Arc<RaftNode>,
+    cache: Arc<EncryptionCache>,
 ) -> Result<()> {
     let bind_addr = format!("0.0.0.0:{}", port);
     let listener = TcpListener::bind(&bind_addr).await?;
@@ -343,9 +620,11 @@ async fn start_work_receiver(
         match listener.accept().await {
             Ok((mut stream, _)) => {
                 let lb_clone = Arc::clone(&lb_state);
+                let raft_clone = Arc::clone(&raft_node);
+                let cache_clone = Arc::clone(&cache);
                 
                 tokio::spawn(async move {
-                    if let Err(e) = handle_forwarded_work(&mut stream, lb_clone).await {
+                    if let Err(e) = handle_forwarded_work(&mut stream, lb_clone, raft_clone, cache_clone).await {
                         error!("Error handling forwarded work: {}", e);
                     }
                 });
@@ -358,11 +637,10 @@ async fn start_work_receiver(
 async fn handle_forwarded_work(
     stream: &mut TcpStream,
     lb_state: Arc<LoadBalancingState>,
+    raft_node: Arc<RaftNode>,
+    cache: Arc<EncryptionCache>,
 ) -> Result<()> {
     let start_time = Instant::now();
-    
-    info!("Received forwarded work from leader");
-    lb_state.increment_connections();
 
     // Read the forwarded work message
     let msg_len = stream.read_u32().await?;
@@ -372,16 +650,16 @@ async fn handle_forwarded_work(
     let message: LoadBalancingMessage = serde_json::from_slice(&msg_buf)?;
     
     match message {
-        LoadBalancingMessage::ForwardWork { metadata, image_data } => {
+        LoadBalancingMessage::ForwardWork { metadata, image_data, unified_image } => {
+            info!("Received forwarded work from leader");
+            lb_state.increment_connections();
             info!("Processing forwarded encryption work...");
             
             // Process the encryption
-            let result = process_encryption_work(&metadata, &image_data).await?;
+            let result = process_encryption_work(&metadata, &image_data, unified_image.as_deref(), &cache).await;
             
             // Send result back
-            let response = LoadBalancingMessage::WorkResult {
-                encrypted_image: result,
-            };
+            let response = work_reply(result)?;
             let response_json = serde_json::to_string(&response)?;
             let response_bytes = response_json.as_bytes();
             
@@ -395,6 +673,109 @@ async fn handle_forwarded_work(
             
             info!("Forwarded work completed in {}ms", elapsed);
         }
+        LoadBalancingMessage::DelegationGrant { ticket, leader_id, term, mac } => {
+            // Anyone can reach this port: only a grant made with the cluster key is
+            // from a server, and only one from the node we recognise as leader counts
+            let authentic = match &lb_state.cluster_key {
+                Some(key) => delegation_mac_matches(key, &ticket, &leader_id, term, &mac)?,
+                None => false,
+            };
+            let from_leader = raft_node.get_leader_id().await.as_deref() == Some(leader_id.as_str())
+                && raft_node.get_current_term().await == term;
+            let accepted = authentic && from_leader;
+
+            if accepted {
+                lb_state.accept_ticket(ticket);
+                info!("Accepted delegation from leader {} (term {})", leader_id, term);
+            } else if lb_state.cluster_key.is_none() {
+                warn!("Refused delegation from {} (term {}): no --cluster-key to check it with", leader_id, term);
+            } else if !authentic {
+                warn!("Refused delegation claiming to be from {} (term {}): bad MAC", leader_id, term);
+            } else {
+                info!("Refused delegation from {} (term {}): not the current leader", leader_id, term);
+            }
+
+            let response = LoadBalancingMessage::DelegationAck { accepted };
+            let response_json = serde_json::to_string(&response)?;
+            let response_bytes = response_json.as_bytes();
+
+            stream.write_u32(response_bytes.len() as u32).await?;
+            stream.write_all(response_bytes).await?;
+            stream.flush().await?;
+        }
+        LoadBalancingMessage::DelegatedWork { ticket, metadata, image_data, unified_image } => {
+            let response = if lb_state.redeem_ticket(&ticket) {
+                info!("Processing redirected client request (leader-delegated)...");
+                lb_state.increment_connections();
+
+                let result = process_encryption_work(&metadata, &image_data, unified_image.as_deref(), &cache).await;
+
+                lb_state.decrement_connections();
+                let elapsed = start_time.elapsed().as_millis() as u64;
+                lb_state.record_request(elapsed);
+                info!("Delegated work completed in {}ms", elapsed);
+
+                // As with any request, the client only gets the image once the leader committed it
+                match result {
+                    Ok(encrypted_image) => match report_delegated_work(&raft_node, &lb_state, &ticket, &encrypted_image).await {
+                        Ok(Some(committed_index)) => {
+                            info!("Leader committed delegated work at index {}", committed_index);
+                            LoadBalancingMessage::DelegatedResult { encrypted_image, committed_index }
+                        }
+                        outcome => {
+                            if let Err(e) = outcome {
+                                info!("Could not report delegated work to the leader: {}", e);
+                            }
+                            info!("Delegated work not committed, told client to retry");
+                            LoadBalancingMessage::WorkRejected {
+                                reason: "NOT_COMMITTED: the leader did not commit the request".to_string(),
+                            }
+                        }
+                    },
+                    Err(e) => work_reply(Err(e))?,
+                }
+            } else {
+                info!("Rejected redirected request with unknown or expired ticket");
+                LoadBalancingMessage::WorkRejected {
+                    reason: "unknown or expired delegation ticket".to_string(),
+                }
+            };
+
+            let response_json = serde_json::to_string(&response)?;
+            let response_bytes = response_json.as_bytes();
+
+            stream.write_u32(response_bytes.len() as u32).await?;
+            stream.write_all(response_bytes).await?;
+            stream.flush().await?;
+        }
+        LoadBalancingMessage::DelegationReport { ticket, digest, mac } => {
+            // Only a report made with the cluster key, on a ticket we granted, is committed
+            let authentic = match &lb_state.cluster_key {
+                Some(key) => delegation_report_mac_matches(key, &ticket, &digest, &mac)?,
+                None => false,
+            };
+            let committed_index = match authentic.then(|| lb_state.take_grant(&ticket)).flatten() {
+                Some(term) if still_leader_for(&raft_node, term).await => {
+                    commit_encryption_digest(&raft_node, &digest).await.unwrap_or(None)
+                }
+                Some(term) => {
+                    info!("Not committing delegated work granted in term {}: no longer leader in it", term);
+                    None
+                }
+                None => {
+                    warn!("Refused report on delegation {}: {}", ticket, if authentic { "not granted by us" } else { "bad MAC" });
+                    None
+                }
+            };
+
+            let response = LoadBalancingMessage::DelegationCommitted { committed_index };
+            let response_json = serde_json::to_string(&response)?;
+            let response_bytes = response_json.as_bytes();
+
+            stream.write_u32(response_bytes.len() as u32).await?;
+            stream.write_all(response_bytes).await?;
+            stream.flush().await?;
+        }
         _ => {
             bail!("Unexpected message type in work receiver");
         }
@@ -403,129 +784,153 @@ async fn handle_forwarded_work(
     Ok(())
 }
 
+/// The reply to finished work: the encrypted image or, for a request that
+/// can never succeed, a rejection carrying its BAD_* reason
+fn work_reply(result: Result<Vec<u8>>) -> Result<LoadBalancingMessage> {
+    match result {
+        Ok(encrypted_image) => Ok(LoadBalancingMessage::WorkResult { encrypted_image }),
+        Err(e) => match e.downcast::<BadRequest>() {
+            Ok(bad) => Ok(LoadBalancingMessage::WorkRejected { reason: bad.to_string() }),
+            Err(e) => Err(e),
+        },
+    }
+}
+
 // =============================================================================
 // CLIENT HANDLER WITH LOAD BALANCING
 // =============================================================================
 
-async fn handle_client_with_load_balancing(
-    mut stream: TcpStream,
+/// Encrypts each request on the server with the lowest load score, or in
+/// redirect mode hands the client to that server
+struct LoadBalancedHandler {
     raft_node: Arc<RaftNode>,
     lb_state: Arc<LoadBalancingState>,
+    cache: Arc<EncryptionCache>,
     peers: Vec<String>,
-) -> Result<()> {
-    let start_time = Instant::now();
+    redirect: bool,
+}
 
-    // Configure TCP buffers for large transfers
-    configure_large_transfer_socket(&stream)?;
+impl RequestHandler for LoadBalancedHandler {
+    async fn handle_request(&self, stream: &mut TcpStream) -> Result<bool> {
+        let start_time = Instant::now();
 
-    // Check if this server is the leader
-    if !raft_node.is_leader().await {
-        // Not the leader, inform client
-        let leader_id = raft_node.get_leader_id().await;
-        let error_msg = match &leader_id {
-            Some(id) => format!("NOT_LEADER:{}", id),
-            None => "NO_LEADER".to_string(),
+        let head = match open_request(stream, &self.raft_node, &self.cache).await? {
+            Opened::Request(head) => head,
+            Opened::Answered(reusable) => return Ok(reusable),
         };
-        
-        let error_bytes = error_msg.as_bytes();
-        stream.write_u64(error_bytes.len() as u64).await?;
-        stream.write_all(error_bytes).await?;
-        stream.flush().await?;
-        
-        info!("Rejected client - not leader. Current leader: {:?}", leader_id);
-        return Ok(());
-    }
-
-    info!("=== LEADER: Performing load balancing ===");
 
-    // Read client request
-    let meta_size = stream.read_u64().await?;
-    let mut meta_buf = vec![0; meta_size as usize];
-    stream.read_exact(&mut meta_buf).await?;
-
-    let img_size = stream.read_u64().await?;
-    let mut img_buf = vec![0; img_size as usize];
-    stream.read_exact(&mut img_buf).await?;
-    
-    info!("Received client request (meta: {} bytes, image: {} bytes)", meta_size, img_size);
+        info!("=== LEADER: Performing load balancing ===");
 
-    // === LOAD BALANCING: Collect metrics from all servers ===
-    // Store both metrics and their corresponding addresses
-    let mut server_info: Vec<(ServerMetrics, Option<String>)> = vec![];
+        // === LOAD BALANCING: Collect metrics from all servers ===
+        // Store both metrics and their corresponding addresses
+        let mut server_info: Vec<(ServerMetrics, Option<String>)> = vec![];
     
-    // Get own metrics (no address needed for self)
-    let my_metrics = lb_state.get_metrics(raft_node.config.server_id.clone());
-    info!("My metrics: connections={}, load={:.1}%, score={:.3}",
-          my_metrics.active_connections, my_metrics.cpu_load, my_metrics.calculate_load_score());
-    server_info.push((my_metrics, None));
+        // Get own metrics (no address needed for self)
+        let my_metrics = self.lb_state.get_metrics(self.raft_node.config.server_id.clone());
+        info!("My metrics: connections={}, load={:.1}%, capacity={}, score={:.3}",
+              my_metrics.active_connections, my_metrics.cpu_load, my_metrics.effective_capacity(),
+              my_metrics.calculate_load_score());
+        server_info.push((my_metrics, None));
     
-    // Get metrics from peers and store their addresses
-    for peer_addr in &peers {
-        match request_metrics_from_peer(peer_addr).await {
-            Ok(metrics) => {
-                info!("Peer {} metrics: connections={}, load={:.1}%, score={:.3}",
-                      metrics.server_id, metrics.active_connections, 
-                      metrics.cpu_load, metrics.calculate_load_score());
-                server_info.push((metrics, Some(peer_addr.clone())));
-            }
-            Err(e) => {
-                info!("Could not get metrics from {}: {}", peer_addr, e);
+        // Get metrics from peers and store their addresses
+        for peer_addr in &self.peers {
+            match request_metrics_from_peer(peer_addr).await {
+                Ok(metrics) => {
+                    info!("Peer {} metrics: connections={}, load={:.1}%, capacity={}, score={:.3}",
+                          metrics.server_id, metrics.active_connections,
+                          metrics.cpu_load, metrics.effective_capacity(), metrics.calculate_load_score());
+                    server_info.push((metrics, Some(peer_addr.clone())));
+                }
+                Err(e) => {
+                    info!("Could not get metrics from {}: {}", peer_addr, e);
+                }
             }
         }
-    }
     
-    // Select best server based on load scores
-    let (best_server, best_addr) = server_info
-        .iter()
-        .min_by(|(a, _), (b, _)| {
-            a.calculate_load_score()
-                .partial_cmp(&b.calculate_load_score())
-                .unwrap_or(std::cmp::Ordering::Equal)
-        })
-        .expect("At least one server should be available");
+        // Select best server based on load scores
+        let (best_server, best_addr) = server_info
+            .iter()
+            .min_by(|(a, _), (b, _)| {
+                a.calculate_load_score()
+                    .partial_cmp(&b.calculate_load_score())
+                    .unwrap_or(std::cmp::Ordering::Equal)
+                    // An idle cluster scores all zeros: start with the largest server
+                    .then(b.effective_capacity().total_cmp(&a.effective_capacity()))
+            })
+            .expect("At least one server should be available");
     
-    info!("=== LOAD BALANCING DECISION ===");
-    info!("Selected server: {} (score: {:.3})", 
-          best_server.server_id, best_server.calculate_load_score());
+        info!("=== LOAD BALANCING DECISION ===");
+        info!("Selected server: {} (score: {:.3})", 
+              best_server.server_id, best_server.calculate_load_score());
+
+        // In redirect mode, hand the client to the worker instead of proxying its bytes:
+        // decided before the image is read, so the image never crosses the leader
+        if self.redirect {
+            if let (Some(target_address), Some(key)) = (best_addr, self.lb_state.cluster_key.as_deref()) {
+                match grant_delegation(target_address, &self.raft_node, key).await {
+                    Ok((work_addr, ticket)) => {
+                        // The worker reports its result back here to be committed
+                        self.lb_state.record_grant(ticket.clone(), head.term);
+                        let redirect_msg = format!("REDIRECT:{}:{}", work_addr, ticket);
+                        let redirect_bytes = redirect_msg.as_bytes();
+                        stream.write_u64(redirect_bytes.len() as u64).await?;
+                        stream.write_all(redirect_bytes).await?;
+                        stream.flush().await?;
+
+                        info!("Redirected client to server {} at {}", best_server.server_id, work_addr);
+
+                        // Closing while the image is still arriving would reset the connection
+                        // and lose the redirect: discard what the client sends until it hangs up
+                        let _ = tokio::time::timeout(REDIRECT_DRAIN_TIMEOUT, tokio::io::copy(stream, &mut tokio::io::sink())).await;
+                        return Ok(false);
+                    }
+                    Err(e) => {
+                        info!("Delegation to {} failed ({}), forwarding instead", target_address, e);
+                    }
+                }
+            }
+        }
+
+        let img_buf = read_request_image(stream, &head).await?;
     
-    // Decide: process locally or forward
-    let result = if best_server.server_id == raft_node.config.server_id {
-        info!("Processing LOCALLY (I am the best choice)");
-        lb_state.increment_connections();
+        // Decide: process locally or forward
+        let result = if best_server.server_id == self.raft_node.config.server_id {
+            info!("Processing LOCALLY (I am the best choice)");
+            self.lb_state.increment_connections();
         
-        let encrypted = process_encryption_work(&meta_buf, &img_buf).await?;
+            let encrypted = process_encryption_work(&head.meta, &img_buf, head.unified_override.as_deref(), &self.cache).await;
         
-        lb_state.decrement_connections();
-        let elapsed = start_time.elapsed().as_millis() as u64;
-        lb_state.record_request(elapsed);
+            self.lb_state.decrement_connections();
+            let elapsed = start_time.elapsed().as_millis() as u64;
+            self.lb_state.record_request(elapsed);
         
-        info!("Local processing completed in {}ms", elapsed);
-        encrypted
-    } else {
-        // Forward to the selected server using its stored address
-        let target_address = best_addr
-            .as_ref()
-            .ok_or_else(|| anyhow::anyhow!("No address found for server {}", best_server.server_id))?;
+            info!("Local processing completed in {}ms", elapsed);
+            encrypted
+        } else {
+            // Forward to the selected server using its stored address
+            let target_address = best_addr
+                .as_ref()
+                .ok_or_else(|| anyhow::anyhow!("No address found for server {}", best_server.server_id))?;
         
-        info!("Forwarding to server {} at {}", best_server.server_id, target_address);
+            info!("Forwarding to server {} at {}", best_server.server_id, target_address);
         
-        let encrypted = forward_work_to_address(
-            target_address,
-            &meta_buf,
-            &img_buf,
-        ).await?;
+            let encrypted = forward_work_to_address(
+                target_address,
+                &head.meta,
+                &img_buf,
+                head.unified_override.clone(),
+            ).await;
         
-        info!("Forwarded work completed");
-        encrypted
-    };
+            info!("Forwarded work completed");
+            encrypted
+        };
 
-    // Send result back to client
-    stream.write_u64(result.len() as u64).await?;
-    stream.write_all(&result).await?;
-    stream.flush().await?;
-    
-    info!("Sent result to client ({} bytes)", result.len());
-    Ok(())
+        finish_request(stream, &self.raft_node, &head, result).await
+    }
+
+    fn load_score(&self) -> Option<f32> {
+        Some(self.lb_state.get_metrics(self.raft_node.config.server_id.clone()).calculate_load_score())
+    }
 }
 
 // =============================================================================
@@ -534,10 +939,7 @@ async fn handle_client_with_load_balancing(
 
 /// Request metrics from a peer server
 async fn request_metrics_from_peer(peer_addr: &str) -> Result<ServerMetrics> {
-    let parts: Vec<&str> = peer_addr.split(':').collect();
-    let base_port: u16 = parts[1].parse()?;
-    let metrics_port = base_port + METRICS_PORT_OFFSET;
-    let metrics_addr = format!("{}:{}", parts[0], metrics_port);
+    let metrics_addr = offset_address(peer_addr, METRICS_PORT_OFFSET)?;
     
     let mut stream = TcpStream::connect(&metrics_addr).await?;
     
@@ -568,11 +970,9 @@ async fn forward_work_to_address(
     target_addr: &str,
     meta_buf: &[u8],
     img_buf: &[u8],
+    unified_image: Option<Vec<u8>>,
 ) -> Result<Vec<u8>> {
-    let parts: Vec<&str> = target_addr.split(':').collect();
-    let base_port: u16 = parts[1].parse()?;
-    let work_port = base_port + WORK_PORT_OFFSET;
-    let work_addr = format!("{}:{}", parts[0], work_port);
+    let work_addr = offset_address(target_addr, WORK_PORT_OFFSET)?;
     
     info!("Connecting to work receiver at {}", work_addr);
     let mut stream = TcpStream::connect(&work_addr).await?;
@@ -581,6 +981,7 @@ async fn forward_work_to_address(
     let message = LoadBalancingMessage::ForwardWork {
         metadata: meta_buf.to_vec(),
         image_data: img_buf.to_vec(),
+        unified_image,
     };
     let json = serde_json::to_string(&message)?;
     let bytes = json.as_bytes();
@@ -603,38 +1004,289 @@ async fn forward_work_to_address(
             info!("Received encrypted result ({} bytes)", encrypted_image.len());
             Ok(encrypted_image)
         },
+        LoadBalancingMessage::WorkRejected { reason } => match BadRequest::from_reply(&reason) {
+            Some(bad) => Err(bad.into()),
+            None => bail!("Work receiver rejected the work: {}", reason),
+        },
         _ => bail!("Unexpected response type from work receiver"),
     }
 }
 
-// server.rs - Make process_encryption_work truly non-blocking
-async fn process_encryption_work(meta_buf: &[u8], img_buf: &[u8]) -> Result<Vec<u8>> {
-    let meta_buf = meta_buf.to_vec();
-    let img_buf = img_buf.to_vec();
-    
-    // Run CPU/IO intensive work on blocking thread pool
-    tokio::task::spawn_blocking(move || {
-        let permissions: ImagePermissions = bincode::deserialize(&meta_buf)?;
-        let img = image::load_from_memory(&img_buf)?;
+/// Authorize a worker to accept one redirected client request, with a grant
+/// authenticated by the cluster key. Returns the worker's work-receiver
+/// address and the ticket the client must present. A worker that doesn't
+/// answer within DELEGATION_GRANT_TIMEOUT counts as refusing.
+async fn grant_delegation(target_addr: &str, raft_node: &RaftNode, cluster_key: &[u8]) -> Result<(String, String)> {
+    let work_addr = offset_address(target_addr, WORK_PORT_OFFSET)?;
+
+    let ticket = format!("{:016x}", rand::random::<u64>());
+    let leader_id = raft_node.config.server_id.clone();
+    let term = raft_node.get_current_term().await;
+    let grant = LoadBalancingMessage::DelegationGrant {
+        mac: delegation_mac(cluster_key, &ticket, &leader_id, term)?,
+        ticket: ticket.clone(),
+        leader_id,
+        term,
+    };
+    let json = serde_json::to_string(&grant)?;
+
+    let exchange = async {
+        let mut stream = TcpStream::connect(&work_addr).await?;
+        let bytes = json.as_bytes();
+
+        stream.write_u32(bytes.len() as u32).await?;
+        stream.write_all(bytes).await?;
+        stream.flush().await?;
+
+        let response_len = stream.read_u32().await?;
+        let mut response_buf = vec![0u8; response_len as usize];
+        stream.read_exact(&mut response_buf).await?;
+        Ok::<Vec<u8>, anyhow::Error>(response_buf)
+    };
+    let response_buf = tokio::time::timeout(DELEGATION_GRANT_TIMEOUT, exchange)
+        .await
+        .map_err(|_| anyhow::anyhow!("{} did not answer the grant within {:?}", work_addr, DELEGATION_GRANT_TIMEOUT))??;
+
+    let response: LoadBalancingMessage = serde_json::from_slice(&response_buf)?;
+
+    match response {
+        LoadBalancingMessage::DelegationAck { accepted: true } => Ok((work_addr, ticket)),
+        LoadBalancingMessage::DelegationAck { accepted: false } => bail!("Worker refused delegation"),
+        _ => bail!("Unexpected response type from work receiver"),
+    }
+}
+
+/// Have the leader commit a redirected request this worker encrypted, and
+/// return the committed log index (None if the leader didn't commit it)
+async fn report_delegated_work(
+    raft_node: &RaftNode,
+    lb_state: &LoadBalancingState,
+    ticket: &str,
+    encrypted_image: &[u8],
+) -> Result<Option<u64>> {
+    let key = lb_state
+        .cluster_key
+        .as_deref()
+        .ok_or_else(|| anyhow::anyhow!("No --cluster-key to sign the report with"))?;
+    let leader_addr = raft_node
+        .get_leader_addr()
+        .await
+        .ok_or_else(|| anyhow::anyhow!("No known leader address to report to"))?;
+    let work_addr = offset_address(&leader_addr, WORK_PORT_OFFSET)?;
+
+    let digest = encryption_digest(encrypted_image);
+    let report = LoadBalancingMessage::DelegationReport {
+        mac: delegation_report_mac(key, ticket, &digest)?,
+        ticket: ticket.to_string(),
+        digest,
+    };
+    let json = serde_json::to_string(&report)?;
+
+    let mut stream = TcpStream::connect(&work_addr).await?;
+    let bytes = json.as_bytes();
+    stream.write_u32(bytes.len() as u32).await?;
+    stream.write_all(bytes).await?;
+    stream.flush().await?;
+
+    let response_len = stream.read_u32().await?;
+    let mut response_buf = vec![0u8; response_len as usize];
+    stream.read_exact(&mut response_buf).await?;
+
+    match serde_json::from_slice(&response_buf)? {
+        LoadBalancingMessage::DelegationCommitted { committed_index } => Ok(committed_index),
+        _ => bail!("Unexpected response type from the leader's work receiver"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cloud_p2p_project::{ImagePermissions, ServerRole};
+    use std::fs;
+
+    /// A Raft node with no peers, keeping its state in a fresh scratch directory
+    fn raft_node(name: &str) -> RaftNode {
+        let data_dir = std::env::temp_dir().join(format!("server-test-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&data_dir);
+        RaftNode::new(RaftConfig {
+            server_id: "n1".to_string(),
+            peers: Vec::new(),
+            election_timeout_min: 150,
+            election_timeout_max: 300,
+            election_timeout_distribution: TimeoutDistribution::Uniform,
+            heartbeat_interval: 50,
+            election_tick: 10,
+            data_dir,
+            advertised_addr: None,
+            election_seed: Some(1),
+            max_rpc_bytes: DEFAULT_MAX_RPC_BYTES,
+            single_port: false,
+            learner: false,
+            learners: Vec::new(),
+            trace_roles: false,
+            trace_file: None,
+            initial_leader: None,
+        })
+        .unwrap()
+    }
 
-        // This blocking I/O won't block heartbeats anymore
-        let unified_image_bytes = fs::read("unified_image.png")?;
+    /// The application address of a server whose work receiver (at +WORK_PORT_OFFSET)
+    /// is served by `handle_forwarded_work` with these states
+    async fn work_receiver(lb_state: Arc<LoadBalancingState>, raft_node: Arc<RaftNode>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let (lb_state, raft_node) = (Arc::clone(&lb_state), Arc::clone(&raft_node));
+                tokio::spawn(async move {
+                    let _ = handle_forwarded_work(&mut stream, lb_state, raft_node, Arc::new(EncryptionCache::new(0))).await;
+                });
+            }
+        });
+        format!("127.0.0.1:{}", port - WORK_PORT_OFFSET)
+    }
+
+    /// Send one work-receiver message and read the reply
+    async fn exchange_work_message(app_addr: &str, message: &LoadBalancingMessage) -> LoadBalancingMessage {
+        let mut stream = TcpStream::connect(offset_address(app_addr, WORK_PORT_OFFSET).unwrap()).await.unwrap();
+        let json = serde_json::to_vec(message).unwrap();
+        stream.write_u32(json.len() as u32).await.unwrap();
+        stream.write_all(&json).await.unwrap();
+        let len = stream.read_u32().await.unwrap();
+        let mut reply = vec![0u8; len as usize];
+        stream.read_exact(&mut reply).await.unwrap();
+        serde_json::from_slice(&reply).unwrap()
+    }
+
+    #[tokio::test]
+    async fn workers_only_accept_grants_made_with_the_cluster_key() {
+        let leader = raft_node("grant-leader");
+        leader.state.lock().await.current_term = 3;
+        let worker_node = Arc::new(raft_node("grant-worker"));
+        {
+            let mut state = worker_node.state.lock().await;
+            state.current_term = 3;
+            state.leader_id = Some("n1".to_string());
+        }
+        let worker = Arc::new(LoadBalancingState::new(1.0, Some(b"cluster secret".to_vec())));
+        let worker_addr = work_receiver(Arc::clone(&worker), Arc::clone(&worker_node)).await;
+
+        let (_, ticket) = grant_delegation(&worker_addr, &leader, b"cluster secret").await.unwrap();
+        assert!(worker.redeem_ticket(&ticket));
 
-        let combined_payload = CombinedPayload {
-            permissions,
-            unified_image: unified_image_bytes,
+        // Anyone reaching the work port can claim to be the leader, but can't make the MAC
+        let err = grant_delegation(&worker_addr, &leader, b"guessed key").await.unwrap_err();
+        assert!(err.to_string().contains("refused"), "{}", err);
+        let forged = LoadBalancingMessage::DelegationGrant {
+            ticket: "forged".to_string(),
+            leader_id: "n1".to_string(),
+            term: 3,
+            mac: String::new(),
         };
-        
-        let final_payload = bincode::serialize(&combined_payload)?;
-        let encoded_img = lsb::encode(&img, &final_payload)?;
-        
-        // Simulate work
-        // std::thread::sleep(std::time::Duration::from_secs(5));
-        
-        let mut out_buf = Vec::new();
-        encoded_img.write_to(&mut Cursor::new(&mut out_buf), ImageOutputFormat::Png)?;
-        
-        Ok::<Vec<u8>, anyhow::Error>(out_buf)
-    })
-    .await?
-}
\ No newline at end of file
+        let reply = exchange_work_message(&worker_addr, &forged).await;
+        assert!(matches!(reply, LoadBalancingMessage::DelegationAck { accepted: false }));
+        assert!(!worker.redeem_ticket("forged"));
+
+        // A worker with no key can check nothing, so it accepts nothing
+        let keyless_addr = work_receiver(Arc::new(LoadBalancingState::new(1.0, None)), worker_node).await;
+        assert!(grant_delegation(&keyless_addr, &leader, b"cluster secret").await.is_err());
+
+        let _ = fs::remove_dir_all(&leader.config.data_dir);
+    }
+
+    /// A blank PNG of the given size
+    fn png(width: u32, height: u32) -> Vec<u8> {
+        let mut png = Vec::new();
+        image::DynamicImage::new_rgb8(width, height)
+            .write_to(&mut std::io::Cursor::new(&mut png), image::ImageOutputFormat::Png)
+            .unwrap();
+        png
+    }
+
+    #[tokio::test]
+    async fn redirected_work_is_committed_by_the_leader_before_the_client_gets_it() {
+        let key = b"cluster secret";
+        let leader = Arc::new(raft_node("report-leader"));
+        {
+            let mut state = leader.state.lock().await;
+            state.current_term = 3;
+            state.role = ServerRole::Leader;
+        }
+        // Started, so committed entries get applied
+        Arc::clone(&leader).start().await;
+        let leader_lb = Arc::new(LoadBalancingState::new(1.0, Some(key.to_vec())));
+        let leader_addr = work_receiver(Arc::clone(&leader_lb), Arc::clone(&leader)).await;
+
+        let worker_node = Arc::new(raft_node("report-worker"));
+        {
+            let mut state = worker_node.state.lock().await;
+            state.current_term = 3;
+            state.leader_id = Some("n1".to_string());
+            state.leader_addr = Some(leader_addr.clone());
+        }
+        let worker_lb = Arc::new(LoadBalancingState::new(1.0, Some(key.to_vec())));
+        let worker_addr = work_receiver(worker_lb, worker_node).await;
+
+        let permissions = ImagePermissions {
+            owner: "alice".to_string(),
+            quotas: HashMap::from([("bob".to_string(), 1)]),
+            note: None,
+            version: 0,
+            view_cooldown_secs: None,
+            last_views: HashMap::new(),
+            signature: None,
+            tokens: HashMap::new(),
+        };
+        let delegated_work = |ticket: &str| LoadBalancingMessage::DelegatedWork {
+            ticket: ticket.to_string(),
+            metadata: permissions.to_bytes().unwrap(),
+            image_data: png(200, 200),
+            unified_image: Some(png(8, 8)),
+        };
+
+        // Granted and recorded as the handler does: the worker reports back and the leader commits
+        let (_, ticket) = grant_delegation(&worker_addr, &leader, key).await.unwrap();
+        leader_lb.record_grant(ticket.clone(), 3);
+        let reply = exchange_work_message(&worker_addr, &delegated_work(&ticket)).await;
+        let LoadBalancingMessage::DelegatedResult { encrypted_image, committed_index } = reply else {
+            panic!("expected a committed result, got {:?}", serde_json::to_string(&reply));
+        };
+        let command = leader.state.lock().await.log[committed_index as usize].command.clone();
+        assert_eq!(command, format!("encrypt:{}", encryption_digest(&encrypted_image)));
+
+        // A ticket the leader has no record of granting is never committed
+        let (_, unrecorded) = grant_delegation(&worker_addr, &leader, key).await.unwrap();
+        let reply = exchange_work_message(&worker_addr, &delegated_work(&unrecorded)).await;
+        assert!(matches!(&reply, LoadBalancingMessage::WorkRejected { reason } if reason.starts_with("NOT_COMMITTED")));
+
+        // Nor is a report without the cluster key's MAC, or one replaying a used ticket
+        leader_lb.record_grant("forged".to_string(), 3);
+        for (ticket, mac) in [("forged", String::new()), (ticket.as_str(), delegation_report_mac(key, &ticket, "00").unwrap())] {
+            let report = LoadBalancingMessage::DelegationReport { ticket: ticket.to_string(), digest: "00".to_string(), mac };
+            let reply = exchange_work_message(&leader_addr, &report).await;
+            assert!(matches!(reply, LoadBalancingMessage::DelegationCommitted { committed_index: None }));
+        }
+        assert_eq!(leader.state.lock().await.log.len(), committed_index as usize + 1);
+
+        leader.shutdown();
+        let _ = fs::remove_dir_all(&leader.config.data_dir);
+    }
+
+    #[tokio::test]
+    async fn grant_delegation_gives_up_on_a_silent_worker() {
+        let leader = raft_node("grant-timeout");
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let silent = format!("127.0.0.1:{}", listener.local_addr().unwrap().port() - WORK_PORT_OFFSET);
+        // Accepts and then never answers
+        let _held = tokio::spawn(async move {
+            let _connection = listener.accept().await;
+            std::future::pending::<()>().await;
+        });
+
+        let started = Instant::now();
+        let err = grant_delegation(&silent, &leader, b"cluster secret").await.unwrap_err();
+        assert!(err.to_string().contains("did not answer the grant"), "{}", err);
+        assert!(started.elapsed() < DELEGATION_GRANT_TIMEOUT + Duration::from_secs(1));
+        let _ = fs::remove_dir_all(&leader.config.data_dir);
+    }
+}
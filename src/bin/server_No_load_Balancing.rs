@@ -1,52 +1,148 @@
 use anyhow::{bail, Result};
-use bincode;
-use cloud_p2p_project::raft::{RaftConfig, RaftNode};
-use cloud_p2p_project::{lsb, CombinedPayload, ImagePermissions, LoadBalancingMessage, RaftMessage, ServerMetrics};
-use image::ImageOutputFormat;
-use log::{error, info};
-use std::env;
-use std::fs;
-use std::io::Cursor;
-use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use clap::Parser;
+use cloud_p2p_project::cache::EncryptionCache;
+use cloud_p2p_project::raft::{election_seed_from_env, RaftConfig, RaftNode, TimeoutDistribution, DEFAULT_MAX_RPC_BYTES};
+use cloud_p2p_project::server::{check_unified_image_periodically, default_encode_threads, finish_request, handle_raft_message, is_raft_connection, open_request, process_encryption_work, read_request_image, serve_client, start_raft_listener, EncodePool, Opened, RequestHandler, ENCODE_POOL, NO_RAFT, PNG_COMPRESSION, REFUSE_INVALID_UNIFIED, SOCKET_BUFFER_BYTES, UNIFIED_IMAGE_FIT};
+use cloud_p2p_project::{raft_addresses, guess_advertised_address, init_logging, is_self_address, load_server_list, set_single_port, run_startup_checks, print_dry_run, PngCompression, RAFT_PORT_OFFSET};
+use log::{error, info, warn};
+use std::path::PathBuf;
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
-use std::time::Instant;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use std::time::{Duration, Instant};
 use tokio::net::{TcpListener, TcpStream};
-use std::os::unix::io::AsRawFd;
-
-/// Configure TCP socket for large file transfers
-fn configure_large_transfer_socket(stream: &TcpStream) -> Result<()> {
-    #[cfg(unix)]
-    {
-        use std::mem;
-        
-        unsafe {
-            let fd = stream.as_raw_fd();
-            let size: libc::c_int = 8 * 1024 * 1024; // 8MB buffer
-            
-            // Set send buffer
-            libc::setsockopt(
-                fd,
-                libc::SOL_SOCKET,
-                libc::SO_SNDBUF,
-                &size as *const _ as *const libc::c_void,
-                mem::size_of_val(&size) as libc::socklen_t,
-            );
-            
-            // Set receive buffer
-            libc::setsockopt(
-                fd,
-                libc::SOL_SOCKET,
-                libc::SO_RCVBUF,
-                &size as *const _ as *const libc::c_void,
-                mem::size_of_val(&size) as libc::socklen_t,
-            );
-        }
-    }
-    Ok(())
-}
 
-const RAFT_PORT_OFFSET: u16 = 1000;    // Raft runs on port + 1000
+#[derive(Parser)]
+#[command(version, about = "Distributed image encryption server (no load balancing)", long_about = None)]
+struct Cli {
+    /// Port for the client-facing application listener
+    port: u16,
+
+    /// Unique id of this server in the Raft cluster
+    server_id: String,
+
+    /// Application addresses of the other servers (host:port)
+    peers: Vec<String>,
+
+    /// Read peers from a file (one host:port per line); takes precedence over PEERS
+    #[arg(long)]
+    peers_file: Option<String>,
+
+    /// Number of encrypted results to keep for identical requests (0 disables)
+    #[arg(long, default_value = "16")]
+    dedup_cache_size: usize,
+
+    /// Directory for Raft state files
+    #[arg(long, default_value = ".")]
+    data_dir: PathBuf,
+
+    /// Address clients should use to reach this server, sent to followers while
+    /// leader so their NOT_LEADER replies can point at it (guessed if omitted)
+    #[arg(long)]
+    advertised_addr: Option<String>,
+
+    /// Serve Raft and status traffic on the application port instead of port + 1000.
+    /// Connections starting with the byte 'R' are Raft; all peers must use this too
+    #[arg(long)]
+    single_port: bool,
+
+    /// Cap on the serialized log entries in one AppendEntries RPC, in bytes
+    /// (an entry larger than this is still sent, on its own)
+    #[arg(long, default_value_t = DEFAULT_MAX_RPC_BYTES)]
+    max_rpc_bytes: usize,
+
+    /// Validate the unified image, peers, Raft state and data directory, then exit
+    #[arg(long)]
+    check: bool,
+
+    /// Print the addresses this server would bind and its resolved Raft config, then exit
+    #[arg(long, conflicts_with = "check")]
+    dry_run: bool,
+
+    /// Write this node's saved Raft state (term, vote and log) to FILE as JSON, then exit
+    #[arg(long, value_name = "FILE", conflicts_with_all = ["check", "dry_run", "import_state"])]
+    export_state: Option<PathBuf>,
+
+    /// Replace this node's saved Raft state with a file from --export-state, then exit.
+    /// The node must be stopped
+    #[arg(long, value_name = "FILE", conflicts_with_all = ["check", "dry_run"])]
+    import_state: Option<PathBuf>,
+
+    /// Import even if this node's state has a higher term or entries the export lacks
+    #[arg(long, requires = "import_state")]
+    force_import: bool,
+
+    /// Keep client connections open for further requests after a successful reply
+    #[arg(long)]
+    keepalive: bool,
+
+    /// Write logs as one JSON object per line instead of plain text
+    #[arg(long)]
+    json_logs: bool,
+
+    /// How election timeouts are drawn between 4 and 10 s: uniform, or exponential
+    /// to favor short timeouts so a leaderless cluster elects sooner
+    #[arg(long, value_name = "DIST", default_value_t = TimeoutDistribution::Uniform)]
+    election_timeout_distribution: TimeoutDistribution,
+
+    /// Log every Raft role change (old role, new role, term and reason) as one line
+    #[arg(long)]
+    verbose_raft: bool,
+
+    /// Also append Raft role changes to FILE, one JSON object per line
+    #[arg(long, value_name = "FILE")]
+    raft_trace_file: Option<PathBuf>,
+
+    /// PNG compression for encrypted images: fast (least CPU, larger output),
+    /// default, or best (smallest output, most CPU). The payload is the same either way
+    #[arg(long, value_name = "LEVEL", default_value_t = PngCompression::Default)]
+    png_compression: PngCompression,
+
+    /// Shrink the unified image to fit the capacity each carrier has left
+    #[arg(long)]
+    fit_unified_image: bool,
+
+    /// Also cap the unified image's width and height (implies --fit-unified-image)
+    #[arg(long)]
+    unified_max_dimension: Option<u32>,
+
+    /// While leader, reload the unified image every this many seconds (0 disables)
+    #[arg(long, default_value = "30")]
+    unified_check_interval: u64,
+
+    /// Answer new requests with UNAVAILABLE while the last unified image check failed
+    #[arg(long)]
+    refuse_invalid_unified: bool,
+
+    /// Send and receive buffer size for client connections, in KiB (defaults to
+    /// the request's image size, between 256 KiB and 8 MiB)
+    #[arg(long, value_name = "KB", value_parser = clap::value_parser!(u32).range(1..=1024 * 1024))]
+    socket_buffer_kb: Option<u32>,
+
+    /// Most encryptions run at the same time; more requests wait for a slot
+    /// (defaults to the number of CPUs)
+    #[arg(long, value_parser = clap::value_parser!(u16).range(1..))]
+    encode_threads: Option<u16>,
+
+    /// Run as a Raft learner: keep a full copy of the log but never vote or lead
+    #[arg(long)]
+    learner: bool,
+
+    /// Application address of a peer that runs with --learner (repeatable). It is
+    /// sent the log but left out of elections and commits; added to the peers if missing
+    #[arg(long = "learner-peer", value_name = "HOST:PORT")]
+    learner_peers: Vec<String>,
+
+    /// Development mode: skip Raft entirely (no elections, no Raft port, nothing
+    /// committed) and always act as the leader. Peers are ignored
+    #[arg(long, conflicts_with_all = ["learner", "learner_peers", "initial_leader"])]
+    no_raft: bool,
+
+    /// On a fresh cluster, let the node with this id win the first election at
+    /// once while the others hold off (give every node the same value). Ignored
+    /// by a node that already has persisted state
+    #[arg(long, value_name = "ID")]
+    initial_leader: Option<String>,
+}
 // ============================================================================
 // LOAD BALANCING - COMMENTED OUT
 // ============================================================================
@@ -120,21 +216,73 @@ const RAFT_PORT_OFFSET: u16 = 1000;    // Raft runs on port + 1000
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    // Initialize logger
-    env_logger::init();
-
     // Parse command-line arguments
-    let args: Vec<String> = env::args().collect();
-    if args.len() < 3 {
-        bail!("Usage: server <port> <server_id> [peer1:port] [peer2:port] ...");
-    }
+    let cli = Cli::parse();
 
-    let port: u16 = args[1].parse()?;
-    let server_id = args[2].clone();
-    let peers: Vec<String> = args[3..].to_vec();
+    // Initialize logger
+    init_logging(cli.json_logs, &cli.server_id);
+    let port = cli.port;
+    let server_id = cli.server_id;
+
+    // A peers file wins over argv; our own entry is skipped so servers.conf can be reused
+    let mut peers: Vec<String> = match &cli.peers_file {
+        Some(path) => {
+            if !cli.peers.is_empty() {
+                info!("Using peers from '{}', ignoring {} peer(s) given on the command line",
+                      path, cli.peers.len());
+            }
+            load_server_list(path)?
+                .into_iter()
+                .filter(|p| !is_self_address(p, port))
+                .collect()
+        }
+        None => cli.peers,
+    };
+    for learner in &cli.learner_peers {
+        if !peers.contains(learner) {
+            peers.push(learner.clone());
+        }
+    }
+    if cli.no_raft && !peers.is_empty() {
+        warn!("--no-raft: ignoring {} peer(s)", peers.len());
+        peers.clear();
+    }
+    let keepalive = cli.keepalive;
+    let single_port = cli.single_port;
+    set_single_port(single_port);
 
     info!("Starting server {} on port {}", server_id, port);
     info!("Peers: {:?}", peers);
+    if cli.no_raft {
+        warn!("CONSENSUS DISABLED (--no-raft): acting as a single-node leader, nothing is replicated or committed");
+    }
+    if cli.learner {
+        info!("Running as a learner: replicating the log without voting");
+    }
+    if !cli.learner_peers.is_empty() {
+        info!("Learner peers (not in the quorum): {:?}", cli.learner_peers);
+    }
+
+    // Cache of encrypted results for identical requests
+    let cache = Arc::new(EncryptionCache::new(cli.dedup_cache_size));
+
+    if let Some(kb) = cli.socket_buffer_kb {
+        SOCKET_BUFFER_BYTES.store(kb as usize * 1024, Ordering::Relaxed);
+    }
+
+    let encode_threads = cli.encode_threads.map_or_else(default_encode_threads, usize::from);
+    let _ = ENCODE_POOL.set(EncodePool::new(encode_threads));
+    info!("Running at most {} encryptions at a time", encode_threads);
+
+    let _ = PNG_COMPRESSION.set(cli.png_compression);
+    if cli.png_compression != PngCompression::Default {
+        info!("Encoding encrypted images with {} PNG compression", cli.png_compression);
+    }
+
+    if cli.fit_unified_image || cli.unified_max_dimension.is_some() {
+        info!("Fitting the unified image to each carrier (max dimension: {:?})", cli.unified_max_dimension);
+        let _ = UNIFIED_IMAGE_FIT.set(cli.unified_max_dimension);
+    }
 
     // ============================================================================
     // LOAD BALANCING - COMMENTED OUT
@@ -142,15 +290,30 @@ async fn main() -> Result<()> {
     // Create load balancing state
     // let lb_state = Arc::new(LoadBalancingState::new());
 
-    // Convert peer addresses to include Raft port
-    let raft_peers: Vec<String> = peers
-        .iter()
-        .map(|p| {
-            let parts: Vec<&str> = p.split(':').collect();
-            let peer_port: u16 = parts[1].parse().unwrap();
-            format!("{}:{}", parts[0], peer_port + RAFT_PORT_OFFSET)
-        })
-        .collect();
+    if cli.check {
+        let passed = run_startup_checks(&peers, &server_id, &cli.data_dir);
+        std::process::exit(if passed { 0 } else { 1 });
+    }
+
+    if let Some(out) = &cli.export_state {
+        println!("{}", RaftNode::export_state(&cli.data_dir, &server_id, out)?);
+        return Ok(());
+    }
+    if let Some(file) = &cli.import_state {
+        // Overwriting the state under a running node would be undone by its next persist
+        if let Err(e) = std::net::TcpListener::bind(("0.0.0.0", port)) {
+            if e.kind() == std::io::ErrorKind::AddrInUse {
+                bail!("port {} is in use: stop {} before importing its state", port, server_id);
+            }
+        }
+        println!("{}", RaftNode::import_state(&cli.data_dir, &server_id, file, cli.force_import)?);
+        return Ok(());
+    }
+
+    // Convert peer addresses to include Raft port (the same port when multiplexed);
+    // a malformed peer stops startup with a message naming it
+    let raft_peers = raft_addresses(&peers)?;
+    let raft_learners = raft_addresses(&cli.learner_peers)?;
 
     // Create Raft configuration
     let raft_config = RaftConfig {
@@ -158,23 +321,73 @@ async fn main() -> Result<()> {
         peers: raft_peers,
         election_timeout_min: 4000,
         election_timeout_max: 10000,
+        election_timeout_distribution: cli.election_timeout_distribution,
         heartbeat_interval: 2000,
+        election_tick: 100,
+        data_dir: cli.data_dir,
+        advertised_addr: cli.advertised_addr.or_else(|| guess_advertised_address(&peers, port)),
+        election_seed: election_seed_from_env(),
+        max_rpc_bytes: cli.max_rpc_bytes,
+        single_port,
+        learner: cli.learner,
+        learners: raft_learners,
+        trace_roles: cli.verbose_raft,
+        trace_file: cli.raft_trace_file,
+        initial_leader: cli.initial_leader,
     };
 
-    // Create and start Raft node
-    let raft_node = Arc::new(RaftNode::new(raft_config));
-    let raft_clone = Arc::clone(&raft_node);
-    raft_clone.start().await;
-
-    // Start Raft message listener on separate port
-    let raft_port = port + RAFT_PORT_OFFSET;
-    let raft_listener_node = Arc::clone(&raft_node);
-    tokio::spawn(async move {
-        if let Err(e) = start_raft_listener(raft_port, raft_listener_node).await {
-            error!("Raft listener error: {}", e);
-        }
+    if cli.dry_run {
+        let raft_listener = if cli.no_raft {
+            "none (--no-raft)".to_string()
+        } else if single_port {
+            format!("0.0.0.0:{} (shared with the application port)", port)
+        } else {
+            format!("0.0.0.0:{}", port + RAFT_PORT_OFFSET)
+        };
+        let listeners = [
+            ("Application", format!("0.0.0.0:{}", port)),
+            ("Raft", raft_listener),
+        ];
+        let valid = print_dry_run(&listeners, &peers, &raft_config, cli.no_raft);
+        std::process::exit(if valid { 0 } else { 1 });
+    }
+
+    // Create and start Raft node. Under --no-raft it is only created, for the
+    // handlers and status replies that expect one, and never started
+    let raft_node = Arc::new(RaftNode::new(raft_config)?);
+    NO_RAFT.store(cli.no_raft, Ordering::Relaxed);
+    if !cli.no_raft {
+        let raft_clone = Arc::clone(&raft_node);
+        raft_clone.start().await;
+    }
+
+    REFUSE_INVALID_UNIFIED.store(cli.refuse_invalid_unified, Ordering::Relaxed);
+    if cli.unified_check_interval > 0 {
+        tokio::spawn(check_unified_image_periodically(
+            Arc::clone(&raft_node),
+            Duration::from_secs(cli.unified_check_interval),
+        ));
+    }
+
+    // Every client request is encrypted here, on the leader
+    let handler = Arc::new(DirectHandler {
+        raft_node: Arc::clone(&raft_node),
+        cache: Arc::clone(&cache),
     });
 
+    // Start Raft message listener on separate port, unless it shares the app port
+    let raft_port = if single_port { port } else { port + RAFT_PORT_OFFSET };
+    if !single_port && !cli.no_raft {
+        let raft_listener_node = Arc::clone(&raft_node);
+        let raft_listener_cache = Arc::clone(&cache);
+        let raft_listener_handler = Arc::clone(&handler);
+        tokio::spawn(async move {
+            if let Err(e) = start_raft_listener(raft_port, raft_listener_node, raft_listener_cache, raft_listener_handler).await {
+                error!("Raft listener error: {}", e);
+            }
+        });
+    }
+
     // ============================================================================
     // LOAD BALANCING - COMMENTED OUT
     // ============================================================================
@@ -201,7 +414,11 @@ async fn main() -> Result<()> {
     let bind_addr = format!("0.0.0.0:{}", port);
     let listener = TcpListener::bind(&bind_addr).await?;
     info!("Application server listening on {}", bind_addr);
-    info!("Raft consensus running on port {}", raft_port);
+    if cli.no_raft {
+        warn!("Raft consensus disabled, no Raft port bound");
+    } else {
+        info!("Raft consensus running on port {}{}", raft_port, if single_port { " (multiplexed)" } else { "" });
+    }
     // ============================================================================
     // LOAD BALANCING - COMMENTED OUT
     // ============================================================================
@@ -210,19 +427,29 @@ async fn main() -> Result<()> {
 
     loop {
         match listener.accept().await {
-            Ok((stream, addr)) => {
-                info!("Client connected from {}", addr);
+            Ok((mut stream, addr)) => {
                 let raft_ref = Arc::clone(&raft_node);
+                let cache_ref = Arc::clone(&cache);
+                let handler_ref = Arc::clone(&handler);
                 // ============================================================================
                 // LOAD BALANCING - COMMENTED OUT
                 // ============================================================================
                 // let lb_ref = Arc::clone(&lb_state);
                 // let peers_clone = peers.clone();
                 tokio::spawn(async move {
+                    // With a shared port, Raft connections announce themselves with MUX_RAFT
+                    if single_port && is_raft_connection(&mut stream).await {
+                        if let Err(e) = handle_raft_message(stream, raft_ref, cache_ref, handler_ref).await {
+                            error!("Error handling Raft message: {}", e);
+                        }
+                        return;
+                    }
+
+                    info!("Client connected from {}", addr);
                     // ============================================================================
                     // WITHOUT LOAD BALANCING - Simple handler
                     // ============================================================================
-                    if let Err(e) = handle_client_simple(stream, raft_ref).await {
+                    if let Err(e) = serve_client(stream, handler_ref, keepalive).await {
                         error!("Error handling client: {}", e);
                     }
                     
@@ -244,50 +471,6 @@ async fn main() -> Result<()> {
     }
 }
 
-// =============================================================================
-// RAFT LISTENER
-// =============================================================================
-
-async fn start_raft_listener(port: u16, raft_node: Arc<RaftNode>) -> Result<()> {
-    let bind_addr = format!("0.0.0.0:{}", port);
-    let listener = TcpListener::bind(&bind_addr).await?;
-    info!("Raft listener started on {}", bind_addr);
-
-    loop {
-        match listener.accept().await {
-            Ok((stream, _)) => {
-                let raft_ref = Arc::clone(&raft_node);
-                tokio::spawn(async move {
-                    if let Err(e) = handle_raft_message(stream, raft_ref).await {
-                        error!("Error handling Raft message: {}", e);
-                    }
-                });
-            }
-            Err(e) => error!("Failed to accept Raft connection: {}", e),
-        }
-    }
-}
-
-async fn handle_raft_message(mut stream: TcpStream, raft_node: Arc<RaftNode>) -> Result<()> {
-    // Read message
-    let msg_len = stream.read_u32().await?;
-    let mut msg_buf = vec![0u8; msg_len as usize];
-    stream.read_exact(&mut msg_buf).await?;
-    
-    let message: RaftMessage = serde_json::from_slice(&msg_buf)?;
-    
-    // Handle message and get response
-    if let Some(response) = raft_node.handle_raft_message(message).await {
-        let response_json = serde_json::to_string(&response)?;
-        let response_bytes = response_json.as_bytes();
-        stream.write_u32(response_bytes.len() as u32).await?;
-        stream.write_all(response_bytes).await?;
-        stream.flush().await?;
-    }
-
-    Ok(())
-}
-
 // =============================================================================
 // METRICS SERVER (for Load Balancing) - COMMENTED OUT
 // =============================================================================
@@ -431,60 +614,32 @@ async fn handle_raft_message(mut stream: TcpStream, raft_node: Arc<RaftNode>) ->
 // =============================================================================
 // SIMPLE CLIENT HANDLER (WITHOUT LOAD BALANCING)
 // =============================================================================
-
-async fn handle_client_simple(
-    mut stream: TcpStream,
+/// Encrypts every request on the leader itself
+struct DirectHandler {
     raft_node: Arc<RaftNode>,
-) -> Result<()> {
-    let start_time = Instant::now();
-
-    // Configure TCP buffers for large transfers
-    configure_large_transfer_socket(&stream)?;
-
-    // Check if this server is the leader
-    if !raft_node.is_leader().await {
-        // Not the leader, inform client
-        let leader_id = raft_node.get_leader_id().await;
-        let error_msg = match &leader_id {
-            Some(id) => format!("NOT_LEADER:{}", id),
-            None => "NO_LEADER".to_string(),
+    cache: Arc<EncryptionCache>,
+}
+
+impl RequestHandler for DirectHandler {
+    async fn handle_request(&self, stream: &mut TcpStream) -> Result<bool> {
+        let start_time = Instant::now();
+
+        let head = match open_request(stream, &self.raft_node, &self.cache).await? {
+            Opened::Request(head) => head,
+            Opened::Answered(reusable) => return Ok(reusable),
         };
-        
-        let error_bytes = error_msg.as_bytes();
-        stream.write_u64(error_bytes.len() as u64).await?;
-        stream.write_all(error_bytes).await?;
-        stream.flush().await?;
-        
-        info!("Rejected client - not leader. Current leader: {:?}", leader_id);
-        return Ok(());
-    }
 
-    info!("=== LEADER: Processing request directly (no load balancing) ===");
-
-    // Read client request
-    let meta_size = stream.read_u64().await?;
-    let mut meta_buf = vec![0; meta_size as usize];
-    stream.read_exact(&mut meta_buf).await?;
-
-    let img_size = stream.read_u64().await?;
-    let mut img_buf = vec![0; img_size as usize];
-    stream.read_exact(&mut img_buf).await?;
-    
-    info!("Received client request (meta: {} bytes, image: {} bytes)", meta_size, img_size);
-
-    // Process the encryption directly (no load balancing)
-    let result = process_encryption_work(&meta_buf, &img_buf).await?;
-    
-    let elapsed = start_time.elapsed().as_millis() as u64;
-    info!("Processing completed in {}ms", elapsed);
-
-    // Send result back to client
-    stream.write_u64(result.len() as u64).await?;
-    stream.write_all(&result).await?;
-    stream.flush().await?;
-    
-    info!("Sent result to client ({} bytes)", result.len());
-    Ok(())
+        info!("=== LEADER: Processing request directly (no load balancing) ===");
+        let img_buf = read_request_image(stream, &head).await?;
+
+        // Process the encryption directly (no load balancing)
+        let result = process_encryption_work(&head.meta, &img_buf, head.unified_override.as_deref(), &self.cache).await;
+
+        let elapsed = start_time.elapsed().as_millis() as u64;
+        info!("Processing completed in {}ms", elapsed);
+
+        finish_request(stream, &self.raft_node, &head, result).await
+    }
 }
 
 // =============================================================================
@@ -616,7 +771,7 @@ async fn handle_client_simple(
 // HELPER FUNCTIONS - COMMENTED OUT (LOAD BALANCING)
 // =============================================================================
 
-/// Request metrics from a peer server - COMMENTED OUT
+// Request metrics from a peer server - COMMENTED OUT
 // async fn request_metrics_from_peer(peer_addr: &str) -> Result<ServerMetrics> {
 //     let parts: Vec<&str> = peer_addr.split(':').collect();
 //     let base_port: u16 = parts[1].parse()?;
@@ -647,7 +802,7 @@ async fn handle_client_simple(
 //     }
 // }
 
-/// Forward work to another server using its direct address - COMMENTED OUT
+// Forward work to another server using its direct address - COMMENTED OUT
 // async fn forward_work_to_address(
 //     target_addr: &str,
 //     meta_buf: &[u8],
@@ -690,38 +845,3 @@ async fn handle_client_simple(
 //         _ => bail!("Unexpected response type from work receiver"),
 //     }
 // }
-
-// =============================================================================
-// ENCRYPTION PROCESSING (USED BY BOTH MODES)
-// =============================================================================
-
-async fn process_encryption_work(meta_buf: &[u8], img_buf: &[u8]) -> Result<Vec<u8>> {
-    let meta_buf = meta_buf.to_vec();
-    let img_buf = img_buf.to_vec();
-    
-    // Run CPU/IO intensive work on blocking thread pool
-    tokio::task::spawn_blocking(move || {
-        let permissions: ImagePermissions = bincode::deserialize(&meta_buf)?;
-        let img = image::load_from_memory(&img_buf)?;
-
-        // This blocking I/O won't block heartbeats anymore
-        let unified_image_bytes = fs::read("unified_image.png")?;
-
-        let combined_payload = CombinedPayload {
-            permissions,
-            unified_image: unified_image_bytes,
-        };
-        
-        let final_payload = bincode::serialize(&combined_payload)?;
-        let encoded_img = lsb::encode(&img, &final_payload)?;
-        
-        // Simulate work
-        // std::thread::sleep(std::time::Duration::from_secs(5));
-        
-        let mut out_buf = Vec::new();
-        encoded_img.write_to(&mut Cursor::new(&mut out_buf), ImageOutputFormat::Png)?;
-        
-        Ok::<Vec<u8>, anyhow::Error>(out_buf)
-    })
-    .await?
-}
\ No newline at end of file
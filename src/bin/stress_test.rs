@@ -9,6 +9,9 @@
 //!
 //! # With custom timeouts and delay
 //! cargo run --bin stress_test -- -n 2000 -t 15 --connect-timeout 10 --rw-timeout 60 -d 100
+//!
+//! # Check every 20th request's payload against every server's own encryption
+//! cargo run --bin stress_test -- -n 500 -i my_image.jpg --verify-consistency --verify-sample 20
 
 // // # Build in release mode for better performance
 // cargo build --release --bin stress_test
@@ -21,19 +24,20 @@
 
 
 use anyhow::{bail, Result};
-use bincode;
-use cloud_p2p_project::ImagePermissions;
+use cloud_p2p_project::{capped_backoff, find_leader, load_server_list, lsb, negotiate_protocol, set_single_port, BadRequest, CombinedPayload, ImagePermissions, LoadBalancingMessage, MAX_INFLATED_FRAME};
 use image::{ImageFormat, GenericImageView};
 use std::collections::HashMap;
 use std::fs;
 use std::io::{Read, Write};
 use std::net::TcpStream;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
 use std::thread;
 use std::time::{Duration, Instant};
 use clap::Parser;
+use flate2::write::GzEncoder;
+use flate2::Compression;
 
 // ============================================================================
 // CLI ARGUMENTS
@@ -78,11 +82,75 @@ struct Cli {
     #[arg(long, default_value = "100")]
     retry_backoff_ms: u64,
 
+    /// Longest wait between retries, however far the backoff has doubled (milliseconds)
+    #[arg(long, default_value = "10000")]
+    max_backoff_ms: u64,
+
     /// Enable verbose output
     #[arg(short = 'v', long)]
     verbose: bool,
+
+    /// Number of successful images to save per thread (0 disables saving)
+    #[arg(long, default_value = "3")]
+    save_samples: usize,
+
+    /// Directory to save sample images into
+    #[arg(long, default_value = "stress_test_samples")]
+    samples_dir: PathBuf,
+
+    /// Also write the decoded payload of each sample next to it as JSON
+    #[arg(long)]
+    dump_payload: bool,
+
+    /// Discover the leader via the status endpoint and send requests only to it,
+    /// re-discovering after a failure
+    #[arg(long, conflicts_with = "multicast")]
+    leader_aware: bool,
+
+    /// Send every request to all servers and keep the first success (default)
+    #[arg(long)]
+    multicast: bool,
+
+    /// The servers run with --single-port: query leader status on the application port
+    #[arg(long)]
+    single_port: bool,
+
+    /// Reuse one connection per server across a thread's requests
+    /// (only takes effect against servers started with --keepalive)
+    #[arg(long)]
+    keepalive: bool,
+
+    /// Gzip the saved report (written as .txt.gz)
+    #[arg(long)]
+    report_compress: bool,
+
+    /// Gzip level for --report-compress, 0 (none) to 9 (best)
+    #[arg(long, default_value = "6", value_parser = clap::value_parser!(u32).range(0..=9), requires = "report_compress")]
+    report_compression_level: u32,
+
+    /// Label (e.g. a git commit) added to the report's filename and header
+    #[arg(long)]
+    report_label: Option<String>,
+
+    /// For sampled requests, have every server encrypt the same request through
+    /// its work receiver and check all the embedded payloads match
+    /// (needs the load-balancing `server`)
+    #[arg(long)]
+    verify_consistency: bool,
+
+    /// Check one in every N requests per thread with --verify-consistency
+    #[arg(long, default_value = "10", value_parser = clap::value_parser!(u64).range(1..), requires = "verify_consistency")]
+    verify_sample: u64,
+
+    /// Seconds between progress updates; each also shows p50/p99 latency of
+    /// the requests that completed since the previous one
+    #[arg(long, default_value = "2", value_parser = clap::value_parser!(u64).range(1..))]
+    metrics_interval: u64,
 }
 
+/// The load-balancing server's work receiver listens on app port + 3000
+const WORK_PORT_OFFSET: u16 = 3000;
+
 // ============================================================================
 // STATISTICS TRACKING
 // ============================================================================
@@ -107,10 +175,22 @@ struct TestStatistics {
     
     // Error breakdown
     connection_errors: AtomicUsize,
+
+    // Connection reuse (--keepalive)
+    connections_opened: AtomicUsize,
+    connections_reused: AtomicUsize,
+
+    // Cross-server consistency (--verify-consistency)
+    consistency_checks: AtomicUsize,
+    inconsistent_responses: AtomicUsize,
+    unreachable_workers: AtomicUsize,
     timeout_errors: AtomicUsize,
+    protocol_errors: AtomicUsize,
+    io_errors: AtomicUsize,
     not_leader_errors: AtomicUsize,
     no_leader_errors: AtomicUsize,
     invalid_response_errors: AtomicUsize,
+    bad_request_errors: AtomicUsize,
     other_errors: AtomicUsize,
     
     // Timing statistics
@@ -143,10 +223,18 @@ impl TestStatistics {
             total_retries: AtomicUsize::new(0),
             requests_with_retries: AtomicUsize::new(0),
             connection_errors: AtomicUsize::new(0),
+            connections_opened: AtomicUsize::new(0),
+            connections_reused: AtomicUsize::new(0),
+            consistency_checks: AtomicUsize::new(0),
+            inconsistent_responses: AtomicUsize::new(0),
+            unreachable_workers: AtomicUsize::new(0),
             timeout_errors: AtomicUsize::new(0),
+            protocol_errors: AtomicUsize::new(0),
+            io_errors: AtomicUsize::new(0),
             not_leader_errors: AtomicUsize::new(0),
             no_leader_errors: AtomicUsize::new(0),
             invalid_response_errors: AtomicUsize::new(0),
+            bad_request_errors: AtomicUsize::new(0),
             other_errors: AtomicUsize::new(0),
             total_response_time_ms: AtomicU64::new(0),
             min_response_time_ms: AtomicU64::new(u64::MAX),
@@ -267,9 +355,12 @@ impl TestStatistics {
         match error_type {
             ErrorType::Connection => self.connection_errors.fetch_add(1, Ordering::Relaxed),
             ErrorType::Timeout => self.timeout_errors.fetch_add(1, Ordering::Relaxed),
+            ErrorType::Protocol => self.protocol_errors.fetch_add(1, Ordering::Relaxed),
+            ErrorType::Io => self.io_errors.fetch_add(1, Ordering::Relaxed),
             ErrorType::NotLeader => self.not_leader_errors.fetch_add(1, Ordering::Relaxed),
             ErrorType::NoLeader => self.no_leader_errors.fetch_add(1, Ordering::Relaxed),
             ErrorType::InvalidResponse => self.invalid_response_errors.fetch_add(1, Ordering::Relaxed),
+            ErrorType::BadRequest => self.bad_request_errors.fetch_add(1, Ordering::Relaxed),
             ErrorType::Other => self.other_errors.fetch_add(1, Ordering::Relaxed),
         };
     }
@@ -339,10 +430,27 @@ impl TestStatistics {
         println!("───────────────────────────────────────────────────────────────");
         println!("  Connection Errors:    {}", self.connection_errors.load(Ordering::Relaxed));
         println!("  Timeout Errors:       {}", self.timeout_errors.load(Ordering::Relaxed));
+        println!("  Protocol Errors:      {}", self.protocol_errors.load(Ordering::Relaxed));
+        println!("  I/O Errors:           {}", self.io_errors.load(Ordering::Relaxed));
         println!("  NOT_LEADER Errors:    {}", self.not_leader_errors.load(Ordering::Relaxed));
         println!("  NO_LEADER Errors:     {}", self.no_leader_errors.load(Ordering::Relaxed));
         println!("  Invalid Response:     {}", self.invalid_response_errors.load(Ordering::Relaxed));
+        println!("  Bad Request:          {}", self.bad_request_errors.load(Ordering::Relaxed));
         println!("  Other Errors:         {}", self.other_errors.load(Ordering::Relaxed));
+
+        println!("\n🔌 CONNECTIONS");
+        println!("───────────────────────────────────────────────────────────────");
+        println!("  Opened:               {}", self.connections_opened.load(Ordering::Relaxed));
+        println!("  Reused (keepalive):   {}", self.connections_reused.load(Ordering::Relaxed));
+
+        let checks = self.consistency_checks.load(Ordering::Relaxed);
+        if checks > 0 {
+            println!("\n🧬 CROSS-SERVER CONSISTENCY");
+            println!("───────────────────────────────────────────────────────────────");
+            println!("  Requests Checked:     {}", checks);
+            println!("  Inconsistent:         {}", self.inconsistent_responses.load(Ordering::Relaxed));
+            println!("  Unreachable Workers:  {}", self.unreachable_workers.load(Ordering::Relaxed));
+        }
         
         if success > 0 {
             let total_response = self.total_response_time_ms.load(Ordering::Relaxed);
@@ -364,10 +472,10 @@ impl TestStatistics {
             let mut times = self.response_times.lock().unwrap();
             if !times.is_empty() {
                 times.sort_unstable();
-                let p50 = times[times.len() * 50 / 100];
-                let p90 = times[times.len() * 90 / 100];
-                let p95 = times[times.len() * 95 / 100];
-                let p99 = times[times.len() * 99 / 100];
+                let p50 = percentile(&times, 50);
+                let p90 = percentile(&times, 90);
+                let p95 = percentile(&times, 95);
+                let p99 = percentile(&times, 99);
                 
                 println!("  50th Percentile (p50): {} ms", p50);
                 println!("  90th Percentile (p90): {} ms", p90);
@@ -397,6 +505,10 @@ impl TestStatistics {
             println!("  ❌ POOR: Success rate < 90%");
         }
         
+        if self.inconsistent_responses.load(Ordering::Relaxed) > 0 {
+            println!("  ❌ INCONSISTENT: servers embedded different payloads for the same request");
+        }
+
         // Image validation assessment
         if success > 0 {
             let valid_rate = (valid_imgs as f64 / success as f64) * 100.0;
@@ -412,7 +524,7 @@ impl TestStatistics {
         println!("\n");
     }
     
-    fn save_to_file(&self, filename: &str) -> Result<()> {
+    fn save_to_file(&self, filename: &str, label: Option<&str>, compression: Option<Compression>) -> Result<()> {
         let total = self.total_requests.load(Ordering::Relaxed);
         let success = self.successful_requests.load(Ordering::Relaxed);
         let failed = self.failed_requests.load(Ordering::Relaxed);
@@ -422,8 +534,24 @@ impl TestStatistics {
         let invalid_imgs = self.invalid_images.load(Ordering::Relaxed);
         let total_img_bytes = self.total_image_bytes.load(Ordering::Relaxed);
         
+        let label_line = label.map(|l| format!("Label: {}\n", l)).unwrap_or_default();
+        let checks = self.consistency_checks.load(Ordering::Relaxed);
+        let consistency_section = if checks > 0 {
+            format!(
+                "\nCross-Server Consistency:\n\
+                 - Requests Checked: {}\n\
+                 - Inconsistent Responses: {}\n\
+                 - Unreachable Workers: {}\n",
+                checks,
+                self.inconsistent_responses.load(Ordering::Relaxed),
+                self.unreachable_workers.load(Ordering::Relaxed),
+            )
+        } else {
+            String::new()
+        };
         let report = format!(
             "Stress Test Report - {}\n\
+             {}\
              ═══════════════════════════════════════\n\
              \n\
              Overall Statistics:\n\
@@ -445,9 +573,12 @@ impl TestStatistics {
              Error Breakdown:\n\
              - Connection Errors: {}\n\
              - Timeout Errors: {}\n\
+             - Protocol Errors: {}\n\
+             - I/O Errors: {}\n\
              - NOT_LEADER Errors: {}\n\
              - NO_LEADER Errors: {}\n\
              - Invalid Response: {}\n\
+             - Bad Request: {}\n\
              - Other Errors: {}\n\
              \n\
              Response Time Statistics (ms):\n\
@@ -457,8 +588,9 @@ impl TestStatistics {
              \n\
              Leader Election:\n\
              - Leader Changes: {}\n\
-             ",
+             {}",
             format_timestamp(),
+            label_line,
             total,
             success, (success as f64 / total as f64) * 100.0,
             failed, (failed as f64 / total as f64) * 100.0,
@@ -471,29 +603,48 @@ impl TestStatistics {
             self.requests_with_retries.load(Ordering::Relaxed),
             self.connection_errors.load(Ordering::Relaxed),
             self.timeout_errors.load(Ordering::Relaxed),
+            self.protocol_errors.load(Ordering::Relaxed),
+            self.io_errors.load(Ordering::Relaxed),
             self.not_leader_errors.load(Ordering::Relaxed),
             self.no_leader_errors.load(Ordering::Relaxed),
             self.invalid_response_errors.load(Ordering::Relaxed),
+            self.bad_request_errors.load(Ordering::Relaxed),
             self.other_errors.load(Ordering::Relaxed),
             if success > 0 { self.total_response_time_ms.load(Ordering::Relaxed) / success as u64 } else { 0 },
             self.min_response_time_ms.load(Ordering::Relaxed),
             self.max_response_time_ms.load(Ordering::Relaxed),
             self.leader_changes.load(Ordering::Relaxed),
+            consistency_section,
         );
         
-        fs::write(filename, report)?;
+        match compression {
+            Some(level) => {
+                let mut encoder = GzEncoder::new(fs::File::create(filename)?, level);
+                encoder.write_all(report.as_bytes())?;
+                encoder.finish()?;
+            }
+            None => fs::write(filename, report)?,
+        }
         println!("📄 Detailed report saved to: {}", filename);
         Ok(())
     }
 }
 
+/// The `pct`th percentile of non-empty, sorted response times
+fn percentile(sorted: &[u64], pct: usize) -> u64 {
+    sorted[sorted.len() * pct / 100]
+}
+
 #[derive(Debug, Clone, Copy)]
 enum ErrorType {
     Connection,
     Timeout,
+    Protocol, // the server broke off or garbled its reply, e.g. a partial read
+    Io,
     NotLeader,
     NoLeader,
     InvalidResponse,
+    BadRequest, // BAD_IMAGE, BAD_METADATA or BAD_CAPACITY: the server will refuse it every time
     Other,
 }
 
@@ -510,7 +661,7 @@ fn validate_encrypted_image(data: &[u8]) -> Result<bool> {
     
     // Check PNG signature (first 8 bytes)
     let png_signature: [u8; 8] = [0x89, 0x50, 0x4E, 0x47, 0x0D, 0x0A, 0x1A, 0x0A];
-    if &data[0..8] != &png_signature {
+    if data[0..8] != png_signature {
         return Ok(false);
     }
     
@@ -525,31 +676,134 @@ fn validate_encrypted_image(data: &[u8]) -> Result<bool> {
     }
 }
 
+/// The leader to send to in leader-aware mode, discovering it through the
+/// status endpoint if it isn't known. Discovery happens under the lock so
+/// concurrent workers don't all query the cluster at once.
+fn current_leader(servers: &[String], known_leader: &Mutex<Option<String>>, config: &Cli) -> Option<String> {
+    let mut leader = known_leader.lock().unwrap();
+    if leader.is_none() {
+        *leader = find_leader(servers, Duration::from_secs(config.connect_timeout)).map(|(addr, _)| addr);
+        if let Some(addr) = leader.as_ref() {
+            if config.verbose {
+                println!("Discovered leader at {}", addr);
+            }
+        }
+    }
+    leader.clone()
+}
+
+/// Drop the cached leader after a failure, unless another worker already replaced it
+fn forget_leader(known_leader: &Mutex<Option<String>>, failed_addr: &str) {
+    let mut leader = known_leader.lock().unwrap();
+    if leader.as_deref() == Some(failed_addr) {
+        *leader = None;
+    }
+}
+
 /// Save sample encrypted images for manual inspection
-fn save_sample_image(data: &[u8], sample_id: usize, thread_id: usize) -> Result<()> {
+fn save_sample_image(data: &[u8], sample_id: usize, thread_id: usize, config: &Cli) -> Result<()> {
     // Create samples directory if it doesn't exist
-    let samples_dir = "stress_test_samples";
-    fs::create_dir_all(samples_dir)?;
+    fs::create_dir_all(&config.samples_dir)?;
     
-    let filename = format!("{}/encrypted_sample_t{}_r{}.png", samples_dir, thread_id, sample_id);
+    let filename = config.samples_dir.join(format!("encrypted_sample_t{}_r{}.png", thread_id, sample_id));
     fs::write(&filename, data)?;
+
+    if config.dump_payload {
+        fs::write(filename.with_extension("json"), describe_payload(data))?;
+    }
     
     Ok(())
 }
 
+/// Decode the payload embedded in an encrypted image into readable JSON.
+/// The unified image is summarised by its size; decode failures are recorded
+/// in the output rather than aborting the sample.
+fn describe_payload(data: &[u8]) -> String {
+    let description = match decode_payload(data) {
+        Ok(payload) => serde_json::json!({
+            "permissions": payload.permissions,
+            "unified_image_bytes": payload.unified_image.len(),
+        }),
+        Err(e) => serde_json::json!({ "error": e.to_string() }),
+    };
+
+    serde_json::to_string_pretty(&description).unwrap_or_default()
+}
+
+/// Decode the payload embedded in an encrypted PNG
+fn decode_payload(data: &[u8]) -> Result<CombinedPayload> {
+    let img = image::load_from_memory(data)?;
+    match lsb::decode(&img)? {
+        Some(bytes) => CombinedPayload::from_bytes(&bytes),
+        None => bail!("no payload embedded"),
+    }
+}
+
+/// Have every server encrypt the request through its work receiver and compare
+/// each embedded payload against the one the cluster returned. The permissions
+/// and unified image must match exactly; the carrier pixels are not compared.
+/// Returns how many servers disagreed.
+fn check_consistency(
+    servers: &[String],
+    meta_bytes: &[u8],
+    img_data: &[u8],
+    reference: &[u8],
+    config: &Cli,
+    stats: &TestStatistics,
+) -> Result<usize> {
+    let expected = decode_payload(reference)?;
+    stats.consistency_checks.fetch_add(1, Ordering::Relaxed);
+
+    let mut mismatches = 0;
+    for server_addr in servers {
+        let result = send_forwarded_work(server_addr, meta_bytes, img_data, config.connect_timeout, config.rw_timeout)
+            .and_then(|encrypted| decode_payload(&encrypted));
+        let payload = match result {
+            Ok(payload) => payload,
+            Err(e) => {
+                stats.unreachable_workers.fetch_add(1, Ordering::Relaxed);
+                if config.verbose {
+                    println!("Consistency: no result from {}: {}", server_addr, e);
+                }
+                continue;
+            }
+        };
+
+        let mut differences = Vec::new();
+        if payload.permissions != expected.permissions {
+            differences.push(format!("permissions {:?} vs {:?}", payload.permissions, expected.permissions));
+        }
+        if payload.unified_image != expected.unified_image {
+            differences.push(format!(
+                "unified image {} bytes vs {} bytes",
+                payload.unified_image.len(),
+                expected.unified_image.len()
+            ));
+        }
+        if !differences.is_empty() {
+            mismatches += 1;
+            println!("\n⚠️  Inconsistent payload from {}: {}", server_addr, differences.join("; "));
+        }
+    }
+
+    stats.inconsistent_responses.fetch_add(mismatches, Ordering::Relaxed);
+    Ok(mismatches)
+}
+
 // ============================================================================
 // MAIN TEST LOGIC
 // ============================================================================
 
 fn main() -> Result<()> {
     let cli = Cli::parse();
+    set_single_port(cli.single_port);
     
     println!("╔═══════════════════════════════════════════════════════════════╗");
     println!("║        DISTRIBUTED IMAGE ENCRYPTION STRESS TEST              ║");
     println!("╚═══════════════════════════════════════════════════════════════╝\n");
     
     // Load servers
-    let servers = load_servers(&cli.server_config)?;
+    let servers = load_server_list(&cli.server_config)?;
     println!("🖥️  Loaded {} servers from '{}'", servers.len(), cli.server_config);
     
     // Load test image
@@ -564,8 +818,14 @@ fn main() -> Result<()> {
     let permissions = ImagePermissions {
         owner: "test_owner".to_string(),
         quotas,
+        note: None,
+        version: 0,
+        view_cooldown_secs: None,
+        last_views: HashMap::new(),
+        signature: None,
+        tokens: HashMap::new(),
     };
-    let meta_bytes = bincode::serialize(&permissions)?;
+    let meta_bytes = permissions.to_bytes()?;
     
     println!("\n📋 TEST CONFIGURATION");
     println!("───────────────────────────────────────────────────────────────");
@@ -575,13 +835,21 @@ fn main() -> Result<()> {
     println!("  Connect timeout:      {} seconds", cli.connect_timeout);
     println!("  Read/Write timeout:   {} seconds", cli.rw_timeout);
     println!("  Max Retries:          {}", cli.max_retries);
-    println!("  Retry Backoff:        {} ms", cli.retry_backoff_ms);
+    println!("  Retry Backoff:        {} ms (max {} ms)", cli.retry_backoff_ms, cli.max_backoff_ms);
     println!("  Verbose mode:         {}", if cli.verbose { "enabled" } else { "disabled" });
+    println!("  Request mode:         {}", if cli.leader_aware { "leader-aware" } else { "multicast" });
+    println!("  Progress interval:    {} s", cli.metrics_interval);
+    if cli.verify_consistency {
+        println!("  Consistency checks:   every {} request(s) per thread", cli.verify_sample);
+    }
     
     println!("\n🚀 Starting stress test...\n");
     
     // Create statistics tracker
     let stats = Arc::new(TestStatistics::new());
+
+    // Leader address shared by all workers in leader-aware mode
+    let known_leader: Arc<Mutex<Option<String>>> = Arc::new(Mutex::new(None));
     
     // Calculate requests per thread
     let requests_per_thread = cli.num_requests / cli.num_threads;
@@ -601,6 +869,7 @@ fn main() -> Result<()> {
         let meta_clone = meta_bytes.clone();
         let img_clone = img_data.clone();
         let config = cli.clone();
+        let leader_clone = Arc::clone(&known_leader);
         
         let handle = thread::spawn(move || {
             run_worker(
@@ -611,6 +880,7 @@ fn main() -> Result<()> {
                 img_clone,
                 stats_clone,
                 config,
+                leader_clone,
             )
         });
         
@@ -620,19 +890,37 @@ fn main() -> Result<()> {
     // Progress monitoring thread
     let stats_monitor = Arc::clone(&stats);
     let total_requests = cli.num_requests;
+    let metrics_interval = Duration::from_secs(cli.metrics_interval);
     let monitor_handle = thread::spawn(move || {
+        // Response times are appended as requests finish, so everything past
+        // `seen` completed during the last interval
+        let mut seen = 0;
         loop {
-            thread::sleep(Duration::from_secs(2));
+            thread::sleep(metrics_interval);
             let completed = stats_monitor.total_requests.load(Ordering::Relaxed);
             let success = stats_monitor.successful_requests.load(Ordering::Relaxed);
             let retries = stats_monitor.total_retries.load(Ordering::Relaxed);
             let valid = stats_monitor.valid_images.load(Ordering::Relaxed);
             let progress = (completed as f64 / total_requests as f64) * 100.0;
+
+            // Copy only the new tail so workers aren't held up while we sort
+            let mut window = {
+                let times = stats_monitor.response_times.lock().unwrap();
+                let window = times[seen..].to_vec();
+                seen = times.len();
+                window
+            };
+            let latency = if window.is_empty() {
+                "p50/p99: -".to_string()
+            } else {
+                window.sort_unstable();
+                format!("p50: {} ms p99: {} ms", percentile(&window, 50), percentile(&window, 99))
+            };
             
-            print!("\r⏳ Progress: {}/{} ({:.1}%) | ✓ Success: {} | ✗ Failed: {} | 🔄 Retries: {} | ✅ Valid: {}    ",
+            print!("\r⏳ Progress: {}/{} ({:.1}%) | ✓ Success: {} | ✗ Failed: {} | 🔄 Retries: {} | ✅ Valid: {} | ⏱️  {}    ",
                    completed, total_requests, progress, success,
                    stats_monitor.failed_requests.load(Ordering::Relaxed),
-                   retries, valid);
+                   retries, valid, latency);
             std::io::stdout().flush().ok();
             
             if completed >= total_requests {
@@ -654,11 +942,24 @@ fn main() -> Result<()> {
     stats.print_report();
     
     let timestamp = format_timestamp();
-    let report_filename = format!("stress_test_report_{}.txt", timestamp);
-    stats.save_to_file(&report_filename)?;
+    // Keep labels filename-safe; the header gets the label verbatim
+    let label_part = cli.report_label.as_deref()
+        .map(|label| {
+            let safe: String = label.chars()
+                .map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '.' { c } else { '_' })
+                .collect();
+            format!("{}_", safe)
+        })
+        .unwrap_or_default();
+    let compression = cli.report_compress.then(|| Compression::new(cli.report_compression_level));
+    let extension = if compression.is_some() { "txt.gz" } else { "txt" };
+    let report_filename = format!("stress_test_report_{}{}.{}", label_part, timestamp, extension);
+    stats.save_to_file(&report_filename, cli.report_label.as_deref(), compression)?;
     
     // Compare image sizes
-    compare_image_sizes(&cli.input_image)?;
+    if cli.save_samples > 0 {
+        compare_image_sizes(&cli.input_image, &cli.samples_dir)?;
+    }
     
     Ok(())
 }
@@ -667,6 +968,7 @@ fn main() -> Result<()> {
 // WORKER LOGIC WITH RETRY MECHANISM (MODIFIED FOR TRUE MULTICAST)
 // ============================================================================
 
+#[allow(clippy::too_many_arguments)]
 fn run_worker(
     thread_id: usize,
     num_requests: usize,
@@ -675,9 +977,10 @@ fn run_worker(
     img_data: Vec<u8>,
     stats: Arc<TestStatistics>,
     config: Cli,
+    known_leader: Arc<Mutex<Option<String>>>,
 ) {
     let mut samples_saved = 0;
-    let max_samples_per_thread = 3; // Save first 3 successful images per thread
+    let mut pool = ConnectionPool::new(config.keepalive);
     
     for request_id in 0..num_requests {
         let start_time = Instant::now();
@@ -697,20 +1000,34 @@ fn run_worker(
             // *******************************************************************
             // MODIFIED LOGIC: TRUE MULTICAST - Send to all servers and only
             // record the FIRST success received for this request attempt.
+            // In leader-aware mode the only target is the known leader.
             // *******************************************************************
-            for server_addr in &servers {
-                let mut current_server_success = false;
+            let targets = if config.leader_aware {
+                match current_leader(&servers, &known_leader, &config) {
+                    Some(leader) => vec![leader],
+                    None => {
+                        last_error = ErrorType::NoLeader;
+                        if config.verbose {
+                            println!("[Thread-{}] Request #{}: No leader found (attempt {})",
+                                     thread_id, request_id, attempt + 1);
+                        }
+                        vec![]
+                    }
+                }
+            } else {
+                servers.clone()
+            };
 
+            for server_addr in &targets {
                 match send_encryption_request(
                     server_addr,
                     &meta_bytes,
                     &img_data,
                     config.connect_timeout,
                     config.rw_timeout,
+                    &mut pool,
                 ) {
                     Ok((encrypted_data, leader_id)) => {
-                        current_server_success = true; // This server responded successfully
-                        
                         // ONLY record success metrics/samples if we haven't already recorded one
                         if !success_reported { 
                             match validate_encrypted_image(&encrypted_data) {
@@ -720,12 +1037,19 @@ fn run_worker(
                                     stats.record_success(response_time, leader_id.clone(), attempt, image_size, true);
                                     success_reported = true; // Mark as successful response received
 
-                                    // Save sample images for manual verification
-                                    if samples_saved < max_samples_per_thread {
-                                        if let Ok(_) = save_sample_image(&encrypted_data, request_id, thread_id) {
-                                            samples_saved += 1;
+                                    if config.verify_consistency && (request_id as u64).is_multiple_of(config.verify_sample) {
+                                        if let Err(e) = check_consistency(&servers, &meta_bytes, &img_data, &encrypted_data, &config, &stats) {
+                                            println!("\n⚠️  [Thread-{}] Request #{}: consistency check skipped: {}",
+                                                     thread_id, request_id, e);
                                         }
                                     }
+
+                                    // Save sample images for manual verification
+                                    if samples_saved < config.save_samples
+                                        && save_sample_image(&encrypted_data, request_id, thread_id, &config).is_ok()
+                                    {
+                                        samples_saved += 1;
+                                    }
                                     
                                     if config.verbose {
                                         println!("[Thread-{}] Request #{}: SUCCESS from {} - Valid PNG ({:.2}KB) in {}ms (leader: {:?})",
@@ -740,7 +1064,7 @@ fn run_worker(
                                                  thread_id, request_id, server_addr, encrypted_data.len());
                                     }
                                     // If validation fails, it's treated as a potential retryable failure (or just ignored for success counting)
-                                    // last_error remains the last encountered *fatal* error type.
+                                    last_error = ErrorType::InvalidResponse;
                                 }
                                 Err(e) => {
                                     if config.verbose {
@@ -756,44 +1080,43 @@ fn run_worker(
                         }
                     }
                     Err(e) => {
-                        // ... (Error classification remains the same)
-                        let err_msg = e.to_string();
-                        
-                        // Classify the error type
-                        let current_error = if err_msg.contains("NOT_LEADER") {
-                            ErrorType::NotLeader
-                        } else if err_msg.contains("NO_LEADER") {
-                            ErrorType::NoLeader
-                        } else if err_msg.contains("timed out") || err_msg.contains("timeout") {
-                            ErrorType::Timeout
-                        } else if err_msg.contains("Connection refused") || err_msg.contains("connect") {
-                            ErrorType::Connection
-                        } else {
-                            ErrorType::Other
-                        };
-
-                        // Only update last_error if we haven't successfully reported for this request yet
-                        if !success_reported {
+                        // The leader may have changed: rediscover on the next attempt
+                        if config.leader_aware {
+                            forget_leader(&known_leader, server_addr);
+                        }
+
+                        let current_error = e.error_type();
+
+                        // Only update last_error if we haven't successfully reported for this request yet.
+                        // A bad request is the leader's verdict, the followers' NOT_LEADER doesn't replace it
+                        if !success_reported && !matches!(last_error, ErrorType::BadRequest) {
                              last_error = current_error;
                         }
                         
                         if config.verbose {
-                            println!("[Thread-{}] Request #{}: Failed on {} - {:?} (attempt {})",
-                                     thread_id, request_id, server_addr, current_error, attempt + 1);
+                            println!("[Thread-{}] Request #{}: Failed on {} - {:?}: {} (attempt {})",
+                                     thread_id, request_id, server_addr, current_error, e, attempt + 1);
                         }
                     }
                 }
             } // END of Multicast Loop (sends to all 3 servers)
             // *******************************************************************
             
+            // Sending the same bytes again would be refused the same way
+            if !success_reported && matches!(last_error, ErrorType::BadRequest) {
+                attempt += 1;
+                break;
+            }
+
             // If the request was not successful on ANY server in this attempt, wait before retry
             if !success_reported && attempt < config.max_retries {
-                let backoff_time = config.retry_backoff_ms * 2u64.pow(attempt as u32);
+                let backoff_time = capped_backoff(Duration::from_millis(config.retry_backoff_ms), attempt as u32,
+                                                  Duration::from_millis(config.max_backoff_ms));
                 if config.verbose {
                     println!("[Thread-{}] Request #{}: Waiting {}ms before retry",
-                             thread_id, request_id, backoff_time);
+                             thread_id, request_id, backoff_time.as_millis());
                 }
-                thread::sleep(Duration::from_millis(backoff_time));
+                thread::sleep(backoff_time);
             }
             
             attempt += 1;
@@ -814,9 +1137,12 @@ fn run_worker(
         }
     }
     
+    stats.connections_opened.fetch_add(pool.opened, Ordering::Relaxed);
+    stats.connections_reused.fetch_add(pool.reused, Ordering::Relaxed);
+
     if config.verbose || samples_saved > 0 {
-        println!("[Thread-{}] Completed. Saved {} sample images to stress_test_samples/", 
-                 thread_id, samples_saved);
+        println!("[Thread-{}] Completed. Saved {} sample images to {}/", 
+                 thread_id, samples_saved, config.samples_dir.display());
     }
 }
 
@@ -824,19 +1150,73 @@ fn run_worker(
 // HELPER FUNCTIONS
 // ============================================================================
 
-fn load_servers(config_file: &str) -> Result<Vec<String>> {
-    let content = fs::read_to_string(config_file)?;
-    let servers: Vec<String> = content
-        .lines()
-        .filter(|s| !s.trim().is_empty())
-        .map(String::from)
-        .collect();
-    
-    if servers.is_empty() {
-        bail!("No servers found in '{}'", config_file);
+/// A thread's persistent connections, one per server, used with --keepalive.
+/// Without it the pool stays empty and only counts the connections opened.
+struct ConnectionPool {
+    enabled: bool,
+    streams: HashMap<String, TcpStream>,
+    opened: usize,
+    reused: usize,
+}
+
+impl ConnectionPool {
+    fn new(enabled: bool) -> Self {
+        Self {
+            enabled,
+            streams: HashMap::new(),
+            opened: 0,
+            reused: 0,
+        }
+    }
+}
+
+/// Why a request to one server failed. I/O errors are sorted by their kind
+/// rather than their message, which differs between platforms and locales.
+#[derive(Debug)]
+enum RequestError {
+    Connection(std::io::Error), // no connection, or the server reset it
+    Timeout(std::io::Error),    // connect, read or write took longer than allowed
+    Protocol(String),           // the reply ended early or wasn't what the protocol allows
+    Io(std::io::Error),         // any other I/O failure
+    Refused(String),            // the server answered with a text reply (NOT_LEADER, BAD_IMAGE...)
+}
+
+impl RequestError {
+    fn error_type(&self) -> ErrorType {
+        match self {
+            RequestError::Connection(_) => ErrorType::Connection,
+            RequestError::Timeout(_) => ErrorType::Timeout,
+            RequestError::Protocol(_) => ErrorType::Protocol,
+            RequestError::Io(_) => ErrorType::Io,
+            RequestError::Refused(reply) if BadRequest::from_reply(reply).is_some() => ErrorType::BadRequest,
+            RequestError::Refused(reply) if reply.starts_with("NOT_LEADER") => ErrorType::NotLeader,
+            RequestError::Refused(reply) if reply.starts_with("NO_LEADER") => ErrorType::NoLeader,
+            RequestError::Refused(_) => ErrorType::Other,
+        }
+    }
+}
+
+impl From<std::io::Error> for RequestError {
+    fn from(e: std::io::Error) -> Self {
+        use std::io::ErrorKind;
+        match e.kind() {
+            // A socket read timeout shows up as WouldBlock on Unix
+            ErrorKind::TimedOut | ErrorKind::WouldBlock => RequestError::Timeout(e),
+            ErrorKind::ConnectionRefused | ErrorKind::ConnectionReset | ErrorKind::ConnectionAborted
+            | ErrorKind::NotConnected | ErrorKind::BrokenPipe | ErrorKind::AddrNotAvailable => RequestError::Connection(e),
+            ErrorKind::UnexpectedEof => RequestError::Protocol(format!("reply cut short: {}", e)),
+            _ => RequestError::Io(e),
+        }
+    }
+}
+
+impl std::fmt::Display for RequestError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            RequestError::Connection(e) | RequestError::Timeout(e) | RequestError::Io(e) => write!(f, "{}", e),
+            RequestError::Protocol(msg) | RequestError::Refused(msg) => write!(f, "{}", msg),
+        }
     }
-    
-    Ok(servers)
 }
 
 fn send_encryption_request(
@@ -845,16 +1225,59 @@ fn send_encryption_request(
     img_buf: &[u8],
     connect_timeout_sec: u64,
     rw_timeout_sec: u64,
-) -> Result<(Vec<u8>, Option<String>)> {
+    pool: &mut ConnectionPool,
+) -> Result<(Vec<u8>, Option<String>), RequestError> {
+    // A pooled connection the server closed while idle fails the write or
+    // ends before any reply; only then is the request resent on a fresh
+    // connection. Past that the server may have acted on it, so any failure
+    // is this request's own
+    if let Some(mut stream) = pool.streams.remove(addr) {
+        if write_request(&mut stream, meta_bytes, img_buf).is_ok() && !closed_before_reply(&stream)? {
+            pool.reused += 1;
+            let (response_buf, reusable) = read_reply(&mut stream, true)?;
+            if reusable {
+                pool.streams.insert(addr.to_string(), stream);
+            }
+            return check_response(response_buf);
+        }
+    }
+
     // Connect with timeout
-    let mut stream = TcpStream::connect_timeout(
-        &addr.parse()?,
-        Duration::from_secs(connect_timeout_sec),
-    )?;
+    let socket_addr = addr.parse().map_err(|e| {
+        RequestError::Connection(std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("'{}': {}", addr, e)))
+    })?;
+    let mut stream = TcpStream::connect_timeout(&socket_addr, Duration::from_secs(connect_timeout_sec))?;
+    pool.opened += 1;
     
     stream.set_read_timeout(Some(Duration::from_secs(rw_timeout_sec)))?;
     stream.set_write_timeout(Some(Duration::from_secs(rw_timeout_sec)))?;
-    
+    // A rejected version comes back as a plain error, I/O failures keep their kind
+    negotiate_protocol(&mut stream).map_err(|e| match e.downcast::<std::io::Error>() {
+        Ok(io_error) => RequestError::from(io_error),
+        Err(e) => RequestError::Protocol(format!("{:#}", e)),
+    })?;
+
+    let (response_buf, reusable) = exchange_request(&mut stream, meta_bytes, img_buf, pool.enabled)?;
+    if pool.enabled && reusable {
+        pool.streams.insert(addr.to_string(), stream);
+    }
+    check_response(response_buf)
+}
+
+/// Send one request and read the reply. With `keepalive`, an image reply's
+/// committed-index trailer is consumed too, and the returned flag says whether
+/// the server left the connection usable for another request.
+fn exchange_request(
+    stream: &mut TcpStream,
+    meta_bytes: &[u8],
+    img_buf: &[u8],
+    keepalive: bool,
+) -> Result<(Vec<u8>, bool), RequestError> {
+    write_request(stream, meta_bytes, img_buf)?;
+    read_reply(stream, keepalive)
+}
+
+fn write_request(stream: &mut TcpStream, meta_bytes: &[u8], img_buf: &[u8]) -> std::io::Result<()> {
     // Send metadata
     let meta_size = meta_bytes.len() as u64;
     stream.write_all(&meta_size.to_be_bytes())?;
@@ -864,25 +1287,48 @@ fn send_encryption_request(
     let img_size = img_buf.len() as u64;
     stream.write_all(&img_size.to_be_bytes())?;
     stream.write_all(img_buf)?;
-    stream.flush()?;
-    
+    stream.flush()
+}
+
+/// Wait for the reply to start. True if the server closed the connection
+/// without sending any of it, as it does to a keepalive connection left idle.
+fn closed_before_reply(stream: &TcpStream) -> Result<bool, RequestError> {
+    use std::io::ErrorKind;
+    match stream.peek(&mut [0u8; 1]) {
+        Ok(0) => Ok(true),
+        Ok(_) => Ok(false),
+        Err(e) if matches!(e.kind(), ErrorKind::ConnectionReset | ErrorKind::ConnectionAborted) => Ok(true),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Read the reply to a request, see `exchange_request`
+fn read_reply(stream: &mut TcpStream, keepalive: bool) -> Result<(Vec<u8>, bool), RequestError> {
     // Receive response size
     let mut size_bytes = [0u8; 8];
     stream.read_exact(&mut size_bytes)?;
     let response_size = u64::from_be_bytes(size_bytes);
+    if response_size > MAX_INFLATED_FRAME {
+        return Err(RequestError::Protocol(format!("reply claims to be {} bytes", response_size)));
+    }
     
     // Read response
     let mut response_buf = vec![0; response_size as usize];
     stream.read_exact(&mut response_buf)?;
-    
+
+    // Text replies (NOT_LEADER etc.) always end the connection; a PNG is never valid UTF-8
+    let is_image = std::str::from_utf8(&response_buf).is_err();
+    let reusable = keepalive && is_image && stream.read_exact(&mut [0u8; 8]).is_ok();
+    Ok((response_buf, reusable))
+}
+
+fn check_response(response_buf: Vec<u8>) -> Result<(Vec<u8>, Option<String>), RequestError> {
     // Check for error messages
     if let Ok(msg) = std::str::from_utf8(&response_buf) {
-        if msg.starts_with("NOT_LEADER:") {
-            let leader = msg.strip_prefix("NOT_LEADER:").map(String::from);
-            bail!("NOT_LEADER:{}", leader.unwrap_or_else(|| "unknown".to_string()));
-        }
-        if msg.starts_with("NO_LEADER") {
-            bail!("NO_LEADER");
+        if msg.starts_with("NOT_LEADER") || msg.starts_with("NO_LEADER") || msg.starts_with("NOT_COMMITTED")
+            || msg.starts_with("UNAVAILABLE") || BadRequest::from_reply(msg).is_some()
+        {
+            return Err(RequestError::Refused(msg.to_string()));
         }
     }
     
@@ -892,7 +1338,44 @@ fn send_encryption_request(
     Ok((response_buf, leader_id))
 }
 
-fn compare_image_sizes(original_path: &PathBuf) -> Result<()> {
+/// Ask one server's work receiver to encrypt the request itself, bypassing
+/// the leader. Only the load-balancing server runs a work receiver.
+fn send_forwarded_work(
+    addr: &str,
+    meta_bytes: &[u8],
+    img_buf: &[u8],
+    connect_timeout_sec: u64,
+    rw_timeout_sec: u64,
+) -> Result<Vec<u8>> {
+    let mut work_addr: std::net::SocketAddr = addr.parse()?;
+    work_addr.set_port(work_addr.port().checked_add(WORK_PORT_OFFSET).ok_or_else(|| anyhow::anyhow!("Port too high for a work receiver"))?);
+
+    let mut stream = TcpStream::connect_timeout(&work_addr, Duration::from_secs(connect_timeout_sec))?;
+    stream.set_read_timeout(Some(Duration::from_secs(rw_timeout_sec)))?;
+    stream.set_write_timeout(Some(Duration::from_secs(rw_timeout_sec)))?;
+
+    let message = LoadBalancingMessage::ForwardWork {
+        metadata: meta_bytes.to_vec(),
+        image_data: img_buf.to_vec(),
+        unified_image: None,
+    };
+    let json = serde_json::to_vec(&message)?;
+    stream.write_all(&(json.len() as u32).to_be_bytes())?;
+    stream.write_all(&json)?;
+    stream.flush()?;
+
+    let mut size_bytes = [0u8; 4];
+    stream.read_exact(&mut size_bytes)?;
+    let mut response_buf = vec![0; u32::from_be_bytes(size_bytes) as usize];
+    stream.read_exact(&mut response_buf)?;
+
+    match serde_json::from_slice(&response_buf)? {
+        LoadBalancingMessage::WorkResult { encrypted_image } => Ok(encrypted_image),
+        _ => bail!("Unexpected response type from work receiver {}", work_addr),
+    }
+}
+
+fn compare_image_sizes(original_path: &PathBuf, samples_dir: &Path) -> Result<()> {
     let original_size = fs::metadata(original_path)?.len();
     
     println!("\n🔍 IMAGE SIZE COMPARISON");
@@ -900,13 +1383,13 @@ fn compare_image_sizes(original_path: &PathBuf) -> Result<()> {
     println!("  Original image size:  {:.2} KB", original_size as f64 / 1024.0);
     
     // Check sample encrypted images
-    let samples_dir = "stress_test_samples";
     if let Ok(entries) = fs::read_dir(samples_dir) {
         let mut total_encrypted = 0u64;
         let mut count = 0;
         let mut sizes = Vec::new();
         
-        for entry in entries.flatten() {
+        // Skip payload dumps written alongside the images
+        for entry in entries.flatten().filter(|e| e.path().extension().is_some_and(|ext| ext == "png")) {
             if let Ok(metadata) = entry.metadata() {
                 let size = metadata.len();
                 total_encrypted += size;
@@ -915,8 +1398,7 @@ fn compare_image_sizes(original_path: &PathBuf) -> Result<()> {
             }
         }
         
-        if count > 0 {
-            let avg_encrypted = total_encrypted / count;
+        if let Some(avg_encrypted) = total_encrypted.checked_div(count) {
             sizes.sort_unstable();
             
             println!("  Encrypted samples:    {} files", count);
@@ -925,7 +1407,7 @@ fn compare_image_sizes(original_path: &PathBuf) -> Result<()> {
             println!("  Max encrypted size:   {:.2} KB", sizes[sizes.len() - 1] as f64 / 1024.0);
             println!("  Size increase:        {:.1}%", 
                      ((avg_encrypted as f64 - original_size as f64) / original_size as f64) * 100.0);
-            println!("\n  📁 Sample images saved in: {}/", samples_dir);
+            println!("\n  📁 Sample images saved in: {}/", samples_dir.display());
         } else {
             println!("  No sample images found");
         }
@@ -963,4 +1445,107 @@ fn format_timestamp() -> String {
             hours,
             minutes,
             seconds)
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::ErrorKind;
+    use std::net::TcpListener;
+
+    /// A server that reads one request and answers it with `reply`, raw
+    fn reply_once(reply: Vec<u8>) -> TcpStream {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            for _ in 0..2 {
+                let mut len = [0u8; 8];
+                stream.read_exact(&mut len).unwrap();
+                let mut body = vec![0u8; u64::from_be_bytes(len) as usize];
+                stream.read_exact(&mut body).unwrap();
+            }
+            stream.write_all(&reply).unwrap();
+        });
+        TcpStream::connect(addr).unwrap()
+    }
+
+    /// A length-prefixed reply frame
+    fn frame(body: &[u8]) -> Vec<u8> {
+        [&(body.len() as u64).to_be_bytes()[..], body].concat()
+    }
+
+    #[test]
+    fn io_errors_are_classified_by_kind() {
+        let kind = |kind: ErrorKind| RequestError::from(std::io::Error::new(kind, "whatever the OS says")).error_type();
+        assert!(matches!(kind(ErrorKind::TimedOut), ErrorType::Timeout));
+        assert!(matches!(kind(ErrorKind::WouldBlock), ErrorType::Timeout));
+        for connection in [ErrorKind::ConnectionRefused, ErrorKind::ConnectionReset, ErrorKind::BrokenPipe] {
+            assert!(matches!(kind(connection), ErrorType::Connection));
+        }
+        assert!(matches!(kind(ErrorKind::UnexpectedEof), ErrorType::Protocol));
+        assert!(matches!(kind(ErrorKind::PermissionDenied), ErrorType::Io));
+
+        let refused = |reply: &str| RequestError::Refused(reply.to_string()).error_type();
+        assert!(matches!(refused("NOT_LEADER n2"), ErrorType::NotLeader));
+        assert!(matches!(refused("NO_LEADER"), ErrorType::NoLeader));
+        assert!(matches!(refused("NOT_COMMITTED"), ErrorType::Other));
+    }
+
+    #[test]
+    fn exchange_classifies_replies() {
+        let exchange = |reply: Vec<u8>| {
+            let mut stream = reply_once(reply);
+            exchange_request(&mut stream, b"meta", b"image", false).and_then(|(buf, _)| check_response(buf))
+        };
+
+        // Anything that isn't UTF-8 is taken for the encrypted image
+        let (image, _) = exchange(frame(b"\x89PNG\r\n")).unwrap();
+        assert_eq!(image, b"\x89PNG\r\n");
+        assert!(matches!(exchange(frame(b"NOT_LEADER n2")).unwrap_err().error_type(), ErrorType::NotLeader));
+        let cut_short = exchange(frame(b"\x89PNG\r\n")[..10].to_vec()).unwrap_err();
+        assert!(matches!(cut_short.error_type(), ErrorType::Protocol), "{}", cut_short);
+        let oversized = exchange((MAX_INFLATED_FRAME + 1).to_be_bytes().to_vec()).unwrap_err();
+        assert!(matches!(oversized.error_type(), ErrorType::Protocol), "{}", oversized);
+
+        // Nothing listens on a port whose listener was dropped
+        let closed = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().to_string();
+        let refused = send_encryption_request(&closed, b"meta", b"image", 1, 1, &mut ConnectionPool::new(false)).unwrap_err();
+        assert!(matches!(refused.error_type(), ErrorType::Connection), "{}", refused);
+    }
+
+    #[test]
+    fn pooled_connection_falls_back_only_if_closed_before_any_reply() {
+        // Nothing listens here, so a fallback to a fresh connection shows up as refused
+        let closed = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().to_string();
+        let send = |stream: TcpStream| {
+            let mut pool = ConnectionPool::new(true);
+            pool.streams.insert(closed.clone(), stream);
+            let result = send_encryption_request(&closed, b"meta", b"image", 1, 1, &mut pool);
+            (result, pool.reused)
+        };
+
+        // Closed while idle: resent on a fresh connection
+        let (result, reused) = send(reply_once(Vec::new()));
+        let refused = result.unwrap_err();
+        assert!(matches!(refused.error_type(), ErrorType::Connection), "{}", refused);
+        assert_eq!(reused, 0);
+
+        // The reply started: whatever goes wrong after that is not resent
+        let (result, reused) = send(reply_once(frame(b"\x89PNG\r\n")[..10].to_vec()));
+        let cut_short = result.unwrap_err();
+        assert!(matches!(cut_short.error_type(), ErrorType::Protocol), "{}", cut_short);
+        assert_eq!(reused, 1);
+        let (result, _) = send(reply_once(frame(b"NOT_COMMITTED: lost quorum")));
+        assert!(matches!(result.unwrap_err(), RequestError::Refused(reply) if reply.starts_with("NOT_COMMITTED")));
+
+        // Still no reply when the read times out: the server may be working on it
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let _held = listener.accept().unwrap();
+        stream.set_read_timeout(Some(Duration::from_millis(100))).unwrap();
+        let (result, _) = send(stream);
+        let timed_out = result.unwrap_err();
+        assert!(matches!(timed_out.error_type(), ErrorType::Timeout), "{}", timed_out);
+    }
+}
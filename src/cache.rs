@@ -0,0 +1,339 @@
+//! Content-addressable caches.
+//!
+//! Two independent requests that carry the same image, the same permissions and
+//! are embedded with the same unified image produce the same output, so the
+//! server can skip the LSB embedding and return the stored PNG instead.
+//!
+//! Likewise a long-running client that decodes the same protected file more
+//! than once (inspect, then view) can keep the decoded payload.
+
+use crate::{decode_payload, CombinedPayload, ImagePermissions};
+use anyhow::{Context, Result};
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, VecDeque};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+pub type CacheKey = [u8; 32];
+
+/// Bounded LRU cache of encrypted PNGs keyed by a hash of the request
+pub struct EncryptionCache {
+    capacity: usize,
+    inner: Mutex<CacheInner>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+struct CacheInner {
+    entries: HashMap<CacheKey, Vec<u8>>,
+    order: VecDeque<CacheKey>, // least recently used at the front
+}
+
+impl EncryptionCache {
+    /// Create a cache holding at most `capacity` results (0 disables caching)
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            inner: Mutex::new(CacheInner {
+                entries: HashMap::new(),
+                order: VecDeque::new(),
+            }),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    /// Hash everything that determines the encrypted output. Permissions
+    /// are hashed in canonical form so HashMap iteration order doesn't matter.
+    pub fn key(image_bytes: &[u8], permissions: &ImagePermissions, unified_image: &[u8]) -> Result<CacheKey> {
+        let mut hasher = Sha256::new();
+
+        // Length-prefix every field so adjacent fields can't be confused
+        hasher.update((image_bytes.len() as u64).to_be_bytes());
+        hasher.update(image_bytes);
+        let permissions = permissions.canonical_bytes()?;
+        hasher.update((permissions.len() as u64).to_be_bytes());
+        hasher.update(&permissions);
+        hasher.update((unified_image.len() as u64).to_be_bytes());
+        hasher.update(unified_image);
+        Ok(hasher.finalize().into())
+    }
+
+    /// Look up a cached result, counting the hit or miss
+    pub fn get(&self, key: &CacheKey) -> Option<Vec<u8>> {
+        if self.capacity == 0 {
+            return None;
+        }
+
+        let mut inner = self.inner.lock().unwrap();
+        match inner.entries.get(key).cloned() {
+            Some(result) => {
+                // Move to the back so it's evicted last
+                inner.order.retain(|k| k != key);
+                inner.order.push_back(*key);
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                Some(result)
+            }
+            None => {
+                self.misses.fetch_add(1, Ordering::Relaxed);
+                None
+            }
+        }
+    }
+
+    /// Store a result, evicting the least recently used entry if full
+    pub fn insert(&self, key: CacheKey, result: Vec<u8>) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        let mut inner = self.inner.lock().unwrap();
+        if inner.entries.insert(key, result).is_some() {
+            inner.order.retain(|k| k != &key);
+        }
+        inner.order.push_back(key);
+
+        while inner.order.len() > self.capacity {
+            if let Some(oldest) = inner.order.pop_front() {
+                inner.entries.remove(&oldest);
+            }
+        }
+    }
+
+    pub fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    pub fn misses(&self) -> u64 {
+        self.misses.load(Ordering::Relaxed)
+    }
+}
+
+/// Bounded LRU of payloads decoded from protected images, keyed by a hash of
+/// the image file's bytes so an edited file is never served a stale payload.
+/// For embedding the client in a long-running process; the CLI decodes each
+/// file once and doesn't use it.
+pub struct DecodeCache {
+    capacity: usize,
+    inner: Mutex<DecodeCacheInner>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+struct DecodeCacheInner {
+    entries: HashMap<CacheKey, CombinedPayload>,
+    order: VecDeque<CacheKey>, // least recently used at the front
+    files: HashMap<PathBuf, CacheKey>, // hash each cached file had when last decoded
+}
+
+impl DecodeCacheInner {
+    /// Forget a payload, and the files it was decoded from
+    fn forget(&mut self, key: &CacheKey) {
+        if self.entries.remove(key).is_some() {
+            self.order.retain(|k| k != key);
+        }
+        self.files.retain(|_, k| k != key);
+    }
+}
+
+impl DecodeCache {
+    /// Create a cache holding at most `capacity` payloads (0 disables caching)
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            inner: Mutex::new(DecodeCacheInner {
+                entries: HashMap::new(),
+                order: VecDeque::new(),
+                files: HashMap::new(),
+            }),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    /// Decode the payload of a protected image file. If the file changed
+    /// since it was last decoded, the old payload is dropped.
+    pub fn decode_file(&self, path: &Path) -> Result<CombinedPayload> {
+        let image_bytes = fs::read(path).with_context(|| format!("Cannot read '{}'", path.display()))?;
+        let key: CacheKey = Sha256::digest(&image_bytes).into();
+
+        if self.capacity > 0 {
+            let mut inner = self.inner.lock().unwrap();
+            if let Some(stale) = inner.files.get(path).copied().filter(|previous| *previous != key) {
+                inner.forget(&stale);
+            }
+        }
+        let payload = self.decode_keyed(key, &image_bytes)?;
+        if self.capacity > 0 {
+            let mut inner = self.inner.lock().unwrap();
+            // Only while the payload is cached, so the map can't outgrow it
+            if inner.entries.contains_key(&key) {
+                inner.files.insert(path.to_path_buf(), key);
+            }
+        }
+        Ok(payload)
+    }
+
+    /// Decode the payload of a protected image held in memory
+    pub fn decode(&self, image_bytes: &[u8]) -> Result<CombinedPayload> {
+        self.decode_keyed(Sha256::digest(image_bytes).into(), image_bytes)
+    }
+
+    fn decode_keyed(&self, key: CacheKey, image_bytes: &[u8]) -> Result<CombinedPayload> {
+        if self.capacity > 0 {
+            let mut inner = self.inner.lock().unwrap();
+            if let Some(payload) = inner.entries.get(&key).cloned() {
+                inner.order.retain(|k| k != &key);
+                inner.order.push_back(key);
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                return Ok(payload);
+            }
+        }
+        self.misses.fetch_add(1, Ordering::Relaxed);
+
+        // Decode without holding the lock, it materializes every pixel. Any
+        // layout the client reads is accepted, not only the plain one
+        let img = image::load_from_memory(image_bytes)?;
+        let (payload, _) = decode_payload(&img)?;
+        if self.capacity == 0 {
+            return Ok(payload);
+        }
+
+        let mut inner = self.inner.lock().unwrap();
+        if inner.entries.insert(key, payload.clone()).is_some() {
+            inner.order.retain(|k| k != &key);
+        }
+        inner.order.push_back(key);
+        while inner.order.len() > self.capacity {
+            if let Some(oldest) = inner.order.front().copied() {
+                inner.forget(&oldest);
+            }
+        }
+        Ok(payload)
+    }
+
+    pub fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    pub fn misses(&self) -> u64 {
+        self.misses.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lsb;
+
+    fn permissions() -> ImagePermissions {
+        ImagePermissions {
+            owner: "alice".to_string(),
+            quotas: HashMap::from([("bob".to_string(), 3), ("carol".to_string(), 1)]),
+            note: None,
+            version: 1,
+            view_cooldown_secs: None,
+            last_views: HashMap::new(),
+            signature: None,
+            tokens: HashMap::new(),
+        }
+    }
+
+    #[test]
+    fn key_depends_on_tokens() {
+        let mut first = permissions();
+        first.tokens.insert(crate::hash_token("one"), None);
+        let mut second = permissions();
+        second.tokens.insert(crate::hash_token("two"), None);
+        let mut expiring = permissions();
+        expiring.tokens.insert(crate::hash_token("one"), Some(1_900_000_000));
+
+        let key = |permissions: &ImagePermissions| EncryptionCache::key(b"image", permissions, b"unified").unwrap();
+        assert_ne!(key(&first), key(&second));
+        assert_ne!(key(&first), key(&expiring));
+        assert_ne!(key(&first), key(&permissions()));
+    }
+
+    #[test]
+    fn key_ignores_map_order() {
+        let mut reordered = permissions();
+        reordered.quotas = HashMap::new();
+        reordered.quotas.insert("carol".to_string(), 1);
+        reordered.quotas.insert("bob".to_string(), 3);
+
+        assert_eq!(
+            EncryptionCache::key(b"image", &permissions(), b"unified").unwrap(),
+            EncryptionCache::key(b"image", &reordered, b"unified").unwrap()
+        );
+    }
+
+    /// Write a PNG protected with `permissions` to `path`
+    fn protect(path: &Path, permissions: ImagePermissions) {
+        let carrier = image::DynamicImage::ImageRgb8(image::RgbImage::from_pixel(64, 64, image::Rgb([90, 120, 150])));
+        let payload = CombinedPayload { permissions, unified_image: vec![7; 16] };
+        lsb::encode(&carrier, &payload.to_bytes().unwrap()).unwrap().save(path).unwrap();
+    }
+
+    #[test]
+    fn decode_file_drops_the_payload_of_an_edited_file() {
+        let dir = std::env::temp_dir().join(format!("cache-test-decode-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("protected.png");
+        protect(&path, permissions());
+
+        let cache = DecodeCache::new(4);
+        assert_eq!(cache.decode_file(&path).unwrap().permissions.quotas["bob"], 3);
+        assert_eq!(cache.decode_file(&path).unwrap().permissions.quotas["bob"], 3);
+        assert_eq!((cache.hits(), cache.misses()), (1, 1));
+
+        // A view spends one of bob's views and rewrites the file
+        let mut viewed = permissions();
+        viewed.quotas.insert("bob".to_string(), 2);
+        viewed.version += 1;
+        protect(&path, viewed);
+        assert_eq!(cache.decode_file(&path).unwrap().permissions.quotas["bob"], 2);
+        assert_eq!((cache.hits(), cache.misses()), (1, 2));
+        assert_eq!(cache.inner.lock().unwrap().entries.len(), 1, "the stale payload is dropped");
+
+        // The same bytes in memory are the entry just decoded
+        assert_eq!(cache.decode(&fs::read(&path).unwrap()).unwrap().permissions.version, 2);
+        assert_eq!((cache.hits(), cache.misses()), (2, 2));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn decode_file_reads_checksummed_layouts_and_forgets_evicted_files() {
+        let dir = std::env::temp_dir().join(format!("cache-test-evict-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let (first, second) = (dir.join("first.png"), dir.join("second.png"));
+        protect(&first, permissions());
+        // Written with redundant copies, as `encrypt --copies 2` does
+        let carrier = image::DynamicImage::ImageRgb8(image::RgbImage::from_pixel(64, 64, image::Rgb([90, 120, 150])));
+        let payload = CombinedPayload { permissions: permissions(), unified_image: vec![7; 16] };
+        lsb::encode_layout(&carrier, &payload.to_bytes().unwrap(), lsb::Layout::Redundant(2))
+            .unwrap()
+            .save(&second)
+            .unwrap();
+
+        let cache = DecodeCache::new(1);
+        cache.decode_file(&first).unwrap();
+        assert_eq!(cache.decode_file(&second).unwrap().permissions.owner, "alice");
+        {
+            let inner = cache.inner.lock().unwrap();
+            assert_eq!(inner.entries.len(), 1);
+            assert_eq!(inner.files.keys().collect::<Vec<_>>(), vec![&second], "the evicted file is forgotten");
+        }
+
+        // Nothing is kept at all with caching disabled
+        let disabled = DecodeCache::new(0);
+        disabled.decode_file(&first).unwrap();
+        assert!(disabled.inner.lock().unwrap().files.is_empty());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
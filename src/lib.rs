@@ -1,30 +1,1174 @@
+use anyhow::{bail, Context, Result};
+use image::codecs::png::{CompressionType, FilterType as PngFilterType, PngEncoder};
+use image::imageops::FilterType;
+use image::{GenericImageView, ImageOutputFormat};
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
-use std::time::SystemTime;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io::{Cursor, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs, UdpSocket};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, SystemTime};
 
 // This line makes our custom lsb.rs file available as a module.
+pub mod cache;
 pub mod lsb;
 pub mod raft;
+pub mod server;
 
 /// The address the server will listen on.
 pub const ADDR: &str = "10.40.7.1:8080";
 
+/// Raft (and the status endpoint) runs on the application port + this offset.
+pub const RAFT_PORT_OFFSET: u16 = 1000;
+
+/// First byte of a Raft or status connection to a server started with
+/// --single-port, which serves them on its application port.
+pub const MUX_RAFT: u8 = b'R';
+
+/// First byte of a client connection to a server started with --single-port.
+/// Optional: a connection starting with anything but MUX_RAFT is a client.
+pub const MUX_CLIENT: u8 = b'C';
+
+/// Client protocol version. A versioned client opens each connection (after
+/// MUX_CLIENT, if it sends one) with this byte, and the server answers with
+/// the same byte, or with VERSION_REJECTED followed by an UNSUPPORTED_VERSION:
+/// text frame. Clients from before versioning open with a u64 length or
+/// marker, so 0x00 or 0xFF, and are served as before; versions therefore stay
+/// clear of those bytes and of MUX_CLIENT/MUX_RAFT.
+pub const PROTOCOL_VERSION: u8 = 1;
+
+/// Server's answer to a client protocol version it doesn't speak.
+pub const VERSION_REJECTED: u8 = 0;
+
+/// How long a client waits for the server's version answer. A server from
+/// before versioning takes the byte as the start of a length and never answers.
+pub const VERSION_ANSWER_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Sent in place of the metadata length to start a batch request: a u32 count
+/// follows, then that many (metadata, image) pairs framed as in a single request.
+pub const BATCH_MARKER: u64 = u64::MAX;
+
+/// Most images a single batch request may carry.
+pub const MAX_BATCH_SIZE: u32 = 64;
+
+/// Per-item status byte in a batch reply: an encrypted image and its committed index follow.
+pub const BATCH_ITEM_OK: u8 = 0;
+
+/// Per-item status byte in a batch reply: an error message follows.
+pub const BATCH_ITEM_FAILED: u8 = 1;
+
+/// Sent in place of the metadata length to start a request whose metadata and
+/// image frames each begin with a FRAME_RAW/FRAME_GZIP flag byte. The client
+/// is also saying it accepts a gzipped reply: a "GZIP:<inflated length>" text
+/// frame followed by the compressed image frame.
+pub const COMPRESSED_MARKER: u64 = u64::MAX - 1;
+
+/// Sent in place of the metadata length to supply the request's own unified
+/// (access denied) PNG, embedded instead of the server's unified_image.png: a
+/// u64-length-prefixed PNG follows, then the request continues from its
+/// metadata length (or COMPRESSED_MARKER) as usual. Not valid with a batch.
+pub const UNIFIED_OVERRIDE_MARKER: u64 = u64::MAX - 2;
+
+/// Largest unified image a client may supply with UNIFIED_OVERRIDE_MARKER.
+pub const MAX_UNIFIED_OVERRIDE: u64 = 16 * 1024 * 1024;
+
+/// Frame flag: the bytes that follow are sent as-is.
+pub const FRAME_RAW: u8 = 0;
+
+/// Frame flag: the bytes that follow are gzip-compressed.
+pub const FRAME_GZIP: u8 = 1;
+
+/// Largest size a gzip frame may inflate to, so a small frame can't exhaust memory.
+pub const MAX_INFLATED_FRAME: u64 = 512 * 1024 * 1024;
+
+// --- SERVER LIST FILES ---
+
+/// Parses a server list: one `host:port` per line, blank lines and `#` comments ignored.
+pub fn parse_server_list(content: &str) -> Vec<String> {
+    content
+        .lines()
+        .map(|line| line.split('#').next().unwrap_or("").trim())
+        .filter(|line| !line.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+/// Reads a server list file such as `servers.conf`, failing if it has no entries.
+pub fn load_server_list(path: &str) -> Result<Vec<String>> {
+    let content = fs::read_to_string(path).with_context(|| format!("Failed to read '{}'", path))?;
+    let servers = parse_server_list(&content);
+    if servers.is_empty() {
+        bail!("No servers found in '{}'", path);
+    }
+    Ok(servers)
+}
+
+/// True if `addr` names this machine on `port`, so a shared server list
+/// can be used as a peer list without the node peering with itself.
+pub fn is_self_address(addr: &str, port: u16) -> bool {
+    match addr.to_socket_addrs() {
+        // Binding succeeds only for addresses assigned to a local interface
+        Ok(resolved) => resolved
+            .into_iter()
+            .any(|a| a.port() == port && UdpSocket::bind((a.ip(), 0)).is_ok()),
+        Err(_) => false,
+    }
+}
+
+/// Best guess at the address clients on other machines can reach us on: the
+/// local IP the OS would use to talk to the first peer, with our app port.
+pub fn guess_advertised_address(peers: &[String], port: u16) -> Option<String> {
+    let peer = peers.first()?.to_socket_addrs().ok()?.next()?;
+    let socket = UdpSocket::bind((std::net::Ipv4Addr::UNSPECIFIED, 0)).ok()?;
+    socket.connect(peer).ok()?; // no packets are sent for UDP connect
+    Some(format!("{}:{}", socket.local_addr().ok()?.ip(), port))
+}
+
+/// Longest note (in bytes) that can be attached to an image, so a note
+/// can't eat an unexpected share of the LSB capacity.
+pub const MAX_NOTE_LEN: usize = 256;
+
+/// Most one-time view tokens an image can carry, for the same reason
+pub const MAX_VIEW_TOKENS: usize = 64;
+
+// --- TRANSPORT COMPRESSION ---
+
+/// Gzip `data`, returning None if that wouldn't make it smaller
+/// (PNGs often don't shrink, so most images are sent raw).
+pub fn gzip_if_smaller(data: &[u8]) -> Option<Vec<u8>> {
+    let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+    encoder.write_all(data).ok()?;
+    let compressed = encoder.finish().ok()?;
+    (compressed.len() < data.len()).then_some(compressed)
+}
+
+/// Inflate a gzip frame, failing if it would grow past MAX_INFLATED_FRAME
+pub fn gunzip_frame(data: &[u8]) -> Result<Vec<u8>> {
+    gunzip_within(data, MAX_INFLATED_FRAME)
+}
+
+fn gunzip_within(data: &[u8], limit: u64) -> Result<Vec<u8>> {
+    let mut inflated = Vec::new();
+    flate2::read::GzDecoder::new(data)
+        .take(limit + 1)
+        .read_to_end(&mut inflated)
+        .context("Corrupt gzip frame")?;
+    if inflated.len() as u64 > limit {
+        bail!("Gzip frame inflates past {} bytes", limit);
+    }
+    Ok(inflated)
+}
+
+// --- RETRIES ---
+
+/// Wait before retry number `retry` (0 for the first retry): `base` doubled
+/// per retry, never more than `cap`, however many retries are configured
+pub fn capped_backoff(base: Duration, retry: u32, cap: Duration) -> Duration {
+    if base.is_zero() {
+        return base; // nothing to double, even once 2^retry overflows
+    }
+    2u32.checked_pow(retry)
+        .and_then(|factor| base.checked_mul(factor))
+        .map_or(cap, |delay| delay.min(cap))
+}
+
+// --- LOGGING ---
+
+/// Set up the `log` facade for a server. With `json`, each event is written
+/// as one JSON object per line (ts, level, target, server_id, term, msg) for
+/// log aggregators; otherwise as env_logger's usual text. RUST_LOG filters both.
+pub fn init_logging(json: bool, server_id: &str) {
+    let mut builder = env_logger::Builder::from_env(env_logger::Env::default());
+    if json {
+        let server_id = server_id.to_string();
+        builder.format(move |buf, record| {
+            let event = serde_json::json!({
+                "ts": buf.timestamp_millis().to_string(),
+                "level": record.level().as_str(),
+                "target": record.target(),
+                "server_id": server_id,
+                "term": raft::current_term_for_logs(),
+                "msg": record.args().to_string(),
+            });
+            writeln!(buf, "{}", event)
+        });
+    }
+    builder.init();
+}
+
+// --- STATUS QUERIES ---
+
+static SINGLE_PORT: AtomicBool = AtomicBool::new(false);
+
+/// Talk to servers started with --single-port: status queries go to the
+/// application port behind a MUX_RAFT byte, and Raft addresses are the
+/// application addresses. Set once at startup, before any query.
+pub fn set_single_port(enabled: bool) {
+    SINGLE_PORT.store(enabled, Ordering::Relaxed);
+}
+
+/// Whether `set_single_port` was turned on
+pub fn single_port() -> bool {
+    SINGLE_PORT.load(Ordering::Relaxed)
+}
+
+/// Split `host:port`, accepting hostnames, IPv4 and bracketed IPv6 (`[::1]:9080`).
+/// Errors name the offending address so they can be shown as-is.
+pub fn split_host_port(addr: &str) -> Result<(&str, u16)> {
+    let (host, port) = if addr.starts_with('[') {
+        let end = addr
+            .find(']')
+            .with_context(|| format!("'{}' has an unclosed '[' around its IPv6 address", addr))?;
+        let port = addr[end + 1..]
+            .strip_prefix(':')
+            .with_context(|| format!("'{}' is missing a port", addr))?;
+        (&addr[..=end], port)
+    } else {
+        let (host, port) = addr
+            .rsplit_once(':')
+            .with_context(|| format!("'{}' is missing a port", addr))?;
+        if host.contains(':') {
+            bail!("'{}' looks like an IPv6 address without brackets; write it as [address]:port", addr);
+        }
+        (host, port)
+    };
+    if host.is_empty() || host == "[]" {
+        bail!("'{}' is missing a host", addr);
+    }
+    if port.is_empty() {
+        bail!("'{}' is missing a port", addr);
+    }
+    let port: u16 = port
+        .parse()
+        .map_err(|_| anyhow::anyhow!("'{}' has an invalid port '{}'", addr, port))?;
+    Ok((host, port))
+}
+
+/// `addr` with `offset` added to its port, failing instead of overflowing
+pub fn offset_address(addr: &str, offset: u16) -> Result<String> {
+    let (host, port) = split_host_port(addr)?;
+    let shifted = port
+        .checked_add(offset)
+        .with_context(|| format!("'{}' has no room for the +{} port offset", addr, offset))?;
+    Ok(format!("{}:{}", host, shifted))
+}
+
+/// Raft/status address for a server's application address (`host:port`).
+/// In single-port mode that's the application address itself.
+pub fn status_address(app_addr: &str) -> Result<String> {
+    if single_port() {
+        split_host_port(app_addr)?;
+        return Ok(app_addr.to_string());
+    }
+    offset_address(app_addr, RAFT_PORT_OFFSET)
+}
+
+/// Raft addresses for a server's peers, reporting every malformed entry at
+/// once instead of stopping at the first
+pub fn raft_addresses(peers: &[String]) -> Result<Vec<String>> {
+    let mut addresses = Vec::with_capacity(peers.len());
+    let mut errors = Vec::new();
+    for peer in peers {
+        match status_address(peer) {
+            Ok(addr) => addresses.push(addr),
+            Err(e) => errors.push(format!("peer {:#}", e)),
+        }
+    }
+    match errors.len() {
+        0 => Ok(addresses),
+        1 => bail!("{}", errors[0]),
+        n => bail!("{} invalid peers:\n  {}", n, errors.join("\n  ")),
+    }
+}
+
+/// Application address for a Raft address, the inverse of `status_address`.
+pub fn app_address(raft_addr: &str) -> Result<String> {
+    if single_port() {
+        return Ok(raft_addr.to_string());
+    }
+    let (host, port) = split_host_port(raft_addr)?;
+    let app_port = port
+        .checked_sub(RAFT_PORT_OFFSET)
+        .with_context(|| format!("Port {} is below the Raft offset", port))?;
+    Ok(format!("{}:{}", host, app_port))
+}
+
+/// Query servers in order and return the first that reports itself leader,
+/// with its status. Unreachable servers are skipped.
+pub fn find_leader(servers: &[String], timeout: Duration) -> Option<(String, ServerStatus)> {
+    servers.iter().find_map(|addr| match query_status(addr, timeout) {
+        Ok(status) if status.raft.role == ServerRole::Leader => Some((addr.clone(), status)),
+        _ => None,
+    })
+}
+
+/// Ask a server (by application address) for its status, blocking up to `timeout`
+/// for each of connect, send and receive.
+pub fn query_status(app_addr: &str, timeout: Duration) -> Result<ServerStatus> {
+    status_exchange(app_addr, &RaftMessage::StatusRequest, timeout)
+}
+
+/// Ask a server to ping its peers, then return its status with the fresh
+/// round-trip times. `timeout` must allow for the pings to unreachable peers.
+pub fn query_peer_latency(app_addr: &str, timeout: Duration) -> Result<ServerStatus> {
+    status_exchange(app_addr, &RaftMessage::PingPeersRequest, timeout)
+}
+
+/// Ask a server to compare its committed log with every peer's.
+/// `timeout` must allow for the digest requests to unreachable peers.
+pub fn query_log_consistency(app_addr: &str, timeout: Duration) -> Result<LogConsistencyReport> {
+    match raft_exchange(app_addr, &RaftMessage::VerifyLogRequest, timeout)? {
+        RaftMessage::VerifyLogResponse { report } => Ok(report),
+        other => bail!("Unexpected reply to log check from {}: {:?}", app_addr, other),
+    }
+}
+
+/// Ask a server for its composite health (see NodeHealth)
+pub fn query_health(app_addr: &str, timeout: Duration) -> Result<NodeHealth> {
+    match raft_exchange(app_addr, &RaftMessage::HealthRequest, timeout)? {
+        RaftMessage::HealthResponse { health } => Ok(health),
+        other => bail!("Unexpected reply to health request from {}: {:?}", app_addr, other),
+    }
+}
+
+fn status_exchange(app_addr: &str, request: &RaftMessage, timeout: Duration) -> Result<ServerStatus> {
+    match raft_exchange(app_addr, request, timeout)? {
+        RaftMessage::StatusResponse { status } => Ok(status),
+        other => bail!("Unexpected reply to status request from {}: {:?}", app_addr, other),
+    }
+}
+
+/// Exchange protocol versions at the start of a client connection (see
+/// PROTOCOL_VERSION). Fails with the server's explanation if it doesn't speak ours.
+pub fn negotiate_protocol(stream: &mut TcpStream) -> Result<()> {
+    stream.write_all(&[PROTOCOL_VERSION])?;
+    stream.flush()?;
+
+    let read_timeout = stream.read_timeout()?;
+    stream.set_read_timeout(Some(VERSION_ANSWER_TIMEOUT))?;
+    let mut answer = [0u8; 1];
+    stream
+        .read_exact(&mut answer)
+        .context("No answer to the protocol version exchange (is the server older than protocol versioning?)")?;
+    stream.set_read_timeout(read_timeout)?;
+
+    match answer[0] {
+        PROTOCOL_VERSION => Ok(()),
+        VERSION_REJECTED => {
+            let mut len_bytes = [0u8; 8];
+            stream.read_exact(&mut len_bytes)?;
+            let mut reason = vec![0u8; u64::from_be_bytes(len_bytes).min(4096) as usize];
+            stream.read_exact(&mut reason)?;
+            bail!("{}", String::from_utf8_lossy(&reason))
+        }
+        other => bail!("Server answered protocol version {} to our version {}", other, PROTOCOL_VERSION),
+    }
+}
+
+/// Send one admin message to a server's Raft port and read its reply
+fn raft_exchange(app_addr: &str, request: &RaftMessage, timeout: Duration) -> Result<RaftMessage> {
+    let raft_addr = status_address(app_addr)?;
+    let socket_addr = raft_addr
+        .to_socket_addrs()?
+        .next()
+        .with_context(|| format!("Cannot resolve '{}'", raft_addr))?;
+
+    let mut stream = TcpStream::connect_timeout(&socket_addr, timeout)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+
+    // Raft port speaks length-prefixed JSON
+    if single_port() {
+        stream.write_all(&[MUX_RAFT])?;
+    }
+    let request = serde_json::to_vec(request)?;
+    stream.write_all(&(request.len() as u32).to_be_bytes())?;
+    stream.write_all(&request)?;
+    stream.flush()?;
+
+    let mut len_bytes = [0u8; 4];
+    stream.read_exact(&mut len_bytes)?;
+    let mut response = vec![0u8; u32::from_be_bytes(len_bytes) as usize];
+    stream.read_exact(&mut response)?;
+
+    Ok(serde_json::from_slice(&response)?)
+}
+
 /// The data we will hide inside the image using steganography.
 /// We use a HashMap to map a specific username to their allowed view count.
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct ImagePermissions {
     pub owner: String,
     pub quotas: HashMap<String, u32>, // username -> remaining views
+    pub note: Option<String>,         // shown to every viewer, even when access is denied
+    pub version: u64,                 // bumped on every re-embed, to detect concurrent writers
+    pub view_cooldown_secs: Option<u64>, // minimum time between two counted views by the same user
+    pub last_views: HashMap<String, u64>, // username -> unix time of their last counted view
+    pub signature: Option<String>,        // hex HMAC-SHA256 of the other fields, keyed by the owner's --sign-key
+    pub tokens: HashMap<String, Option<u64>>, // hex SHA-256 of each unused one-time view token -> unix time it expires, if ever
+}
+
+/// What a one-time view token is worth against an image's permissions
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TokenStatus {
+    Valid,
+    Expired(u64), // unix time it expired
+    Unknown,      // never issued for this image, or already used
+}
+
+/// Layout of ImagePermissions before `note` was added
+#[derive(Deserialize)]
+struct LegacyImagePermissions {
+    owner: String,
+    quotas: HashMap<String, u32>,
+}
+
+impl From<LegacyImagePermissions> for ImagePermissions {
+    fn from(legacy: LegacyImagePermissions) -> Self {
+        Self {
+            owner: legacy.owner,
+            quotas: legacy.quotas,
+            note: None,
+            version: 0,
+            view_cooldown_secs: None,
+            last_views: HashMap::new(),
+            signature: None,
+            tokens: HashMap::new(),
+        }
+    }
+}
+
+/// Layout of ImagePermissions before `version` was added
+#[derive(Deserialize)]
+struct UnversionedImagePermissions {
+    owner: String,
+    quotas: HashMap<String, u32>,
+    note: Option<String>,
+}
+
+impl From<UnversionedImagePermissions> for ImagePermissions {
+    fn from(unversioned: UnversionedImagePermissions) -> Self {
+        Self {
+            owner: unversioned.owner,
+            quotas: unversioned.quotas,
+            note: unversioned.note,
+            version: 0,
+            view_cooldown_secs: None,
+            last_views: HashMap::new(),
+            signature: None,
+            tokens: HashMap::new(),
+        }
+    }
+}
+
+/// Layout of ImagePermissions before the view cooldown was added
+#[derive(Deserialize)]
+struct UnthrottledImagePermissions {
+    owner: String,
+    quotas: HashMap<String, u32>,
+    note: Option<String>,
+    version: u64,
+}
+
+impl From<UnthrottledImagePermissions> for ImagePermissions {
+    fn from(unthrottled: UnthrottledImagePermissions) -> Self {
+        Self {
+            owner: unthrottled.owner,
+            quotas: unthrottled.quotas,
+            note: unthrottled.note,
+            version: unthrottled.version,
+            view_cooldown_secs: None,
+            last_views: HashMap::new(),
+            signature: None,
+            tokens: HashMap::new(),
+        }
+    }
+}
+
+/// Layout of ImagePermissions before signatures were added
+#[derive(Deserialize)]
+struct UnsignedImagePermissions {
+    owner: String,
+    quotas: HashMap<String, u32>,
+    note: Option<String>,
+    version: u64,
+    view_cooldown_secs: Option<u64>,
+    last_views: HashMap<String, u64>,
+}
+
+impl From<UnsignedImagePermissions> for ImagePermissions {
+    fn from(unsigned: UnsignedImagePermissions) -> Self {
+        Self {
+            owner: unsigned.owner,
+            quotas: unsigned.quotas,
+            note: unsigned.note,
+            version: unsigned.version,
+            view_cooldown_secs: unsigned.view_cooldown_secs,
+            last_views: unsigned.last_views,
+            signature: None,
+            tokens: HashMap::new(),
+        }
+    }
+}
+
+/// Layout of ImagePermissions before one-time view tokens were added
+#[derive(Deserialize)]
+struct UntokenedImagePermissions {
+    owner: String,
+    quotas: HashMap<String, u32>,
+    note: Option<String>,
+    version: u64,
+    view_cooldown_secs: Option<u64>,
+    last_views: HashMap<String, u64>,
+    signature: Option<String>,
+}
+
+impl From<UntokenedImagePermissions> for ImagePermissions {
+    fn from(untokened: UntokenedImagePermissions) -> Self {
+        Self {
+            owner: untokened.owner,
+            quotas: untokened.quotas,
+            note: untokened.note,
+            version: untokened.version,
+            view_cooldown_secs: untokened.view_cooldown_secs,
+            last_views: untokened.last_views,
+            signature: untokened.signature,
+            tokens: HashMap::new(),
+        }
+    }
+}
+
+/// The fields a signature covers, with the maps sorted: bincode writes a
+/// HashMap in iteration order, which differs from one process to the next
+#[derive(Serialize)]
+struct SignedFields<'a> {
+    owner: &'a str,
+    quotas: BTreeMap<&'a String, &'a u32>,
+    note: Option<&'a str>,
+    version: u64,
+    view_cooldown_secs: Option<u64>,
+    last_views: BTreeMap<&'a String, &'a u64>,
+    // Left out when empty, so images signed before tokens existed still verify
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    tokens: BTreeMap<&'a String, &'a Option<u64>>,
+}
+
+/// Every field of ImagePermissions with the maps sorted, so equal
+/// permissions always serialize to the same bytes
+#[derive(Serialize)]
+struct CanonicalPermissions<'a> {
+    owner: &'a str,
+    quotas: BTreeMap<&'a String, &'a u32>,
+    note: Option<&'a str>,
+    version: u64,
+    view_cooldown_secs: Option<u64>,
+    last_views: BTreeMap<&'a String, &'a u64>,
+    signature: Option<&'a str>,
+    tokens: BTreeMap<&'a String, &'a Option<u64>>,
+}
+
+/// Hex SHA-256 of a view token, the form stored in ImagePermissions::tokens
+pub fn hash_token(token: &str) -> String {
+    Sha256::digest(token.trim().as_bytes()).iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Compare every byte, so the time taken doesn't reveal how much matched
+fn constant_time_eq(a: &str, b: &str) -> bool {
+    a.len() == b.len() && a.bytes().zip(b.bytes()).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+/// HMAC-SHA256 (RFC 2104)
+fn hmac_sha256(key: &[u8], message: &[u8]) -> [u8; 32] {
+    const BLOCK_SIZE: usize = 64;
+    let mut block = [0u8; BLOCK_SIZE];
+    if key.len() > BLOCK_SIZE {
+        block[..32].copy_from_slice(&Sha256::digest(key));
+    } else {
+        block[..key.len()].copy_from_slice(key);
+    }
+
+    let mut inner = Sha256::new();
+    inner.update(block.map(|byte| byte ^ 0x36));
+    inner.update(message);
+    let mut outer = Sha256::new();
+    outer.update(block.map(|byte| byte ^ 0x5c));
+    outer.update(inner.finalize());
+    outer.finalize().into()
+}
+
+/// The bincode settings everything persisted or embedded is written with:
+/// Raft state files, permissions sent to the servers and the payloads hidden
+/// in images. These are the settings of `bincode::serialize` when those
+/// formats were created (fixed-width little-endian integers, no size limit,
+/// trailing bytes ignored), spelled out so that a change to bincode's
+/// defaults can't make existing files and images unreadable.
+pub fn bincode_options() -> impl bincode::Options {
+    use bincode::Options;
+    bincode::DefaultOptions::new()
+        .with_fixint_encoding()
+        .with_little_endian()
+        .with_no_limit()
+        .allow_trailing_bytes()
+}
+
+/// Serialize with `bincode_options`
+pub fn to_bincode<T: Serialize + ?Sized>(value: &T) -> Result<Vec<u8>> {
+    use bincode::Options;
+    Ok(bincode_options().serialize(value)?)
+}
+
+/// Deserialize with `bincode_options`
+pub fn from_bincode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T> {
+    use bincode::Options;
+    Ok(bincode_options().deserialize(bytes)?)
+}
+
+/// Bytes `to_bincode` would produce for `value`
+pub fn bincode_size<T: Serialize + ?Sized>(value: &T) -> Result<u64> {
+    use bincode::Options;
+    Ok(bincode_options().serialized_size(value)?)
+}
+
+/// `bincode_options`, but requiring the whole input to be consumed so the
+/// current and legacy layouts can't be confused.
+fn exact_bincode() -> impl bincode::Options {
+    use bincode::Options;
+    bincode_options().reject_trailing_bytes()
+}
+
+/// Starts every payload and set of permissions written since formats were
+/// tagged, followed by the u32 format version. Untagged bytes begin with the
+/// u64 length of the owner's name, which these bytes would make billions, so
+/// a legacy layout can't be mistaken for a tagged one.
+const FORMAT_TAG: [u8; 4] = *b"IPRM";
+
+/// Format of ImagePermissions and CombinedPayload that `to_bytes` writes.
+/// Bump it, and decode the previous one in `from_tagged`, when a field changes.
+pub const FORMAT_VERSION: u32 = 1;
+
+/// Bytes the format tag and version add in front of the bincode
+pub const FORMAT_HEADER_LEN: usize = FORMAT_TAG.len() + 4;
+
+/// `to_bincode`, behind the format tag and version
+fn to_tagged<T: Serialize>(value: &T) -> Result<Vec<u8>> {
+    let mut bytes = Vec::with_capacity(FORMAT_HEADER_LEN + bincode_size(value)? as usize);
+    bytes.extend_from_slice(&FORMAT_TAG);
+    bytes.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+    bytes.extend_from_slice(&to_bincode(value)?);
+    Ok(bytes)
+}
+
+/// Decode bytes written by `to_tagged`, or None if they carry no format tag
+fn from_tagged<T: DeserializeOwned>(bytes: &[u8]) -> Option<Result<T>> {
+    use bincode::Options;
+    let rest = bytes.strip_prefix(&FORMAT_TAG)?;
+    let Some((version, body)) = rest.split_first_chunk::<4>() else {
+        return Some(Err(anyhow::anyhow!("Format tag without a version")));
+    };
+    Some(match u32::from_le_bytes(*version) {
+        FORMAT_VERSION => exact_bincode().deserialize(body).map_err(Into::into),
+        other => Err(anyhow::anyhow!(
+            "Written in format version {}, this build reads up to {}", other, FORMAT_VERSION
+        )),
+    })
+}
+
+impl ImagePermissions {
+    /// Serializes permissions to send to the servers, tagged with FORMAT_VERSION
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        to_tagged(self)
+    }
+
+    /// Deserializes permissions sent by a client by their format version.
+    /// Untagged bytes from older clients are tried against the current, the
+    /// pre-token, pre-signature, pre-cooldown, pre-version and pre-note layouts.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        use bincode::Options;
+        if let Some(permissions) = from_tagged(bytes) {
+            return permissions;
+        }
+        match exact_bincode().deserialize::<ImagePermissions>(bytes) {
+            Ok(permissions) => Ok(permissions),
+            Err(e) => exact_bincode()
+                .deserialize::<UntokenedImagePermissions>(bytes)
+                .map(Self::from)
+                .or_else(|_| exact_bincode().deserialize::<UnsignedImagePermissions>(bytes).map(Self::from))
+                .or_else(|_| exact_bincode().deserialize::<UnthrottledImagePermissions>(bytes).map(Self::from))
+                .or_else(|_| exact_bincode().deserialize::<UnversionedImagePermissions>(bytes).map(Self::from))
+                .or_else(|_| exact_bincode().deserialize::<LegacyImagePermissions>(bytes).map(Self::from))
+                .map_err(|_| e.into()),
+        }
+    }
+
+    /// Checks limits that keep the payload a predictable size.
+    pub fn validate(&self) -> Result<()> {
+        if let Some(note) = &self.note {
+            if note.len() > MAX_NOTE_LEN {
+                bail!("Note is {} bytes, the limit is {}", note.len(), MAX_NOTE_LEN);
+            }
+        }
+        if let Some(user) = self.last_views.keys().find(|user| !self.quotas.contains_key(*user)) {
+            bail!("Last view recorded for '{}', who has no quota", user);
+        }
+        if self.tokens.len() > MAX_VIEW_TOKENS {
+            bail!("{} view tokens, the limit is {}", self.tokens.len(), MAX_VIEW_TOKENS);
+        }
+        Ok(())
+    }
+
+    /// HMAC-SHA256 under `key` of everything but the signature itself
+    fn mac(&self, key: &[u8]) -> Result<[u8; 32]> {
+        let fields = SignedFields {
+            owner: &self.owner,
+            quotas: self.quotas.iter().collect(),
+            note: self.note.as_deref(),
+            version: self.version,
+            view_cooldown_secs: self.view_cooldown_secs,
+            last_views: self.last_views.iter().collect(),
+            tokens: self.tokens.iter().collect(),
+        };
+        Ok(hmac_sha256(key, &to_bincode(&fields)?))
+    }
+
+    /// Serialization of every field that doesn't depend on HashMap order,
+    /// for hashing or comparing permissions
+    pub fn canonical_bytes(&self) -> Result<Vec<u8>> {
+        to_bincode(&CanonicalPermissions {
+            owner: &self.owner,
+            quotas: self.quotas.iter().collect(),
+            note: self.note.as_deref(),
+            version: self.version,
+            view_cooldown_secs: self.view_cooldown_secs,
+            last_views: self.last_views.iter().collect(),
+            signature: self.signature.as_deref(),
+            tokens: self.tokens.iter().collect(),
+        })
+    }
+
+    /// Sign the current fields with the owner's key. Sign again after every
+    /// change, since a signature only matches the fields it was made over.
+    pub fn sign(&mut self, key: &[u8]) -> Result<()> {
+        self.signature = Some(self.mac(key)?.iter().map(|byte| format!("{:02x}", byte)).collect());
+        Ok(())
+    }
+
+    /// Whether the permissions carry a signature made with `key` over their
+    /// current fields. Unsigned permissions never match.
+    pub fn signature_matches(&self, key: &[u8]) -> Result<bool> {
+        let Some(signature) = &self.signature else {
+            return Ok(false);
+        };
+        let expected: String = self.mac(key)?.iter().map(|byte| format!("{:02x}", byte)).collect();
+        Ok(constant_time_eq(signature, &expected))
+    }
+
+    /// Issue a one-time view token, good until `expires_at` (unix time) if
+    /// given. Only its hash is kept, so the token itself is returned to be
+    /// handed out. Sign afterwards, or anyone could add tokens of their own.
+    pub fn issue_token(&mut self, expires_at: Option<u64>) -> String {
+        let token: String = rand::random::<[u8; 16]>().iter().map(|byte| format!("{:02x}", byte)).collect();
+        self.tokens.insert(hash_token(&token), expires_at);
+        token
+    }
+
+    /// Whether `token` would grant a view at `now_secs`
+    pub fn token_status(&self, token: &str, now_secs: u64) -> TokenStatus {
+        match self.tokens.get(&hash_token(token)) {
+            None => TokenStatus::Unknown,
+            Some(Some(expires_at)) if *expires_at <= now_secs => TokenStatus::Expired(*expires_at),
+            Some(_) => TokenStatus::Valid,
+        }
+    }
+
+    /// Use up `token`, so it grants no further views. Returns whether it was unused.
+    pub fn consume_token(&mut self, token: &str) -> bool {
+        self.tokens.remove(&hash_token(token)).is_some()
+    }
+
+    /// Seconds `user` still has to wait before their next view counts, or
+    /// `None` if they may view now. A last view stamped in the future (clock
+    /// skew between peers) is treated as just now rather than waited out.
+    pub fn view_cooldown_remaining(&self, user: &str, now_secs: u64) -> Option<u64> {
+        let cooldown = self.view_cooldown_secs?;
+        let last = (*self.last_views.get(user)?).min(now_secs);
+        let ready_at = last.saturating_add(cooldown);
+        (ready_at > now_secs).then(|| ready_at - now_secs)
+    }
 }
 
+/// A request that fails the same way however often it is retried. Servers
+/// answer it with a text frame holding the Display form, e.g.
+/// "BAD_IMAGE: <decode error>", instead of dropping the connection.
+#[derive(Debug, Clone, PartialEq)]
+pub enum BadRequest {
+    Metadata(String),
+    Image(String),
+    Capacity(String), // the image is too small to carry the payload
+}
+
+impl BadRequest {
+    /// Recognise a rejection in a text reply (or an error message carrying one)
+    pub fn from_reply(msg: &str) -> Option<Self> {
+        if let Some(reason) = msg.strip_prefix("BAD_METADATA:") {
+            Some(BadRequest::Metadata(reason.trim().to_string()))
+        } else if let Some(reason) = msg.strip_prefix("BAD_CAPACITY:") {
+            Some(BadRequest::Capacity(reason.trim().to_string()))
+        } else {
+            msg.strip_prefix("BAD_IMAGE:").map(|reason| BadRequest::Image(reason.trim().to_string()))
+        }
+    }
+}
+
+impl std::fmt::Display for BadRequest {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BadRequest::Metadata(reason) => write!(f, "BAD_METADATA: {}", reason),
+            BadRequest::Image(reason) => write!(f, "BAD_IMAGE: {}", reason),
+            BadRequest::Capacity(reason) => write!(f, "BAD_CAPACITY: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for BadRequest {}
+
 /// This struct holds both the permissions and the raw bytes of the
 /// "unified image" which will be used as the "Access Denied" image.
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct CombinedPayload {
     pub permissions: ImagePermissions,
     pub unified_image: Vec<u8>, // Raw bytes of the PNG
 }
 
+/// Layout of CombinedPayload embedded by versions without notes
+#[derive(Deserialize)]
+struct LegacyCombinedPayload {
+    permissions: LegacyImagePermissions,
+    unified_image: Vec<u8>,
+}
+
+/// Layout of CombinedPayload embedded by versions without a permissions version
+#[derive(Deserialize)]
+struct UnversionedCombinedPayload {
+    permissions: UnversionedImagePermissions,
+    unified_image: Vec<u8>,
+}
+
+/// Layout of CombinedPayload embedded by versions without view tokens
+#[derive(Deserialize)]
+struct UntokenedCombinedPayload {
+    permissions: UntokenedImagePermissions,
+    unified_image: Vec<u8>,
+}
+
+/// Layout of CombinedPayload embedded by versions without signatures
+#[derive(Deserialize)]
+struct UnsignedCombinedPayload {
+    permissions: UnsignedImagePermissions,
+    unified_image: Vec<u8>,
+}
+
+/// Layout of CombinedPayload embedded by versions without a view cooldown
+#[derive(Deserialize)]
+struct UnthrottledCombinedPayload {
+    permissions: UnthrottledImagePermissions,
+    unified_image: Vec<u8>,
+}
+
+impl CombinedPayload {
+    /// Serializes the payload to embed in an image, tagged with FORMAT_VERSION
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        to_tagged(self)
+    }
+
+    /// Bytes `to_bytes` takes for a payload with these permissions, besides
+    /// the unified image itself
+    pub fn overhead(permissions: &ImagePermissions) -> Result<usize> {
+        Ok(FORMAT_HEADER_LEN + bincode_size(permissions)? as usize + 8) // + unified image length prefix
+    }
+
+    /// Deserializes a payload decoded from an image by its format version.
+    /// Untagged bytes, from images protected before formats were tagged, are
+    /// tried against the current layout and those from before view tokens,
+    /// signatures, view cooldowns, versions or notes were added.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        use bincode::Options;
+        if let Some(payload) = from_tagged(bytes) {
+            return payload;
+        }
+        match exact_bincode().deserialize::<CombinedPayload>(bytes) {
+            Ok(payload) => Ok(payload),
+            Err(e) => exact_bincode()
+                .deserialize::<UntokenedCombinedPayload>(bytes)
+                .map(|untokened| Self {
+                    permissions: untokened.permissions.into(),
+                    unified_image: untokened.unified_image,
+                })
+                .or_else(|_| {
+                    exact_bincode().deserialize::<UnsignedCombinedPayload>(bytes).map(|unsigned| Self {
+                        permissions: unsigned.permissions.into(),
+                        unified_image: unsigned.unified_image,
+                    })
+                })
+                .or_else(|_| {
+                    exact_bincode().deserialize::<UnthrottledCombinedPayload>(bytes).map(|unthrottled| Self {
+                        permissions: unthrottled.permissions.into(),
+                        unified_image: unthrottled.unified_image,
+                    })
+                })
+                .or_else(|_| {
+                    exact_bincode().deserialize::<UnversionedCombinedPayload>(bytes).map(|unversioned| Self {
+                        permissions: unversioned.permissions.into(),
+                        unified_image: unversioned.unified_image,
+                    })
+                })
+                .or_else(|_| {
+                    exact_bincode().deserialize::<LegacyCombinedPayload>(bytes).map(|legacy| Self {
+                        permissions: legacy.permissions.into(),
+                        unified_image: legacy.unified_image,
+                    })
+                })
+                .map_err(|_| e.into()),
+        }
+    }
+}
+
+/// Decode and deserialize the payload of a protected image, along with the
+/// layout it was embedded with: the plain one the servers write, or the
+/// redundant copies or parity shards the client's `encrypt --copies` and
+/// `--parity` write.
+pub fn decode_payload(encoded_img: &image::DynamicImage) -> Result<(CombinedPayload, lsb::Layout)> {
+    // A plain payload has no checksum, it only counts if it deserializes
+    let plain_error = match lsb::decode_protected(encoded_img).map(|payload| CombinedPayload::from_bytes(&payload)) {
+        Ok(Ok(combined_data)) => return Ok((combined_data, lsb::Layout::Sequential(lsb::detect_channels(encoded_img)))),
+        Ok(Err(e)) => e,
+        Err(e @ lsb::DecodeError::CorruptLength { .. }) => {
+            anyhow::anyhow!("The image is damaged or was never protected: {}", e)
+        }
+        Err(e) => anyhow::anyhow!("No hidden metadata found! ({})", e),
+    };
+
+    lsb::decode_checksummed(encoded_img)
+        .find_map(|(layout, payload)| Some((CombinedPayload::from_bytes(&payload).ok()?, layout)))
+        .ok_or(plain_error)
+}
+
+/// Shrink the unified (access denied) image so its PNG takes at most `budget`
+/// bytes and neither side exceeds `max_dimension`. An image that already fits
+/// is returned unchanged, so large carriers keep the original quality.
+pub fn fit_unified_image(unified_png: &[u8], budget: usize, max_dimension: Option<u32>) -> Result<Vec<u8>> {
+    let img = image::load_from_memory(unified_png).context("Unified image is not a valid image")?;
+    let (width, height) = img.dimensions();
+    let max_dimension = max_dimension.unwrap_or(u32::MAX);
+    if unified_png.len() <= budget && width.max(height) <= max_dimension {
+        return Ok(unified_png.to_vec());
+    }
+
+    // First try a plain recompress at the allowed size, then keep scaling down.
+    // PNG size grows roughly with pixel count, so scale each side by the square
+    // root of how far over budget we are, with some margin.
+    let mut target = width.max(height).min(max_dimension).max(1);
+    loop {
+        let resized = img.resize(target, target, FilterType::Triangle);
+        let mut png = Vec::new();
+        resized.write_to(&mut Cursor::new(&mut png), ImageOutputFormat::Png)?;
+        if png.len() <= budget {
+            return Ok(png);
+        }
+        if target == 1 {
+            bail!("Carrier has room for only {} bytes of unified image, too few even for a 1x1 PNG", budget);
+        }
+
+        let scale = (budget as f64 / png.len() as f64).sqrt() * 0.9;
+        target = ((target as f64 * scale) as u32).clamp(1, target - 1);
+    }
+}
+
+/// How hard servers compress the PNGs they return. Only the encoding changes;
+/// the pixels, and so the embedded payload, are identical at every level.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PngCompression {
+    /// Fastest deflate and no scanline filtering: least CPU, largest files
+    Fast,
+    /// The image crate's defaults (what servers always used)
+    #[default]
+    Default,
+    /// Best deflate with adaptive filtering: smallest files, most CPU
+    Best,
+}
+
+impl PngCompression {
+    /// Encode `img` as a PNG at this level
+    pub fn encode(self, img: &image::DynamicImage) -> Result<Vec<u8>> {
+        let mut png = Vec::new();
+        let encoder = match self {
+            PngCompression::Fast => PngEncoder::new_with_quality(&mut png, CompressionType::Fast, PngFilterType::NoFilter),
+            PngCompression::Default => PngEncoder::new(&mut png),
+            PngCompression::Best => PngEncoder::new_with_quality(&mut png, CompressionType::Best, PngFilterType::Adaptive),
+        };
+        img.write_with_encoder(encoder)?;
+        Ok(png)
+    }
+}
+
+impl std::str::FromStr for PngCompression {
+    type Err = String;
+
+    fn from_str(value: &str) -> std::result::Result<Self, Self::Err> {
+        match value.to_ascii_lowercase().as_str() {
+            "fast" => Ok(PngCompression::Fast),
+            "default" => Ok(PngCompression::Default),
+            "best" => Ok(PngCompression::Best),
+            _ => Err(format!("'{}' is not a compression level (fast, default or best)", value)),
+        }
+    }
+}
+
+impl std::fmt::Display for PngCompression {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(match self {
+            PngCompression::Fast => "fast",
+            PngCompression::Default => "default",
+            PngCompression::Best => "best",
+        })
+    }
+}
+
+// --- STARTUP CHECKS ---
+
+/// Where servers load the unified (access denied) image from, relative to their working directory.
+pub const UNIFIED_IMAGE_PATH: &str = "unified_image.png";
+
+/// Load the unified image and describe it, or explain how to fix it
+pub fn check_unified_image() -> Result<String> {
+    image::open(UNIFIED_IMAGE_PATH)
+        .map(|img| format!("{} is {}x{}", UNIFIED_IMAGE_PATH, img.width(), img.height()))
+        .map_err(|e| anyhow::anyhow!(
+            "cannot load {} from the working directory ({}); \
+             place the access-denied PNG there", UNIFIED_IMAGE_PATH, e))
+}
+
+/// Validate a server's environment without serving (`server --check`): the
+/// unified image, the peer list, the Raft state file and the data directory.
+/// Prints one line per check and returns true if all of them passed.
+pub fn run_startup_checks(peers: &[String], server_id: &str, data_dir: &Path) -> bool {
+    let mut checks: Vec<(String, Result<String>)> = Vec::new();
+
+    checks.push(("Unified image".to_string(), check_unified_image()));
+
+    if peers.is_empty() {
+        checks.push(("Peers".to_string(), Ok("none (single-node cluster)".to_string())));
+    }
+    for peer in peers {
+        let result = peer
+            .to_socket_addrs()
+            .map_err(anyhow::Error::from)
+            .and_then(|mut resolved| resolved.next().context("resolves to no addresses"))
+            .and_then(|resolved| status_address(peer).map(|raft| format!("{} (Raft {})", resolved, raft)))
+            .map_err(|e| anyhow::anyhow!("'{}' is not a valid host:port ({}); fix the peer list", peer, e));
+        checks.push((format!("Peer {}", peer), result));
+    }
+
+    let state_file = raft::RaftNode::state_file_in(data_dir, server_id);
+    checks.push((
+        "Raft state".to_string(),
+        match raft::RaftNode::inspect_state_file(&state_file) {
+            Ok(Some(summary)) => Ok(summary),
+            Ok(None) => Ok("no state file yet (fresh node)".to_string()),
+            Err(e) => Err(anyhow::anyhow!(
+                "{} can't be used: {}; the node will refuse to start",
+                state_file.display(), e)),
+        },
+    ));
+
+    let probe = data_dir.join(".write_check");
+    checks.push((
+        "Data directory".to_string(),
+        fs::create_dir_all(data_dir)
+            .and_then(|_| fs::write(&probe, b"ok"))
+            .and_then(|_| fs::remove_file(&probe))
+            .map(|_| format!("{} is writable", data_dir.display()))
+            .map_err(|e| anyhow::anyhow!(
+                "cannot write to {} ({}); create it or fix its permissions, or pass --data-dir",
+                data_dir.display(), e)),
+    ));
+
+    let mut all_passed = true;
+    for (name, result) in &checks {
+        match result {
+            Ok(detail) => println!("✓ {}: {}", name, detail),
+            Err(e) => {
+                println!("✗ {}: {}", name, e);
+                all_passed = false;
+            }
+        }
+    }
+    all_passed
+}
+
+/// Print what a server would run with (`server --dry-run`): the addresses it
+/// would bind and its resolved Raft config. `peers` are the application
+/// addresses the config's Raft peers were derived from, in the same order.
+/// Returns false if the config would be rejected at startup.
+pub fn print_dry_run(listeners: &[(&str, String)], peers: &[String], config: &raft::RaftConfig, no_raft: bool) -> bool {
+    println!("Dry run for server {} (nothing is bound)", config.server_id);
+    for (name, addr) in listeners {
+        println!("  {:<24} {}", format!("{} listener:", name), addr);
+    }
+
+    let role = if no_raft {
+        "none (--no-raft)"
+    } else if config.learner {
+        "learner (never votes or leads)"
+    } else {
+        "voter"
+    };
+    println!("  {:<24} {}", "Raft role:", role);
+    if config.peers.is_empty() {
+        println!("  {:<24} none", "Peers:");
+    }
+    for (app, raft) in peers.iter().zip(&config.peers) {
+        let learner = if config.learners.contains(raft) { " (learner)" } else { "" };
+        println!("  {:<24} {} -> Raft {}{}", "Peer:", app, raft, learner);
+    }
+    if !no_raft {
+        println!("  {:<24} {} of {} voters (tolerates {} down)", "Quorum:",
+                 config.quorum(), config.voters(), config.fault_tolerance());
+    }
+    println!("  {:<24} {}", "Advertised address:", config.advertised_addr.as_deref().unwrap_or("none (could not guess)"));
+    println!("  {:<24} {}-{} ms, {} (checked every {} ms)", "Election timeout:",
+             config.election_timeout_min, config.election_timeout_max,
+             config.election_timeout_distribution, config.election_tick);
+    println!("  {:<24} {} ms", "Heartbeat interval:", config.heartbeat_interval);
+    match config.election_seed {
+        Some(seed) => println!("  {:<24} {}", "Election seed:", seed),
+        None => println!("  {:<24} random", "Election seed:"),
+    }
+    println!("  {:<24} {}", "Max RPC bytes:", config.max_rpc_bytes);
+    println!("  {:<24} {}", "Data directory:", config.data_dir.display());
+    println!("  {:<24} {}", "State file:", raft::RaftNode::state_file_path(config).display());
+    if let Some(leader) = &config.initial_leader {
+        println!("  {:<24} {}", "Initial leader:", leader);
+    }
+    if let Some(path) = &config.trace_file {
+        println!("  {:<24} {}", "Raft trace file:", path.display());
+    }
+
+    match config.validate() {
+        Ok(()) => {
+            println!("✓ Config is valid");
+            true
+        }
+        Err(e) => {
+            println!("✗ Config would be rejected: {}", e);
+            false
+        }
+    }
+}
+
 // --- RAFT MESSAGE TYPES ---
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -43,12 +1187,69 @@ pub enum RaftMessage {
     Heartbeat {
         term: u64,
         leader_id: String,
+        #[serde(default)]
+        leader_addr: Option<String>, // leader's client-facing address
     },
     HeartbeatResponse {
         term: u64,
         follower_id: String,
         success: bool,
     },
+    /// Leader replicates log entries (empty `entries` doubles as a heartbeat)
+    AppendEntries {
+        term: u64,
+        leader_id: String,
+        prev_log_index: u64,
+        prev_log_term: u64,
+        entries: Vec<LogEntry>,
+        leader_commit: u64,
+        #[serde(default)]
+        leader_addr: Option<String>, // leader's client-facing address
+    },
+    AppendEntriesResponse {
+        term: u64,
+        follower_id: String,
+        success: bool,
+        match_index: u64, // last replicated index on success, follower's last index on failure
+    },
+    /// Admin tools ask a node for its current status
+    StatusRequest,
+    StatusResponse {
+        status: ServerStatus,
+    },
+    /// Latency probe between peers, answered with a Pong echoing the nonce
+    Ping {
+        nonce: u64,
+    },
+    Pong {
+        nonce: u64,
+    },
+    /// Admin tools ask a node to ping its peers, then report its status
+    PingPeersRequest,
+    /// Ask a node to summarize its log, hashing entries 0..=up_to_index
+    LogDigest {
+        up_to_index: u64,
+    },
+    LogDigestResponse {
+        summary: LogSummary,
+    },
+    /// Admin tools ask a node to compare its committed log with every peer's
+    VerifyLogRequest,
+    VerifyLogResponse {
+        report: LogConsistencyReport,
+    },
+    /// Proxies and load balancers ask a node for its composite health
+    HealthRequest,
+    HealthResponse {
+        health: NodeHealth,
+    },
+}
+
+/// A single entry in the replicated Raft log
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct LogEntry {
+    pub term: u64,
+    pub command: String,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
@@ -56,6 +1257,166 @@ pub enum ServerRole {
     Follower,
     Candidate,
     Leader,
+    Learner, // replicates the log but never votes, campaigns or counts toward commits
+}
+
+// --- STATUS TYPES ---
+
+/// Raft view of a single node
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RaftStatus {
+    pub server_id: String,
+    pub role: ServerRole,
+    pub current_term: u64,
+    pub leader_id: Option<String>,
+    pub leader_addr: Option<String>,
+    pub commit_index: u64,
+    pub last_applied: u64,
+    pub last_log_index: u64,
+    pub peers: Vec<PeerStatus>,
+}
+
+/// State of the circuit breaker guarding RPCs to a peer
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BreakerState {
+    Closed,   // RPCs flow normally
+    Open,     // Peer considered dead, RPCs skipped until the cooldown ends
+    HalfOpen, // Cooldown over, next RPC is a probe
+}
+
+/// A node's view of one of its peers
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PeerStatus {
+    pub address: String,
+    pub breaker: BreakerState,
+    pub consecutive_failures: u32,
+    #[serde(default)]
+    pub rtt_ms: Option<f64>, // average of the latest ping round trips, if the peer was ever pinged
+    #[serde(default)]
+    pub replication_lag: Option<u64>, // leader only: log entries the peer is known to be missing
+    #[serde(default)]
+    pub learner: bool, // replicated to, but left out of elections and the commit quorum
+}
+
+/// A node's log length and last term, with a hash of a prefix of its log
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LogSummary {
+    pub server_id: String,
+    pub last_log_index: u64,
+    pub last_log_term: u64,
+    pub commit_index: u64,
+    pub up_to_index: u64,
+    pub digest: Option<String>, // hex SHA-256 of entries 0..=up_to_index, None if the log is shorter
+}
+
+/// How a peer's log compares with the checking node's committed prefix
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogVerdict {
+    Match,       // same entries up to the checked index
+    Diverged,    // has entries up to the checked index, but different ones
+    Behind,      // hasn't received every checked entry yet
+    Unreachable, // didn't answer the digest request
+}
+
+/// One peer's result in a LogConsistencyReport
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PeerLogCheck {
+    pub address: String,
+    pub verdict: LogVerdict,
+    pub summary: Option<LogSummary>,
+}
+
+/// Result of comparing one node's committed log prefix with its peers' logs
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LogConsistencyReport {
+    pub checked_by: LogSummary,
+    pub peers: Vec<PeerLogCheck>,
+}
+
+impl LogConsistencyReport {
+    /// False if any peer holds different entries in the checked prefix
+    pub fn is_consistent(&self) -> bool {
+        self.peers.iter().all(|peer| peer.verdict != LogVerdict::Diverged)
+    }
+}
+
+/// Everything a node reports through the status endpoint
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ServerStatus {
+    pub raft: RaftStatus,
+    pub dedup_cache_hits: u64,
+    pub dedup_cache_misses: u64,
+    #[serde(default)] // absent from older servers
+    pub unified_image: Option<UnifiedImageCheck>, // None until the leader has checked it
+    #[serde(default)] // absent from older servers
+    pub encodes: EncodeLoad,
+}
+
+/// Composite health of one node, small enough for a proxy to poll: enough to
+/// route clients to a leader that can actually commit, and to skip busy nodes
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct NodeHealth {
+    pub server_id: String,
+    pub role: ServerRole,
+    pub is_leader: bool,
+    pub current_term: u64,
+    pub leader_id: Option<String>,
+    pub quorum_contact: bool,               // heard from a quorum (leader) or a leader (others) within an election timeout
+    pub load_score: Option<f32>,            // ServerMetrics::calculate_load_score, None on servers without load balancing
+    pub max_replication_lag: Option<u64>,   // leader only: entries the furthest-behind voting peer is missing
+    pub healthy: bool,
+    pub problems: Vec<String>,              // why the node isn't healthy, empty if it is
+}
+
+impl NodeHealth {
+    /// Combine a node's Raft status with its quorum contact and load. A node
+    /// is healthy when it is in contact with a quorum, so a partitioned
+    /// leader reports itself unhealthy even though it still thinks it leads.
+    pub fn new(raft: &RaftStatus, quorum_contact: bool, load_score: Option<f32>) -> Self {
+        let is_leader = raft.role == ServerRole::Leader;
+        let max_replication_lag = raft
+            .peers
+            .iter()
+            .filter(|peer| !peer.learner)
+            .filter_map(|peer| peer.replication_lag)
+            .max();
+
+        let mut problems = Vec::new();
+        if !quorum_contact {
+            problems.push(match raft.role {
+                ServerRole::Leader => "leader has lost contact with a quorum of voters".to_string(),
+                _ => "no contact with a leader".to_string(),
+            });
+        }
+
+        NodeHealth {
+            server_id: raft.server_id.clone(),
+            role: raft.role,
+            is_leader,
+            current_term: raft.current_term,
+            leader_id: raft.leader_id.clone(),
+            quorum_contact,
+            load_score,
+            max_replication_lag: if is_leader { max_replication_lag } else { None },
+            healthy: problems.is_empty(),
+            problems,
+        }
+    }
+}
+
+/// Encryptions running on, and waiting for, the --encode-threads slots
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct EncodeLoad {
+    pub threads: u64, // slots, 0 if the server doesn't report them
+    pub active: u64,
+    pub queued: u64,
+}
+
+/// Result of the leader's latest periodic check of the unified image
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct UnifiedImageCheck {
+    pub checked_at: u64,       // unix time of the check
+    pub error: Option<String>, // why the image is unusable, None if it loaded
 }
 
 // --- LOAD BALANCING TYPES ---
@@ -69,6 +1430,8 @@ pub struct ServerMetrics {
     pub avg_response_time_ms: u64,  // Historical average response time
     pub total_requests: u64,        // Total requests processed
     pub timestamp: SystemTime,      // When metrics were collected
+    #[serde(default)] // absent from older servers, scored as 1.0
+    pub capacity: f32,              // Declared relative capacity (--capacity)
 }
 
 impl ServerMetrics {
@@ -86,9 +1449,21 @@ impl ServerMetrics {
         let normalized_response = (self.avg_response_time_ms as f32) / 10000.0; // normalize to 10 seconds
         
         // Calculate weighted sum
-        cpu_weight * normalized_cpu +
-        connection_weight * normalized_connections +
-        response_weight * normalized_response
+        let load = cpu_weight * normalized_cpu +
+            connection_weight * normalized_connections +
+            response_weight * normalized_response;
+
+        // A server declaring twice the capacity looks half as loaded
+        load / self.effective_capacity()
+    }
+
+    /// Declared capacity, with 1.0 standing in for a missing or nonsensical one
+    pub fn effective_capacity(&self) -> f32 {
+        if self.capacity.is_finite() && self.capacity > 0.0 {
+            self.capacity
+        } else {
+            1.0
+        }
     }
 }
 
@@ -107,10 +1482,268 @@ pub enum LoadBalancingMessage {
     ForwardWork {
         metadata: Vec<u8>,
         image_data: Vec<u8>,
+        #[serde(default)]
+        unified_image: Option<Vec<u8>>, // client-supplied, replaces the worker's unified_image.png
     },
     
     /// Worker server sends encrypted result back to leader
     WorkResult {
         encrypted_image: Vec<u8>,
     },
-}
\ No newline at end of file
+
+    /// Leader authorizes a worker to accept one redirected client request
+    DelegationGrant {
+        ticket: String,
+        leader_id: String,
+        term: u64,
+        #[serde(default)] // absent from older servers, whose grants are refused
+        mac: String,      // delegation_mac with the cluster key
+    },
+
+    /// Worker tells the leader whether it accepted the delegation
+    DelegationAck {
+        accepted: bool,
+    },
+
+    /// Client resends its request directly to the worker the leader chose
+    DelegatedWork {
+        ticket: String,
+        metadata: Vec<u8>,
+        image_data: Vec<u8>,
+        #[serde(default)]
+        unified_image: Option<Vec<u8>>, // client-supplied, replaces the worker's unified_image.png
+    },
+
+    /// Worker refuses a request it cannot process
+    WorkRejected {
+        reason: String,
+    },
+
+    /// Worker asks the leader to commit a redirected request it encrypted,
+    /// before it replies to the client
+    DelegationReport {
+        ticket: String,
+        digest: String, // encryption_digest of the encrypted image
+        mac: String,    // delegation_report_mac with the cluster key
+    },
+
+    /// Leader answers a report with the committed log index, None if it didn't commit
+    DelegationCommitted {
+        committed_index: Option<u64>,
+    },
+
+    /// Worker sends a redirected client its encrypted image once the leader committed it
+    DelegatedResult {
+        encrypted_image: Vec<u8>,
+        committed_index: u64,
+    },
+}
+
+/// Hex HMAC-SHA256 of a delegation grant with the cluster key. The work port
+/// is open to anyone, so a worker only takes a grant carrying this MAC as
+/// coming from another server.
+pub fn delegation_mac(key: &[u8], ticket: &str, leader_id: &str, term: u64) -> Result<String> {
+    let mac = hmac_sha256(key, &to_bincode(&(ticket, leader_id, term))?);
+    Ok(mac.iter().map(|byte| format!("{:02x}", byte)).collect())
+}
+
+/// Whether `mac` is the delegation_mac of this grant under `key`
+pub fn delegation_mac_matches(key: &[u8], ticket: &str, leader_id: &str, term: u64, mac: &str) -> Result<bool> {
+    Ok(constant_time_eq(mac, &delegation_mac(key, ticket, leader_id, term)?))
+}
+
+/// Hex HMAC-SHA256 of a worker's report on delegated work, so the leader only
+/// commits results from servers and not from the client holding the ticket
+pub fn delegation_report_mac(key: &[u8], ticket: &str, digest: &str) -> Result<String> {
+    let mac = hmac_sha256(key, &to_bincode(&("report", ticket, digest))?);
+    Ok(mac.iter().map(|byte| format!("{:02x}", byte)).collect())
+}
+
+/// Whether `mac` is the delegation_report_mac of this report under `key`
+pub fn delegation_report_mac_matches(key: &[u8], ticket: &str, digest: &str, mac: &str) -> Result<bool> {
+    Ok(constant_time_eq(mac, &delegation_report_mac(key, ticket, digest)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A payload embedded before the bincode settings were pinned: owner
+    /// alice, bob with 3 views, signed with key "k1", one view token "tok"
+    const PAYLOAD_FIXTURE: &[u8] = include_bytes!("../tests/fixtures/payload.bin");
+
+    #[test]
+    fn payload_fixture_decodes_and_reencodes_to_the_same_bytes() {
+        let payload = CombinedPayload::from_bytes(PAYLOAD_FIXTURE).unwrap();
+        let permissions = &payload.permissions;
+        assert_eq!(permissions.owner, "alice");
+        assert_eq!(permissions.quotas, HashMap::from([("bob".to_string(), 3)]));
+        assert_eq!(permissions.note.as_deref(), Some("hi"));
+        assert_eq!(permissions.version, 7);
+        assert_eq!(permissions.view_cooldown_secs, Some(30));
+        assert_eq!(permissions.last_views, HashMap::from([("bob".to_string(), 1_700_000_000)]));
+        assert_eq!(permissions.token_status("tok", 1_800_000_000), TokenStatus::Valid);
+        assert!(permissions.signature_matches(b"k1").unwrap());
+        assert_eq!(payload.unified_image, vec![1, 2, 3, 250]);
+
+        assert_eq!(to_bincode(&payload).unwrap(), PAYLOAD_FIXTURE);
+        assert_eq!(bincode_size(&payload).unwrap(), PAYLOAD_FIXTURE.len() as u64);
+    }
+
+    #[test]
+    fn tagged_payloads_decode_by_version_and_untagged_ones_by_layout() {
+        let payload = CombinedPayload::from_bytes(PAYLOAD_FIXTURE).unwrap();
+        let tagged = payload.to_bytes().unwrap();
+        assert_eq!(tagged[..FORMAT_HEADER_LEN], [&FORMAT_TAG[..], &FORMAT_VERSION.to_le_bytes()].concat());
+        assert_eq!(tagged[FORMAT_HEADER_LEN..], *PAYLOAD_FIXTURE);
+        assert_eq!(to_bincode(&CombinedPayload::from_bytes(&tagged).unwrap()).unwrap(), PAYLOAD_FIXTURE);
+        assert_eq!(
+            CombinedPayload::overhead(&payload.permissions).unwrap() + payload.unified_image.len(),
+            tagged.len()
+        );
+
+        let permissions = payload.permissions.to_bytes().unwrap();
+        assert_eq!(ImagePermissions::from_bytes(&permissions).unwrap().canonical_bytes().unwrap(),
+                   payload.permissions.canonical_bytes().unwrap());
+
+        // A later format is refused outright rather than guessed at
+        let mut newer = tagged.clone();
+        newer[FORMAT_TAG.len()..FORMAT_HEADER_LEN].copy_from_slice(&(FORMAT_VERSION + 1).to_le_bytes());
+        let err = CombinedPayload::from_bytes(&newer).unwrap_err();
+        assert_eq!(err.to_string(), format!("Written in format version {}, this build reads up to {}", FORMAT_VERSION + 1, FORMAT_VERSION));
+        assert!(CombinedPayload::from_bytes(&tagged[..tagged.len() - 1]).is_err());
+        assert!(ImagePermissions::from_bytes(&FORMAT_TAG).is_err());
+    }
+
+    #[test]
+    fn gzip_frames_round_trip_and_stop_at_the_inflate_limit() {
+        let compressible = vec![42u8; 10_000];
+        let gzipped = gzip_if_smaller(&compressible).unwrap();
+        assert_eq!(gunzip_frame(&gzipped).unwrap(), compressible);
+
+        // Bytes that don't shrink are left for the caller to send raw
+        let noise: Vec<u8> = (0..4096).map(|_| rand::random()).collect();
+        assert_eq!(gzip_if_smaller(&noise), None);
+
+        // Checked at a small limit: inflating MAX_INFLATED_FRAME + 1 bytes would take too long here
+        assert_eq!(gunzip_within(&gzipped, 10_000).unwrap(), compressible);
+        let err = gunzip_within(&gzipped, 9_999).unwrap_err();
+        assert_eq!(err.to_string(), "Gzip frame inflates past 9999 bytes");
+        assert!(gunzip_frame(&gzipped[..gzipped.len() / 2]).is_err());
+    }
+
+    fn metrics(active_connections: u32, capacity: f32) -> ServerMetrics {
+        ServerMetrics {
+            server_id: "n1".to_string(),
+            cpu_load: active_connections as f32 * 10.0,
+            active_connections,
+            avg_response_time_ms: 500,
+            total_requests: 0,
+            timestamp: SystemTime::UNIX_EPOCH,
+            capacity,
+        }
+    }
+
+    #[test]
+    fn load_score_is_divided_by_the_declared_capacity() {
+        let base = metrics(4, 1.0).calculate_load_score();
+        assert!(base > 0.0);
+        assert!((metrics(4, 4.0).calculate_load_score() - base / 4.0).abs() < 1e-6);
+        assert!((metrics(4, 0.5).calculate_load_score() - base * 2.0).abs() < 1e-6);
+        assert!(metrics(8, 4.0).calculate_load_score() < metrics(4, 1.0).calculate_load_score());
+
+        // Missing or nonsensical capacities score as 1.0
+        for capacity in [0.0, -2.0, f32::NAN, f32::INFINITY] {
+            assert_eq!(metrics(4, capacity).effective_capacity(), 1.0);
+            assert_eq!(metrics(4, capacity).calculate_load_score(), base);
+        }
+        let mut older = serde_json::to_value(metrics(4, 3.0)).unwrap();
+        older.as_object_mut().unwrap().remove("capacity");
+        let older: ServerMetrics = serde_json::from_value(older).unwrap();
+        assert_eq!((older.capacity, older.calculate_load_score()), (0.0, base));
+    }
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+
+    #[test]
+    fn hmac_matches_the_rfc_4231_vectors() {
+        assert_eq!(hex(&hmac_sha256(b"Jefe", b"what do ya want for nothing?")),
+                   "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843");
+        // A key longer than the block is hashed first
+        assert_eq!(hex(&hmac_sha256(&[0xaa; 131], b"Test Using Larger Than Block-Size Key - Hash Key First")),
+                   "60e431591ee0b67f0d8a26aacbf5b77f8e0bc6213728c5140546040f0ee37f54");
+    }
+
+    #[test]
+    fn signature_matches_only_the_signed_fields_and_key() {
+        let mut permissions = ImagePermissions {
+            owner: "alice".to_string(),
+            quotas: HashMap::from([("bob".to_string(), 3), ("carol".to_string(), 1)]),
+            note: Some("hi".to_string()),
+            version: 2,
+            view_cooldown_secs: Some(30),
+            last_views: HashMap::new(),
+            signature: None,
+            tokens: HashMap::new(),
+        };
+        assert!(!permissions.signature_matches(b"key").unwrap(), "unsigned never matches");
+        permissions.sign(b"key").unwrap();
+        assert!(permissions.signature_matches(b"key").unwrap());
+        assert!(!permissions.signature_matches(b"other key").unwrap());
+
+        // Map order doesn't matter: a rebuilt map has its own hasher, and so its own order
+        let mut reordered = permissions.clone();
+        let mut quotas: Vec<(String, u32)> = permissions.quotas.clone().into_iter().collect();
+        quotas.reverse();
+        reordered.quotas = quotas.into_iter().collect();
+        assert!(reordered.signature_matches(b"key").unwrap());
+
+        let tampered: [&dyn Fn(&mut ImagePermissions); 7] = [
+            &|p| p.owner = "mallory".to_string(),
+            &|p| *p.quotas.get_mut("bob").unwrap() += 1,
+            &|p| p.note = None,
+            &|p| p.version += 1,
+            &|p| p.view_cooldown_secs = None,
+            &|p| { p.last_views.insert("bob".to_string(), 0); },
+            &|p| { p.issue_token(None); },
+        ];
+        for (i, tamper) in tampered.iter().enumerate() {
+            let mut copy = permissions.clone();
+            tamper(&mut copy);
+            assert!(!copy.signature_matches(b"key").unwrap(), "change {} went unnoticed", i);
+            copy.sign(b"key").unwrap();
+            assert!(copy.signature_matches(b"key").unwrap());
+        }
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_cap() {
+        let ms = Duration::from_millis;
+        let waits: Vec<Duration> = (0..6).map(|retry| capped_backoff(ms(200), retry, ms(1000))).collect();
+        assert_eq!(waits, vec![ms(200), ms(400), ms(800), ms(1000), ms(1000), ms(1000)]);
+
+        // Far past where the doubling overflows, the cap still holds
+        for retry in [31, 32, 64, 1000, u32::MAX] {
+            assert_eq!(capped_backoff(ms(200), retry, ms(1000)), ms(1000));
+            assert_eq!(capped_backoff(Duration::MAX, retry, ms(1000)), ms(1000));
+        }
+        assert_eq!(capped_backoff(ms(2000), 0, ms(1000)), ms(1000), "the cap bounds the first retry too");
+        assert_eq!(capped_backoff(Duration::ZERO, 40, ms(1000)), Duration::ZERO, "no backoff stays none");
+    }
+
+    #[test]
+    fn delegation_mac_covers_ticket_leader_and_term() {
+        let mac = delegation_mac(b"cluster secret", "ticket", "n1", 3).unwrap();
+        assert!(delegation_mac_matches(b"cluster secret", "ticket", "n1", 3, &mac).unwrap());
+
+        assert!(!delegation_mac_matches(b"other secret", "ticket", "n1", 3, &mac).unwrap());
+        assert!(!delegation_mac_matches(b"cluster secret", "ticket2", "n1", 3, &mac).unwrap());
+        assert!(!delegation_mac_matches(b"cluster secret", "ticket", "n2", 3, &mac).unwrap());
+        assert!(!delegation_mac_matches(b"cluster secret", "ticket", "n1", 4, &mac).unwrap());
+        assert!(!delegation_mac_matches(b"cluster secret", "ticket", "n1", 3, "").unwrap());
+        // Fields are length-prefixed, so moving text between them changes the MAC
+        assert!(!delegation_mac_matches(b"cluster secret", "ticketn", "1", 3, &mac).unwrap());
+    }
+}
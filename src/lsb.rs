@@ -1,16 +1,106 @@
 //! Manual implementation of Least Significant Bit (LSB) steganography.
+//!
+//! 8-bit grayscale, grayscale+alpha, RGB and RGBA carriers keep their color type:
+//! the payload is written into the LSB of every channel byte as stored. Palette
+//! PNGs are expanded to RGB(A) by the `image` decoder before they reach us, so the
+//! output of a palette input is a truecolor PNG. 16-bit and floating point carriers
+//! are converted to 8-bit RGBA first, which loses precision; `lossy_conversion`
+//! reports when that will happen and `encode` logs a warning.
+//!
+//! `encode_channels` can restrict the payload to a subset of the channels (e.g.
+//! blue only, where changes are least visible). Such images start with a small
+//! channel header so `decode` knows which channels to read; images written by
+//! plain `encode` have no header and use every channel byte.
+//!
+//! `decode_at` reads a payload that starts at any channel byte and uses only
+//! the channels in a mask, so a region written separately from the main
+//! payload can be decoded on its own.
+//!
+//! `encode_async` and `decode_async` run `encode` and `decode` on tokio's
+//! blocking pool, for callers inside the async runtime. The sync versions
+//! stay for the client and other non-async code.
+//!
+//! `encode_redundant` writes several checksummed copies of the payload into
+//! separate tiles of the image so one damaged area doesn't destroy it.
+//!
+//! `encode_ecc` instead splits the payload into checksummed shards and adds
+//! Reed-Solomon parity shards, so `decode_ecc` can rebuild the payload when a
+//! few scattered LSBs have flipped, at a lower capacity cost than full copies.
+//!
+//! `encode_layout` writes a payload with any of these layouts, and
+//! `decode_checksummed` finds one written with a checksummed layout without
+//! being told which.
+//!
+//! `encode_fragmented` splits a payload too large for any one carrier across
+//! several, each fragment carrying a header with a shared set id, its index
+//! and the fragment count. `decode_fragmented` reassembles the payload from
+//! the images in any order and names the fragments that are missing.
 
 use anyhow::{bail, Result};
 // use image::{DynamicImage, GenericImageView, Rgba};
 use image::{DynamicImage, GenericImageView};
+use std::borrow::Cow;
+use log::warn;
+use reed_solomon_erasure::galois_8::ReedSolomon;
+
+/// Describes the precision loss `encode` will cause for this carrier, if any.
+pub fn lossy_conversion(img: &DynamicImage) -> Option<String> {
+    match img {
+        DynamicImage::ImageLuma8(_)
+        | DynamicImage::ImageLumaA8(_)
+        | DynamicImage::ImageRgb8(_)
+        | DynamicImage::ImageRgba8(_) => None,
+        other => Some(format!(
+            "{:?} carrier will be converted to 8-bit RGBA before embedding",
+            other.color()
+        )),
+    }
+}
+
+/// Largest payload, in bytes, that `encode` can embed in this carrier.
+pub fn capacity_bytes(img: &DynamicImage) -> usize {
+    let channels = match lossy_conversion(img) {
+        Some(_) => 4, // converted to RGBA8
+        None => img.color().channel_count() as usize,
+    };
+    let channel_bytes = img.width() as usize * img.height() as usize * channels;
+    (channel_bytes / 8).saturating_sub(4) // 32-bit length header
+}
+
+/// Returns the carrier in the 8-bit layout the payload is embedded in,
+/// borrowed if it already is in one.
+fn to_carrier(img: &DynamicImage) -> Cow<'_, DynamicImage> {
+    match img {
+        DynamicImage::ImageLuma8(_)
+        | DynamicImage::ImageLumaA8(_)
+        | DynamicImage::ImageRgb8(_)
+        | DynamicImage::ImageRgba8(_) => Cow::Borrowed(img),
+        _ => Cow::Owned(DynamicImage::ImageRgba8(img.to_rgba8())),
+    }
+}
+
+/// Mutable access to the raw channel bytes of a carrier produced by `to_carrier`.
+fn carrier_bytes_mut(img: &mut DynamicImage) -> &mut [u8] {
+    match img {
+        DynamicImage::ImageLuma8(buf) => buf,
+        DynamicImage::ImageLumaA8(buf) => buf,
+        DynamicImage::ImageRgb8(buf) => buf,
+        DynamicImage::ImageRgba8(buf) => buf,
+        _ => unreachable!("to_carrier always yields an 8-bit image"),
+    }
+}
 
 /// Encodes a payload of bytes into the least significant bits of an image's pixels.
 pub fn encode(img: &DynamicImage, payload: &[u8]) -> Result<DynamicImage> {
-    let mut img_buf = img.to_rgba8();
-    let (width, height) = img.dimensions();
+    if let Some(reason) = lossy_conversion(img) {
+        warn!("{}", reason);
+    }
+
+    let mut carrier = to_carrier(img).into_owned();
+    let img_buf = carrier_bytes_mut(&mut carrier);
 
     // Total bytes available for hiding data (1 bit per color channel byte)
-    let capacity = (width * height * 4) as usize;
+    let capacity = img_buf.len();
 
     // Total bits to encode: 32 bits for the payload length + payload bits
     let total_bits_needed = (payload.len() + 4) * 8;
@@ -44,25 +134,240 @@ pub fn encode(img: &DynamicImage, payload: &[u8]) -> Result<DynamicImage> {
         }
     }
 
-    Ok(DynamicImage::ImageRgba8(img_buf))
+    Ok(carrier)
+}
+
+/// Which channels of the carrier hold payload bits.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChannelSelection {
+    /// Every channel byte, the layout written by `encode`.
+    All,
+    /// Only the blue channel: a third of the capacity of an RGB carrier, but
+    /// the eye is least sensitive to changes in blue.
+    BlueOnly,
+}
+
+/// First byte of a channel header. A plain `encode` header starting with this
+/// byte would claim a payload of over 3 GB, which no carrier can hold.
+const CHANNEL_HEADER_MAGIC: u8 = 0xB5;
+/// Channel header: the magic byte then the channel mask, one bit per channel
+/// in the order the carrier stores them (bit 0 = red, 1 = green, 2 = blue,
+/// 3 = alpha for RGB(A); bit 0 = luma, 1 = alpha for grayscale).
+const CHANNEL_HEADER_BITS: usize = 16;
+const BLUE_MASK: u8 = 1 << 2;
+/// Channel mask selecting every channel of the carrier.
+pub const ALL_CHANNELS: u8 = 0x0F;
+
+/// Reads the channel mask from an image written by `encode_channels`, or
+/// `None` if the image uses the plain all-channel layout.
+fn read_channel_header(pixels: &[u8]) -> Option<u8> {
+    if pixels.len() < CHANNEL_HEADER_BITS {
+        return None;
+    }
+    let byte_at = |start: usize| {
+        pixels[start..start + 8]
+            .iter()
+            .fold(0u8, |acc, byte| (acc << 1) | (byte & 1))
+    };
+    let mask = byte_at(8);
+    (byte_at(0) == CHANNEL_HEADER_MAGIC && mask != 0 && mask <= 0x0F).then_some(mask)
+}
+
+/// Indices of the channel bytes in `start..len` that belong to the channels in `mask`.
+fn masked_positions(start: usize, len: usize, channels: usize, mask: u8) -> impl Iterator<Item = usize> {
+    (start..len).filter(move |i| mask & (1 << (i % channels)) != 0)
+}
+
+/// Number of channel bytes in `start..len` that belong to the channels in
+/// `mask`, without visiting them.
+fn masked_count(start: usize, len: usize, channels: usize, mask: u8) -> usize {
+    // Indices below `n` in channel `c`
+    let below = |n: usize, c: usize| (n + channels - 1 - c) / channels;
+    (0..channels)
+        .filter(|&c| mask & (1 << c) != 0)
+        .map(|c| below(len, c).saturating_sub(below(start, c)))
+        .sum()
+}
+
+/// Which channels an image's payload was embedded in, read from its header.
+pub fn detect_channels(img: &DynamicImage) -> ChannelSelection {
+    match read_channel_header(to_carrier(img).as_bytes()) {
+        Some(BLUE_MASK) => ChannelSelection::BlueOnly,
+        _ => ChannelSelection::All,
+    }
+}
+
+/// Like `encode`, but only writes into the selected channels. `All` produces
+/// exactly what `encode` does; anything else is prefixed with a channel header.
+pub fn encode_channels(
+    img: &DynamicImage,
+    payload: &[u8],
+    selection: ChannelSelection,
+) -> Result<DynamicImage> {
+    let mask = match selection {
+        ChannelSelection::All => return encode(img, payload),
+        ChannelSelection::BlueOnly => BLUE_MASK,
+    };
+
+    if let Some(reason) = lossy_conversion(img) {
+        warn!("{}", reason);
+    }
+
+    let mut carrier = to_carrier(img).into_owned();
+    let channels = carrier.color().channel_count() as usize;
+    if channels < 3 {
+        bail!("{:?} carrier has no blue channel", carrier.color());
+    }
+    let img_buf = carrier_bytes_mut(&mut carrier);
+
+    let capacity = masked_count(CHANNEL_HEADER_BITS, img_buf.len(), channels, mask);
+    let total_bits_needed = (payload.len() + 4) * 8;
+    if img_buf.len() < CHANNEL_HEADER_BITS || total_bits_needed > capacity {
+        bail!(
+            "Image capacity too small. Needs {} bits, has {} bits available in the selected channels.",
+            total_bits_needed,
+            capacity
+        );
+    }
+
+    let to_bits = |byte: u8| (0..8).map(move |i| (byte >> (7 - i)) & 1);
+
+    // The header goes into the first 16 channel bytes regardless of the mask
+    let header_bits = [CHANNEL_HEADER_MAGIC, mask].into_iter().flat_map(to_bits);
+    for (pixel_byte, bit) in img_buf.iter_mut().zip(header_bits) {
+        *pixel_byte = (*pixel_byte & 0xFE) | bit;
+    }
+
+    let len_bytes = (payload.len() as u32).to_be_bytes();
+    let bits_to_encode = len_bytes.into_iter().chain(payload.iter().copied()).flat_map(to_bits);
+    let positions = masked_positions(CHANNEL_HEADER_BITS, img_buf.len(), channels, mask);
+    for (i, bit) in positions.zip(bits_to_encode) {
+        img_buf[i] = (img_buf[i] & 0xFE) | bit;
+    }
+
+    Ok(carrier)
+}
+
+/// Why a payload couldn't be read from an image that should contain one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DecodeError {
+    /// The image is too small to even hold the 32-bit length header.
+    TooSmall { capacity_bits: usize },
+    /// The length header claims more bytes than the image can hold, so the
+    /// header (or the file) is damaged.
+    CorruptLength { claimed: usize, capacity: usize },
+}
+
+impl std::fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DecodeError::TooSmall { capacity_bits } => write!(
+                f,
+                "image holds only {} bits, too few for a payload header",
+                capacity_bits
+            ),
+            DecodeError::CorruptLength { claimed, capacity } => write!(
+                f,
+                "embedded length of {} bytes exceeds the {} bytes the image can hold (corrupt header)",
+                claimed, capacity
+            ),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+/// `encode` on the blocking thread pool, so a large image doesn't stall the
+/// async runtime. The image and payload are moved into the pool, hence the
+/// owned arguments.
+pub async fn encode_async(img: DynamicImage, payload: Vec<u8>) -> Result<DynamicImage> {
+    tokio::task::spawn_blocking(move || encode(&img, &payload)).await?
+}
+
+/// `decode` on the blocking thread pool. The image is moved into the pool.
+pub async fn decode_async(img: DynamicImage) -> Result<Option<Vec<u8>>> {
+    tokio::task::spawn_blocking(move || decode(&img)).await?
 }
 
 /// Decodes a payload of bytes from the least significant bits of an image's pixels.
+/// Returns `Ok(None)` if the image doesn't look like it carries a payload.
 pub fn decode(img: &DynamicImage) -> Result<Option<Vec<u8>>> {
-    let pixels: Vec<u8> = img.to_rgba8().into_raw();
-    let mut bits = pixels.iter().map(|byte| byte & 1);
-
-    // 1. Decode the payload length (first 32 bits)
-    let mut len_bits = 0u32;
-    for _ in 0..32 {
-        len_bits = (len_bits << 1) | bits.next().unwrap_or(0) as u32;
+    match decode_protected(img) {
+        Ok(payload) => Ok(Some(payload)),
+        Err(_) => Ok(None), // Likely no message here
     }
-    let payload_len = len_bits as usize;
+}
 
-    // Check if the decoded length is plausible
-    if payload_len > (pixels.len() - 32) / 8 {
-        return Ok(None); // Likely no message here
+/// Like `decode`, for images known to be protected: an implausible header is
+/// reported as a `DecodeError` instead of being treated as "no message".
+pub fn decode_protected(img: &DynamicImage) -> std::result::Result<Vec<u8>, DecodeError> {
+    let carrier = to_carrier(img);
+    let channels = carrier.color().channel_count() as usize;
+    let pixels = carrier.as_bytes();
+
+    match read_channel_header(pixels) {
+        Some(mask) => read_masked(pixels, channels, CHANNEL_HEADER_BITS, mask),
+        None => read_payload(pixels.iter().map(|byte| byte & 1), pixels.len()),
     }
+}
+
+/// Decodes a length-prefixed payload whose bits start at channel byte
+/// `bit_offset` of the carrier and sit only in the channels in `mask`, laid
+/// out as in the channel header (bit 1 is green in RGB(A) but alpha in a
+/// grayscale+alpha carrier); everything before the offset or outside the
+/// mask is skipped. Any channel header is ignored, so
+/// offset 0 with `ALL_CHANNELS` reads what plain `encode` wrote.
+pub fn decode_at(img: &DynamicImage, bit_offset: usize, mask: u8) -> std::result::Result<Vec<u8>, DecodeError> {
+    let carrier = to_carrier(img);
+    let channels = carrier.color().channel_count() as usize;
+    let pixels = carrier.as_bytes();
+    read_masked(pixels, channels, bit_offset, mask)
+}
+
+/// Reads a payload from the LSBs of the channel bytes `masked_positions` picks.
+fn read_masked(pixels: &[u8], channels: usize, start: usize, mask: u8) -> std::result::Result<Vec<u8>, DecodeError> {
+    let bits = masked_positions(start, pixels.len(), channels, mask).map(|i| pixels[i] & 1);
+    read_payload(bits, masked_count(start, pixels.len(), channels, mask))
+}
+
+/// Channel bytes `peek_length` reads: the channel header, then a 32-bit
+/// length spread over one channel in four at worst.
+const PEEK_BYTES: usize = CHANNEL_HEADER_BITS + 32 * 4;
+
+/// Reads only the payload length from an image's header, without extracting
+/// the payload. Returns `Ok(None)` wherever `decode` would find no message.
+/// Only the first few pixels are read, and only those are converted for a
+/// carrier `encode` would convert.
+pub fn peek_length(img: &DynamicImage) -> Result<Option<usize>> {
+    let (channels, head): (usize, Cow<[u8]>) = match lossy_conversion(img) {
+        None => {
+            let bytes = img.as_bytes();
+            (img.color().channel_count() as usize, Cow::Borrowed(&bytes[..PEEK_BYTES.min(bytes.len())]))
+        }
+        Some(_) => {
+            let pixels = img.pixels().take(PEEK_BYTES.div_ceil(4));
+            (4, Cow::Owned(pixels.flat_map(|(_, _, pixel)| pixel.0).collect()))
+        }
+    };
+    let len = img.width() as usize * img.height() as usize * channels;
+
+    let length = match read_channel_header(&head) {
+        Some(mask) => {
+            let mut bits = masked_positions(CHANNEL_HEADER_BITS, head.len(), channels, mask).map(|i| head[i] & 1);
+            read_length(&mut bits, masked_count(CHANNEL_HEADER_BITS, len, channels, mask))
+        }
+        None => read_length(&mut head.iter().map(|byte| byte & 1), len),
+    };
+    Ok(length.ok())
+}
+
+/// Reads a 32-bit length followed by that many bytes from a stream of bits,
+/// `capacity_bits` being how many bits the stream holds in total.
+fn read_payload(
+    mut bits: impl Iterator<Item = u8>,
+    capacity_bits: usize,
+) -> std::result::Result<Vec<u8>, DecodeError> {
+    let payload_len = read_length(&mut bits, capacity_bits)?;
 
     // 2. Decode the payload data
     let mut payload = Vec::with_capacity(payload_len);
@@ -74,5 +379,769 @@ pub fn decode(img: &DynamicImage) -> Result<Option<Vec<u8>>> {
         payload.push(byte);
     }
 
-    Ok(Some(payload))
+    Ok(payload)
+}
+
+/// Reads the 32-bit length header and checks it fits in `capacity_bits`.
+fn read_length(
+    bits: &mut impl Iterator<Item = u8>,
+    capacity_bits: usize,
+) -> std::result::Result<usize, DecodeError> {
+    if capacity_bits < 32 {
+        return Err(DecodeError::TooSmall { capacity_bits });
+    }
+
+    // 1. Decode the payload length (first 32 bits)
+    let mut len_bits = 0u32;
+    for _ in 0..32 {
+        len_bits = (len_bits << 1) | bits.next().unwrap_or(0) as u32;
+    }
+    let payload_len = len_bits as usize;
+
+    // Check if the decoded length is plausible
+    let capacity = (capacity_bits - 32) / 8;
+    if payload_len > capacity {
+        return Err(DecodeError::CorruptLength { claimed: payload_len, capacity });
+    }
+
+    Ok(payload_len)
+}
+
+/// Number of bits in a redundant copy's header: 32-bit length + 32-bit CRC.
+const REDUNDANT_HEADER_BITS: usize = 64;
+
+/// A rectangular tile of the carrier, in pixels.
+#[derive(Debug, Clone, Copy)]
+struct Region {
+    x: u32,
+    y: u32,
+    width: u32,
+    height: u32,
+}
+
+/// Splits the image into `copies` equal tiles on a near-square grid
+/// (e.g. 9 copies -> 3x3). Any leftover pixels at the right and bottom edges
+/// are not used.
+fn grid_regions(width: u32, height: u32, copies: usize) -> Vec<Region> {
+    let cols = (copies as f64).sqrt().ceil() as u32;
+    let rows = (copies as u32).div_ceil(cols);
+    let (tile_w, tile_h) = (width / cols, height / rows);
+
+    (0..copies as u32)
+        .map(|i| Region {
+            x: (i % cols) * tile_w,
+            y: (i / cols) * tile_h,
+            width: tile_w,
+            height: tile_h,
+        })
+        .collect()
+}
+
+/// Indices of the channel bytes inside `region`, row by row.
+fn region_byte_indices(image_width: u32, channels: usize, region: Region) -> impl Iterator<Item = usize> {
+    (region.y..region.y + region.height).flat_map(move |y| {
+        let row_start = (y as usize * image_width as usize + region.x as usize) * channels;
+        row_start..row_start + region.width as usize * channels
+    })
+}
+
+/// Bits one tile can hold when the image is split for `copies` copies.
+pub fn redundant_capacity_bits(img: &DynamicImage, copies: usize) -> usize {
+    if copies == 0 {
+        return 0;
+    }
+    let carrier = to_carrier(img);
+    let channels = carrier.color().channel_count() as usize;
+    let region = grid_regions(carrier.width(), carrier.height(), copies)[0];
+    region.width as usize * region.height as usize * channels
+}
+
+/// CRC32 of a redundant copy: the copy count, then the payload.
+fn redundant_crc(copies: usize, payload: &[u8]) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(&(copies as u32).to_be_bytes());
+    hasher.update(payload);
+    hasher.finalize()
+}
+
+/// Encodes `copies` copies of the payload, each in its own tile of the image
+/// and protected by a CRC32, so `decode_redundant` can recover the payload as
+/// long as one tile survives intact. Tiles are located from the image size,
+/// so this guards against damage to part of the image, not against cropping
+/// or resizing. The CRC also covers the copy count: tiles of different counts
+/// can overlap (the first tile of 2 and of 4 copies start alike), and a copy
+/// must only be read back with the count it was written with.
+pub fn encode_redundant(img: &DynamicImage, payload: &[u8], copies: usize) -> Result<DynamicImage> {
+    if copies == 0 {
+        bail!("At least one copy of the payload is required");
+    }
+    if let Some(reason) = lossy_conversion(img) {
+        warn!("{}", reason);
+    }
+
+    let capacity = redundant_capacity_bits(img, copies);
+    let total_bits_needed = REDUNDANT_HEADER_BITS + payload.len() * 8;
+    if total_bits_needed > capacity {
+        bail!(
+            "Image capacity too small for {} copies. Each copy needs {} bits, each tile has {} bits available.",
+            copies,
+            total_bits_needed,
+            capacity
+        );
+    }
+
+    let mut carrier = to_carrier(img).into_owned();
+    let (width, height) = (carrier.width(), carrier.height());
+    let channels = carrier.color().channel_count() as usize;
+    let img_buf = carrier_bytes_mut(&mut carrier);
+
+    let len_bytes = (payload.len() as u32).to_be_bytes();
+    let crc_bytes = redundant_crc(copies, payload).to_be_bytes();
+
+    for region in grid_regions(width, height, copies) {
+        let bits_to_encode = len_bytes
+            .iter()
+            .chain(crc_bytes.iter())
+            .chain(payload.iter())
+            .flat_map(|&byte| (0..8).map(move |i| (byte >> (7 - i)) & 1));
+
+        for (index, bit) in region_byte_indices(width, channels, region).zip(bits_to_encode) {
+            img_buf[index] = (img_buf[index] & 0xFE) | bit;
+        }
+    }
+
+    Ok(carrier)
+}
+
+/// Decodes a payload written by `encode_redundant` with the same number of
+/// copies. Returns the first copy whose CRC matches, or `None` if every tile
+/// is damaged.
+pub fn decode_redundant(img: &DynamicImage, copies: usize) -> Result<Option<Vec<u8>>> {
+    if copies == 0 {
+        bail!("At least one copy of the payload is required");
+    }
+
+    let carrier = to_carrier(img);
+    let (width, height) = (carrier.width(), carrier.height());
+    let channels = carrier.color().channel_count() as usize;
+    let pixels = carrier.as_bytes();
+    let capacity = redundant_capacity_bits(img, copies);
+
+    for (i, region) in grid_regions(width, height, copies).into_iter().enumerate() {
+        let mut bits = region_byte_indices(width, channels, region).map(|index| pixels[index] & 1);
+        let mut next_u32 = || (0..32).fold(0u32, |acc, _| (acc << 1) | bits.next().unwrap_or(0) as u32);
+
+        let payload_len = next_u32() as usize;
+        let expected_crc = next_u32();
+        if capacity < REDUNDANT_HEADER_BITS || payload_len > (capacity - REDUNDANT_HEADER_BITS) / 8 {
+            warn!("Copy {} has an implausible length header, skipping", i);
+            continue;
+        }
+
+        let payload: Vec<u8> = (0..payload_len)
+            .map(|_| (0..8).fold(0u8, |acc, _| (acc << 1) | bits.next().unwrap_or(0)))
+            .collect();
+
+        if redundant_crc(copies, &payload) == expected_crc {
+            return Ok(Some(payload));
+        }
+        warn!("Copy {} failed its CRC check, trying the next one", i);
+    }
+
+    Ok(None)
+}
+
+/// Most copies `encode_layout` writes and `decode_checksummed` looks for.
+pub const MAX_REDUNDANT_COPIES: usize = 16;
+
+/// How a payload is spread over the carrier.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Layout {
+    /// One copy in the selected channels, written by `encode_channels`.
+    Sequential(ChannelSelection),
+    /// This many CRC-checked copies, one per tile, written by `encode_redundant`.
+    Redundant(usize),
+    /// Reed-Solomon shards with this parity ratio, written by `encode_ecc`.
+    Ecc(f32),
+}
+
+/// Encodes the payload with the given layout.
+pub fn encode_layout(img: &DynamicImage, payload: &[u8], layout: Layout) -> Result<DynamicImage> {
+    match layout {
+        Layout::Sequential(channels) => encode_channels(img, payload, channels),
+        Layout::Redundant(copies) if copies > MAX_REDUNDANT_COPIES => {
+            bail!("At most {} copies of the payload are supported, got {}", MAX_REDUNDANT_COPIES, copies)
+        }
+        Layout::Redundant(copies) => encode_redundant(img, payload, copies),
+        Layout::Ecc(parity_ratio) => encode_ecc(img, payload, parity_ratio),
+    }
+}
+
+/// Payloads found under the layouts that carry their own checksums, each with
+/// the layout it was found under: ECC shards, then redundant copies for each
+/// count up to `MAX_REDUNDANT_COPIES`. A sequential payload has no checksum and any
+/// image decodes to one, so the caller tries that first and validates it.
+/// Lazy, the caller can stop at the first payload it accepts.
+pub fn decode_checksummed(img: &DynamicImage) -> impl Iterator<Item = (Layout, Vec<u8>)> + '_ {
+    // Converted once up front; each attempt below then borrows it as is
+    let carrier = std::rc::Rc::new(to_carrier(img));
+    let ecc_carrier = std::rc::Rc::clone(&carrier);
+    let ecc = std::iter::once_with(move || read_ecc(&ecc_carrier).ok()).flatten().map(|(payload, layout)| {
+        (Layout::Ecc(layout.parity_shards as f32 / layout.data_shards as f32), payload)
+    });
+    let redundant = (1..=MAX_REDUNDANT_COPIES).filter_map(move |copies| {
+        let payload = decode_redundant(&carrier, copies).ok().flatten()?;
+        Some((Layout::Redundant(copies), payload))
+    });
+    ecc.chain(redundant)
+}
+
+/// Bits in an ECC header: 32-bit payload length, 16-bit data and parity shard
+/// counts, 32-bit shard size. The header is written ECC_HEADER_COPIES times
+/// and read back by majority vote, since it can't be rebuilt from parity.
+const ECC_HEADER_BITS: usize = 96;
+const ECC_HEADER_COPIES: usize = 3;
+
+/// Payload bytes per data shard to aim for; a flipped bit costs one shard.
+const ECC_TARGET_SHARD_LEN: usize = 64;
+
+/// Most data shards a payload is split into (data + parity must stay within
+/// the 256 shards GF(2^8) Reed-Solomon supports, with parity_ratio up to 1).
+const ECC_MAX_DATA_SHARDS: usize = 128;
+
+/// Shard layout chosen for a payload
+struct EccLayout {
+    data_shards: usize,
+    parity_shards: usize,
+    shard_len: usize,
+}
+
+impl EccLayout {
+    fn for_payload(payload_len: usize, parity_ratio: f32) -> Result<Self> {
+        if !(parity_ratio > 0.0 && parity_ratio <= 1.0) {
+            bail!("parity_ratio must be in (0, 1], got {}", parity_ratio);
+        }
+        let data_shards = payload_len.div_ceil(ECC_TARGET_SHARD_LEN).clamp(1, ECC_MAX_DATA_SHARDS);
+        Ok(Self {
+            data_shards,
+            parity_shards: (data_shards as f32 * parity_ratio).ceil() as usize,
+            shard_len: payload_len.div_ceil(data_shards).max(1),
+        })
+    }
+
+    /// Bits taken by the header copies and every shard with its CRC32
+    fn total_bits(&self) -> usize {
+        ECC_HEADER_BITS * ECC_HEADER_COPIES + (self.data_shards + self.parity_shards) * (self.shard_len + 4) * 8
+    }
+}
+
+/// Encodes the payload as Reed-Solomon shards, each followed by a CRC32.
+/// `parity_ratio` is parity shards per data shard (0.25 adds 25% parity and
+/// survives damage to a quarter as many shards as there are data shards).
+/// Read it back with `decode_ecc`.
+pub fn encode_ecc(img: &DynamicImage, payload: &[u8], parity_ratio: f32) -> Result<DynamicImage> {
+    if let Some(reason) = lossy_conversion(img) {
+        warn!("{}", reason);
+    }
+
+    let layout = EccLayout::for_payload(payload.len(), parity_ratio)?;
+    let mut carrier = to_carrier(img).into_owned();
+    let capacity = carrier.as_bytes().len();
+    if layout.total_bits() > capacity {
+        bail!(
+            "Image capacity too small for the payload with parity. Needs {} bits, has {} bits available.",
+            layout.total_bits(),
+            capacity
+        );
+    }
+
+    // Zero-pad the payload to whole data shards, then fill in the parity shards
+    let mut shards: Vec<Vec<u8>> = (0..layout.data_shards + layout.parity_shards)
+        .map(|i| {
+            let start = (i * layout.shard_len).min(payload.len());
+            let end = ((i + 1) * layout.shard_len).min(payload.len());
+            let mut shard = if i < layout.data_shards { payload[start..end].to_vec() } else { Vec::new() };
+            shard.resize(layout.shard_len, 0);
+            shard
+        })
+        .collect();
+    ReedSolomon::new(layout.data_shards, layout.parity_shards)
+        .map_err(|e| anyhow::anyhow!("Cannot set up Reed-Solomon: {:?}", e))?
+        .encode(&mut shards)
+        .map_err(|e| anyhow::anyhow!("Reed-Solomon encoding failed: {:?}", e))?;
+
+    let mut header = Vec::with_capacity(ECC_HEADER_BITS / 8);
+    header.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    header.extend_from_slice(&(layout.data_shards as u16).to_be_bytes());
+    header.extend_from_slice(&(layout.parity_shards as u16).to_be_bytes());
+    header.extend_from_slice(&(layout.shard_len as u32).to_be_bytes());
+
+    let mut bytes = header.repeat(ECC_HEADER_COPIES);
+    for shard in &shards {
+        bytes.extend_from_slice(shard);
+        bytes.extend_from_slice(&crc32fast::hash(shard).to_be_bytes());
+    }
+
+    let img_buf = carrier_bytes_mut(&mut carrier);
+    let bits = bytes.iter().flat_map(|&byte| (0..8).map(move |i| (byte >> (7 - i)) & 1));
+    for (channel, bit) in img_buf.iter_mut().zip(bits) {
+        *channel = (*channel & 0xFE) | bit;
+    }
+
+    Ok(carrier)
+}
+
+/// Decodes a payload written by `encode_ecc`, rebuilding shards whose CRC
+/// doesn't match from the parity shards. Fails if more shards are damaged
+/// than there are parity shards.
+pub fn decode_ecc(img: &DynamicImage) -> Result<Vec<u8>> {
+    read_ecc(img).map(|(payload, _)| payload)
+}
+
+/// `decode_ecc`, also returning the shard layout the payload was written with.
+fn read_ecc(img: &DynamicImage) -> Result<(Vec<u8>, EccLayout)> {
+    let carrier = to_carrier(img);
+    let pixels = carrier.as_bytes();
+    let mut bits = pixels.iter().map(|channel| channel & 1);
+    let mut next_byte = || (0..8).fold(0u8, |acc, _| (acc << 1) | bits.next().unwrap_or(0));
+
+    // Majority vote over the header copies, bit by bit
+    let copies: Vec<Vec<u8>> = (0..ECC_HEADER_COPIES)
+        .map(|_| (0..ECC_HEADER_BITS / 8).map(|_| next_byte()).collect())
+        .collect();
+    let header: Vec<u8> = (0..ECC_HEADER_BITS / 8)
+        .map(|i| {
+            (0..8).fold(0u8, |acc, bit| {
+                let votes = copies.iter().filter(|copy| copy[i] & (0x80 >> bit) != 0).count();
+                acc | if votes * 2 > ECC_HEADER_COPIES { 0x80 >> bit } else { 0 }
+            })
+        })
+        .collect();
+
+    let payload_len = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as usize;
+    let layout = EccLayout {
+        data_shards: u16::from_be_bytes([header[4], header[5]]) as usize,
+        parity_shards: u16::from_be_bytes([header[6], header[7]]) as usize,
+        shard_len: u32::from_be_bytes([header[8], header[9], header[10], header[11]]) as usize,
+    };
+    let plausible = layout.data_shards > 0
+        && layout.parity_shards > 0
+        && layout.data_shards + layout.parity_shards <= 256
+        && payload_len <= layout.data_shards * layout.shard_len
+        && layout.total_bits() <= pixels.len();
+    if !plausible {
+        bail!("No ECC payload found (implausible header)");
+    }
+
+    // Shards failing their CRC become erasures for Reed-Solomon to fill in
+    let mut damaged = 0;
+    let mut shards: Vec<Option<Vec<u8>>> = (0..layout.data_shards + layout.parity_shards)
+        .map(|i| {
+            let shard: Vec<u8> = (0..layout.shard_len).map(|_| next_byte()).collect();
+            let crc = u32::from_be_bytes([next_byte(), next_byte(), next_byte(), next_byte()]);
+            if crc32fast::hash(&shard) == crc {
+                Some(shard)
+            } else {
+                warn!("ECC shard {} failed its CRC check", i);
+                damaged += 1;
+                None
+            }
+        })
+        .collect();
+
+    if damaged > layout.parity_shards {
+        bail!("{} shards are damaged, parity can repair at most {}", damaged, layout.parity_shards);
+    }
+    if damaged > 0 {
+        ReedSolomon::new(layout.data_shards, layout.parity_shards)
+            .map_err(|e| anyhow::anyhow!("Cannot set up Reed-Solomon: {:?}", e))?
+            .reconstruct_data(&mut shards)
+            .map_err(|e| anyhow::anyhow!("Reed-Solomon reconstruction failed: {:?}", e))?;
+        warn!("Repaired {} damaged shards from parity", damaged);
+    }
+
+    let mut payload: Vec<u8> = shards
+        .into_iter()
+        .take(layout.data_shards)
+        .flat_map(|shard| shard.unwrap_or_default())
+        .collect();
+    payload.truncate(payload_len);
+    Ok((payload, layout))
+}
+
+/// Marks a fragment written by `encode_fragmented`.
+const FRAGMENT_MAGIC: [u8; 4] = *b"LSBF";
+
+/// Bytes in a fragment header: magic, 64-bit set id, 16-bit index and count,
+/// then the whole payload's 32-bit length and CRC32.
+const FRAGMENT_HEADER_LEN: usize = 24;
+
+/// Header at the start of every fragment
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct FragmentHeader {
+    set_id: u64,
+    index: u16,
+    count: u16,
+    payload_len: u32,
+    payload_crc: u32,
+}
+
+impl FragmentHeader {
+    fn to_bytes(self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(FRAGMENT_HEADER_LEN);
+        bytes.extend_from_slice(&FRAGMENT_MAGIC);
+        bytes.extend_from_slice(&self.set_id.to_be_bytes());
+        bytes.extend_from_slice(&self.index.to_be_bytes());
+        bytes.extend_from_slice(&self.count.to_be_bytes());
+        bytes.extend_from_slice(&self.payload_len.to_be_bytes());
+        bytes.extend_from_slice(&self.payload_crc.to_be_bytes());
+        bytes
+    }
+
+    /// Splits a decoded fragment into its header and data, or `None` if it
+    /// doesn't start with a fragment header.
+    fn parse(bytes: &[u8]) -> Option<(Self, &[u8])> {
+        if bytes.len() < FRAGMENT_HEADER_LEN || bytes[..4] != FRAGMENT_MAGIC {
+            return None;
+        }
+        let u16_at = |i: usize| u16::from_be_bytes([bytes[i], bytes[i + 1]]);
+        let u32_at = |i: usize| u32::from_be_bytes([bytes[i], bytes[i + 1], bytes[i + 2], bytes[i + 3]]);
+        let header = Self {
+            set_id: u64::from_be_bytes(bytes[4..12].try_into().ok()?),
+            index: u16_at(12),
+            count: u16_at(14),
+            payload_len: u32_at(16),
+            payload_crc: u32_at(20),
+        };
+        if header.count == 0 || header.index >= header.count {
+            return None;
+        }
+        Some((header, &bytes[FRAGMENT_HEADER_LEN..]))
+    }
+}
+
+/// Splits the payload across all the carriers in proportion to what each
+/// can hold, and encodes one fragment into each with `encode`. Every carrier
+/// gets a fragment, so rewriting a set with a slightly longer or shorter
+/// payload keeps the same images; all of them are needed to read the payload
+/// back with `decode_fragmented`.
+pub fn encode_fragmented(payload: &[u8], carriers: &[DynamicImage]) -> Result<Vec<DynamicImage>> {
+    let payload_len = u32::try_from(payload.len())
+        .map_err(|_| anyhow::anyhow!("Payload of {} bytes is too large to fragment", payload.len()))?;
+    if carriers.is_empty() {
+        bail!("No carriers to fragment the payload over");
+    }
+
+    // Data bytes each carrier takes once its fragment header is written
+    let mut room = Vec::new();
+    for (i, carrier) in carriers.iter().enumerate() {
+        let capacity = capacity_bytes(carrier);
+        if capacity <= FRAGMENT_HEADER_LEN {
+            bail!("Carrier {} holds {} bytes, too few for a fragment header", i, capacity);
+        }
+        room.push(capacity - FRAGMENT_HEADER_LEN);
+    }
+    let total_room: usize = room.iter().sum();
+    if payload.len() > total_room {
+        bail!(
+            "Payload of {} bytes doesn't fit: the {} carriers hold {} bytes after fragment headers",
+            payload.len(),
+            carriers.len(),
+            total_room
+        );
+    }
+
+    // Shares rounded down, then the bytes left over go to carriers with room to spare
+    let mut shares: Vec<usize> = room.iter().map(|&r| (payload.len() as u128 * r as u128 / total_room as u128) as usize).collect();
+    let mut left = payload.len() - shares.iter().sum::<usize>();
+    for (share, &r) in shares.iter_mut().zip(&room) {
+        let extra = left.min(r - *share);
+        *share += extra;
+        left -= extra;
+    }
+    let mut chunks = Vec::with_capacity(shares.len());
+    let mut rest = payload;
+    for share in shares {
+        let (chunk, tail) = rest.split_at(share);
+        chunks.push(chunk);
+        rest = tail;
+    }
+    let count = u16::try_from(chunks.len())
+        .map_err(|_| anyhow::anyhow!("{} carriers given, at most {} fragments are supported", chunks.len(), u16::MAX))?;
+
+    let set_id: u64 = rand::random();
+    let payload_crc = crc32fast::hash(payload);
+    chunks
+        .iter()
+        .zip(carriers)
+        .enumerate()
+        .map(|(index, (chunk, carrier))| {
+            let header = FragmentHeader { set_id, index: index as u16, count, payload_len, payload_crc };
+            let mut fragment = header.to_bytes();
+            fragment.extend_from_slice(chunk);
+            encode(carrier, &fragment)
+        })
+        .collect()
+}
+
+/// Reassembles a payload written by `encode_fragmented` from its images, in
+/// any order. Fails, naming the culprits, if an image holds no fragment, the
+/// images come from different sets, fragments are missing, or the result
+/// doesn't match the payload's length and CRC.
+pub fn decode_fragmented(imgs: &[DynamicImage]) -> Result<Vec<u8>> {
+    let mut set: Option<FragmentHeader> = None;
+    let mut fragments: Vec<Option<Vec<u8>>> = Vec::new();
+
+    for (i, img) in imgs.iter().enumerate() {
+        let bytes = decode_protected(img).map_err(|e| anyhow::anyhow!("Image {} holds no fragment: {}", i, e))?;
+        let Some((header, data)) = FragmentHeader::parse(&bytes) else {
+            bail!("Image {} holds no fragment (no fragment header)", i);
+        };
+
+        let first = *set.get_or_insert(header);
+        if (header.set_id, header.count, header.payload_len, header.payload_crc)
+            != (first.set_id, first.count, first.payload_len, first.payload_crc)
+        {
+            bail!("Image {} belongs to fragment set {:016x}, not {:016x}", i, header.set_id, first.set_id);
+        }
+        if fragments.is_empty() {
+            fragments = vec![None; first.count as usize];
+        }
+
+        // The same fragment twice is harmless as long as both copies agree
+        match &fragments[header.index as usize] {
+            Some(existing) if existing.as_slice() != data => {
+                bail!("Image {} holds fragment {} again, with different contents", i, header.index);
+            }
+            _ => fragments[header.index as usize] = Some(data.to_vec()),
+        }
+    }
+
+    let Some(set) = set else {
+        bail!("No images to reassemble");
+    };
+    let missing: Vec<String> = fragments
+        .iter()
+        .enumerate()
+        .filter(|(_, fragment)| fragment.is_none())
+        .map(|(index, _)| index.to_string())
+        .collect();
+    if !missing.is_empty() {
+        bail!("Missing {} of {} fragments: {}", missing.len(), set.count, missing.join(", "));
+    }
+
+    let payload: Vec<u8> = fragments.into_iter().flatten().flatten().collect();
+    if payload.len() != set.payload_len as usize || crc32fast::hash(&payload) != set.payload_crc {
+        bail!(
+            "Reassembled {} bytes that don't match the {}-byte payload the fragments describe",
+            payload.len(),
+            set.payload_len
+        );
+    }
+    Ok(payload)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{ColorType, ImageBuffer};
+
+    /// An RGBA carrier with varied channel values, converted to `color`
+    fn carrier(width: u32, height: u32, color: ColorType) -> DynamicImage {
+        let rgba = ImageBuffer::from_fn(width, height, |x, y| {
+            image::Rgba([(x * 7 + y) as u8, (x + y * 13) as u8, (x * y) as u8, 200 + (x % 50) as u8])
+        });
+        let img = DynamicImage::ImageRgba8(rgba);
+        match color {
+            ColorType::L8 => DynamicImage::ImageLuma8(img.to_luma8()),
+            ColorType::La8 => DynamicImage::ImageLumaA8(img.to_luma_alpha8()),
+            ColorType::Rgb8 => DynamicImage::ImageRgb8(img.to_rgb8()),
+            ColorType::Rgb16 => DynamicImage::ImageRgb16(img.to_rgb16()),
+            _ => img,
+        }
+    }
+
+    #[test]
+    fn encode_keeps_8_bit_color_types() {
+        for color in [ColorType::L8, ColorType::La8, ColorType::Rgb8, ColorType::Rgba8] {
+            let img = carrier(32, 32, color);
+            assert_eq!(lossy_conversion(&img), None);
+
+            let encoded = encode(&img, b"payload").unwrap();
+            assert_eq!(encoded.color(), color);
+
+            // The payload survives being saved as a PNG of the same color type
+            let mut png = std::io::Cursor::new(Vec::new());
+            encoded.write_to(&mut png, image::ImageOutputFormat::Png).unwrap();
+            let reloaded = image::load_from_memory(png.get_ref()).unwrap();
+            assert_eq!(reloaded.color(), color);
+            assert_eq!(decode(&reloaded).unwrap().as_deref(), Some(&b"payload"[..]));
+        }
+    }
+
+    #[test]
+    fn encode_converts_16_bit_carriers_to_rgba8() {
+        let img = carrier(32, 32, ColorType::Rgb16);
+        assert!(lossy_conversion(&img).is_some());
+        assert_eq!(capacity_bytes(&img), 32 * 32 * 4 / 8 - 4);
+
+        let encoded = encode(&img, b"payload").unwrap();
+        assert_eq!(encoded.color(), ColorType::Rgba8);
+        assert_eq!(decode(&encoded).unwrap().as_deref(), Some(&b"payload"[..]));
+    }
+
+    #[tokio::test]
+    async fn async_variants_match_the_sync_ones() {
+        let img = carrier(32, 32, ColorType::Rgb8);
+        let capacity = capacity_bytes(&img);
+        let encoded = encode_async(img.clone(), b"payload".to_vec()).await.unwrap();
+        assert_eq!(encoded.as_bytes(), encode(&img, b"payload").unwrap().as_bytes());
+        assert_eq!(decode_async(encoded).await.unwrap().as_deref(), Some(&b"payload"[..]));
+        assert!(encode_async(img, vec![0; capacity + 1]).await.is_err());
+    }
+
+    #[test]
+    fn peek_length_matches_the_decoded_payload() {
+        let payload = vec![0xAB; 57];
+        for color in [ColorType::L8, ColorType::La8, ColorType::Rgb8, ColorType::Rgba8, ColorType::Rgb16] {
+            let img = carrier(32, 32, color);
+            let encoded = encode(&img, &payload).unwrap();
+            assert_eq!(peek_length(&encoded).unwrap(), Some(payload.len()), "{:?}", color);
+            // A 16-bit carrier is peeked through the same conversion `encode` applies
+            if color == ColorType::Rgb16 {
+                let rgb16 = DynamicImage::ImageRgb16(encoded.to_rgb16());
+                assert_eq!(peek_length(&rgb16).unwrap(), decode(&rgb16).unwrap().map(|p| p.len()));
+            }
+        }
+
+        let blue = encode_channels(&carrier(32, 32, ColorType::Rgb8), &payload, ChannelSelection::BlueOnly).unwrap();
+        assert_eq!(peek_length(&blue).unwrap(), Some(payload.len()));
+        assert_eq!(masked_count(CHANNEL_HEADER_BITS, 32 * 32 * 3, 3, BLUE_MASK), 32 * 32 - 5);
+
+        // Too small for a header, and a length the image can't hold
+        assert_eq!(peek_length(&carrier(2, 2, ColorType::Rgb8)).unwrap(), None);
+        let mut full = carrier(8, 8, ColorType::Rgb8);
+        carrier_bytes_mut(&mut full).iter_mut().for_each(|byte| *byte |= 1);
+        assert_eq!(peek_length(&full).unwrap(), None);
+        assert_eq!(decode(&full).unwrap(), None);
+    }
+
+    #[test]
+    fn decode_at_reads_the_masked_channels_from_an_offset() {
+        let payload = b"region payload";
+        // Alpha is channel 1 of a grayscale+alpha carrier and channel 3 of RGBA
+        for (color, alpha_mask) in [(ColorType::La8, 0b10), (ColorType::Rgba8, 0b1000)] {
+            let img = carrier(32, 32, color);
+            let mut written = img.clone();
+            let channels = color.channel_count() as usize;
+            let bytes = carrier_bytes_mut(&mut written);
+            let bits = (payload.len() as u32)
+                .to_be_bytes()
+                .into_iter()
+                .chain(payload.iter().copied())
+                .flat_map(|byte| (0..8).map(move |i| (byte >> (7 - i)) & 1));
+            for (i, bit) in masked_positions(101, bytes.len(), channels, alpha_mask).zip(bits) {
+                bytes[i] = (bytes[i] & 0xFE) | bit;
+            }
+
+            // Only alpha bytes past the offset changed
+            for (i, (before, after)) in img.as_bytes().iter().zip(written.as_bytes()).enumerate() {
+                assert!(before == after || (i >= 101 && i % channels == channels - 1), "{:?} byte {}", color, i);
+            }
+            assert_eq!(decode_at(&written, 101, alpha_mask).unwrap(), payload, "{:?}", color);
+            assert_ne!(decode_at(&written, 101, 0b1).ok().as_deref(), Some(&payload[..]));
+        }
+
+        for (start, len, channels, mask) in [(0, 10, 3, 0b100), (16, 3072, 4, 0b1010), (7, 5, 2, 0b11), (101, 2048, 2, 0b10)] {
+            assert_eq!(masked_count(start, len, channels, mask), masked_positions(start, len, channels, mask).count());
+        }
+    }
+
+    #[test]
+    fn fragments_reassemble_in_any_order_and_missing_ones_are_named() {
+        let carriers = [carrier(16, 16, ColorType::Rgb8), carrier(32, 16, ColorType::Rgba8), carrier(16, 16, ColorType::L8)];
+        let room: usize = carriers.iter().map(|c| capacity_bytes(c) - FRAGMENT_HEADER_LEN).sum();
+        let payload: Vec<u8> = (0..room).map(|i| (i * 31) as u8).collect();
+
+        // Filled to the last byte, every carrier holds a fragment
+        let fragments = encode_fragmented(&payload, &carriers).unwrap();
+        assert_eq!(fragments.len(), 3);
+        let shuffled = [fragments[2].clone(), fragments[0].clone(), fragments[1].clone()];
+        assert_eq!(decode_fragmented(&shuffled).unwrap(), payload);
+
+        // A short payload still spreads over all of them
+        let short = encode_fragmented(b"tiny", &carriers).unwrap();
+        assert_eq!(short.len(), 3);
+        assert_eq!(decode_fragmented(&short).unwrap(), b"tiny");
+
+        let err = decode_fragmented(&fragments[..1]).unwrap_err().to_string();
+        assert!(err.contains("Missing 2 of 3 fragments: 1, 2"), "{}", err);
+        let err = decode_fragmented(&[fragments[0].clone(), short[1].clone()]).unwrap_err().to_string();
+        assert!(err.contains("belongs to fragment set"), "{}", err);
+        let err = decode_fragmented(&[fragments[0].clone(), encode(&carriers[1], b"plain").unwrap()]).unwrap_err().to_string();
+        assert!(err.contains("Image 1 holds no fragment"), "{}", err);
+        assert!(encode_fragmented(&vec![0; room + 1], &carriers).is_err());
+        assert!(encode_fragmented(b"tiny", &[]).is_err());
+    }
+
+    #[test]
+    fn redundant_copies_survive_a_damaged_tile() {
+        let img = carrier(64, 64, ColorType::Rgb8);
+        let payload: Vec<u8> = (0..100).collect();
+        let mut encoded = encode_layout(&img, &payload, Layout::Redundant(4)).unwrap();
+
+        // Flip every LSB of the top-left tile's first rows, copy 0's header and payload
+        let width = encoded.width() as usize;
+        let tile = Region { x: 0, y: 0, width: 32, height: 8 };
+        let indices: Vec<usize> = region_byte_indices(width as u32, 3, tile).collect();
+        let bytes = carrier_bytes_mut(&mut encoded);
+        for &index in &indices {
+            bytes[index] ^= 1;
+        }
+        assert_eq!(decode_redundant(&encoded, 4).unwrap(), Some(payload.clone()));
+
+        // The first tiles of 2 and 4 copies start alike, the CRC tells them apart
+        assert_eq!(decode_redundant(&encoded, 2).unwrap(), None);
+        let two = encode_redundant(&img, &payload, 2).unwrap();
+        assert_eq!(decode_checksummed(&two).next(), Some((Layout::Redundant(2), payload.clone())));
+        assert_eq!(decode_checksummed(&encoded).next(), Some((Layout::Redundant(4), payload.clone())));
+
+        // With every tile damaged the CRCs catch it instead of returning garbage
+        for region in grid_regions(64, 64, 4) {
+            let indices: Vec<usize> = region_byte_indices(width as u32, 3, region).take(200).collect();
+            let bytes = carrier_bytes_mut(&mut encoded);
+            for index in indices {
+                bytes[index] ^= 1;
+            }
+        }
+        assert_eq!(decode_redundant(&encoded, 4).unwrap(), None);
+        assert_eq!(decode_checksummed(&encoded).next(), None);
+
+        // A plain payload has no checksum to find
+        assert_eq!(decode_checksummed(&encode(&img, &payload).unwrap()).next(), None);
+        assert!(encode_layout(&img, &payload, Layout::Redundant(MAX_REDUNDANT_COPIES + 1)).is_err());
+    }
+
+    #[test]
+    fn ecc_repairs_flipped_bits_within_the_parity_budget() {
+        let img = carrier(64, 64, ColorType::Rgb8);
+        let payload: Vec<u8> = (0..300).map(|i| (i * 7) as u8).collect();
+        // 300 bytes make 5 data shards of 60 bytes, a ratio of 0.5 adds 3 parity shards
+        let mut encoded = encode_layout(&img, &payload, Layout::Ecc(0.5)).unwrap();
+        let shard_start = |shard: usize| ECC_HEADER_BITS * ECC_HEADER_COPIES + shard * (60 + 4) * 8;
+
+        // One bit of one header copy is outvoted, one bit in each of three shards is rebuilt
+        let bytes = carrier_bytes_mut(&mut encoded);
+        bytes[5] ^= 1;
+        for shard in [0, 2, 6] {
+            bytes[shard_start(shard) + 17] ^= 1;
+        }
+        assert_eq!(decode_ecc(&encoded).unwrap(), payload);
+        assert_eq!(decode_checksummed(&encoded).next(), Some((Layout::Ecc(0.6), payload.clone())));
+
+        // A fourth damaged shard is more than the parity can rebuild
+        carrier_bytes_mut(&mut encoded)[shard_start(4)] ^= 1;
+        assert!(decode_ecc(&encoded).is_err());
+        assert_eq!(decode_checksummed(&encoded).next(), None);
+    }
 }
@@ -1,14 +1,94 @@
-use crate::{RaftMessage, ServerRole};
-use anyhow::Result;
-use log::{debug, info};
-use rand::Rng;
-use std::collections::HashSet;
+use crate::{from_bincode, to_bincode, BreakerState, LogConsistencyReport, LogEntry, LogSummary, LogVerdict, PeerLogCheck, PeerStatus, RaftMessage, MUX_RAFT, RaftStatus, ServerRole};
+use anyhow::{bail, Result};
+use log::{debug, error, info, warn};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
 use std::time::{Duration, Instant};
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::TcpStream;
-use tokio::sync::Mutex;
-use tokio::time::sleep;
+use tokio::sync::{Mutex, Notify};
+use tokio::task::JoinHandle;
+use tokio::time::{sleep, timeout};
+
+/// Default cap on the serialized size of the entries in one AppendEntries RPC
+pub const DEFAULT_MAX_RPC_BYTES: usize = 1024 * 1024;
+
+/// Upper bound on a single Raft RPC (connect + request + response)
+const RPC_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Consecutive RPC failures before a peer's circuit breaker opens
+const BREAKER_FAILURE_THRESHOLD: u32 = 3;
+
+/// Round-trip samples per peer the reported average is taken over
+const RTT_WINDOW: usize = 5;
+
+/// Pings sent to each peer per `ping_peers` call
+const PINGS_PER_REQUEST: usize = 3;
+
+/// Command of the entry a new leader appends to commit earlier terms' entries
+pub const NOOP_COMMAND: &str = "noop";
+
+/// Command of the placeholder entry every log starts with at index 0
+const INIT_COMMAND: &str = "init";
+
+/// Term of the index-0 placeholder. A follower's first AppendEntries is
+/// checked against it (prev_log_index 0), so every node must use the same
+const INIT_TERM: u64 = 0;
+
+/// Pause between an initial leader's campaigns while its peers are still starting
+const BOOTSTRAP_RETRY: Duration = Duration::from_secs(1);
+
+/// Pause before restarting a background task that died, so one that keeps
+/// failing right away doesn't spin
+const TASK_RESTART_DELAY: Duration = Duration::from_secs(1);
+
+/// Term of the Raft state this process last persisted, for tagging log lines
+static LOGGED_TERM: AtomicU64 = AtomicU64::new(0);
+
+/// The term to tag log lines with: the last one this process persisted
+pub fn current_term_for_logs() -> u64 {
+    LOGGED_TERM.load(Ordering::Relaxed)
+}
+
+/// How many leading entries of `entries` fit in one AppendEntries RPC without
+/// their serialized size going over `max_bytes`. One entry is always allowed,
+/// so an entry bigger than the budget still gets replicated on its own.
+pub fn entries_within_budget(entries: &[LogEntry], max_bytes: usize) -> usize {
+    let mut total = 0;
+    for (count, entry) in entries.iter().enumerate() {
+        // Sized as sent: entries travel as JSON, plus a comma between them
+        total += serde_json::to_vec(entry).map(|bytes| bytes.len()).unwrap_or(usize::MAX) + 1;
+        if count > 0 && total > max_bytes {
+            return count;
+        }
+    }
+    entries.len()
+}
+
+/// Hex SHA-256 over a run of log entries, as compared between nodes
+fn log_digest(entries: &[LogEntry]) -> String {
+    let mut hasher = Sha256::new();
+    for entry in entries {
+        // Length-prefix commands so adjacent entries can't be confused
+        hasher.update(entry.term.to_be_bytes());
+        hasher.update((entry.command.len() as u64).to_be_bytes());
+        hasher.update(entry.command.as_bytes());
+    }
+    hasher.finalize().iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Environment variable that fixes the election timeout seed, to replay a run
+pub const ELECTION_SEED_ENV: &str = "RAFT_SEED";
 
 #[derive(Debug, Clone)]
 pub struct RaftConfig {
@@ -16,7 +96,151 @@ pub struct RaftConfig {
     pub peers: Vec<String>, // List of all peer addresses (excluding self)
     pub election_timeout_min: u64, // milliseconds
     pub election_timeout_max: u64, // milliseconds
+    pub election_timeout_distribution: TimeoutDistribution, // how timeouts are drawn from [min, max]
     pub heartbeat_interval: u64,   // milliseconds
+    pub election_tick: u64,        // milliseconds between election timeout checks
+    pub data_dir: PathBuf,         // where state files are kept ("." by default)
+    pub advertised_addr: Option<String>, // client-facing address sent to followers while leader
+    pub election_seed: Option<u64>,      // seed for election timeouts (random if None, logged either way)
+    pub max_rpc_bytes: usize,            // serialized entry bytes per AppendEntries (at least one entry is always sent)
+    pub single_port: bool,               // peers serve Raft on their app port: prefix each RPC with MUX_RAFT
+    pub learner: bool,                   // this node only replicates: it never votes or starts elections
+    pub learners: Vec<String>,           // peers (from `peers`) that are learners: replicated to, but not in the quorum
+    pub trace_roles: bool,               // log every role change as one structured event
+    pub trace_file: Option<PathBuf>,     // also append role changes to this file, one JSON object per line
+    pub initial_leader: Option<String>,  // on a fresh cluster, this node campaigns at once and the others hold off
+}
+
+/// How election timeouts are spread over [election_timeout_min, election_timeout_max]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TimeoutDistribution {
+    /// Every value equally likely
+    #[default]
+    Uniform,
+    /// Truncated exponential: most timeouts fall near the minimum, so a
+    /// leaderless cluster elects sooner, with a tail that still separates nodes
+    Exponential,
+}
+
+/// Rate of the truncated exponential over the range scaled to [0, 1]. At 3,
+/// two thirds of timeouts fall in the lower third and the mean is 28% of the way up.
+const EXPONENTIAL_RATE: f64 = 3.0;
+
+impl TimeoutDistribution {
+    /// Draw a timeout in [min, max] milliseconds
+    pub fn sample<R: Rng + ?Sized>(self, rng: &mut R, min: u64, max: u64) -> u64 {
+        match self {
+            TimeoutDistribution::Uniform => rng.gen_range(min..=max),
+            TimeoutDistribution::Exponential => {
+                // Inverse CDF of an exponential truncated to [0, 1]
+                let u: f64 = rng.gen();
+                let x = -(1.0 - u * (1.0 - (-EXPONENTIAL_RATE).exp())).ln() / EXPONENTIAL_RATE;
+                min + ((max - min) as f64 * x).round().min((max - min) as f64) as u64
+            }
+        }
+    }
+}
+
+impl std::str::FromStr for TimeoutDistribution {
+    type Err = String;
+
+    fn from_str(value: &str) -> std::result::Result<Self, Self::Err> {
+        match value.to_ascii_lowercase().as_str() {
+            "uniform" => Ok(TimeoutDistribution::Uniform),
+            "exponential" => Ok(TimeoutDistribution::Exponential),
+            _ => Err(format!("'{}' is not a distribution (uniform or exponential)", value)),
+        }
+    }
+}
+
+impl std::fmt::Display for TimeoutDistribution {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(match self {
+            TimeoutDistribution::Uniform => "uniform",
+            TimeoutDistribution::Exponential => "exponential",
+        })
+    }
+}
+
+impl RaftConfig {
+    /// Reject timings that can't produce a stable cluster. A heartbeat interval
+    /// at or above the minimum election timeout lets followers time out between
+    /// heartbeats, so the cluster keeps re-electing instead of failing clearly.
+    pub fn validate(&self) -> Result<()> {
+        if self.election_timeout_min > self.election_timeout_max {
+            bail!(
+                "election_timeout_min ({}ms) is greater than election_timeout_max ({}ms)",
+                self.election_timeout_min, self.election_timeout_max
+            );
+        }
+        if self.heartbeat_interval >= self.election_timeout_min {
+            bail!(
+                "heartbeat_interval ({}ms) must be shorter than election_timeout_min ({}ms), \
+                 or followers start elections between heartbeats",
+                self.heartbeat_interval, self.election_timeout_min
+            );
+        }
+        if let Some(learner) = self.learners.iter().find(|l| !self.peers.contains(l)) {
+            bail!("learner {} is not one of the peers", learner);
+        }
+        if self.learner && self.initial_leader.as_ref() == Some(&self.server_id) {
+            bail!("{} is a learner and can't be the initial leader", self.server_id);
+        }
+        Ok(())
+    }
+
+    /// Voting members of the cluster: this node and every peer that isn't a learner
+    pub fn voters(&self) -> usize {
+        self.peers.iter().filter(|peer| !self.learners.contains(peer)).count() + 1
+    }
+
+    /// Votes or replicas (including our own) needed for a majority of the voters.
+    /// A single voter is its own majority: it elects itself and commits on append.
+    pub fn quorum(&self) -> usize {
+        self.voters() / 2 + 1
+    }
+
+    /// How many voters can be down while the rest still elect and commit. Two
+    /// voters tolerate none, like one, but are also stuck when either is down,
+    /// so a second node only helps once a third voter joins.
+    pub fn fault_tolerance(&self) -> usize {
+        self.voters() - self.quorum()
+    }
+}
+
+/// Read the election timeout seed from ELECTION_SEED_ENV, if set
+pub fn election_seed_from_env() -> Option<u64> {
+    let value = std::env::var(ELECTION_SEED_ENV).ok()?;
+    match value.trim().parse() {
+        Ok(seed) => Some(seed),
+        Err(_) => {
+            warn!("Ignoring {}={:?}: not a u64", ELECTION_SEED_ENV, value);
+            None
+        }
+    }
+}
+
+/// The part of RaftState that must survive a restart
+#[derive(Serialize, Deserialize)]
+struct PersistentState {
+    current_term: u64,
+    voted_for: Option<String>,
+    log: Vec<LogEntry>,
+}
+
+/// Layout version of files written by `export_state`
+const STATE_EXPORT_FORMAT: u32 = 1;
+
+/// A node's persistent state as a portable JSON file, for backups
+#[derive(Serialize, Deserialize)]
+struct StateExport {
+    format: u32,
+    server_id: String, // node the state was exported from
+    exported_at: u64,  // unix time
+    current_term: u64,
+    voted_for: Option<String>,
+    log: Vec<LogEntry>,
+    log_sha256: String, // log_digest of `log`, checked on import
 }
 
 #[derive(Debug)]
@@ -25,8 +249,41 @@ pub struct RaftState {
     pub voted_for: Option<String>,
     pub role: ServerRole,
     pub leader_id: Option<String>,
-    pub last_heartbeat: Instant,
+    pub leader_addr: Option<String>,        // leader's client-facing address, if it advertised one
+    pub last_heartbeat: Instant,            // last leader contact, or when an election started or ended
     pub votes_received: HashSet<String>,
+    pub log: Vec<LogEntry>,                 // index 0 is a dummy entry so real entries start at 1
+    pub commit_index: u64,
+    pub last_applied: u64,
+    pub next_index: HashMap<String, u64>,   // leader only: next entry to send to each peer
+    pub match_index: HashMap<String, u64>,  // leader only: highest entry known replicated on each peer
+}
+
+impl Default for RaftState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The entry at index 0 of every log
+fn init_entry() -> LogEntry {
+    LogEntry { term: INIT_TERM, command: INIT_COMMAND.to_string() }
+}
+
+/// Check that a saved log starts with this build's init entry. A log written
+/// with a different one would never replicate: every AppendEntries for its
+/// first entry would be rejected at index 0, with nothing saying why.
+fn check_init_entry(log: &[LogEntry], source: &std::path::Path) -> Result<()> {
+    match log.first() {
+        Some(first) if *first == init_entry() => Ok(()),
+        Some(first) => bail!(
+            "{} starts with term {} {:?} at index 0, but this build expects term {} {:?}: it was written by \
+             an incompatible version. Run this node with the version that wrote it, or move the file aside \
+             so the node rejoins empty and catches up from the leader (forgetting its earlier votes)",
+            source.display(), first.term, first.command, INIT_TERM, INIT_COMMAND
+        ),
+        None => bail!("{} has an empty log", source.display()),
+    }
 }
 
 impl RaftState {
@@ -36,93 +293,698 @@ impl RaftState {
             voted_for: None,
             role: ServerRole::Follower,
             leader_id: None,
+            leader_addr: None,
             last_heartbeat: Instant::now(),
             votes_received: HashSet::new(),
+            log: vec![init_entry()],
+            commit_index: 0,
+            last_applied: 0,
+            next_index: HashMap::new(),
+            match_index: HashMap::new(),
+        }
+    }
+
+    pub fn last_log_index(&self) -> u64 {
+        (self.log.len() - 1) as u64
+    }
+
+    pub fn last_log_term(&self) -> u64 {
+        self.log.last().map(|entry| entry.term).unwrap_or(0)
+    }
+}
+
+/// Per-peer circuit breaker, so a dead peer doesn't cost a connect/RPC timeout every cycle
+#[derive(Debug, Default)]
+struct PeerBreaker {
+    consecutive_failures: u32,
+    open_until: Option<Instant>, // set while open; RPCs are skipped until then
+    last_success: Option<Instant>, // when the peer last answered an RPC, for `quorum_contact`
+}
+
+impl PeerBreaker {
+    fn state(&self) -> BreakerState {
+        match self.open_until {
+            None => BreakerState::Closed,
+            Some(until) if Instant::now() < until => BreakerState::Open,
+            Some(_) => BreakerState::HalfOpen,
         }
     }
 }
 
+/// Outcome of `propose_entry`: where the entry went and how far it got in the
+/// first replication round
+#[derive(Debug, Clone, Copy)]
+pub struct Proposal {
+    pub index: u64,
+    pub term: u64,
+    pub replicated: usize, // voting peers (not counting us) that accepted the entry in that round
+    pub committed: bool,   // whether the entry was committed by the time the round ended
+}
+
+/// State machine hook called once per committed entry, in log order
+pub type ApplyFn = Arc<dyn Fn(u64, &LogEntry) + Send + Sync>;
+
 pub struct RaftNode {
     pub config: RaftConfig,
     pub state: Arc<Mutex<RaftState>>,
+    breakers: std::sync::Mutex<HashMap<String, PeerBreaker>>,
+    apply_fn: ApplyFn,
+    tasks: std::sync::Mutex<Vec<JoinHandle<()>>>, // background tasks spawned by start()
+    rng: std::sync::Mutex<StdRng>,                 // election timeouts, seeded from config.election_seed
+    rtt_samples: std::sync::Mutex<HashMap<String, VecDeque<Duration>>>, // latest ping round trips per peer
+    commit_advanced: Notify,                       // signalled whenever commit_index or last_applied moves forward, or the role changes
+    commit_to_announce: Notify,                    // leader: a commit followers haven't been told about yet
+    trace_file: Option<std::sync::Mutex<fs::File>>, // opened from config.trace_file
+}
+
+/// Aborts the task when dropped, so a supervised task dies with its watchdog
+struct AbortOnDrop(JoinHandle<()>);
+
+impl AbortOnDrop {
+    async fn join(mut self) -> std::result::Result<(), tokio::task::JoinError> {
+        (&mut self.0).await
+    }
+}
+
+impl Drop for AbortOnDrop {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+/// The message a panic was raised with, when it is a string
+fn panic_message(panic: Box<dyn std::any::Any + Send>) -> String {
+    match panic.downcast::<String>() {
+        Ok(message) => *message,
+        Err(panic) => match panic.downcast::<&'static str>() {
+            Ok(message) => message.to_string(),
+            Err(_) => "(non-string panic)".to_string(),
+        },
+    }
+}
+
+/// How a node starts its first election, see `RaftNode::initial_leader_plan`
+#[derive(Clone, Copy)]
+enum FirstElection {
+    Normal,
+    Campaign, // we are the initial leader
+    HoldOff,  // another node is
+}
+
+/// One role change, as written to the trace file
+#[derive(Serialize)]
+struct RoleChange<'a> {
+    at_ms: u64, // unix time
+    server_id: &'a str,
+    from: ServerRole,
+    to: ServerRole,
+    term: u64,
+    reason: String,
 }
 
 impl RaftNode {
-    pub fn new(config: RaftConfig) -> Self {
-        Self {
+    /// Fails if the config's timings are inconsistent (see `RaftConfig::validate`)
+    pub fn new(config: RaftConfig) -> Result<Self> {
+        config.validate()?;
+
+        if let Err(e) = fs::create_dir_all(&config.data_dir) {
+            error!("[{}] Cannot create data directory {}: {}", config.server_id, config.data_dir.display(), e);
+        }
+
+        let mut state = RaftState::new();
+        if config.learner {
+            state.role = ServerRole::Learner;
+        }
+        if let Some((saved, path)) = Self::load_state(&config.data_dir, &config.server_id)? {
+            check_init_entry(&saved.log, &path)?;
+            info!("[{}] Restored term {} and {} log entries from {}",
+                  config.server_id, saved.current_term, saved.log.len() - 1, path.display());
+            state.current_term = saved.current_term;
+            LOGGED_TERM.store(saved.current_term, Ordering::Relaxed);
+            state.voted_for = saved.voted_for;
+            state.log = saved.log;
+        }
+
+        // Log the seed even when it's random, so a flaky run can be replayed
+        let seed = config.election_seed.unwrap_or_else(rand::random);
+        info!("[{}] Election timeout seed {} (set {}={} to replay)", config.server_id, seed, ELECTION_SEED_ENV, seed);
+
+        // Mix in the server id so nodes sharing a seed still draw different timeouts
+        let mut hasher = DefaultHasher::new();
+        config.server_id.hash(&mut hasher);
+        let rng = StdRng::seed_from_u64(seed ^ hasher.finish());
+
+        if !config.learner {
+            match config.voters() {
+                1 => info!("[{}] Only voter in the cluster: it elects itself and commits entries as soon as they are appended",
+                           config.server_id),
+                2 => warn!("[{}] 2 voters: both are needed to elect a leader or commit, so the cluster stops if either is down; \
+                            run 1 or at least 3 voters", config.server_id),
+                _ => {}
+            }
+        }
+
+        let trace_file = match &config.trace_file {
+            Some(path) => {
+                let file = fs::OpenOptions::new().create(true).append(true).open(path)
+                    .map_err(|e| anyhow::anyhow!("cannot open Raft trace file {}: {}", path.display(), e))?;
+                Some(std::sync::Mutex::new(file))
+            }
+            None => None,
+        };
+
+        Ok(Self {
             config,
-            state: Arc::new(Mutex::new(RaftState::new())),
+            state: Arc::new(Mutex::new(state)),
+            breakers: std::sync::Mutex::new(HashMap::new()),
+            apply_fn: Arc::new(|_, _| {}),
+            tasks: std::sync::Mutex::new(Vec::new()),
+            rng: std::sync::Mutex::new(rng),
+            rtt_samples: std::sync::Mutex::new(HashMap::new()),
+            commit_advanced: Notify::new(),
+            commit_to_announce: Notify::new(),
+            trace_file,
+        })
+    }
+
+    /// Change role, the only place that does after startup, so every transition
+    /// can be traced. Setting the current role again is not a transition and
+    /// records nothing; `reason` is only formatted when something is recorded.
+    fn set_role(&self, state: &mut RaftState, role: ServerRole, reason: std::fmt::Arguments) {
+        if state.role == role {
+            return;
+        }
+        let from = std::mem::replace(&mut state.role, role);
+        // Wakes propose_and_wait, which gives up once this node stops leading
+        self.commit_advanced.notify_waiters();
+        if !self.config.trace_roles && self.trace_file.is_none() {
+            return;
+        }
+
+        let reason = reason.to_string();
+        if self.config.trace_roles {
+            info!("[{}] Role {:?} -> {:?} at term {}: {}", self.config.server_id, from, role, state.current_term, reason);
+        }
+        if let Some(file) = &self.trace_file {
+            let event = RoleChange {
+                at_ms: std::time::SystemTime::now()
+                    .duration_since(std::time::UNIX_EPOCH)
+                    .map(|elapsed| elapsed.as_millis() as u64)
+                    .unwrap_or(0),
+                server_id: &self.config.server_id,
+                from,
+                to: role,
+                term: state.current_term,
+                reason,
+            };
+            let written = serde_json::to_string(&event)
+                .map_err(anyhow::Error::from)
+                .and_then(|line| Ok(writeln!(file.lock().unwrap(), "{}", line)?));
+            if let Err(e) = written {
+                warn!("[{}] Failed to write to the Raft trace file: {}", self.config.server_id, e);
+            }
+        }
+    }
+
+    /// The role to fall back to on seeing a leader or a higher term
+    fn follower_role(&self) -> ServerRole {
+        if self.config.learner {
+            ServerRole::Learner
+        } else {
+            ServerRole::Follower
+        }
+    }
+
+    /// Peers that vote and count toward commits (everyone but the learners)
+    fn voting_peers(&self) -> impl Iterator<Item = &String> {
+        self.config.peers.iter().filter(|peer| !self.config.learners.contains(peer))
+    }
+
+    /// Votes or replicas (including our own) needed for a majority of the voters
+    fn majority(&self) -> usize {
+        self.config.quorum()
+    }
+
+    /// Set the function committed entries are applied with (a no-op by default)
+    pub fn with_apply_fn(mut self, apply_fn: ApplyFn) -> Self {
+        self.apply_fn = apply_fn;
+        self
+    }
+
+    fn read_state_file(path: &std::path::Path) -> Result<PersistentState> {
+        let bytes = fs::read(path)?;
+        let saved: PersistentState = from_bincode(&bytes)?;
+        if saved.log.is_empty() {
+            bail!("log is empty");
         }
+        Ok(saved)
     }
 
-    /// Start the Raft node (election timer and heartbeat sender)
+    /// Read the saved state, falling back to the backup of the previous write
+    /// when the state file is missing or unreadable. Starting empty after
+    /// having voted could let this node vote twice in a term, so if neither
+    /// copy is usable this fails instead. None means a fresh node.
+    fn load_state(data_dir: &std::path::Path, server_id: &str) -> Result<Option<(PersistentState, PathBuf)>> {
+        let path = Self::state_file_in(data_dir, server_id);
+        let backup = Self::backup_path(&path);
+
+        let primary_error = if path.exists() {
+            match Self::read_state_file(&path) {
+                Ok(saved) => return Ok(Some((saved, path))),
+                Err(e) => {
+                    error!("[{}] State file {} is unreadable: {}", server_id, path.display(), e);
+                    Some(e)
+                }
+            }
+        } else {
+            None
+        };
+
+        if !backup.exists() {
+            return match primary_error {
+                Some(e) => bail!(
+                    "Raft state file {} is unreadable ({}) and there is no backup; refusing to start \
+                     with an empty state, which could vote twice in a term. Move the file aside only \
+                     if this node's votes don't matter (e.g. the whole cluster is being reset)",
+                    path.display(), e
+                ),
+                None => Ok(None),
+            };
+        }
+
+        // Without a primary error we crashed between persist's two renames
+        match Self::read_state_file(&backup) {
+            Ok(saved) => {
+                if primary_error.is_some() {
+                    warn!("[{}] Recovered from backup {}: it may be one write behind what this node last persisted",
+                          server_id, backup.display());
+                    // Otherwise the next persist would rotate the damaged file into the backup slot
+                    if let Err(e) = fs::copy(&backup, &path) {
+                        warn!("[{}] Could not restore {} from its backup: {}", server_id, path.display(), e);
+                    }
+                }
+                Ok(Some((saved, backup)))
+            }
+            Err(e) => bail!(
+                "Raft state file {} and its backup are both unusable ({}; backup: {}); refusing to start \
+                 with an empty state, which could vote twice in a term",
+                path.display(),
+                primary_error.map(|e| e.to_string()).unwrap_or_else(|| "missing".to_string()),
+                e
+            ),
+        }
+    }
+
+    /// Where persist keeps the previous state file
+    fn backup_path(path: &std::path::Path) -> PathBuf {
+        path.with_extension("bin.bak")
+    }
+
+    /// Validate a node's state file without starting it. Returns None if
+    /// there is no file yet, otherwise a summary of what it holds.
+    pub fn inspect_state_file(path: &std::path::Path) -> Result<Option<String>> {
+        let summary = |saved: &PersistentState| format!("term {}, {} log entries", saved.current_term, saved.log.len() - 1);
+        let primary_error = match path.exists().then(|| Self::read_state_file(path)) {
+            Some(Ok(saved)) => {
+                check_init_entry(&saved.log, path)?;
+                return Ok(Some(summary(&saved)));
+            }
+            Some(Err(e)) => Some(e),
+            None => None,
+        };
+
+        // The same fallback load_state makes at startup
+        let backup = Self::backup_path(path);
+        match (primary_error, backup.exists().then(|| Self::read_state_file(&backup))) {
+            (None, None) => Ok(None),
+            (None, Some(Ok(saved))) => {
+                check_init_entry(&saved.log, &backup)?;
+                Ok(Some(format!("{} (from backup {})", summary(&saved), backup.display())))
+            }
+            (Some(e), Some(Ok(saved))) => Ok(Some(format!(
+                "unreadable ({}), would recover {} from backup {}", e, summary(&saved), backup.display()
+            ))),
+            (Some(e), _) | (None, Some(Err(e))) => bail!("unreadable ({}) and there is no usable backup", e),
+        }
+    }
+
+    /// Path of the file holding this node's term, vote and log
+    pub fn state_file_path(config: &RaftConfig) -> PathBuf {
+        Self::state_file_in(&config.data_dir, &config.server_id)
+    }
+
+    /// Path of the state file for `server_id` in `data_dir`
+    pub fn state_file_in(data_dir: &std::path::Path, server_id: &str) -> PathBuf {
+        data_dir.join(format!("raft_state_{}.bin", server_id))
+    }
+
+    /// Save term, vote and log. Written to a temp file and renamed so a crash
+    /// mid-write leaves the previous state intact; the previous file is kept
+    /// as a backup for `load_state` in case this one is later damaged.
+    fn persist(&self, state: &RaftState) {
+        LOGGED_TERM.store(state.current_term, Ordering::Relaxed);
+        let saved = PersistentState {
+            current_term: state.current_term,
+            voted_for: state.voted_for.clone(),
+            log: state.log.clone(),
+        };
+        let path = Self::state_file_path(&self.config);
+        if let Err(e) = Self::write_state_file(&path, &saved) {
+            error!("[{}] Failed to persist Raft state to {}: {}", self.config.server_id, path.display(), e);
+        }
+    }
+
+    /// Replace a state file, moving the previous one to its backup path
+    fn write_state_file(path: &std::path::Path, saved: &PersistentState) -> Result<()> {
+        let tmp_path = path.with_extension("bin.tmp");
+        let backup_path = Self::backup_path(path);
+
+        fs::write(&tmp_path, to_bincode(saved)?)?;
+        match fs::rename(path, &backup_path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+            _ => {}
+        }
+        fs::rename(&tmp_path, path)?;
+        Ok(())
+    }
+
+    /// Write `server_id`'s saved state (term, vote and full log) to `out` as
+    /// JSON, for `import_state`. Reads the state the way startup would,
+    /// including the backup fallback. Returns a summary of what was written.
+    pub fn export_state(data_dir: &std::path::Path, server_id: &str, out: &std::path::Path) -> Result<String> {
+        let Some((saved, path)) = Self::load_state(data_dir, server_id)? else {
+            bail!("{} has no saved state to export", server_id);
+        };
+        let export = StateExport {
+            format: STATE_EXPORT_FORMAT,
+            server_id: server_id.to_string(),
+            exported_at: std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map(|elapsed| elapsed.as_secs())
+                .unwrap_or(0),
+            current_term: saved.current_term,
+            voted_for: saved.voted_for,
+            log_sha256: log_digest(&saved.log),
+            log: saved.log,
+        };
+        fs::write(out, serde_json::to_vec_pretty(&export)?)?;
+        Ok(format!("exported term {} and {} log entries from {} to {}",
+                   export.current_term, export.log.len() - 1, path.display(), out.display()))
+    }
+
+    /// Replace `server_id`'s saved state with a file from `export_state`.
+    /// Unless `force` is set, this refuses when the node's current state has
+    /// a higher term, or has log entries the export lacks or contradicts. Either
+    /// would let the node forget a vote or drop entries it may have helped commit.
+    /// The vote is kept only when it was cast by this node.
+    pub fn import_state(data_dir: &std::path::Path, server_id: &str, file: &std::path::Path, force: bool) -> Result<String> {
+        let export: StateExport = serde_json::from_slice(&fs::read(file)?)
+            .map_err(|e| anyhow::anyhow!("{} is not a state export: {}", file.display(), e))?;
+        if export.format != STATE_EXPORT_FORMAT {
+            bail!("{} has export format {}, this build reads {}", file.display(), export.format, STATE_EXPORT_FORMAT);
+        }
+        check_init_entry(&export.log, file)?;
+        if log_digest(&export.log) != export.log_sha256 {
+            bail!("{} is damaged: its log doesn't match the recorded digest", file.display());
+        }
+
+        // A vote belongs to the node that cast it; another node must not inherit it
+        let mut voted_for = if export.server_id == server_id { export.voted_for } else { None };
+
+        let mut overridden = String::new();
+        let existing = Self::load_state(data_dir, server_id)?;
+        if let Some((current, _)) = &existing {
+            let conflicts = if current.current_term > export.current_term {
+                Some(format!("this node is at term {}, the export at term {}", current.current_term, export.current_term))
+            } else if !export.log.starts_with(&current.log) {
+                Some(format!("this node's {} log entries are not a prefix of the export's {}",
+                             current.log.len() - 1, export.log.len() - 1))
+            } else {
+                None
+            };
+            match conflicts {
+                Some(reason) if !force => bail!("refusing to import: {} (--force-import overrides)", reason),
+                Some(reason) => overridden = format!(", overriding: {}", reason),
+                None => {}
+            }
+            if current.current_term == export.current_term {
+                voted_for = current.voted_for.clone();
+            }
+        }
+
+        let path = Self::state_file_in(data_dir, server_id);
+        let entries = export.log.len() - 1;
+        fs::create_dir_all(data_dir)?;
+        Self::write_state_file(&path, &PersistentState {
+            current_term: export.current_term,
+            voted_for,
+            log: export.log,
+        })?;
+        Ok(format!("imported term {} and {} log entries from {} (exported by {}) into {}{}",
+                   export.current_term, entries, file.display(), export.server_id, path.display(), overridden))
+    }
+
+    /// Start the Raft node (election timer, heartbeat sender and apply loop)
     pub async fn start(self: Arc<Self>) {
-        let node_election = Arc::clone(&self);
-        let node_heartbeat = Arc::clone(&self);
+        let first_election = self.initial_leader_plan().await;
+        let handles = vec![
+            // Election timeout checker; a restarted timer skips the first-election plan
+            self.supervise("election timer", move |node, restarts| async move {
+                if restarts == 0 {
+                    match first_election {
+                        FirstElection::Campaign => node.campaign_as_initial_leader().await,
+                        FirstElection::HoldOff => {
+                            // Give the initial leader a full timeout of its own before competing
+                            sleep(Duration::from_millis(node.config.election_timeout_max)).await;
+                            node.state.lock().await.last_heartbeat = Instant::now();
+                        }
+                        FirstElection::Normal => {}
+                    }
+                }
+                node.run_election_timer().await;
+            }),
+            // Heartbeat sender (if leader)
+            self.supervise("heartbeat sender", |node, _| async move {
+                node.run_heartbeat_sender().await;
+            }),
+            // Apply loop
+            self.supervise("apply loop", |node, _| async move {
+                node.run_apply_loop().await;
+            }),
+            // Commit announcements (if leader)
+            self.supervise("commit announcer", |node, _| async move {
+                node.run_commit_announcer().await;
+            }),
+        ];
+        self.tasks.lock().unwrap().extend(handles);
+    }
 
-        // Spawn election timeout checker
+    /// Run a background task under a watchdog: if it panics or returns, which
+    /// none of them should, log it and start it again, so the node doesn't keep
+    /// running with no elections, heartbeats or applies. `run` gets how many
+    /// times the task has been restarted. Aborting the returned handle (as
+    /// `shutdown` does) also aborts the running task.
+    fn supervise<F, Fut>(self: &Arc<Self>, name: &'static str, run: F) -> JoinHandle<()>
+    where
+        F: Fn(Arc<Self>, u32) -> Fut + Send + 'static,
+        Fut: std::future::Future<Output = ()> + Send + 'static,
+    {
+        let node = Arc::clone(self);
         tokio::spawn(async move {
-            node_election.run_election_timer().await;
-        });
+            let mut restarts = 0;
+            loop {
+                let task = AbortOnDrop(tokio::spawn(run(Arc::clone(&node), restarts)));
+                let outcome = match task.join().await {
+                    Ok(()) => "stopped".to_string(),
+                    Err(e) if e.is_panic() => format!("panicked: {}", panic_message(e.into_panic())),
+                    Err(_) => return, // aborted from outside
+                };
+                restarts += 1;
+                error!("[{}] Raft {} {}; restarting it in {:?} (restart {})",
+                       node.config.server_id, name, outcome, TASK_RESTART_DELAY, restarts);
+                sleep(TASK_RESTART_DELAY).await;
+            }
+        })
+    }
 
-        // Spawn heartbeat sender (if leader)
-        tokio::spawn(async move {
-            node_heartbeat.run_heartbeat_sender().await;
-        });
+    /// Stop the background tasks started by `start`. The node keeps its state
+    /// and still answers messages passed to `handle_raft_message`, but no longer
+    /// runs elections, sends heartbeats or applies entries.
+    pub fn shutdown(&self) {
+        let tasks: Vec<JoinHandle<()>> = self.tasks.lock().unwrap().drain(..).collect();
+        if tasks.is_empty() {
+            return;
+        }
+        for task in &tasks {
+            task.abort();
+        }
+        info!("[{}] Stopped {} background tasks", self.config.server_id, tasks.len());
+    }
+
+    /// Apply committed entries in order. last_applied only moves after an entry's
+    /// apply function has returned, so anything reading it under the lock never
+    /// sees an entry that is committed but not yet applied.
+    async fn run_apply_loop(&self) {
+        loop {
+            // Registered before checking, so an advance in between isn't missed
+            let committed = self.commit_advanced.notified();
+            tokio::pin!(committed);
+            committed.as_mut().enable();
+
+            let next = {
+                let state = self.state.lock().await;
+                if state.last_applied < state.commit_index {
+                    let index = state.last_applied + 1;
+                    Some((index, state.log[index as usize].clone()))
+                } else {
+                    None
+                }
+            }; // Lock released here
+
+            let Some((index, entry)) = next else {
+                committed.await;
+                continue;
+            };
+
+            // Committed entries are never truncated, so applying outside the lock is safe
+            let apply_fn = Arc::clone(&self.apply_fn);
+            let applied = tokio::task::spawn_blocking(move || apply_fn(index, &entry)).await;
+            if let Err(e) = applied {
+                error!("[{}] Applying entry {} panicked: {}", self.config.server_id, index, e);
+            }
+
+            let mut state = self.state.lock().await;
+            state.last_applied = index;
+            self.commit_advanced.notify_waiters();
+            debug!("[{}] Applied entry {}", self.config.server_id, index);
+        }
+    }
+
+    /// What `--initial-leader` means for this node's first election. It is only
+    /// honored on a fresh node: once any term has been persisted the cluster
+    /// may already have a leader, and a node must never skip the normal
+    /// election for a term it might already have voted in.
+    async fn initial_leader_plan(&self) -> FirstElection {
+        let Some(initial_leader) = &self.config.initial_leader else {
+            return FirstElection::Normal;
+        };
+        let state = self.state.lock().await;
+        if state.current_term > 0 || state.log.len() > 1 {
+            warn!("[{}] Ignoring initial leader {}: this node already has state (term {}, {} log entries)",
+                  self.config.server_id, initial_leader, state.current_term, state.log.len() - 1);
+            return FirstElection::Normal;
+        }
+        if *initial_leader == self.config.server_id {
+            info!("[{}] Fresh cluster: campaigning at once as the initial leader", self.config.server_id);
+            FirstElection::Campaign
+        } else {
+            info!("[{}] Fresh cluster: waiting for initial leader {}", self.config.server_id, initial_leader);
+            FirstElection::HoldOff
+        }
+    }
+
+    /// Campaign right away instead of waiting out an election timeout, retrying
+    /// while peers are still starting. An ordinary election, so peers that have
+    /// moved on (a higher term, a longer log) still refuse their votes. Gives up
+    /// once a leader is known, a peer turns out to be past our term (so the
+    /// cluster isn't fresh and retrying would only disrupt it), or a full
+    /// election timeout has passed, leaving the rest to the election timer.
+    async fn campaign_as_initial_leader(self: &Arc<Self>) {
+        let deadline = Instant::now() + Duration::from_millis(self.config.election_timeout_max);
+        loop {
+            let term = {
+                let state = self.state.lock().await;
+                if state.leader_id.is_some() {
+                    return;
+                }
+                state.current_term + 1
+            };
+            self.start_election("initial leader").await;
+            {
+                let state = self.state.lock().await;
+                if state.role == ServerRole::Leader || state.leader_id.is_some() {
+                    return;
+                }
+                if state.current_term > term {
+                    info!("[{}] A peer is already at term {}: not a fresh cluster, leaving the election to timeouts",
+                          self.config.server_id, state.current_term);
+                    return;
+                }
+            }
+            if Instant::now() + BOOTSTRAP_RETRY >= deadline {
+                info!("[{}] No quorum for the initial election, falling back to election timeouts", self.config.server_id);
+                return;
+            }
+            sleep(BOOTSTRAP_RETRY).await;
+        }
     }
 
     /// Run the election timer
-    async fn run_election_timer(&self) {
+    async fn run_election_timer(self: &Arc<Self>) {
+        // Poll on a short fixed tick; the randomized timeout is only the threshold.
+        // Each cycle starts at the latest heartbeat, or at the start or end of an election,
+        // and gets a fresh timeout, so a failed candidate waits a full timeout before retrying.
+        let tick = Duration::from_millis(self.config.election_tick);
+        let mut timeout = self.get_random_election_timeout();
+        let mut cycle_start = self.state.lock().await.last_heartbeat;
+
         loop {
-            let timeout = self.get_random_election_timeout();
-            sleep(timeout).await;
+            sleep(tick).await;
 
             let should_start_election = {
                 let state = self.state.lock().await;
+
+                if state.last_heartbeat > cycle_start {
+                    cycle_start = state.last_heartbeat;
+                    timeout = self.get_random_election_timeout();
+                }
                 
                 // Check if we're a follower and haven't heard from leader
-                if state.role == ServerRole::Follower {
-                    let elapsed = state.last_heartbeat.elapsed();
-                    elapsed >= timeout
-                } else {
-                    false
-                }
+                state.role == ServerRole::Follower && cycle_start.elapsed() >= timeout
             }; // Lock is released here
 
             if should_start_election {
                 info!("[{}] Election timeout! Starting election.", self.config.server_id);
-                self.start_election().await;
+                self.start_election("election timeout").await;
             }
         }
     }
 
     /// Start a new election
-    async fn start_election(&self) {
-        let current_term = {
+    async fn start_election(self: &Arc<Self>, reason: &str) {
+        let (current_term, last_log_index, last_log_term) = {
             let mut state = self.state.lock().await;
             
             // Transition to candidate
-            state.role = ServerRole::Candidate;
             state.current_term += 1;
+            self.set_role(&mut state, ServerRole::Candidate, format_args!("{}", reason));
             state.voted_for = Some(self.config.server_id.clone());
             state.votes_received.clear();
             state.votes_received.insert(self.config.server_id.clone()); // Vote for self
-            
+            state.last_heartbeat = Instant::now();
+
+            self.persist(&state);
+
             let current_term = state.current_term;
             info!("[{}] Starting election for term {}", self.config.server_id, current_term);
-            current_term
+            (current_term, state.last_log_index(), state.last_log_term())
         }; // Lock released here
 
-        // Request votes from all peers
+        // Request votes from all voting peers
         let mut vote_count = 1; // We already voted for ourselves
-        let majority = (self.config.peers.len() + 1) / 2 + 1;
+        let majority = self.majority();
+        if vote_count >= majority {
+            // Sole voter: our own vote is the majority, there is no one to ask
+            self.become_leader(current_term).await;
+            return;
+        }
 
-        for peer_addr in &self.config.peers {
+        for peer_addr in self.voting_peers() {
             let vote_request = RaftMessage::RequestVote {
                 term: current_term,
                 candidate_id: self.config.server_id.clone(),
-                last_log_index: 0,
-                last_log_term: 0,
+                last_log_index,
+                last_log_term,
             };
 
             match self.send_raft_message(peer_addr, &vote_request).await {
@@ -131,8 +993,11 @@ impl RaftNode {
                         // Found a higher term, step down
                         let mut state = self.state.lock().await;
                         state.current_term = term;
-                        state.role = ServerRole::Follower;
+                        self.set_role(&mut state, self.follower_role(),
+                                      format_args!("vote response from {} at higher term", peer_addr));
                         state.voted_for = None;
+                        state.last_heartbeat = Instant::now();
+                        self.persist(&state);
                         info!("[{}] Stepping down due to higher term {}", self.config.server_id, term);
                         return;
                     }
@@ -143,7 +1008,7 @@ impl RaftNode {
                               self.config.server_id, voter_id, vote_count, majority);
                         
                         if vote_count >= majority {
-                            self.become_leader().await;
+                            self.become_leader(current_term).await;
                             return;
                         }
                     }
@@ -157,63 +1022,353 @@ impl RaftNode {
         let mut state = self.state.lock().await;
         if state.role == ServerRole::Candidate {
             info!("[{}] Election failed, returning to follower", self.config.server_id);
-            state.role = ServerRole::Follower;
+            self.set_role(&mut state, ServerRole::Follower, format_args!("election failed"));
         }
+        // Vote RPCs can take longer than the election timeout, so count the
+        // next timeout from when this election ended rather than when it began
+        state.last_heartbeat = Instant::now();
     }
 
-    /// Become the leader
-    async fn become_leader(&self) {
-        let mut state = self.state.lock().await;
-        state.role = ServerRole::Leader;
-        state.leader_id = Some(self.config.server_id.clone());
-        info!("[{}] BECAME LEADER for term {}", self.config.server_id, state.current_term);
+    /// Become the leader for `election_term` and append a no-op entry for it.
+    /// Entries from earlier terms can't be committed by counting replicas, only
+    /// by committing a later entry of our own, so without it they would wait
+    /// for the next client request. Votes are collected without holding the
+    /// lock, so by the time they add up we may have stepped down or moved to
+    /// a later term; then the votes are stale and nothing changes.
+    async fn become_leader(self: &Arc<Self>, election_term: u64) {
+        {
+            let mut state = self.state.lock().await;
+            if state.role != ServerRole::Candidate || state.current_term != election_term {
+                info!("[{}] Ignoring majority for term {}: now {:?} at term {}",
+                      self.config.server_id, election_term, state.role, state.current_term);
+                return;
+            }
+            self.set_role(&mut state, ServerRole::Leader, format_args!("won election"));
+            state.leader_id = Some(self.config.server_id.clone());
+            state.leader_addr = self.config.advertised_addr.clone();
+
+            // Start replication optimistically from the end of our log
+            let next = state.last_log_index() + 1;
+            for peer_addr in &self.config.peers {
+                state.next_index.insert(peer_addr.clone(), next);
+                state.match_index.insert(peer_addr.clone(), 0);
+            }
+
+            let term = state.current_term;
+            state.log.push(LogEntry { term, command: NOOP_COMMAND.to_string() });
+            self.persist(&state);
+            self.advance_commit_index(&mut state);
+
+            info!("[{}] BECAME LEADER for term {} (no-op at index {})",
+                  self.config.server_id, term, state.last_log_index());
+        } // Lock released here
+
+        for peer_addr in &self.config.peers {
+            let node = Arc::clone(self);
+            let peer = peer_addr.clone();
+            tokio::spawn(async move {
+                node.replicate_to_peer(&peer).await;
+            });
+        }
     }
 
-    /// Send heartbeats periodically if we're the leader
-    async fn run_heartbeat_sender(&self) {
+    /// Send heartbeats (empty or catch-up AppendEntries) periodically if we're the leader
+    async fn run_heartbeat_sender(self: Arc<Self>) {
         loop {
             sleep(Duration::from_millis(self.config.heartbeat_interval)).await;
 
-            let (is_leader, current_term) = {
-                let state = self.state.lock().await;
-                (state.role == ServerRole::Leader, state.current_term)
-            }; // Lock released here
-
-            if !is_leader {
+            if !self.is_leader().await {
                 continue;
             }
 
-            let leader_id = self.config.server_id.clone();
-
-            // Send heartbeats to all peers
+            // Contact peers in parallel so one dead peer doesn't delay the others
             for peer_addr in &self.config.peers {
-                let heartbeat = RaftMessage::Heartbeat {
-                    term: current_term,
-                    leader_id: leader_id.clone(),
-                };
-
-                let _ = self.send_raft_message(peer_addr, &heartbeat).await;
+                let node = Arc::clone(&self);
+                let peer = peer_addr.clone();
+                tokio::spawn(async move {
+                    node.replicate_to_peer(&peer).await;
+                });
             }
         }
     }
 
-    /// Handle incoming Raft messages
-    pub async fn handle_raft_message(&self, message: RaftMessage) -> Option<RaftMessage> {
-        match message {
-            RaftMessage::RequestVote { term, candidate_id, .. } => {
-                let mut state = self.state.lock().await;
+    /// Send an AppendEntries to every peer as soon as commit_index advances,
+    /// so followers learn of the commit (and apply it) without waiting up to a
+    /// heartbeat interval. Advances made while a round is going out are
+    /// announced by one more round rather than one each.
+    async fn run_commit_announcer(self: Arc<Self>) {
+        loop {
+            self.commit_to_announce.notified().await;
 
-                // If term is higher, update and step down
-                if term > state.current_term {
+            if !self.is_leader().await {
+                continue;
+            }
+
+            for peer_addr in &self.config.peers {
+                let node = Arc::clone(&self);
+                let peer = peer_addr.clone();
+                tokio::spawn(async move {
+                    node.replicate_to_peer(&peer).await;
+                });
+            }
+        }
+    }
+
+    /// Append a command to the leader's log and replicate it to every peer
+    /// once. Waits for that round to finish, so a peer that doesn't answer
+    /// holds it up for as long as its RPC timeout (unless its breaker is open).
+    pub async fn propose_entry(self: &Arc<Self>, command: String) -> Result<Proposal> {
+        let (index, term) = {
+            let mut state = self.state.lock().await;
+            if state.role != ServerRole::Leader {
+                bail!("Not the leader");
+            }
+
+            let term = state.current_term;
+            state.log.push(LogEntry { term, command });
+            self.persist(&state);
+            let index = state.last_log_index();
+
+            // With no other voters the entry is committed as soon as it's appended
+            self.advance_commit_index(&mut state);
+            (index, term)
+        };
+
+        // Spawned rather than joined in place, so the round completes even if the caller is dropped
+        let rounds: Vec<(String, JoinHandle<bool>)> = self
+            .config
+            .peers
+            .iter()
+            .map(|peer_addr| {
+                let node = Arc::clone(self);
+                let peer = peer_addr.clone();
+                (peer_addr.clone(), tokio::spawn(async move { node.replicate_to_peer(&peer).await }))
+            })
+            .collect();
+
+        let mut replicated = 0;
+        for (peer_addr, round) in rounds {
+            if round.await.unwrap_or(false) && !self.config.learners.contains(&peer_addr) {
+                replicated += 1;
+            }
+        }
+
+        let state = self.state.lock().await;
+        let committed = state.commit_index >= index && state.log.get(index as usize).map(|e| e.term) == Some(term);
+        debug!("[{}] Entry {} replicated to {} of {} voting peers (committed: {})",
+               self.config.server_id, index, replicated, self.voting_peers().count(), committed);
+
+        Ok(Proposal { index, term, replicated, committed })
+    }
+
+    /// Propose a command and wait until it is committed on a majority and applied.
+    /// Returns the applied index, or None if it wasn't committed within `wait`
+    /// (lost quorum) or we stopped being leader for the term it was appended in.
+    pub async fn propose_and_wait(self: &Arc<Self>, command: String, wait: Duration) -> Result<Option<u64>> {
+        let deadline = tokio::time::Instant::now() + wait;
+        let Proposal { index, term, .. } = self.propose_entry(command).await?;
+
+        loop {
+            // Registered before checking, so an apply or step-down in between isn't missed
+            let advanced = self.commit_advanced.notified();
+            tokio::pin!(advanced);
+            advanced.as_mut().enable();
+
+            {
+                let state = self.state.lock().await;
+                if state.last_applied >= index && state.log.get(index as usize).map(|e| e.term) == Some(term) {
+                    return Ok(Some(index));
+                }
+                if state.role != ServerRole::Leader || state.current_term != term {
+                    return Ok(None);
+                }
+            } // Lock released here
+
+            if tokio::time::timeout_at(deadline, advanced).await.is_err() {
+                return Ok(None);
+            }
+        }
+    }
+
+    /// Wait until `index` is committed on this node, as leader or follower.
+    /// Returns false if it wasn't committed within `wait`. Wakes on each advance
+    /// of commit_index rather than polling, so pair it with the index returned
+    /// by `propose_entry` when its round didn't already commit the entry.
+    pub async fn wait_for_commit(&self, index: u64, wait: Duration) -> Result<bool> {
+        let deadline = tokio::time::Instant::now() + wait;
+        loop {
+            // Registered before checking, so an advance in between isn't missed
+            let committed = self.commit_advanced.notified();
+            tokio::pin!(committed);
+            committed.as_mut().enable();
+
+            if self.state.lock().await.commit_index >= index {
+                return Ok(true);
+            }
+            if tokio::time::timeout_at(deadline, committed).await.is_err() {
+                return Ok(false);
+            }
+        }
+    }
+
+    /// Keep sending AppendEntries to a peer until it has caught up or an RPC fails.
+    /// Returns true if the peer accepted everything up to the end of our log.
+    async fn replicate_to_peer(&self, peer_addr: &str) -> bool {
+        loop {
+            match self.send_append_entries(peer_addr).await {
+                Ok(true) => {
+                    let state = self.state.lock().await;
+                    let next = state.next_index.get(peer_addr).copied().unwrap_or(1);
+                    if state.role != ServerRole::Leader {
+                        return false;
+                    }
+                    if next > state.last_log_index() {
+                        return true;
+                    }
+                }
+                Ok(false) => {
+                    // Rejected on a log mismatch: retry with the lowered next_index
+                    if !self.is_leader().await {
+                        return false;
+                    }
+                }
+                Err(e) => {
+                    debug!("[{}] AppendEntries to {} failed: {}", self.config.server_id, peer_addr, e);
+                    return false;
+                }
+            }
+        }
+    }
+
+    /// Send one AppendEntries RPC to a peer and process the response.
+    /// Returns true if the peer accepted the entries.
+    async fn send_append_entries(&self, peer_addr: &str) -> Result<bool> {
+        let (request, term, sent_up_to) = {
+            let state = self.state.lock().await;
+            if state.role != ServerRole::Leader {
+                return Ok(false);
+            }
+
+            let last_index = state.last_log_index();
+            let next = state
+                .next_index
+                .get(peer_addr)
+                .copied()
+                .unwrap_or(last_index + 1)
+                .clamp(1, last_index + 1);
+            let prev_log_index = next - 1;
+            let count = entries_within_budget(&state.log[next as usize..], self.config.max_rpc_bytes);
+            let entries: Vec<LogEntry> = state.log[next as usize..next as usize + count].to_vec();
+            let sent_up_to = prev_log_index + entries.len() as u64;
+
+            let request = RaftMessage::AppendEntries {
+                term: state.current_term,
+                leader_id: self.config.server_id.clone(),
+                prev_log_index,
+                prev_log_term: state.log[prev_log_index as usize].term,
+                entries,
+                leader_commit: state.commit_index,
+                leader_addr: self.config.advertised_addr.clone(),
+            };
+            (request, state.current_term, sent_up_to)
+        }; // Lock released here
+
+        let response = self.send_raft_message(peer_addr, &request).await?;
+
+        let mut state = self.state.lock().await;
+        match response {
+            Some(RaftMessage::AppendEntriesResponse { term: resp_term, success, match_index, .. }) => {
+                if resp_term > state.current_term {
+                    info!("[{}] Stepping down: {} is at higher term {}",
+                          self.config.server_id, peer_addr, resp_term);
+                    state.current_term = resp_term;
+                    self.set_role(&mut state, self.follower_role(),
+                                  format_args!("AppendEntries response from {} at higher term", peer_addr));
+                    state.voted_for = None;
+                    state.leader_id = None;
+                    state.leader_addr = None;
+                    self.persist(&state);
+                    return Ok(false);
+                }
+
+                // Ignore responses that arrive after we lost leadership or changed term
+                if state.role != ServerRole::Leader || state.current_term != term {
+                    return Ok(false);
+                }
+
+                if success {
+                    let matched = state.match_index.entry(peer_addr.to_string()).or_insert(0);
+                    *matched = (*matched).max(sent_up_to);
+                    let matched = *matched;
+                    state.next_index.insert(peer_addr.to_string(), matched + 1);
+                    self.advance_commit_index(&mut state);
+                    Ok(true)
+                } else {
+                    // Back up, jumping straight past the end of the follower's log if it's shorter
+                    let next = state.next_index.get(peer_addr).copied().unwrap_or(1);
+                    let lowered = next.saturating_sub(1).min(match_index + 1).max(1);
+                    state.next_index.insert(peer_addr.to_string(), lowered);
+                    Ok(false)
+                }
+            }
+            _ => bail!("Unexpected response to AppendEntries from {}", peer_addr),
+        }
+    }
+
+    /// Advance commit_index to the highest current-term entry stored on a
+    /// majority of the voters. Learners' match_index is tracked but not counted.
+    fn advance_commit_index(&self, state: &mut RaftState) {
+        let majority = self.majority();
+
+        for index in (state.commit_index + 1..=state.last_log_index()).rev() {
+            // Only entries from the current term may be committed by counting replicas
+            if state.log[index as usize].term != state.current_term {
+                break;
+            }
+
+            let replicas = 1 + self
+                .voting_peers()
+                .filter(|peer| state.match_index.get(*peer).is_some_and(|&m| m >= index))
+                .count();
+            if replicas >= majority {
+                info!("[{}] Committed up to index {} ({} uncommitted)",
+                      self.config.server_id, index, state.last_log_index() - index);
+                state.commit_index = index;
+                self.commit_advanced.notify_waiters();
+                // Stores a permit if the announcer is busy, so this commit isn't missed
+                self.commit_to_announce.notify_one();
+                break;
+            }
+        }
+    }
+
+    /// Handle incoming Raft messages
+    pub async fn handle_raft_message(&self, message: RaftMessage) -> Option<RaftMessage> {
+        match message {
+            RaftMessage::RequestVote { term, candidate_id, last_log_index, last_log_term } => {
+                let mut state = self.state.lock().await;
+                let mut changed = false;
+
+                // If term is higher, update and step down
+                if term > state.current_term {
                     state.current_term = term;
                     state.voted_for = None;
-                    state.role = ServerRole::Follower;
+                    self.set_role(&mut state, self.follower_role(),
+                                  format_args!("RequestVote from {} at higher term", candidate_id));
+                    changed = true;
                 }
 
-                // Grant vote if we haven't voted or voted for this candidate
-                let vote_granted = if term == state.current_term && 
+                // Only vote for candidates whose log is at least as up to date as ours
+                let log_ok = last_log_term > state.last_log_term()
+                    || (last_log_term == state.last_log_term()
+                        && last_log_index >= state.last_log_index());
+
+                // Grant vote if we haven't voted or voted for this candidate.
+                // Learners never vote; candidates don't ask them, this covers a misconfigured one
+                let vote_granted = if !self.config.learner && term == state.current_term && log_ok &&
                                      (state.voted_for.is_none() || 
                                       state.voted_for.as_ref() == Some(&candidate_id)) {
+                    changed |= state.voted_for.is_none();
                     state.voted_for = Some(candidate_id.clone());
                     state.last_heartbeat = Instant::now();
                     info!("[{}] Granted vote to {} for term {}", 
@@ -223,22 +1378,29 @@ impl RaftNode {
                     false
                 };
 
+                // Term and vote must be on disk before the vote leaves this node
+                if changed {
+                    self.persist(&state);
+                }
+
                 Some(RaftMessage::RequestVoteResponse {
                     term: state.current_term,
                     vote_granted,
                     voter_id: self.config.server_id.clone(),
                 })
             }
-            RaftMessage::Heartbeat { term, leader_id } => {
+            RaftMessage::Heartbeat { term, leader_id, leader_addr } => {
                 let mut state = self.state.lock().await;
 
                 if term >= state.current_term {
                     if term > state.current_term {
                         state.current_term = term;
                         state.voted_for = None;
+                        self.persist(&state);
                     }
-                    state.role = ServerRole::Follower;
+                    self.set_role(&mut state, self.follower_role(), format_args!("heartbeat from leader {}", leader_id));
                     state.leader_id = Some(leader_id.clone());
+                    state.leader_addr = leader_addr;
                     state.last_heartbeat = Instant::now();
                 }
 
@@ -248,13 +1410,311 @@ impl RaftNode {
                     success: term >= state.current_term,
                 })
             }
+            RaftMessage::AppendEntries {
+                term,
+                leader_id,
+                prev_log_index,
+                prev_log_term,
+                entries,
+                leader_commit,
+                leader_addr,
+            } => {
+                let mut state = self.state.lock().await;
+
+                if term < state.current_term {
+                    return Some(RaftMessage::AppendEntriesResponse {
+                        term: state.current_term,
+                        follower_id: self.config.server_id.clone(),
+                        success: false,
+                        match_index: state.last_log_index(),
+                    });
+                }
+
+                if term > state.current_term {
+                    state.current_term = term;
+                    state.voted_for = None;
+                    self.persist(&state);
+                }
+                self.set_role(&mut state, self.follower_role(), format_args!("AppendEntries from leader {}", leader_id));
+                state.leader_id = Some(leader_id);
+                state.leader_addr = leader_addr;
+                state.last_heartbeat = Instant::now();
+
+                // Our log must contain the entry just before the new ones
+                let consistent = prev_log_index <= state.last_log_index()
+                    && state.log[prev_log_index as usize].term == prev_log_term;
+                if !consistent {
+                    return Some(RaftMessage::AppendEntriesResponse {
+                        term: state.current_term,
+                        follower_id: self.config.server_id.clone(),
+                        success: false,
+                        match_index: state.last_log_index().min(prev_log_index.saturating_sub(1)),
+                    });
+                }
+
+                // Append new entries, dropping any conflicting suffix. Entries past
+                // the last one sent are left alone when nothing conflicts: they may be
+                // stale leftovers from an old term, but this RPC can't tell them apart
+                // from newer entries it was reordered behind. A stale suffix is dropped
+                // once the leader sends its own entry at that index, and is never
+                // committed before then since commit_index stops at match_index.
+                let match_index = prev_log_index + entries.len() as u64;
+                let mut log_changed = false;
+                for (offset, entry) in entries.into_iter().enumerate() {
+                    let index = prev_log_index as usize + 1 + offset;
+                    if index < state.log.len() {
+                        if state.log[index].term == entry.term {
+                            continue;
+                        }
+                        state.log.truncate(index);
+                    }
+                    state.log.push(entry);
+                    log_changed = true;
+                }
+                if log_changed {
+                    self.persist(&state);
+                }
+
+                // Never moves back: an RPC sent before an earlier commit announcement
+                // can arrive after it, with a lower match_index
+                let commit = leader_commit.min(match_index);
+                if commit > state.commit_index {
+                    state.commit_index = commit;
+                    self.commit_advanced.notify_waiters();
+                }
+
+                Some(RaftMessage::AppendEntriesResponse {
+                    term: state.current_term,
+                    follower_id: self.config.server_id.clone(),
+                    success: true,
+                    match_index,
+                })
+            }
+            RaftMessage::Ping { nonce } => Some(RaftMessage::Pong { nonce }),
+            RaftMessage::LogDigest { up_to_index } => {
+                let state = self.state.lock().await;
+                Some(RaftMessage::LogDigestResponse {
+                    summary: self.log_summary(&state, up_to_index),
+                })
+            }
             _ => None,
         }
     }
 
-    /// Send a Raft message to a peer
+    /// Send a Raft message to a peer, giving up after RPC_TIMEOUT.
+    /// Fails immediately while the peer's circuit breaker is open.
     async fn send_raft_message(&self, peer_addr: &str, message: &RaftMessage) -> Result<Option<RaftMessage>> {
+        if !self.breaker_allows(peer_addr) {
+            bail!("Circuit open for {}, skipping RPC", peer_addr);
+        }
+
+        let result = match timeout(RPC_TIMEOUT, self.exchange_raft_message(peer_addr, message)).await {
+            Ok(result) => result,
+            Err(_) => Err(anyhow::anyhow!("RPC to {} timed out", peer_addr)),
+        };
+
+        self.record_rpc_result(peer_addr, result.is_ok());
+        result
+    }
+
+    /// How long an open breaker skips a peer before letting a probe through.
+    /// A peer that comes back restarts its election timer, and the leader's
+    /// next heartbeat after the cooldown is the probe, so the cooldown plus a
+    /// heartbeat interval must stay under the minimum election timeout or
+    /// the peer would start an election before hearing from the leader.
+    fn breaker_cooldown(&self) -> Duration {
+        Duration::from_millis((self.config.election_timeout_min - self.config.heartbeat_interval) / 2)
+    }
+
+    /// Whether an RPC to this peer may go out. Once the cooldown of an open
+    /// breaker ends, one probe is let through and the breaker re-arms so
+    /// concurrent callers keep skipping until the probe succeeds.
+    fn breaker_allows(&self, peer_addr: &str) -> bool {
+        let mut breakers = self.breakers.lock().unwrap_or_else(|e| e.into_inner());
+        let breaker = breakers.entry(peer_addr.to_string()).or_default();
+        match breaker.state() {
+            BreakerState::Closed => true,
+            BreakerState::Open => false,
+            BreakerState::HalfOpen => {
+                debug!("[{}] Probing {} after breaker cooldown", self.config.server_id, peer_addr);
+                breaker.open_until = Some(Instant::now() + self.breaker_cooldown());
+                true
+            }
+        }
+    }
+
+    fn record_rpc_result(&self, peer_addr: &str, success: bool) {
+        let mut breakers = self.breakers.lock().unwrap_or_else(|e| e.into_inner());
+        let breaker = breakers.entry(peer_addr.to_string()).or_default();
+
+        if success {
+            if breaker.open_until.is_some() {
+                info!("[{}] {} is reachable again, closing circuit", self.config.server_id, peer_addr);
+            }
+            *breaker = PeerBreaker { last_success: Some(Instant::now()), ..PeerBreaker::default() };
+            return;
+        }
+
+        breaker.consecutive_failures += 1;
+        if breaker.consecutive_failures >= BREAKER_FAILURE_THRESHOLD {
+            if breaker.open_until.is_none() {
+                info!("[{}] {} failed {} times in a row, opening circuit for {:?}",
+                      self.config.server_id, peer_addr, breaker.consecutive_failures, self.breaker_cooldown());
+            }
+            breaker.open_until = Some(Instant::now() + self.breaker_cooldown());
+        }
+    }
+
+    /// Breaker state, average ping round trip and (on the leader) replication
+    /// lag of every configured peer
+    fn peer_statuses(&self, state: &RaftState) -> Vec<PeerStatus> {
+        let breakers = self.breakers.lock().unwrap_or_else(|e| e.into_inner());
+        let rtt_samples = self.rtt_samples.lock().unwrap_or_else(|e| e.into_inner());
+        self.config
+            .peers
+            .iter()
+            .map(|peer_addr| {
+                let breaker = breakers.get(peer_addr);
+                let rtt_ms = rtt_samples
+                    .get(peer_addr)
+                    .filter(|samples| !samples.is_empty())
+                    .map(|samples| samples.iter().map(|rtt| rtt.as_secs_f64() * 1000.0).sum::<f64>() / samples.len() as f64);
+                // match_index moves as AppendEntriesResponses arrive, so this is current
+                // as of the last response; only the leader tracks it
+                let replication_lag = match state.role {
+                    ServerRole::Leader => {
+                        let matched = state.match_index.get(peer_addr).copied().unwrap_or(0);
+                        Some(state.last_log_index().saturating_sub(matched))
+                    }
+                    _ => None,
+                };
+                PeerStatus {
+                    learner: self.config.learners.contains(peer_addr),
+                    address: peer_addr.clone(),
+                    breaker: breaker.map(|b| b.state()).unwrap_or(BreakerState::Closed),
+                    consecutive_failures: breaker.map(|b| b.consecutive_failures).unwrap_or(0),
+                    rtt_ms,
+                    replication_lag,
+                }
+            })
+            .collect()
+    }
+
+    /// Measure the round trip to every peer with a few pings, keeping the
+    /// last RTT_WINDOW samples per peer for the average in `status`. Peers
+    /// that don't answer get no sample.
+    pub async fn ping_peers(self: &Arc<Self>) {
+        // Ping peers in parallel so one dead peer doesn't delay the others
+        let handles: Vec<JoinHandle<()>> = self
+            .config
+            .peers
+            .iter()
+            .map(|peer_addr| {
+                let node = Arc::clone(self);
+                let peer = peer_addr.clone();
+                tokio::spawn(async move {
+                    node.ping_peer(&peer).await;
+                })
+            })
+            .collect();
+
+        for handle in handles {
+            let _ = handle.await;
+        }
+    }
+
+    async fn ping_peer(&self, peer_addr: &str) {
+        for _ in 0..PINGS_PER_REQUEST {
+            let nonce = rand::random();
+            let sent = Instant::now();
+            let failure = match self.send_raft_message(peer_addr, &RaftMessage::Ping { nonce }).await {
+                Ok(Some(RaftMessage::Pong { nonce: echoed })) if echoed == nonce => {
+                    let mut rtt_samples = self.rtt_samples.lock().unwrap_or_else(|e| e.into_inner());
+                    let samples = rtt_samples.entry(peer_addr.to_string()).or_default();
+                    samples.push_back(sent.elapsed());
+                    if samples.len() > RTT_WINDOW {
+                        samples.pop_front();
+                    }
+                    continue;
+                }
+                Ok(other) => format!("unexpected reply {:?}", other),
+                Err(e) => e.to_string(),
+            };
+
+            // Don't keep reporting the latency of a peer that has stopped answering
+            debug!("[{}] Ping to {} failed: {}", self.config.server_id, peer_addr, failure);
+            self.rtt_samples.lock().unwrap_or_else(|e| e.into_inner()).remove(peer_addr);
+            return;
+        }
+    }
+
+    /// Summarize the log, hashing entries 0..=up_to_index if we have them all
+    fn log_summary(&self, state: &RaftState, up_to_index: u64) -> LogSummary {
+        let digest = state.log.get(..=up_to_index as usize).map(log_digest);
+
+        LogSummary {
+            server_id: self.config.server_id.clone(),
+            last_log_index: state.last_log_index(),
+            last_log_term: state.last_log_term(),
+            commit_index: state.commit_index,
+            up_to_index,
+            digest,
+        }
+    }
+
+    /// Compare our committed log prefix with every peer's log. Committed
+    /// entries must never differ between nodes, so any Diverged peer means a
+    /// replication bug or a damaged state file.
+    pub async fn verify_log_consistency(self: &Arc<Self>) -> LogConsistencyReport {
+        let checked_by = {
+            let state = self.state.lock().await;
+            self.log_summary(&state, state.commit_index)
+        };
+
+        // Ask peers in parallel so one dead peer doesn't delay the others
+        let up_to_index = checked_by.up_to_index;
+        let handles: Vec<JoinHandle<Result<Option<RaftMessage>>>> = self
+            .config
+            .peers
+            .iter()
+            .map(|peer_addr| {
+                let node = Arc::clone(self);
+                let peer = peer_addr.clone();
+                tokio::spawn(async move {
+                    node.send_raft_message(&peer, &RaftMessage::LogDigest { up_to_index }).await
+                })
+            })
+            .collect();
+
+        let mut peers = Vec::with_capacity(handles.len());
+        for (peer_addr, handle) in self.config.peers.iter().zip(handles) {
+            let summary = match handle.await {
+                Ok(Ok(Some(RaftMessage::LogDigestResponse { summary }))) => Some(summary),
+                _ => None,
+            };
+            let verdict = match &summary {
+                None => LogVerdict::Unreachable,
+                Some(summary) => match &summary.digest {
+                    None => LogVerdict::Behind,
+                    Some(digest) if Some(digest) == checked_by.digest.as_ref() => LogVerdict::Match,
+                    Some(_) => LogVerdict::Diverged,
+                },
+            };
+            if verdict == LogVerdict::Diverged {
+                warn!("[{}] {}'s log differs from ours within the first {} entries",
+                      self.config.server_id, peer_addr, up_to_index);
+            }
+            peers.push(PeerLogCheck { address: peer_addr.clone(), verdict, summary });
+        }
+
+        LogConsistencyReport { checked_by, peers }
+    }
+
+    async fn exchange_raft_message(&self, peer_addr: &str, message: &RaftMessage) -> Result<Option<RaftMessage>> {
         let mut stream = TcpStream::connect(peer_addr).await?;
+        if self.config.single_port {
+            stream.write_u8(MUX_RAFT).await?;
+        }
         
         // Serialize and send message
         let msg_json = serde_json::to_string(message)?;
@@ -272,11 +1732,12 @@ impl RaftNode {
         Ok(Some(response))
     }
 
-    /// Get random election timeout
+    /// Get random election timeout, drawn from the node's seeded RNG
     fn get_random_election_timeout(&self) -> Duration {
-        let mut rng = rand::thread_rng();
-        let timeout_ms = rng.gen_range(
-            self.config.election_timeout_min..=self.config.election_timeout_max
+        let timeout_ms = self.config.election_timeout_distribution.sample(
+            &mut *self.rng.lock().unwrap(),
+            self.config.election_timeout_min,
+            self.config.election_timeout_max,
         );
         Duration::from_millis(timeout_ms)
     }
@@ -292,4 +1753,992 @@ impl RaftNode {
         let state = self.state.lock().await;
         state.leader_id.clone()
     }
-}
\ No newline at end of file
+
+    /// Get the current leader's client-facing address, if it advertised one
+    pub async fn get_leader_addr(&self) -> Option<String> {
+        let state = self.state.lock().await;
+        state.leader_addr.clone()
+    }
+
+    /// Get the current term
+    pub async fn get_current_term(&self) -> u64 {
+        let state = self.state.lock().await;
+        state.current_term
+    }
+
+    /// Get the highest log index known to be committed
+    pub async fn get_commit_index(&self) -> u64 {
+        let state = self.state.lock().await;
+        state.commit_index
+    }
+
+    /// Get the highest log index whose entry has been applied. Reads of
+    /// replicated state should go no further than this.
+    pub async fn get_last_applied(&self) -> u64 {
+        let state = self.state.lock().await;
+        state.last_applied
+    }
+
+    /// Whether this node has been in touch with a quorum within the last
+    /// election timeout. The leader counts itself and the voting peers that
+    /// answered an RPC in that time; any other node needs to have heard from a
+    /// leader. A leader cut off from its quorum can't commit anything.
+    pub async fn quorum_contact(&self) -> bool {
+        let window = Duration::from_millis(self.config.election_timeout_max);
+        let state = self.state.lock().await;
+        if state.role != ServerRole::Leader {
+            return state.leader_id.is_some() && state.last_heartbeat.elapsed() < window;
+        }
+        let breakers = self.breakers.lock().unwrap_or_else(|e| e.into_inner());
+        let answered = self
+            .voting_peers()
+            .filter(|peer| {
+                breakers
+                    .get(*peer)
+                    .and_then(|breaker| breaker.last_success)
+                    .is_some_and(|at| at.elapsed() < window)
+            })
+            .count();
+        answered + 1 >= self.majority()
+    }
+
+    /// Snapshot of this node's Raft state for the status endpoint
+    pub async fn status(&self) -> RaftStatus {
+        let state = self.state.lock().await;
+        RaftStatus {
+            server_id: self.config.server_id.clone(),
+            role: state.role,
+            current_term: state.current_term,
+            leader_id: state.leader_id.clone(),
+            leader_addr: state.leader_addr.clone(),
+            commit_index: state.commit_index,
+            last_applied: state.last_applied,
+            last_log_index: state.last_log_index(),
+            peers: self.peer_statuses(&state),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::NodeHealth;
+    use std::sync::atomic::AtomicUsize;
+    use tokio::net::TcpListener;
+
+    /// A scratch data directory, removed when dropped
+    struct TestDir(PathBuf);
+
+    impl TestDir {
+        fn new(name: &str) -> Self {
+            let path = std::env::temp_dir().join(format!("raft-test-{}-{}", name, std::process::id()));
+            let _ = fs::remove_dir_all(&path);
+            fs::create_dir_all(&path).unwrap();
+            Self(path)
+        }
+    }
+
+    impl Drop for TestDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn test_config(server_id: &str, peers: Vec<String>, dir: &TestDir) -> RaftConfig {
+        RaftConfig {
+            server_id: server_id.to_string(),
+            peers,
+            election_timeout_min: 150,
+            election_timeout_max: 300,
+            election_timeout_distribution: TimeoutDistribution::Uniform,
+            heartbeat_interval: 50,
+            election_tick: 10,
+            data_dir: dir.0.clone(),
+            advertised_addr: None,
+            election_seed: Some(1),
+            max_rpc_bytes: DEFAULT_MAX_RPC_BYTES,
+            single_port: false,
+            learner: false,
+            learners: Vec::new(),
+            trace_roles: false,
+            trace_file: None,
+            initial_leader: None,
+        }
+    }
+
+    /// An address nothing listens on, so RPCs to it fail at once
+    async fn dead_peer() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        listener.local_addr().unwrap().to_string()
+    }
+
+    /// A peer answering each Raft RPC with whatever `respond` returns
+    async fn fake_peer<F, Fut>(respond: F) -> String
+    where
+        F: Fn(RaftMessage) -> Fut + Send + Sync + 'static,
+        Fut: std::future::Future<Output = RaftMessage> + Send,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let respond = Arc::new(respond);
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let respond = Arc::clone(&respond);
+                tokio::spawn(async move {
+                    let len = stream.read_u32().await.unwrap();
+                    let mut buf = vec![0u8; len as usize];
+                    stream.read_exact(&mut buf).await.unwrap();
+                    let reply = respond(serde_json::from_slice(&buf).unwrap()).await;
+                    let json = serde_json::to_vec(&reply).unwrap();
+                    stream.write_u32(json.len() as u32).await.unwrap();
+                    stream.write_all(&json).await.unwrap();
+                });
+            }
+        });
+        addr
+    }
+
+    /// Address of a peer whose Raft RPCs are answered by `node`
+    async fn serve(node: Arc<RaftNode>) -> String {
+        fake_peer(move |message| {
+            let node = Arc::clone(&node);
+            async move { node.handle_raft_message(message).await.expect("no reply") }
+        })
+        .await
+    }
+
+    fn entry(term: u64, command: &str) -> LogEntry {
+        LogEntry { term, command: command.to_string() }
+    }
+
+    #[tokio::test]
+    async fn become_leader_ignores_a_majority_from_an_earlier_term() {
+        let dir = TestDir::new("stale-majority");
+        let node = Arc::new(RaftNode::new(test_config("n1", vec![dead_peer().await], &dir)).unwrap());
+        {
+            let mut state = node.state.lock().await;
+            state.current_term = 3;
+            state.role = ServerRole::Candidate;
+        }
+
+        node.become_leader(2).await;
+        assert_eq!(node.state.lock().await.role, ServerRole::Candidate);
+
+        node.become_leader(3).await;
+        let state = node.state.lock().await;
+        assert_eq!(state.role, ServerRole::Leader);
+        assert_eq!(state.leader_id.as_deref(), Some("n1"));
+    }
+
+    #[tokio::test]
+    async fn late_vote_after_stepping_down_appends_no_noop() {
+        let dir = TestDir::new("late-vote");
+        let (asked_tx, mut asked) = tokio::sync::mpsc::unbounded_channel();
+        let release = Arc::new(Notify::new());
+        let voter = fake_peer({
+            let release = Arc::clone(&release);
+            move |message| {
+                let (asked_tx, release) = (asked_tx.clone(), Arc::clone(&release));
+                async move {
+                    let RaftMessage::RequestVote { term, .. } = message else {
+                        panic!("unexpected {:?}", message);
+                    };
+                    asked_tx.send(term).unwrap();
+                    release.notified().await;
+                    RaftMessage::RequestVoteResponse { term, vote_granted: true, voter_id: "n2".to_string() }
+                }
+            }
+        })
+        .await;
+        let node = Arc::new(RaftNode::new(test_config("n1", vec![voter, dead_peer().await], &dir)).unwrap());
+
+        let election = tokio::spawn({
+            let node = Arc::clone(&node);
+            async move { node.start_election("test").await }
+        });
+        assert_eq!(asked.recv().await, Some(1));
+
+        // While the vote is in flight, a leader of a later term takes over
+        let reply = node
+            .handle_raft_message(RaftMessage::AppendEntries {
+                term: 2,
+                leader_id: "n3".to_string(),
+                prev_log_index: 0,
+                prev_log_term: INIT_TERM,
+                entries: Vec::new(),
+                leader_commit: 0,
+                leader_addr: None,
+            })
+            .await;
+        assert!(matches!(reply, Some(RaftMessage::AppendEntriesResponse { success: true, .. })));
+
+        release.notify_one();
+        election.await.unwrap();
+
+        let state = node.state.lock().await;
+        assert_eq!(state.role, ServerRole::Follower);
+        assert_eq!(state.current_term, 2);
+        assert_eq!(state.leader_id.as_deref(), Some("n3"));
+        assert_eq!(state.log, vec![init_entry()]);
+    }
+
+    #[tokio::test]
+    async fn open_breaker_probes_again_before_an_election_timeout() {
+        let dir = TestDir::new("breaker-cooldown");
+        let peer = dead_peer().await;
+        let node = RaftNode::new(test_config("n1", vec![peer.clone()], &dir)).unwrap();
+        let config = &node.config;
+        assert!(node.breaker_cooldown() + Duration::from_millis(config.heartbeat_interval)
+            < Duration::from_millis(config.election_timeout_min));
+
+        for _ in 0..BREAKER_FAILURE_THRESHOLD {
+            assert!(node.breaker_allows(&peer));
+            node.record_rpc_result(&peer, false);
+        }
+        assert!(!node.breaker_allows(&peer));
+
+        sleep(node.breaker_cooldown()).await;
+        assert!(node.breaker_allows(&peer), "the probe goes out once the cooldown ends");
+        assert!(!node.breaker_allows(&peer), "only one probe at a time");
+        node.record_rpc_result(&peer, true);
+        assert!(node.breaker_allows(&peer));
+    }
+
+    #[test]
+    fn state_fixture_decodes_and_reencodes_to_the_same_bytes() {
+        // Written by node n1 before the bincode settings were pinned
+        const STATE_FIXTURE: &[u8] = include_bytes!("../tests/fixtures/raft_state.bin");
+
+        let saved: PersistentState = from_bincode(STATE_FIXTURE).unwrap();
+        assert_eq!(saved.current_term, 1);
+        assert_eq!(saved.voted_for.as_deref(), Some("n3"));
+        assert_eq!(saved.log.len(), 3);
+        assert_eq!(saved.log[1], entry(1, NOOP_COMMAND));
+        assert!(saved.log[2].command.starts_with("encrypt:"));
+        check_init_entry(&saved.log, std::path::Path::new("fixture")).unwrap();
+
+        assert_eq!(to_bincode(&saved).unwrap(), STATE_FIXTURE);
+    }
+
+    #[tokio::test]
+    async fn append_entries_replaces_a_stale_suffix_and_keeps_committed_entries() {
+        let dir = TestDir::new("stale-suffix");
+        let node = RaftNode::new(test_config("n2", vec![dead_peer().await], &dir)).unwrap();
+        {
+            // Entries 3 and 4 came from a term-2 leader that lost leadership before committing them
+            let mut state = node.state.lock().await;
+            state.current_term = 2;
+            state.log.extend([entry(1, "a"), entry(1, "b"), entry(2, "stale-c"), entry(2, "stale-d")]);
+            state.commit_index = 2;
+        }
+
+        let reply = node
+            .handle_raft_message(RaftMessage::AppendEntries {
+                term: 3,
+                leader_id: "n1".to_string(),
+                prev_log_index: 2,
+                prev_log_term: 1,
+                entries: vec![entry(3, "c")],
+                leader_commit: 3,
+                leader_addr: None,
+            })
+            .await;
+        assert!(matches!(reply, Some(RaftMessage::AppendEntriesResponse { success: true, match_index: 3, .. })));
+
+        let state = node.state.lock().await;
+        assert_eq!(state.log, vec![init_entry(), entry(1, "a"), entry(1, "b"), entry(3, "c")]);
+        assert_eq!(state.commit_index, 3);
+        assert_eq!(state.current_term, 3);
+
+        let (saved, _) = RaftNode::load_state(&dir.0, "n2").unwrap().unwrap();
+        assert_eq!(saved.log, state.log);
+    }
+
+    fn saved_state(term: u64) -> PersistentState {
+        PersistentState { current_term: term, voted_for: Some("n1".to_string()), log: vec![init_entry(), entry(term, "x")] }
+    }
+
+    #[test]
+    fn corrupt_state_file_falls_back_to_the_backup() {
+        let dir = TestDir::new("backup-fallback");
+        let path = RaftNode::state_file_in(&dir.0, "n1");
+        RaftNode::write_state_file(&path, &saved_state(1)).unwrap();
+        RaftNode::write_state_file(&path, &saved_state(2)).unwrap();
+        fs::write(&path, b"not a state file").unwrap();
+
+        let (saved, from) = RaftNode::load_state(&dir.0, "n1").unwrap().unwrap();
+        assert_eq!(saved.current_term, 1);
+        assert_eq!(from, RaftNode::backup_path(&path));
+
+        // The damaged file is replaced, so the next persist doesn't rotate it into the backup slot
+        assert_eq!(fs::read(&path).unwrap(), fs::read(RaftNode::backup_path(&path)).unwrap());
+        let node = RaftNode::new(test_config("n1", Vec::new(), &dir)).unwrap();
+        assert_eq!(node.state.try_lock().unwrap().current_term, 1);
+    }
+
+    #[test]
+    fn corrupt_state_file_and_backup_refuse_to_start() {
+        let dir = TestDir::new("both-corrupt");
+        let path = RaftNode::state_file_in(&dir.0, "n1");
+        fs::write(&path, b"not a state file").unwrap();
+        fs::write(RaftNode::backup_path(&path), b"nor is this").unwrap();
+
+        let error = RaftNode::load_state(&dir.0, "n1").err().expect("both copies are unusable");
+        assert!(error.to_string().contains("both unusable"), "{}", error);
+        assert!(RaftNode::new(test_config("n1", Vec::new(), &dir)).is_err());
+
+        // Nothing was overwritten while failing
+        assert_eq!(fs::read(&path).unwrap(), b"not a state file");
+    }
+
+    #[test]
+    fn corrupt_state_file_without_backup_refuses_to_start() {
+        let dir = TestDir::new("no-backup");
+        fs::write(RaftNode::state_file_in(&dir.0, "n1"), b"not a state file").unwrap();
+
+        let error = RaftNode::load_state(&dir.0, "n1").err().expect("there is no backup");
+        assert!(error.to_string().contains("no backup"), "{}", error);
+    }
+
+    /// An empty AppendEntries from `leader_id`, as a heartbeat
+    fn heartbeat(term: u64, leader_id: &str) -> RaftMessage {
+        RaftMessage::AppendEntries {
+            term,
+            leader_id: leader_id.to_string(),
+            prev_log_index: 0,
+            prev_log_term: INIT_TERM,
+            entries: Vec::new(),
+            leader_commit: 0,
+            leader_addr: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn election_timer_waits_for_heartbeats_to_stop() {
+        let dir = TestDir::new("election-timer");
+        let node = Arc::new(RaftNode::new(test_config("n2", vec![dead_peer().await], &dir)).unwrap());
+        let timer = AbortOnDrop(tokio::spawn({
+            let node = Arc::clone(&node);
+            async move { node.run_election_timer().await }
+        }));
+
+        // Heartbeats well inside the timeout, for longer than the largest timeout
+        for _ in 0..10 {
+            node.handle_raft_message(heartbeat(1, "n1")).await;
+            sleep(Duration::from_millis(node.config.heartbeat_interval)).await;
+        }
+        assert_eq!(node.get_current_term().await, 1, "no election while the leader is heard from");
+
+        // Silence: the timer fires within a timeout plus a tick
+        let max_wait = Duration::from_millis(node.config.election_timeout_max + node.config.election_tick * 3);
+        sleep(max_wait).await;
+        assert!(node.get_current_term().await > 1, "an election starts once heartbeats stop");
+        drop(timer);
+    }
+    #[tokio::test]
+    async fn failed_election_restarts_the_timeout_when_it_ends() {
+        let dir = TestDir::new("election-end");
+        let slow_voter = fake_peer(|message| async move {
+            let RaftMessage::RequestVote { term, .. } = message else {
+                panic!("unexpected {:?}", message);
+            };
+            sleep(Duration::from_millis(200)).await;
+            RaftMessage::RequestVoteResponse { term, vote_granted: false, voter_id: "n2".to_string() }
+        })
+        .await;
+        let node = Arc::new(RaftNode::new(test_config("n1", vec![slow_voter], &dir)).unwrap());
+
+        let started = Instant::now();
+        node.start_election("test").await;
+        let ended = Instant::now();
+
+        let state = node.state.lock().await;
+        assert_eq!(state.role, ServerRole::Follower);
+        assert!(ended - started >= Duration::from_millis(200));
+        assert!(state.last_heartbeat >= started + Duration::from_millis(200),
+                "the next timeout counts from the end of the election, not its start");
+    }
+
+    /// A node whose log holds `entries` after the init entry
+    fn node_with_log(dir: &TestDir, server_id: &str, peers: Vec<String>, entries: Vec<LogEntry>) -> Arc<RaftNode> {
+        let node = RaftNode::new(test_config(server_id, peers, dir)).unwrap();
+        node.state.try_lock().unwrap().log.extend(entries);
+        Arc::new(node)
+    }
+
+    #[tokio::test]
+    async fn log_consistency_check_classifies_each_peer() {
+        let dir = TestDir::new("consistency");
+        let peers = vec![
+            serve(node_with_log(&dir, "same", Vec::new(), vec![entry(1, "a"), entry(1, "b"), entry(2, "uncommitted")])).await,
+            serve(node_with_log(&dir, "diverged", Vec::new(), vec![entry(1, "a"), entry(1, "x")])).await,
+            serve(node_with_log(&dir, "behind", Vec::new(), vec![entry(1, "a")])).await,
+            dead_peer().await,
+        ];
+        let node = node_with_log(&dir, "checker", peers.clone(), vec![entry(1, "a"), entry(1, "b")]);
+        node.state.lock().await.commit_index = 2;
+
+        let report = node.verify_log_consistency().await;
+        assert_eq!(report.checked_by.up_to_index, 2);
+        let verdicts: Vec<(String, LogVerdict)> =
+            report.peers.iter().map(|peer| (peer.address.clone(), peer.verdict)).collect();
+        assert_eq!(verdicts, vec![
+            (peers[0].clone(), LogVerdict::Match),
+            (peers[1].clone(), LogVerdict::Diverged),
+            (peers[2].clone(), LogVerdict::Behind),
+            (peers[3].clone(), LogVerdict::Unreachable),
+        ]);
+        assert!(!report.is_consistent());
+    }
+
+    #[test]
+    fn entries_within_budget_counts_serialized_bytes() {
+        let entries = vec![entry(1, "aaaa"), entry(1, "bbbb"), entry(1, "cccc")];
+        let one = serde_json::to_vec(&entries[0]).unwrap().len() + 1; // + separator
+
+        assert_eq!(entries_within_budget(&entries, one * 3), 3);
+        assert_eq!(entries_within_budget(&entries, one * 2), 2);
+        assert_eq!(entries_within_budget(&entries, one * 2 - 1), 1);
+        assert_eq!(entries_within_budget(&entries, 1), 1, "an entry over the budget still goes alone");
+        assert_eq!(entries_within_budget(&[], 1), 0);
+    }
+
+    #[tokio::test]
+    async fn replication_splits_the_log_into_rpcs_within_the_budget() {
+        let dir = TestDir::new("rpc-budget");
+        let follower = node_with_log(&dir, "follower", Vec::new(), Vec::new());
+        let received = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let follower_addr = fake_peer({
+            let (follower, received) = (Arc::clone(&follower), Arc::clone(&received));
+            move |message| {
+                let (follower, received) = (Arc::clone(&follower), Arc::clone(&received));
+                async move {
+                    if let RaftMessage::AppendEntries { entries, .. } = &message {
+                        received.lock().unwrap().push(entries.len());
+                    }
+                    follower.handle_raft_message(message).await.unwrap()
+                }
+            }
+        })
+        .await;
+
+        let entries: Vec<LogEntry> = (0..5).map(|i| entry(1, &format!("command-{}", i))).collect();
+        let mut config = test_config("leader", vec![follower_addr.clone()], &dir);
+        config.max_rpc_bytes = 2 * (serde_json::to_vec(&entries[0]).unwrap().len() + 1);
+        let leader = RaftNode::new(config).unwrap();
+        {
+            let mut state = leader.state.lock().await;
+            state.current_term = 1;
+            state.role = ServerRole::Leader;
+            state.log.extend(entries);
+            state.next_index.insert(follower_addr.clone(), 1);
+        }
+
+        assert!(leader.replicate_to_peer(&follower_addr).await);
+        assert_eq!(*received.lock().unwrap(), vec![2, 2, 1]);
+        assert_eq!(follower.state.lock().await.log, leader.state.lock().await.log);
+    }
+
+    #[test]
+    fn validate_rejects_inconsistent_configs() {
+        let dir = TestDir::new("validate");
+        let rejects = |change: &dyn Fn(&mut RaftConfig), expected: &str| {
+            let mut config = test_config("n1", vec!["n2".to_string(), "n3".to_string()], &dir);
+            change(&mut config);
+            let err = config.validate().unwrap_err().to_string();
+            assert!(err.contains(expected), "{:?} should mention {:?}", err, expected);
+        };
+
+        assert!(test_config("n1", Vec::new(), &dir).validate().is_ok());
+        rejects(&|c| c.election_timeout_min = 400, "greater than election_timeout_max");
+        rejects(&|c| c.heartbeat_interval = 150, "must be shorter than election_timeout_min");
+        rejects(&|c| c.learners = vec!["n4".to_string()], "learner n4 is not one of the peers");
+        rejects(&|c| {
+            c.learner = true;
+            c.initial_leader = Some("n1".to_string());
+        }, "can't be the initial leader");
+        assert!(RaftNode::new(test_config("n1", Vec::new(), &dir)).is_ok());
+        let mut config = test_config("n1", Vec::new(), &dir);
+        config.heartbeat_interval = 200;
+        assert!(RaftNode::new(config).is_err(), "new must validate the config");
+    }
+
+    #[tokio::test]
+    async fn learners_neither_vote_nor_count_toward_commits() {
+        let dir = TestDir::new("learners");
+        let peers = vec!["v2".to_string(), "v3".to_string(), "l4".to_string(), "l5".to_string()];
+        let mut config = test_config("v1", peers, &dir);
+        config.learners = vec!["l4".to_string(), "l5".to_string()];
+        assert_eq!((config.voters(), config.quorum(), config.fault_tolerance()), (3, 2, 1));
+
+        // A learner turns down every vote, even from an up-to-date candidate
+        let mut learner_config = test_config("l4", vec!["v1".to_string()], &dir);
+        learner_config.learner = true;
+        let learner = RaftNode::new(learner_config).unwrap();
+        assert_eq!(learner.state.lock().await.role, ServerRole::Learner);
+        let reply = learner
+            .handle_raft_message(RaftMessage::RequestVote {
+                term: 1,
+                candidate_id: "v1".to_string(),
+                last_log_index: 0,
+                last_log_term: INIT_TERM,
+            })
+            .await
+            .unwrap();
+        assert!(matches!(reply, RaftMessage::RequestVoteResponse { vote_granted: false, .. }));
+
+        // Both learners holding the entry is not a majority; one voter is
+        let leader = RaftNode::new(config).unwrap();
+        let mut state = leader.state.lock().await;
+        state.current_term = 1;
+        state.role = ServerRole::Leader;
+        state.log.push(entry(1, "set x"));
+        state.match_index.insert("l4".to_string(), 1);
+        state.match_index.insert("l5".to_string(), 1);
+        leader.advance_commit_index(&mut state);
+        assert_eq!(state.commit_index, 0);
+        state.match_index.insert("v3".to_string(), 1);
+        leader.advance_commit_index(&mut state);
+        assert_eq!(state.commit_index, 1);
+    }
+
+    #[test]
+    fn exported_state_imports_back_and_conflicts_need_force() {
+        let dir = TestDir::new("export-import");
+        let export = dir.0.join("n1-export.json");
+        RaftNode::write_state_file(&RaftNode::state_file_in(&dir.0, "n1"), &saved_state(2)).unwrap();
+        RaftNode::export_state(&dir.0, "n1", &export).unwrap();
+
+        // Into a fresh node: same term and log, but not n1's vote
+        RaftNode::import_state(&dir.0, "n2", &export, false).unwrap();
+        let (imported, _) = RaftNode::load_state(&dir.0, "n2").unwrap().unwrap();
+        assert_eq!((imported.current_term, imported.voted_for, imported.log), (2, None, saved_state(2).log));
+
+        // Back into n1 itself, which keeps its vote
+        RaftNode::import_state(&dir.0, "n1", &export, false).unwrap();
+        let (restored, _) = RaftNode::load_state(&dir.0, "n1").unwrap().unwrap();
+        assert_eq!((restored.current_term, restored.voted_for), (2, Some("n1".to_string())));
+
+        // A node at a later term would go back in time
+        RaftNode::write_state_file(&RaftNode::state_file_in(&dir.0, "n3"), &saved_state(3)).unwrap();
+        let err = RaftNode::import_state(&dir.0, "n3", &export, false).unwrap_err();
+        assert!(err.to_string().contains("this node is at term 3, the export at term 2"), "{}", err);
+        assert_eq!(RaftNode::load_state(&dir.0, "n3").unwrap().unwrap().0.current_term, 3);
+        RaftNode::import_state(&dir.0, "n3", &export, true).unwrap();
+        assert_eq!(RaftNode::load_state(&dir.0, "n3").unwrap().unwrap().0.current_term, 2);
+
+        // An edited log no longer matches the digest
+        let edited = fs::read_to_string(&export).unwrap().replace("\"x\"", "\"y\"");
+        fs::write(&export, edited).unwrap();
+        let err = RaftNode::import_state(&dir.0, "n4", &export, true).unwrap_err();
+        assert!(err.to_string().contains("is damaged"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn initial_leader_plan_only_applies_to_a_fresh_node() {
+        let dir = TestDir::new("initial-plan");
+        let plan = |server_id: &str, initial_leader: Option<&str>| {
+            let mut config = test_config(server_id, vec!["n2".to_string()], &dir);
+            config.initial_leader = initial_leader.map(str::to_string);
+            RaftNode::new(config).unwrap()
+        };
+
+        assert!(matches!(plan("n1", None).initial_leader_plan().await, FirstElection::Normal));
+        assert!(matches!(plan("n1", Some("n1")).initial_leader_plan().await, FirstElection::Campaign));
+        assert!(matches!(plan("n1", Some("n2")).initial_leader_plan().await, FirstElection::HoldOff));
+
+        let restarted = plan("n1", Some("n1"));
+        restarted.state.lock().await.current_term = 1;
+        assert!(matches!(restarted.initial_leader_plan().await, FirstElection::Normal));
+        let restarted = plan("n1", Some("n1"));
+        restarted.state.lock().await.log.push(entry(0, "x"));
+        assert!(matches!(restarted.initial_leader_plan().await, FirstElection::Normal));
+    }
+
+    #[tokio::test]
+    async fn initial_leader_campaigns_without_waiting_for_a_timeout() {
+        let dir = TestDir::new("initial-campaign");
+        let voter = serve(node_with_log(&dir, "n2", Vec::new(), Vec::new())).await;
+        let node = Arc::new(RaftNode::new(test_config("n1", vec![voter], &dir)).unwrap());
+
+        let started = Instant::now();
+        node.campaign_as_initial_leader().await;
+        assert!(started.elapsed() < Duration::from_millis(node.config.election_timeout_min));
+        let state = node.state.lock().await;
+        assert_eq!((state.role, state.current_term), (ServerRole::Leader, 1));
+    }
+
+    #[tokio::test]
+    async fn initial_leader_campaign_stops_at_a_peer_past_its_term() {
+        let dir = TestDir::new("initial-not-fresh");
+        let asked = Arc::new(AtomicUsize::new(0));
+        let peer = fake_peer({
+            let asked = Arc::clone(&asked);
+            move |_| {
+                asked.fetch_add(1, Ordering::SeqCst);
+                async { RaftMessage::RequestVoteResponse { term: 5, vote_granted: false, voter_id: "n2".to_string() } }
+            }
+        })
+        .await;
+        let node = Arc::new(RaftNode::new(test_config("n1", vec![peer], &dir)).unwrap());
+
+        node.campaign_as_initial_leader().await;
+        let state = node.state.lock().await;
+        assert_eq!((state.role, state.current_term), (ServerRole::Follower, 5));
+        assert_eq!(asked.load(Ordering::SeqCst), 1, "no second election once the cluster isn't fresh");
+    }
+
+    #[test]
+    fn timeout_distributions_stay_in_range_and_parse_back() {
+        let mut rng = StdRng::seed_from_u64(7);
+        for distribution in [TimeoutDistribution::Uniform, TimeoutDistribution::Exponential] {
+            let draws: Vec<u64> = (0..3000).map(|_| distribution.sample(&mut rng, 150, 300)).collect();
+            assert!(draws.iter().all(|d| (150..=300).contains(d)), "{} left the range", distribution);
+            assert_eq!(distribution.sample(&mut rng, 200, 200), 200);
+
+            // Exponential puts about two thirds in the lower third, uniform one third
+            let lower_third = draws.iter().filter(|&&d| d < 200).count() as f64 / draws.len() as f64;
+            let expected = match distribution {
+                TimeoutDistribution::Uniform => 1.0 / 3.0,
+                TimeoutDistribution::Exponential => 0.65,
+            };
+            assert!((lower_third - expected).abs() < 0.05, "{}: {} in the lower third", distribution, lower_third);
+
+            assert_eq!(distribution.to_string().parse::<TimeoutDistribution>(), Ok(distribution));
+        }
+        assert_eq!("Exponential".parse(), Ok(TimeoutDistribution::Exponential));
+        assert!("normal".parse::<TimeoutDistribution>().unwrap_err().contains("uniform or exponential"));
+    }
+
+    #[tokio::test]
+    async fn supervise_restarts_a_panicking_task_until_aborted() {
+        /// Sets its flag when the task holding it is dropped
+        struct Dropped(Arc<std::sync::atomic::AtomicBool>);
+        impl Drop for Dropped {
+            fn drop(&mut self) {
+                self.0.store(true, Ordering::SeqCst);
+            }
+        }
+
+        let dir = TestDir::new("supervise");
+        let node = Arc::new(RaftNode::new(test_config("n1", Vec::new(), &dir)).unwrap());
+        let (runs, mut run_started) = tokio::sync::mpsc::unbounded_channel();
+        let dropped = Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let supervisor = node.supervise("test task", {
+            let dropped = Arc::clone(&dropped);
+            move |_, restarts| {
+                let (runs, dropped) = (runs.clone(), Arc::clone(&dropped));
+                async move {
+                    runs.send(restarts).unwrap();
+                    if restarts == 0 {
+                        panic!("first run fails");
+                    }
+                    let _guard = Dropped(dropped);
+                    std::future::pending::<()>().await;
+                }
+            }
+        });
+
+        let wait = Duration::from_secs(5);
+        assert_eq!(timeout(wait, run_started.recv()).await.unwrap(), Some(0));
+        assert_eq!(timeout(wait, run_started.recv()).await.unwrap(), Some(1));
+        assert!(!dropped.load(Ordering::SeqCst));
+
+        // Aborting the supervisor, as shutdown does, stops the running task too
+        supervisor.abort();
+        let _ = supervisor.await;
+        timeout(wait, async {
+            while !dropped.load(Ordering::SeqCst) {
+                sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("the supervised task outlived its supervisor");
+    }
+
+    #[test]
+    fn a_log_with_another_init_entry_refuses_to_start() {
+        let dir = TestDir::new("init-entry");
+        // A node per case, so no case falls back to the previous one's backup
+        let start = |server_id: &str, log: Vec<LogEntry>| {
+            let saved = PersistentState { current_term: 1, voted_for: None, log };
+            RaftNode::write_state_file(&RaftNode::state_file_in(&dir.0, server_id), &saved).unwrap();
+            RaftNode::new(test_config(server_id, Vec::new(), &dir)).map(|_| ())
+        };
+
+        let err = start("n1", vec![entry(INIT_TERM, "OLD_INIT"), entry(1, "x")]).unwrap_err().to_string();
+        assert!(err.contains("starts with term 0 \"OLD_INIT\" at index 0"), "{}", err);
+        assert!(err.contains("incompatible version"), "{}", err);
+        assert!(start("n2", vec![entry(1, INIT_COMMAND)]).is_err(), "the init entry's term must match too");
+        let err = start("n3", Vec::new()).unwrap_err();
+        assert!(err.to_string().contains("(log is empty)"), "{}", err);
+        start("n4", vec![init_entry(), entry(1, "x")]).unwrap();
+    }
+
+    #[tokio::test]
+    async fn health_needs_contact_with_a_quorum_of_voters() {
+        let dir = TestDir::new("quorum-contact");
+        // A follower needs a recent heartbeat from a leader
+        let follower = RaftNode::new(test_config("n1", vec!["n2".to_string()], &dir)).unwrap();
+        assert!(!follower.quorum_contact().await);
+        follower.handle_raft_message(heartbeat(1, "n2")).await.unwrap();
+        assert!(follower.quorum_contact().await);
+
+        // A leader needs answers from enough voters; learners don't count
+        let peers = vec!["v2".to_string(), "v3".to_string(), "v4".to_string(), "v5".to_string(), "l6".to_string()];
+        let mut config = test_config("v1", peers, &dir);
+        config.learners = vec!["l6".to_string()];
+        let leader = RaftNode::new(config).unwrap();
+        {
+            let mut state = leader.state.lock().await;
+            state.current_term = 1;
+            state.role = ServerRole::Leader;
+            state.log.extend([entry(1, "a"), entry(1, "b"), entry(1, "c")]);
+            state.match_index.extend([("v2", 3), ("v3", 1), ("v4", 1), ("v5", 2), ("l6", 0)].map(|(peer, m)| (peer.to_string(), m)));
+        }
+        leader.record_rpc_result("l6", true);
+        leader.record_rpc_result("v2", true);
+        let unhealthy = NodeHealth::new(&leader.status().await, leader.quorum_contact().await, None);
+        assert!(!unhealthy.healthy);
+        assert_eq!(unhealthy.problems, vec!["leader has lost contact with a quorum of voters"]);
+        // The learner is furthest behind, but only voters' lag is reported
+        assert_eq!(unhealthy.max_replication_lag, Some(2));
+
+        leader.record_rpc_result("v3", true);
+        let healthy = NodeHealth::new(&leader.status().await, leader.quorum_contact().await, Some(0.5));
+        assert!(healthy.healthy && healthy.is_leader && healthy.problems.is_empty());
+        assert_eq!((healthy.current_term, healthy.load_score), (1, Some(0.5)));
+    }
+
+    #[tokio::test]
+    async fn commits_are_announced_without_waiting_for_a_heartbeat() {
+        let dir = TestDir::new("commit-announcer");
+        let follower = node_with_log(&dir, "n2", Vec::new(), vec![entry(1, "a")]);
+        follower.state.lock().await.current_term = 1;
+        let follower_addr = serve(Arc::clone(&follower)).await;
+
+        let leader = node_with_log(&dir, "n1", vec![follower_addr.clone()], vec![entry(1, "a")]);
+        {
+            let mut state = leader.state.lock().await;
+            state.current_term = 1;
+            state.role = ServerRole::Leader;
+            state.next_index.insert(follower_addr.clone(), 2);
+        }
+        // Only the announcer runs: no heartbeat sender could deliver the commit
+        let announcer = AbortOnDrop(tokio::spawn(Arc::clone(&leader).run_commit_announcer()));
+
+        {
+            let mut state = leader.state.lock().await;
+            state.match_index.insert(follower_addr, 1);
+            leader.advance_commit_index(&mut state);
+            assert_eq!(state.commit_index, 1);
+        }
+        let heartbeat_interval = Duration::from_millis(leader.config.heartbeat_interval);
+        timeout(heartbeat_interval, async {
+            while follower.state.lock().await.commit_index < 1 {
+                sleep(Duration::from_millis(1)).await;
+            }
+        })
+        .await
+        .expect("the follower should learn of the commit within a heartbeat interval");
+        drop(announcer);
+    }
+
+    #[tokio::test]
+    async fn propose_and_wait_wakes_on_apply_and_on_stepping_down() {
+        let dir = TestDir::new("propose-and-wait");
+        let leader = |node: &Arc<RaftNode>| {
+            let mut state = node.state.try_lock().unwrap();
+            state.current_term = 1;
+            state.role = ServerRole::Leader;
+        };
+
+        // Alone, the leader commits at once and the apply loop wakes the proposer
+        let solo = Arc::new(RaftNode::new(test_config("n1", Vec::new(), &dir)).unwrap());
+        leader(&solo);
+        let _apply = AbortOnDrop(tokio::spawn({
+            let solo = Arc::clone(&solo);
+            async move { solo.run_apply_loop().await }
+        }));
+        let applied = timeout(Duration::from_secs(1), solo.propose_and_wait("a".to_string(), Duration::from_secs(30)))
+            .await
+            .expect("should return as soon as the entry is applied");
+        assert_eq!(applied.unwrap(), Some(1));
+
+        // With its only peer down nothing commits, until a later term ends the wait
+        let node = Arc::new(RaftNode::new(test_config("n2", vec![dead_peer().await], &dir)).unwrap());
+        leader(&node);
+        let proposal = tokio::spawn({
+            let node = Arc::clone(&node);
+            async move { node.propose_and_wait("b".to_string(), Duration::from_secs(30)).await }
+        });
+        while node.state.lock().await.log.len() < 2 {
+            sleep(Duration::from_millis(1)).await;
+        }
+        node.handle_raft_message(heartbeat(2, "n3")).await;
+        let outcome = timeout(Duration::from_secs(1), proposal)
+            .await
+            .expect("should return as soon as the node steps down");
+        assert_eq!(outcome.unwrap().unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn three_nodes_commit_with_one_follower_down_but_not_with_two() {
+        let dir = TestDir::new("commit-quorum");
+        let leader = |server_id: &str, peers: Vec<String>| {
+            let node = Arc::new(RaftNode::new(test_config(server_id, peers, &dir)).unwrap());
+            {
+                let mut state = node.state.try_lock().unwrap();
+                state.current_term = 1;
+                state.role = ServerRole::Leader;
+            }
+            let apply = AbortOnDrop(tokio::spawn({
+                let node = Arc::clone(&node);
+                async move { node.run_apply_loop().await }
+            }));
+            (node, apply)
+        };
+
+        // One live follower and the leader itself are 2 of 3
+        let follower = node_with_log(&dir, "n2", Vec::new(), Vec::new());
+        let (node, _apply) = leader("n1", vec![serve(Arc::clone(&follower)).await, dead_peer().await]);
+        let applied = node.propose_and_wait("a".to_string(), Duration::from_secs(5)).await.unwrap();
+        assert_eq!(applied, Some(1));
+        assert_eq!(follower.state.lock().await.log, node.state.lock().await.log);
+
+        // With both followers down the leader alone is not a majority
+        let (node, _apply) = leader("n3", vec![dead_peer().await, dead_peer().await]);
+        let applied = node.propose_and_wait("b".to_string(), Duration::from_millis(300)).await.unwrap();
+        assert_eq!(applied, None, "the caller answers NOT_COMMITTED");
+        let state = node.state.lock().await;
+        assert_eq!((state.last_log_index(), state.commit_index), (1, 0));
+    }
+
+    #[tokio::test]
+    async fn reads_up_to_last_applied_never_see_an_unapplied_entry() {
+        let dir = TestDir::new("slow-apply");
+        let applied = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let node = RaftNode::new(test_config("n1", Vec::new(), &dir)).unwrap().with_apply_fn({
+            let applied = Arc::clone(&applied);
+            Arc::new(move |index, _| {
+                std::thread::sleep(Duration::from_millis(20));
+                applied.lock().unwrap().push(index);
+            })
+        });
+        let node = Arc::new(node);
+        {
+            let mut state = node.state.lock().await;
+            state.current_term = 1;
+            state.role = ServerRole::Leader;
+        }
+        let _apply = AbortOnDrop(tokio::spawn({
+            let node = Arc::clone(&node);
+            async move { node.run_apply_loop().await }
+        }));
+
+        // Alone, every proposal commits at once and the apply loop falls behind
+        for i in 0..5 {
+            assert!(node.propose_entry(format!("set {}", i)).await.unwrap().committed);
+        }
+
+        let mut saw_unapplied_commit = false;
+        timeout(Duration::from_secs(5), async {
+            loop {
+                let last_applied = node.get_last_applied().await;
+                let applied = applied.lock().unwrap().clone();
+                assert_eq!(applied[..last_applied as usize], (1..=last_applied).collect::<Vec<_>>());
+                saw_unapplied_commit |= node.get_commit_index().await > last_applied;
+                if last_applied == 5 {
+                    break;
+                }
+                sleep(Duration::from_millis(1)).await;
+            }
+        })
+        .await
+        .expect("every entry should be applied");
+        assert!(saw_unapplied_commit, "the reads should have overlapped a slow apply");
+    }
+
+    #[tokio::test]
+    async fn prior_term_entries_commit_once_the_new_leaders_noop_replicates() {
+        let dir = TestDir::new("noop-commit");
+        let follower = node_with_log(&dir, "n2", Vec::new(), vec![entry(1, "a")]);
+        follower.state.lock().await.current_term = 1;
+        // Holds each AppendEntries until released, then hands it to the follower
+        let release = Arc::new(Notify::new());
+        let follower_addr = fake_peer({
+            let (follower, release) = (Arc::clone(&follower), Arc::clone(&release));
+            move |message| {
+                let (follower, release) = (Arc::clone(&follower), Arc::clone(&release));
+                async move {
+                    release.notified().await;
+                    follower.handle_raft_message(message).await.unwrap()
+                }
+            }
+        })
+        .await;
+
+        let leader = node_with_log(&dir, "n1", vec![follower_addr.clone(), dead_peer().await], vec![entry(1, "a")]);
+        {
+            let mut state = leader.state.lock().await;
+            state.current_term = 2;
+            state.role = ServerRole::Candidate;
+        }
+        leader.become_leader(2).await;
+
+        // The term-1 entry is on a majority, but counting replicas can't commit it
+        {
+            let mut state = leader.state.lock().await;
+            assert_eq!(state.log[2], entry(2, NOOP_COMMAND));
+            state.match_index.insert(follower_addr.clone(), 1);
+            leader.advance_commit_index(&mut state);
+            assert_eq!(state.commit_index, 0);
+        }
+
+        release.notify_one();
+        timeout(Duration::from_secs(1), async {
+            while leader.get_commit_index().await < 2 {
+                sleep(Duration::from_millis(1)).await;
+            }
+        })
+        .await
+        .expect("the no-op should commit, and the term-1 entry with it");
+        assert_eq!(follower.state.lock().await.log, leader.state.lock().await.log);
+    }
+
+    #[tokio::test]
+    async fn sole_voter_commits_on_propose_and_two_nodes_need_both() {
+        let dir = TestDir::new("cluster-size");
+        let leader = |config: RaftConfig| {
+            let node = Arc::new(RaftNode::new(config).unwrap());
+            {
+                let mut state = node.state.try_lock().unwrap();
+                state.current_term = 1;
+                state.role = ServerRole::Leader;
+            }
+            node
+        };
+
+        // One node: committed as it's appended, before any AppendEntries round
+        let config = test_config("n1", Vec::new(), &dir);
+        assert_eq!((config.voters(), config.quorum(), config.fault_tolerance()), (1, 1, 0));
+        let solo = leader(config);
+        let proposal = solo.propose_entry("a".to_string()).await.unwrap();
+        assert!(proposal.committed);
+        assert_eq!((proposal.index, proposal.replicated), (1, 0));
+
+        // The same with a learner that is down: it doesn't count either way
+        let mut config = test_config("n2", vec![dead_peer().await], &dir);
+        config.learners = config.peers.clone();
+        let with_learner = leader(config);
+        assert!(with_learner.propose_entry("a".to_string()).await.unwrap().committed);
+
+        // Two nodes need both, so one down stops every commit
+        let config = test_config("n4", vec![dead_peer().await], &dir);
+        assert_eq!((config.voters(), config.quorum(), config.fault_tolerance()), (2, 2, 0));
+        let pair = leader(config);
+        let proposal = pair.propose_entry("a".to_string()).await.unwrap();
+        assert!(!proposal.committed);
+        assert_eq!(pair.get_commit_index().await, 0);
+    }
+}
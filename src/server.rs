@@ -0,0 +1,917 @@
+//! Client request handling shared by the server binaries.
+//!
+//! `server` balances load across the cluster and `server_No_load_Balancing`
+//! encrypts every request on the leader; both accept connections, speak the
+//! client protocol, encrypt, commit through Raft and reply with the code here,
+//! and only decide where each request's encryption runs.
+
+use crate::cache::EncryptionCache;
+use crate::raft::RaftNode;
+use crate::{check_unified_image, fit_unified_image, gunzip_frame, gzip_if_smaller, lsb, BadRequest, CombinedPayload, EncodeLoad, ImagePermissions, NodeHealth, PngCompression, RaftMessage, ServerStatus, UnifiedImageCheck, BATCH_ITEM_FAILED, BATCH_ITEM_OK, BATCH_MARKER, COMPRESSED_MARKER, FRAME_GZIP, FRAME_RAW, MAX_BATCH_SIZE, MAX_UNIFIED_OVERRIDE, MUX_CLIENT, MUX_RAFT, PROTOCOL_VERSION, UNIFIED_IMAGE_PATH, UNIFIED_OVERRIDE_MARKER, VERSION_REJECTED};
+use anyhow::{bail, Result};
+use log::{error, info, warn};
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::Semaphore;
+
+/// Bounds for client transfer socket buffers sized from the declared request
+const MIN_SOCKET_BUFFER: usize = 256 * 1024;
+const MAX_SOCKET_BUFFER: usize = 8 * 1024 * 1024;
+
+/// Set from --socket-buffer-kb, in bytes; 0 sizes the buffers to each request
+pub static SOCKET_BUFFER_BYTES: AtomicUsize = AtomicUsize::new(0);
+
+/// Set once the OS has been seen capping a buffer, so the hint is logged only once
+static SOCKET_BUFFER_CAPPED: AtomicBool = AtomicBool::new(false);
+
+/// Size a client connection's send and receive buffers for a transfer of
+/// about `declared` bytes (None when the request doesn't say, e.g. compressed
+/// or batch requests), or to --socket-buffer-kb if given. A refused or capped
+/// size is logged rather than failing the request.
+fn configure_large_transfer_socket(stream: &TcpStream, declared: Option<u64>) {
+    let size = match SOCKET_BUFFER_BYTES.load(Ordering::Relaxed) {
+        0 => declared.map_or(MAX_SOCKET_BUFFER, |bytes| {
+            usize::try_from(bytes).unwrap_or(usize::MAX).clamp(MIN_SOCKET_BUFFER, MAX_SOCKET_BUFFER)
+        }),
+        configured => configured,
+    };
+
+    let socket = socket2::SockRef::from(stream);
+    if let Err(e) = socket.set_send_buffer_size(size) {
+        warn!("Could not set SO_SNDBUF to {} bytes: {}", size, e);
+    }
+    if let Err(e) = socket.set_recv_buffer_size(size) {
+        warn!("Could not set SO_RCVBUF to {} bytes: {}", size, e);
+    }
+
+    // The OS may silently cap the size (Linux at net.core.rmem_max)
+    if let Ok(reported) = socket.recv_buffer_size() {
+        // Linux reports double what it keeps, for its own bookkeeping
+        let kept = if cfg!(target_os = "linux") { reported / 2 } else { reported };
+        if kept < size && !SOCKET_BUFFER_CAPPED.swap(true, Ordering::Relaxed) {
+            warn!("The OS capped SO_RCVBUF at {} bytes (asked for {}); raise net.core.rmem_max for larger buffers",
+                  kept, size);
+        }
+    }
+}
+
+/// How long the leader waits for a request's log entry to commit before giving up
+const COMMIT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How many images of one batch request are encrypted at the same time
+const BATCH_CONCURRENCY: usize = 4;
+
+/// How long a keepalive connection may sit idle before the server closes it
+const KEEPALIVE_IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Set when the unified image is fitted to each carrier; holds the optional max dimension
+pub static UNIFIED_IMAGE_FIT: OnceLock<Option<u32>> = OnceLock::new();
+
+/// PNG compression for encrypted images, from --png-compression
+pub static PNG_COMPRESSION: OnceLock<PngCompression> = OnceLock::new();
+
+/// Latest periodic check of the unified image, reported by the status endpoint
+static UNIFIED_IMAGE_CHECK: Mutex<Option<UnifiedImageCheck>> = Mutex::new(None);
+
+/// Set from --refuse-invalid-unified: turn new requests away while the last check failed
+pub static REFUSE_INVALID_UNIFIED: AtomicBool = AtomicBool::new(false);
+
+/// Set from --no-raft: no consensus at all, this node always acts as the leader
+pub static NO_RAFT: AtomicBool = AtomicBool::new(false);
+
+/// Slots for CPU-bound encryption, sized by --encode-threads
+pub static ENCODE_POOL: OnceLock<EncodePool> = OnceLock::new();
+
+/// Runs encryptions on the blocking pool, at most `threads` at a time, so a
+/// burst of requests queues here instead of taking over the blocking pool
+pub struct EncodePool {
+    threads: usize,
+    slots: Semaphore,
+    active: AtomicU64,
+    queued: AtomicU64,
+}
+
+impl EncodePool {
+    pub fn new(threads: usize) -> Self {
+        Self {
+            threads,
+            slots: Semaphore::new(threads),
+            active: AtomicU64::new(0),
+            queued: AtomicU64::new(0),
+        }
+    }
+
+    /// Wait for a free slot, then run `work` on the blocking pool
+    async fn run<T: Send + 'static>(&self, work: impl FnOnce() -> T + Send + 'static) -> Result<T> {
+        let permit = {
+            let _queued = Counted::new(&self.queued);
+            self.slots.acquire().await?
+        };
+        let _active = Counted::new(&self.active);
+        let result = tokio::task::spawn_blocking(work).await;
+        drop(permit);
+        Ok(result?)
+    }
+
+    fn load(&self) -> EncodeLoad {
+        EncodeLoad {
+            threads: self.threads as u64,
+            active: self.active.load(Ordering::Relaxed),
+            queued: self.queued.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Holds one count on a gauge, released on drop so a cancelled wait doesn't leak it
+struct Counted<'a>(&'a AtomicU64);
+
+impl<'a> Counted<'a> {
+    fn new(gauge: &'a AtomicU64) -> Self {
+        gauge.fetch_add(1, Ordering::Relaxed);
+        Self(gauge)
+    }
+}
+
+impl Drop for Counted<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// The encode pool, or one sized by default_encode_threads if main hasn't set it
+fn encode_pool() -> &'static EncodePool {
+    ENCODE_POOL.get_or_init(|| EncodePool::new(default_encode_threads()))
+}
+
+/// One encryption per CPU
+pub fn default_encode_threads() -> usize {
+    std::thread::available_parallelism().map(|n| n.get()).unwrap_or(4)
+}
+
+// =============================================================================
+// RAFT LISTENER
+// =============================================================================
+
+pub async fn start_raft_listener<H: RequestHandler>(
+    port: u16,
+    raft_node: Arc<RaftNode>,
+    cache: Arc<EncryptionCache>,
+    handler: Arc<H>,
+) -> Result<()> {
+    let bind_addr = format!("0.0.0.0:{}", port);
+    let listener = TcpListener::bind(&bind_addr).await?;
+    info!("Raft listener started on {}", bind_addr);
+
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                let raft_ref = Arc::clone(&raft_node);
+                let cache_ref = Arc::clone(&cache);
+                let handler_ref = Arc::clone(&handler);
+                tokio::spawn(async move {
+                    if let Err(e) = handle_raft_message(stream, raft_ref, cache_ref, handler_ref).await {
+                        error!("Error handling Raft message: {}", e);
+                    }
+                });
+            }
+            Err(e) => error!("Failed to accept Raft connection: {}", e),
+        }
+    }
+}
+
+/// On a shared port, tell a Raft connection from a client one by its first
+/// byte, consuming it if it's a discriminator. Clients that send no
+/// discriminator start with a u64 length, whose first byte is never MUX_RAFT.
+/// A connection that sends nothing for a second is left to the client handler.
+pub async fn is_raft_connection(stream: &mut TcpStream) -> bool {
+    let mut byte = [0u8; 1];
+    match tokio::time::timeout(Duration::from_secs(1), stream.peek(&mut byte)).await {
+        Ok(Ok(1)) if byte[0] == MUX_RAFT || byte[0] == MUX_CLIENT => {
+            let _ = stream.read_exact(&mut byte).await;
+            byte[0] == MUX_RAFT
+        }
+        _ => false,
+    }
+}
+
+pub async fn handle_raft_message<H: RequestHandler>(
+    mut stream: TcpStream,
+    raft_node: Arc<RaftNode>,
+    cache: Arc<EncryptionCache>,
+    handler: Arc<H>,
+) -> Result<()> {
+    // Read message
+    let msg_len = stream.read_u32().await?;
+    let mut msg_buf = vec![0u8; msg_len as usize];
+    stream.read_exact(&mut msg_buf).await?;
+    
+    let message: RaftMessage = serde_json::from_slice(&msg_buf)?;
+    
+    // Status requests are answered here since they include server-level counters
+    let response = match message {
+        RaftMessage::StatusRequest | RaftMessage::PingPeersRequest => {
+            // A ping request refreshes the peer round-trip times before reporting them
+            if matches!(message, RaftMessage::PingPeersRequest) {
+                raft_node.ping_peers().await;
+            }
+            Some(RaftMessage::StatusResponse {
+                status: ServerStatus {
+                    raft: raft_node.status().await,
+                    dedup_cache_hits: cache.hits(),
+                    dedup_cache_misses: cache.misses(),
+                    unified_image: UNIFIED_IMAGE_CHECK.lock().unwrap().clone(),
+                    encodes: encode_pool().load(),
+                },
+            })
+        }
+        RaftMessage::VerifyLogRequest => Some(RaftMessage::VerifyLogResponse {
+            report: raft_node.verify_log_consistency().await,
+        }),
+        RaftMessage::HealthRequest => Some(RaftMessage::HealthResponse {
+            health: NodeHealth::new(&raft_node.status().await, raft_node.quorum_contact().await, handler.load_score()),
+        }),
+        message => raft_node.handle_raft_message(message).await,
+    };
+
+    // Handle message and get response
+    if let Some(response) = response {
+        let response_json = serde_json::to_string(&response)?;
+        let response_bytes = response_json.as_bytes();
+        stream.write_u32(response_bytes.len() as u32).await?;
+        stream.write_all(response_bytes).await?;
+        stream.flush().await?;
+    }
+
+    Ok(())
+}
+
+// =============================================================================
+// CLIENT CONNECTIONS
+// =============================================================================
+
+/// Where a server binary runs the requests `serve_client` accepts
+pub trait RequestHandler: Send + Sync + 'static {
+    /// Handle one request. Returns true if it was answered with an image and
+    /// the connection can carry another request (see `serve_client`).
+    fn handle_request(&self, stream: &mut TcpStream) -> impl Future<Output = Result<bool>> + Send;
+
+    /// This node's load score for health replies; None without load balancing
+    fn load_score(&self) -> Option<f32> {
+        None
+    }
+}
+
+/// Serve a client connection: a single request, or with keepalive as many as
+/// the client sends for as long as each one is answered with an image. Any
+/// other reply closes the connection, since the request body may be unread.
+pub async fn serve_client<H: RequestHandler>(mut stream: TcpStream, handler: Arc<H>, keepalive: bool) -> Result<()> {
+    if !accept_protocol_version(&mut stream).await? {
+        return Ok(());
+    }
+    loop {
+        let reusable = handler.handle_request(&mut stream).await?;
+        if !keepalive || !reusable || !next_request_pending(&stream).await {
+            return Ok(());
+        }
+    }
+}
+
+/// Answer a versioned client's PROTOCOL_VERSION byte. Clients from before
+/// versioning open with a u64 length or marker (first byte 0x00 or 0xFF) and
+/// are served as before. False if the client's version was refused or it hung up.
+async fn accept_protocol_version(stream: &mut TcpStream) -> Result<bool> {
+    let mut byte = [0u8; 1];
+    if stream.peek(&mut byte).await? == 0 {
+        return Ok(false);
+    }
+    if byte[0] == 0x00 || byte[0] == 0xFF {
+        return Ok(true);
+    }
+
+    stream.read_exact(&mut byte).await?;
+    let version = byte[0];
+    if version == PROTOCOL_VERSION {
+        stream.write_all(&[PROTOCOL_VERSION]).await?;
+        stream.flush().await?;
+        return Ok(true);
+    }
+
+    let error_msg = format!(
+        "UNSUPPORTED_VERSION: client speaks protocol version {}, this server speaks {}",
+        version, PROTOCOL_VERSION
+    );
+    stream.write_all(&[VERSION_REJECTED]).await?;
+    stream.write_u64(error_msg.len() as u64).await?;
+    stream.write_all(error_msg.as_bytes()).await?;
+    stream.flush().await?;
+
+    warn!("Refused client speaking protocol version {}", version);
+    Ok(false)
+}
+
+/// Wait for a keepalive client's next request. False once the client closes
+/// the connection or leaves it idle for KEEPALIVE_IDLE_TIMEOUT.
+async fn next_request_pending(stream: &TcpStream) -> bool {
+    let mut byte = [0u8; 1];
+    matches!(tokio::time::timeout(KEEPALIVE_IDLE_TIMEOUT, stream.peek(&mut byte)).await, Ok(Ok(n)) if n > 0)
+}
+
+/// A single-image request whose permissions frame has been read; the image
+/// frame is still on the connection, see `read_request_image`
+pub struct RequestHead {
+    pub term: u64, // the leader's term when the request was accepted
+    pub meta: Vec<u8>,
+    pub compressed: bool, // COMPRESSED_MARKER framing, the reply may be gzipped
+    pub unified_override: Option<Vec<u8>>,
+}
+
+/// How far `open_request` got
+pub enum Opened {
+    /// A single-image request, for the caller to encrypt
+    Request(RequestHead),
+    /// Already answered (refused, or a batch); true if the connection can be reused
+    Answered(bool),
+}
+
+/// Accept a request if this node is the leader and the unified image is
+/// usable, and read it up to its image frame. Batch requests are handled
+/// here in full, on this node.
+pub async fn open_request(stream: &mut TcpStream, raft_node: &Arc<RaftNode>, cache: &Arc<EncryptionCache>) -> Result<Opened> {
+    // Check if this server is the leader
+    if !acts_as_leader(raft_node).await {
+        // Not the leader, inform client
+        reject_not_leader(stream, raft_node).await?;
+        return Ok(Opened::Answered(false));
+    }
+
+    if let Some(reason) = unified_image_refusal() {
+        let error_msg = format!("UNAVAILABLE: unified image is invalid: {}", reason);
+        stream.write_u64(error_msg.len() as u64).await?;
+        stream.write_all(error_msg.as_bytes()).await?;
+        stream.flush().await?;
+
+        info!("Refused client request, the unified image failed its last check");
+        return Ok(Opened::Answered(false));
+    }
+
+    // Remember the term we accepted the request in, so we can tell if leadership changed meanwhile
+    let term = raft_node.get_current_term().await;
+
+    // Read client request, which may open with its own unified image
+    let mut meta_size = stream.read_u64().await?;
+    let unified_override = if meta_size == UNIFIED_OVERRIDE_MARKER {
+        match read_unified_override(stream).await? {
+            Ok(unified_image) => {
+                meta_size = stream.read_u64().await?;
+                Some(unified_image)
+            }
+            Err(bad) => {
+                reject_bad_request(stream, &bad).await?;
+                return Ok(Opened::Answered(false));
+            }
+        }
+    } else {
+        None
+    };
+    if meta_size == BATCH_MARKER {
+        if unified_override.is_some() {
+            bail!("Client sent a unified image with a batch request");
+        }
+        configure_large_transfer_socket(stream, None);
+        return Ok(Opened::Answered(handle_batch(stream, raft_node, cache, term).await?));
+    }
+    let compressed = meta_size == COMPRESSED_MARKER;
+    let meta = if compressed {
+        configure_large_transfer_socket(stream, None);
+        read_flagged_frame(stream).await?
+    } else {
+        let mut meta = vec![0; meta_size as usize];
+        stream.read_exact(&mut meta).await?;
+        meta
+    };
+
+    Ok(Opened::Request(RequestHead { term, meta, compressed, unified_override }))
+}
+
+/// Read the image frame of an opened request
+pub async fn read_request_image(stream: &mut TcpStream, head: &RequestHead) -> Result<Vec<u8>> {
+    let img_buf = if head.compressed {
+        read_flagged_frame(stream).await?
+    } else {
+        // Size the TCP buffers for the image before it arrives
+        let img_size = stream.read_u64().await?;
+        configure_large_transfer_socket(stream, Some(img_size));
+        let mut img_buf = vec![0; img_size as usize];
+        stream.read_exact(&mut img_buf).await?;
+        img_buf
+    };
+
+    info!("Received client request (meta: {} bytes, image: {} bytes{}{})",
+          head.meta.len(), img_buf.len(), if head.compressed { ", compressed framing" } else { "" },
+          if head.unified_override.is_some() { ", own unified image" } else { "" });
+    Ok(img_buf)
+}
+
+/// Reply to a request with its encrypted image once that is committed on a
+/// majority, or tell the client why not. Returns true if the image was sent.
+pub async fn finish_request(
+    stream: &mut TcpStream,
+    raft_node: &Arc<RaftNode>,
+    head: &RequestHead,
+    result: Result<Vec<u8>>,
+) -> Result<bool> {
+    let request_term = head.term;
+
+    // A request that can't be encrypted is the client's to fix, tell it why
+    let result = match result {
+        Ok(result) => result,
+        Err(e) => match e.downcast_ref::<BadRequest>() {
+            Some(bad) => {
+                reject_bad_request(stream, bad).await?;
+                return Ok(false);
+            }
+            None => return Err(e),
+        },
+    };
+
+    // A new leader may have been elected while we were processing: don't confirm a stale write
+    if !still_leader_for(raft_node, request_term).await {
+        info!("Lost leadership during processing (accepted in term {})", request_term);
+        reject_not_leader(stream, raft_node).await?;
+        return Ok(false);
+    }
+
+    // Don't confirm to the client until the operation is committed on a majority
+    let committed_index = match commit_encryption(raft_node, &result).await {
+        Ok(Some(index)) => index,
+        Ok(None) | Err(_) if !still_leader_for(raft_node, request_term).await => {
+            info!("Lost leadership while waiting for commit (accepted in term {})", request_term);
+            reject_not_leader(stream, raft_node).await?;
+            return Ok(false);
+        }
+        Ok(None) | Err(_) => {
+            let error_msg = "NOT_COMMITTED: lost quorum before the request could be committed";
+            stream.write_u64(error_msg.len() as u64).await?;
+            stream.write_all(error_msg.as_bytes()).await?;
+            stream.flush().await?;
+
+            info!("Request not committed within {:?}, told client to retry", COMMIT_TIMEOUT);
+            return Ok(false);
+        }
+    };
+
+    // Send result back to client, followed by the committed log index
+    let sent = write_image_reply(stream, &result, head.compressed).await?;
+    stream.write_u64(committed_index).await?;
+    stream.flush().await?;
+    
+    info!("Sent result to client ({} bytes, {} on the wire, committed at index {})", result.len(), sent, committed_index);
+    Ok(true)
+}
+
+/// Read one frame of a COMPRESSED_MARKER request: a flag byte, then a
+/// length-prefixed body that is inflated if the flag says it's gzipped
+async fn read_flagged_frame(stream: &mut TcpStream) -> Result<Vec<u8>> {
+    let flag = stream.read_u8().await?;
+    let size = stream.read_u64().await?;
+    let mut buf = vec![0; size as usize];
+    stream.read_exact(&mut buf).await?;
+    match flag {
+        FRAME_RAW => Ok(buf),
+        FRAME_GZIP => gunzip_frame(&buf),
+        other => bail!("Unknown frame flag {}", other),
+    }
+}
+
+/// Read the PNG that follows UNIFIED_OVERRIDE_MARKER. An oversized one is the
+/// client's to fix, so it comes back as a BadRequest instead of being read.
+async fn read_unified_override(stream: &mut TcpStream) -> Result<std::result::Result<Vec<u8>, BadRequest>> {
+    let size = stream.read_u64().await?;
+    if size > MAX_UNIFIED_OVERRIDE {
+        return Ok(Err(BadRequest::Image(format!(
+            "unified image is {} bytes, the limit is {}", size, MAX_UNIFIED_OVERRIDE
+        ))));
+    }
+    let mut buf = vec![0; size as usize];
+    stream.read_exact(&mut buf).await?;
+    Ok(Ok(buf))
+}
+
+/// Write the encrypted image frame. If the client asked for compression and
+/// gzip actually shrinks the image, a "GZIP:<inflated length>" header frame
+/// goes first and the frame carries the compressed bytes. Returns the bytes sent.
+async fn write_image_reply(stream: &mut TcpStream, image: &[u8], compress: bool) -> Result<usize> {
+    let compressed = if compress { gzip_if_smaller(image) } else { None };
+    let body = match &compressed {
+        Some(gzipped) => {
+            let header = format!("GZIP:{}", image.len());
+            stream.write_u64(header.len() as u64).await?;
+            stream.write_all(header.as_bytes()).await?;
+            gzipped.as_slice()
+        }
+        None => image,
+    };
+    stream.write_u64(body.len() as u64).await?;
+    stream.write_all(body).await?;
+    Ok(body.len())
+}
+
+/// Handle a batch request (the metadata length was BATCH_MARKER). Items are
+/// encrypted concurrently, at most BATCH_CONCURRENCY at a time, and each one
+/// gets its own status in the reply so a bad image doesn't fail the others.
+async fn handle_batch(
+    stream: &mut TcpStream,
+    raft_node: &Arc<RaftNode>,
+    cache: &Arc<EncryptionCache>,
+    request_term: u64,
+) -> Result<bool> {
+    let count = stream.read_u32().await?;
+    if count == 0 || count > MAX_BATCH_SIZE {
+        let error_msg = format!("BAD_BATCH: batches carry 1 to {} images, got {}", MAX_BATCH_SIZE, count);
+        stream.write_u64(error_msg.len() as u64).await?;
+        stream.write_all(error_msg.as_bytes()).await?;
+        stream.flush().await?;
+        return Ok(false);
+    }
+
+    let mut items = Vec::with_capacity(count as usize);
+    for _ in 0..count {
+        let meta_size = stream.read_u64().await?;
+        let mut meta_buf = vec![0; meta_size as usize];
+        stream.read_exact(&mut meta_buf).await?;
+
+        let img_size = stream.read_u64().await?;
+        let mut img_buf = vec![0; img_size as usize];
+        stream.read_exact(&mut img_buf).await?;
+        items.push((meta_buf, img_buf));
+    }
+    info!("Received batch of {} images", count);
+
+    let start_time = Instant::now();
+    let limit = Arc::new(Semaphore::new(BATCH_CONCURRENCY));
+    let tasks: Vec<_> = items
+        .into_iter()
+        .map(|(meta_buf, img_buf)| {
+            let limit = Arc::clone(&limit);
+            let cache = Arc::clone(cache);
+            tokio::spawn(async move {
+                let _permit = limit.acquire_owned().await?;
+                process_encryption_work(&meta_buf, &img_buf, None, &cache).await
+            })
+        })
+        .collect();
+
+    let mut results = Vec::with_capacity(tasks.len());
+    for task in tasks {
+        results.push(task.await.map_err(anyhow::Error::from).and_then(|result| result));
+    }
+    info!("Batch processing completed in {}ms", start_time.elapsed().as_millis());
+
+    // Commit every encrypted image before confirming any of them
+    let mut replies = Vec::with_capacity(results.len());
+    for result in results {
+        if !still_leader_for(raft_node, request_term).await {
+            info!("Lost leadership during batch (accepted in term {})", request_term);
+            reject_not_leader(stream, raft_node).await?;
+            return Ok(false);
+        }
+
+        let reply = match result {
+            Ok(encrypted) => match commit_encryption(raft_node, &encrypted).await {
+                Ok(Some(index)) => Ok((encrypted, index)),
+                Ok(None) | Err(_) => Err("NOT_COMMITTED: lost quorum before the request could be committed".to_string()),
+            },
+            Err(e) => Err(e.to_string()),
+        };
+        replies.push(reply);
+    }
+
+    // A header frame, then per item a status byte and either the image and
+    // its committed index, or an error message
+    let header = format!("BATCH:{}", replies.len());
+    stream.write_u64(header.len() as u64).await?;
+    stream.write_all(header.as_bytes()).await?;
+
+    let mut failed = 0;
+    for reply in &replies {
+        match reply {
+            Ok((encrypted, index)) => {
+                stream.write_u8(BATCH_ITEM_OK).await?;
+                stream.write_u64(encrypted.len() as u64).await?;
+                stream.write_all(encrypted).await?;
+                stream.write_u64(*index).await?;
+            }
+            Err(error_msg) => {
+                failed += 1;
+                stream.write_u8(BATCH_ITEM_FAILED).await?;
+                stream.write_u64(error_msg.len() as u64).await?;
+                stream.write_all(error_msg.as_bytes()).await?;
+            }
+        }
+    }
+    stream.flush().await?;
+
+    info!("Sent batch results to client ({} ok, {} failed)", replies.len() - failed, failed);
+    Ok(true)
+}
+
+/// Tell the client we're not the leader, pointing it at the current leader if known
+async fn reject_not_leader(stream: &mut TcpStream, raft_node: &RaftNode) -> Result<()> {
+    // Prefer the leader's client-facing address so the client can connect to it directly
+    let leader_id = raft_node.get_leader_id().await;
+    let error_msg = match (raft_node.get_leader_addr().await, &leader_id) {
+        (Some(addr), _) => format!("NOT_LEADER:{}", addr),
+        (None, Some(id)) => format!("NOT_LEADER:{}", id),
+        (None, None) => "NO_LEADER".to_string(),
+    };
+
+    let error_bytes = error_msg.as_bytes();
+    stream.write_u64(error_bytes.len() as u64).await?;
+    stream.write_all(error_bytes).await?;
+    stream.flush().await?;
+
+    info!("Rejected client - not leader. Current leader: {:?}", leader_id);
+    Ok(())
+}
+
+/// Answer a request that can't succeed on retry with its BAD_* text frame
+async fn reject_bad_request(stream: &mut TcpStream, bad: &BadRequest) -> Result<()> {
+    let error_msg = bad.to_string();
+    stream.write_u64(error_msg.len() as u64).await?;
+    stream.write_all(error_msg.as_bytes()).await?;
+    stream.flush().await?;
+
+    info!("Rejected client request: {}", error_msg);
+    Ok(())
+}
+
+/// While leader, reload the unified image every `interval`, so a file that
+/// was replaced with something unusable is reported before a request fails on it
+pub async fn check_unified_image_periodically(raft_node: Arc<RaftNode>, interval: Duration) {
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+        if !acts_as_leader(&raft_node).await {
+            continue;
+        }
+
+        let error = match tokio::task::spawn_blocking(check_unified_image).await {
+            Ok(Ok(_)) => None,
+            Ok(Err(e)) => Some(e.to_string()),
+            Err(e) => Some(format!("check did not complete ({})", e)),
+        };
+        let checked_at = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+        let previous = UNIFIED_IMAGE_CHECK
+            .lock()
+            .unwrap()
+            .replace(UnifiedImageCheck { checked_at, error: error.clone() });
+
+        match error {
+            Some(e) => warn!("Unified image check failed: {}", e),
+            None if previous.is_some_and(|check| check.error.is_some()) => info!("Unified image is valid again"),
+            None => {}
+        }
+    }
+}
+
+/// Why new requests are turned away, if --refuse-invalid-unified is set and
+/// the last unified image check failed
+fn unified_image_refusal() -> Option<String> {
+    if !REFUSE_INVALID_UNIFIED.load(Ordering::Relaxed) {
+        return None;
+    }
+    UNIFIED_IMAGE_CHECK.lock().unwrap().as_ref()?.error.clone()
+}
+
+/// True if we're still the leader in the term the request was accepted in
+pub async fn still_leader_for(raft_node: &RaftNode, term: u64) -> bool {
+    NO_RAFT.load(Ordering::Relaxed) || (raft_node.is_leader().await && raft_node.get_current_term().await == term)
+}
+
+/// True if this node should serve client requests: the Raft leader, or any node under --no-raft
+async fn acts_as_leader(raft_node: &RaftNode) -> bool {
+    NO_RAFT.load(Ordering::Relaxed) || raft_node.is_leader().await
+}
+
+/// Commit the record of an encryption and return its log index. Under
+/// --no-raft nothing is committed and the index reported is 0.
+pub async fn commit_encryption(raft_node: &Arc<RaftNode>, encrypted: &[u8]) -> Result<Option<u64>> {
+    commit_encryption_digest(raft_node, &encryption_digest(encrypted)).await
+}
+
+/// `commit_encryption` for an image encrypted elsewhere, known by its digest
+pub async fn commit_encryption_digest(raft_node: &Arc<RaftNode>, digest: &str) -> Result<Option<u64>> {
+    if NO_RAFT.load(Ordering::Relaxed) {
+        return Ok(Some(0));
+    }
+    raft_node.propose_and_wait(format!("encrypt:{}", digest), COMMIT_TIMEOUT).await
+}
+
+/// Hex SHA-256 of an encrypted image, as recorded in the log
+pub fn encryption_digest(encrypted_image: &[u8]) -> String {
+    Sha256::digest(encrypted_image)
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+// =============================================================================
+// ENCRYPTION PROCESSING (USED BY BOTH MODES)
+// =============================================================================
+
+pub async fn process_encryption_work(
+    meta_buf: &[u8],
+    img_buf: &[u8],
+    unified_override: Option<&[u8]>,
+    cache: &Arc<EncryptionCache>,
+) -> Result<Vec<u8>> {
+    let meta_buf = meta_buf.to_vec();
+    let img_buf = img_buf.to_vec();
+    let unified_override = unified_override.map(<[u8]>::to_vec);
+    let cache = Arc::clone(cache);
+    
+    // Run CPU/IO intensive work on blocking thread pool, in one of the encode slots
+    encode_pool().run(move || {
+        let permissions = ImagePermissions::from_bytes(&meta_buf)
+            .map_err(|e| BadRequest::Metadata(format!("undecodable permissions ({})", e)))?;
+        permissions.validate().map_err(|e| BadRequest::Metadata(e.to_string()))?;
+
+        // A unified image sent with the request replaces ours; the file read
+        // is blocking I/O, but that won't block heartbeats anymore
+        let unified_image_bytes = match unified_override {
+            Some(png) => {
+                image::load_from_memory(&png)
+                    .map_err(|e| BadRequest::Image(format!("unified image sent with the request: {}", e)))?;
+                png
+            }
+            None => fs::read(UNIFIED_IMAGE_PATH)?,
+        };
+
+        // Identical requests produce identical output, so reuse a previous result
+        let cache_key = EncryptionCache::key(&img_buf, &permissions, &unified_image_bytes)?;
+        if let Some(cached) = cache.get(&cache_key) {
+            info!("Dedup cache hit, returning stored result ({} bytes)", cached.len());
+            return Ok(cached);
+        }
+
+        let img = image::load_from_memory(&img_buf).map_err(|e| BadRequest::Image(e.to_string()))?;
+
+        // Give the denied image whatever capacity the permissions leave over
+        let unified_image = match UNIFIED_IMAGE_FIT.get() {
+            Some(&max_dimension) => {
+                let overhead = CombinedPayload::overhead(&permissions)?;
+                let budget = lsb::capacity_bytes(&img).saturating_sub(overhead);
+                let fitted = fit_unified_image(&unified_image_bytes, budget, max_dimension)?;
+                if fitted.len() != unified_image_bytes.len() {
+                    info!("Shrunk unified image from {} to {} bytes to fit the carrier", unified_image_bytes.len(), fitted.len());
+                }
+                fitted
+            }
+            None => unified_image_bytes,
+        };
+
+        let combined_payload = CombinedPayload {
+            permissions,
+            unified_image,
+        };
+        
+        let final_payload = combined_payload.to_bytes()?;
+        // Plain encode only fails when the payload doesn't fit
+        let encoded_img = lsb::encode(&img, &final_payload).map_err(|e| BadRequest::Capacity(e.to_string()))?;
+        
+        // Simulate work
+        // std::thread::sleep(std::time::Duration::from_secs(5));
+        
+        let out_buf = PNG_COMPRESSION.get().copied().unwrap_or_default().encode(&encoded_img)?;
+
+        cache.insert(cache_key, out_buf.clone());
+        
+        Ok::<Vec<u8>, anyhow::Error>(out_buf)
+    })
+    .await?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::raft::{RaftConfig, TimeoutDistribution, DEFAULT_MAX_RPC_BYTES};
+    use crate::ServerRole;
+
+    /// A Raft node with no peers, keeping its state in a fresh scratch directory
+    fn raft_node(name: &str) -> RaftNode {
+        let data_dir = std::env::temp_dir().join(format!("server-test-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&data_dir);
+        RaftNode::new(RaftConfig {
+            server_id: "n1".to_string(),
+            peers: Vec::new(),
+            election_timeout_min: 150,
+            election_timeout_max: 300,
+            election_timeout_distribution: TimeoutDistribution::Uniform,
+            heartbeat_interval: 50,
+            election_tick: 10,
+            data_dir,
+            advertised_addr: None,
+            election_seed: Some(1),
+            max_rpc_bytes: DEFAULT_MAX_RPC_BYTES,
+            single_port: false,
+            learner: false,
+            learners: Vec::new(),
+            trace_roles: false,
+            trace_file: None,
+            initial_leader: None,
+        })
+        .unwrap()
+    }
+
+    /// Read one length-prefixed text reply, as the client does
+    async fn read_text_frame(stream: &mut TcpStream) -> String {
+        let len = stream.read_u64().await.unwrap();
+        let mut reply = vec![0u8; len as usize];
+        stream.read_exact(&mut reply).await.unwrap();
+        String::from_utf8(reply).unwrap()
+    }
+
+    /// Both ends of a loopback connection
+    async fn connected_pair() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (server_side, _) = listener.accept().await.unwrap();
+        (client, server_side)
+    }
+
+    #[tokio::test]
+    async fn flagged_frames_and_gzip_replies_round_trip() {
+        let (mut client, mut server_side) = connected_pair().await;
+        let image = vec![42u8; 10_000];
+
+        // Requests: a gzipped frame, a raw one, then an unknown flag
+        let gzipped = gzip_if_smaller(&image).unwrap();
+        for (flag, body) in [(FRAME_GZIP, gzipped.as_slice()), (FRAME_RAW, image.as_slice()), (7, b"x".as_slice())] {
+            client.write_u8(flag).await.unwrap();
+            client.write_u64(body.len() as u64).await.unwrap();
+            client.write_all(body).await.unwrap();
+        }
+        assert_eq!(read_flagged_frame(&mut server_side).await.unwrap(), image);
+        assert_eq!(read_flagged_frame(&mut server_side).await.unwrap(), image);
+        assert_eq!(read_flagged_frame(&mut server_side).await.unwrap_err().to_string(), "Unknown frame flag 7");
+
+        // Replies: gzipped behind a GZIP:<len> header only when asked for and smaller
+        let sent = write_image_reply(&mut server_side, &image, true).await.unwrap();
+        assert_eq!(read_text_frame(&mut client).await, "GZIP:10000");
+        let len = client.read_u64().await.unwrap();
+        assert_eq!((len as usize, sent), (gzipped.len(), gzipped.len()));
+        let mut body = vec![0u8; len as usize];
+        client.read_exact(&mut body).await.unwrap();
+        assert_eq!(gunzip_frame(&body).unwrap(), image);
+
+        let noise: Vec<u8> = (0..4096).map(|_| rand::random()).collect();
+        for (reply, compress) in [(&image, false), (&noise, true)] {
+            assert_eq!(write_image_reply(&mut server_side, reply, compress).await.unwrap(), reply.len());
+            let len = client.read_u64().await.unwrap();
+            let mut body = vec![0u8; len as usize];
+            client.read_exact(&mut body).await.unwrap();
+            assert_eq!(&body, reply);
+        }
+    }
+
+    #[tokio::test]
+    async fn still_leader_for_requires_the_same_term() {
+        let node = raft_node("still-leader");
+        {
+            let mut state = node.state.lock().await;
+            state.current_term = 4;
+            state.role = ServerRole::Leader;
+        }
+        assert!(still_leader_for(&node, 4).await);
+        assert!(!still_leader_for(&node, 3).await, "re-elected in a later term since the request arrived");
+
+        node.state.lock().await.role = ServerRole::Follower;
+        assert!(!still_leader_for(&node, 4).await);
+        let _ = fs::remove_dir_all(&node.config.data_dir);
+    }
+
+    #[tokio::test]
+    async fn reject_not_leader_points_at_the_leader() {
+        let node = raft_node("reject-not-leader");
+        node.state.lock().await.leader_id = Some("n2".to_string());
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (mut server_side, _) = listener.accept().await.unwrap();
+
+        reject_not_leader(&mut server_side, &node).await.unwrap();
+        assert_eq!(read_text_frame(&mut client).await, "NOT_LEADER:n2");
+
+        // The client-facing address is preferred, so the client can connect to it
+        node.state.lock().await.leader_addr = Some("10.0.0.2:8080".to_string());
+        reject_not_leader(&mut server_side, &node).await.unwrap();
+        assert_eq!(read_text_frame(&mut client).await, "NOT_LEADER:10.0.0.2:8080");
+        let _ = fs::remove_dir_all(&node.config.data_dir);
+    }
+}